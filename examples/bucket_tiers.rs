@@ -0,0 +1,25 @@
+//! A sample application aggregating the same metrics at two resolutions,
+//! printing fine-grained stats every second and coarse stats every five seconds.
+
+use dipstick::*;
+use std::thread::sleep;
+use std::time::Duration;
+
+fn main() {
+    let fine = AtomicBucket::new();
+    fine.drain(Stream::write_to_stdout().named("fine"));
+
+    let coarse = AtomicBucket::new();
+    coarse.drain(Stream::write_to_stdout().named("coarse"));
+
+    let app_metrics = TieredBucket::new()
+        .add_tier(Duration::from_secs(1), fine)
+        .add_tier(Duration::from_secs(5), coarse);
+    let _handles = app_metrics.flush_tiers();
+
+    let counter = app_metrics.counter("counter_a");
+    loop {
+        counter.count(11);
+        sleep(Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,257 @@
+//! Own a scope on a dedicated thread, for scopes that can be sent but not shared between threads.
+//! Metric definition, writes and flushes are all performed by the owner thread, in order.
+//! Unlike the asynchronous queue, flushing waits for the owner thread and returns its result.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::{Input, InputKind, InputMetric, InputScope};
+use crate::label::Labels;
+use crate::name::MetricName;
+use crate::{CachedInput, Flush, MetricValue, QueuedInput};
+
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::time::SystemTime;
+use std::{io, thread};
+
+/// Default number of commands that can be waiting for the owner thread before callers block.
+pub const DEFAULT_ACTOR_QUEUE_LENGTH: usize = 1024;
+
+/// Wrap this scope behind a dedicated owner thread, sharing it through a `Send + Sync` handle.
+/// An alternative to locking for scopes that are `Send` but not `Sync`.
+pub trait ActorScope: InputScope + Send + 'static + Sized {
+    /// Move this scope to a dedicated thread, returning a shareable handle to it.
+    fn actor(self) -> InputActorScope {
+        InputActorScope::new(self, DEFAULT_ACTOR_QUEUE_LENGTH)
+    }
+}
+
+impl<S: InputScope + Send + 'static> ActorScope for S {}
+
+/// Commands executed by the owner thread.
+enum ActorCmd {
+    Define(MetricName, InputKind, mpsc::Sender<InputMetric>),
+    Write(InputMetric, MetricValue, Labels),
+    WriteFloat(InputMetric, f64, Labels),
+    WriteWeighted(InputMetric, MetricValue, usize, Labels),
+    WriteAt(InputMetric, SystemTime, MetricValue, Labels),
+    Flush(mpsc::Sender<io::Result<()>>),
+}
+
+/// # Panics
+///
+/// Panics if the OS fails to create a thread.
+fn new_actor<S: InputScope + Send + 'static>(
+    scope: S,
+    length: usize,
+) -> Arc<mpsc::SyncSender<ActorCmd>> {
+    let (sender, receiver) = mpsc::sync_channel::<ActorCmd>(length);
+
+    thread::Builder::new()
+        .name("dipstick-actor".to_string())
+        .spawn(move || {
+            // loop ends when every handle and metric has been dropped
+            for cmd in receiver {
+                match cmd {
+                    ActorCmd::Define(name, kind, reply) => {
+                        let _ = reply.send(scope.new_metric(name, kind));
+                    }
+                    ActorCmd::Write(metric, value, labels) => metric.write(value, labels),
+                    ActorCmd::WriteFloat(metric, value, labels) => metric.write_f64(value, labels),
+                    ActorCmd::WriteWeighted(metric, value, weight, labels) => {
+                        metric.write_n(value, weight, labels)
+                    }
+                    ActorCmd::WriteAt(metric, timestamp, value, labels) => {
+                        metric.write_at(timestamp, value, labels)
+                    }
+                    ActorCmd::Flush(reply) => {
+                        let _ = reply.send(scope.flush());
+                    }
+                }
+            }
+        })
+        .unwrap(); // TODO: Panic, change API to return Result?
+    Arc::new(sender)
+}
+
+fn actor_gone<E>(_: E) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "scope actor thread is gone")
+}
+
+/// A shareable handle to a scope owned by a dedicated thread.
+#[derive(Clone)]
+pub struct InputActorScope {
+    attributes: Attributes,
+    sender: Arc<mpsc::SyncSender<ActorCmd>>,
+}
+
+impl InputActorScope {
+    /// Move the scope to a new dedicated thread.
+    /// Up to `queue_length` commands can be waiting for the thread before callers block.
+    pub fn new<S: InputScope + Send + 'static>(scope: S, queue_length: usize) -> Self {
+        InputActorScope {
+            attributes: Attributes::default(),
+            sender: new_actor(scope, queue_length),
+        }
+    }
+}
+
+impl QueuedInput for InputActorScope {}
+impl CachedInput for InputActorScope {}
+
+impl WithAttributes for InputActorScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+/// All scopes opened from the handle share the owner thread's scope.
+impl Input for InputActorScope {
+    type SCOPE = InputActorScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        self.clone()
+    }
+}
+
+impl InputScope for InputActorScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let (reply, defined) = mpsc::channel();
+        let target_metric = match self
+            .sender
+            .send(ActorCmd::Define(name.clone(), kind, reply))
+            .map_err(actor_gone)
+            .and_then(|_| defined.recv().map_err(actor_gone))
+        {
+            Ok(metric) => metric,
+            Err(e) => {
+                debug!("Failed to define actor metric {:?}: {}", name, e);
+                return InputMetric::new(MetricId::forge("actor", name), |_, _| {});
+            }
+        };
+        let sender = self.sender.clone();
+        let float_metric = target_metric.clone();
+        let float_sender = self.sender.clone();
+        let timestamped_metric = target_metric.clone();
+        let timestamped_sender = self.sender.clone();
+        let weighted_metric = target_metric.clone();
+        let weighted_sender = self.sender.clone();
+        InputMetric::new(MetricId::forge("actor", name), move |value, mut labels| {
+            labels.save_context();
+            if let Err(e) = sender.send(ActorCmd::Write(target_metric.clone(), value, labels)) {
+                debug!("Failed to send actor metric: {}", e);
+            }
+        })
+        .with_float(move |value, mut labels| {
+            labels.save_context();
+            let cmd = ActorCmd::WriteFloat(float_metric.clone(), value, labels);
+            if let Err(e) = float_sender.send(cmd) {
+                debug!("Failed to send actor metric: {}", e);
+            }
+        })
+        .with_timestamped(move |timestamp, value, mut labels| {
+            labels.save_context();
+            let cmd = ActorCmd::WriteAt(timestamped_metric.clone(), timestamp, value, labels);
+            if let Err(e) = timestamped_sender.send(cmd) {
+                debug!("Failed to send actor metric: {}", e);
+            }
+        })
+        .with_weighted(move |value, weight, mut labels| {
+            labels.save_context();
+            let cmd = ActorCmd::WriteWeighted(weighted_metric.clone(), value, weight, labels);
+            if let Err(e) = weighted_sender.send(cmd) {
+                debug!("Failed to send actor metric: {}", e);
+            }
+        })
+    }
+}
+
+impl Flush for InputActorScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        let (reply, flushed) = mpsc::channel();
+        self.sender
+            .send(ActorCmd::Flush(reply))
+            .map_err(actor_gone)?;
+        flushed.recv().map_err(actor_gone)?
+    }
+}
+
+#[cfg(feature = "bench")]
+mod bench {
+
+    use super::*;
+    use crate::output::map::StatsMapScope;
+    use std::sync::Mutex;
+
+    #[bench]
+    fn actor_write(b: &mut test::Bencher) {
+        let actor = StatsMapScope::default().actor();
+        let counter = actor.counter("aaa");
+        b.iter(|| test::black_box(counter.count(1)));
+        actor.flush().unwrap();
+    }
+
+    #[bench]
+    fn mutex_write(b: &mut test::Bencher) {
+        let locked = Mutex::new(StatsMapScope::default());
+        let counter = locked.lock().unwrap().counter("aaa");
+        b.iter(|| test::black_box(locked.lock().map(|_| counter.count(1))));
+    }
+}
+
+#[cfg(test)]
+mod mtest {
+    use super::*;
+    use crate::input::ShareScope;
+
+    use std::cell::RefCell;
+    use std::sync::atomic::{AtomicIsize, Ordering};
+
+    /// Keeps its definitions in a `RefCell`, so can not be shared between threads.
+    #[derive(Default)]
+    struct RawScope {
+        defined: RefCell<Vec<String>>,
+        sum: Arc<AtomicIsize>,
+        flushed: Arc<AtomicIsize>,
+    }
+
+    impl InputScope for RawScope {
+        fn new_metric(&self, name: MetricName, _kind: InputKind) -> InputMetric {
+            self.defined.borrow_mut().push(name.join("."));
+            let sum = self.sum.clone();
+            InputMetric::new(MetricId::forge("raw", name), move |value, _labels| {
+                sum.fetch_add(value, Ordering::Relaxed);
+            })
+        }
+    }
+
+    impl ShareScope for RawScope {}
+
+    impl Flush for RawScope {
+        fn flush(&self) -> io::Result<()> {
+            let count = self.defined.borrow().len() as isize;
+            self.flushed
+                .store(self.sum.load(Ordering::Relaxed) * count, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn flush_after_writes() {
+        let raw = RawScope::default();
+        let flushed = raw.flushed.clone();
+        let actor = raw.actor().named("app");
+        let handle = actor.clone();
+        thread::spawn(move || handle.counter("a").count(3))
+            .join()
+            .unwrap();
+        actor.counter("b").count(4);
+        actor.flush().unwrap();
+        // two definitions, seven total
+        assert_eq!(14, flushed.load(Ordering::Relaxed));
+    }
+}
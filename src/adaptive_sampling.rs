@@ -0,0 +1,241 @@
+//! Sample values of busy metrics, keeping each metric's forwarded writes within a budget of writes per second.
+//! Throughput is measured over one-second windows; the sampling rate for the next window is the budget divided by
+//! the observed throughput, and goes back to 1.0 (no sampling) once the load drops below the budget.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::clock::TimeHandle;
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::label_sampling::sampled_metric;
+use crate::name::MetricName;
+use crate::pcg32;
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::*;
+use std::sync::Arc;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// Length of the window over which throughput is measured.
+const WINDOW_US: u64 = 1_000_000;
+
+/// Throughput and current sampling rate of a metric.
+struct Throughput {
+    budget: f64,
+    epoch: TimeHandle,
+    window_start: AtomicU64,
+    writes: AtomicU64,
+    rate: AtomicU64,
+}
+
+impl Throughput {
+    fn new(budget: f64) -> Self {
+        Throughput {
+            budget,
+            epoch: TimeHandle::now(),
+            window_start: AtomicU64::new(0),
+            writes: AtomicU64::new(0),
+            rate: AtomicU64::new(1.0f64.to_bits()),
+        }
+    }
+
+    /// Count a write and return its weight if it is accepted.
+    fn sample(&self) -> Option<usize> {
+        self.writes.fetch_add(1, Relaxed);
+        let now = self.epoch.elapsed_us();
+        let start = self.window_start.load(Relaxed);
+        let elapsed = now.saturating_sub(start);
+        if elapsed >= WINDOW_US
+            && self
+                .window_start
+                .compare_exchange(start, now, AcqRel, Relaxed)
+                .is_ok()
+        {
+            let writes = self.writes.swap(0, AcqRel);
+            let per_sec = writes as f64 * 1_000_000.0 / elapsed as f64;
+            let rate = if per_sec > self.budget {
+                self.budget / per_sec
+            } else {
+                1.0
+            };
+            self.rate.store(rate.to_bits(), Relaxed);
+        }
+
+        pcg32::sample_weight(f64::from_bits(self.rate.load(Relaxed)))
+    }
+}
+
+/// Throughput of metrics by name, shared by all scopes of an input.
+type ThroughputMap = Arc<RwLock<HashMap<MetricName, Arc<Throughput>>>>;
+
+fn throughput_of(map: &ThroughputMap, name: &MetricName, budget: f64) -> Arc<Throughput> {
+    if let Some(throughput) = read_lock!(map).get(name) {
+        return throughput.clone();
+    }
+    write_lock!(map)
+        .entry(name.clone())
+        .or_insert_with(|| Arc::new(Throughput::new(budget)))
+        .clone()
+}
+
+/// Sample values of metrics exceeding a budget of writes per second.
+pub trait AdaptiveSampledInput: Input + Send + Sync + 'static + Sized {
+    /// Wrap this input with a decorator sampling each metric down to at most `budget` writes per second.
+    fn sampled_adaptively(self, budget: f64) -> InputAdaptiveSampling {
+        InputAdaptiveSampling::wrap(self, budget)
+    }
+}
+
+impl<T: Input + Send + Sync + 'static> AdaptiveSampledInput for T {}
+
+/// Input decorator sampling values of busy metrics.
+#[derive(Clone)]
+pub struct InputAdaptiveSampling {
+    attributes: Attributes,
+    target: Arc<dyn InputDyn + Send + Sync + 'static>,
+    budget: f64,
+    throughput: ThroughputMap,
+}
+
+impl InputAdaptiveSampling {
+    /// Wrap an input with a decorator sampling each metric down to at most `budget` writes per second.
+    pub fn wrap<OUT: Input + Send + Sync + 'static>(
+        target: OUT,
+        budget: f64,
+    ) -> InputAdaptiveSampling {
+        assert!(budget > 0.0);
+        InputAdaptiveSampling {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            budget,
+            throughput: ThroughputMap::default(),
+        }
+    }
+}
+
+impl QueuedInput for InputAdaptiveSampling {}
+impl CachedInput for InputAdaptiveSampling {}
+
+impl WithAttributes for InputAdaptiveSampling {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for InputAdaptiveSampling {
+    type SCOPE = InputAdaptiveSamplingScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        InputAdaptiveSamplingScope {
+            attributes: self.attributes.clone(),
+            target: self.target.input_dyn(),
+            budget: self.budget,
+            throughput: self.throughput.clone(),
+        }
+    }
+}
+
+/// Scope decorator sampling values of metrics exceeding a budget of writes per second.
+/// Accepted values are weighted as by `InputLabelSamplingScope`, with the inverse of the rate
+/// in effect when they were written.
+#[derive(Clone)]
+pub struct InputAdaptiveSamplingScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    budget: f64,
+    throughput: ThroughputMap,
+}
+
+impl InputAdaptiveSamplingScope {
+    /// Wrap a scope with a decorator sampling each metric down to at most `budget` writes per second.
+    pub fn wrap<SC: InputScope + Send + Sync + 'static>(target: SC, budget: f64) -> Self {
+        assert!(budget > 0.0);
+        InputAdaptiveSamplingScope {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            budget,
+            throughput: ThroughputMap::default(),
+        }
+    }
+}
+
+impl WithAttributes for InputAdaptiveSamplingScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for InputAdaptiveSamplingScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let target = self.target.new_metric(name.clone(), kind);
+        let throughput = throughput_of(&self.throughput, &name, self.budget);
+        sampled_metric(
+            MetricId::forge("adaptive_sampling", name),
+            target,
+            kind,
+            move |_labels| throughput.sample(),
+        )
+    }
+}
+
+impl Flush for InputAdaptiveSamplingScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::atomic::AtomicBucket;
+    use crate::clock::{mock_clock_advance, mock_clock_reset};
+    use crate::output::map::StatsMapScope;
+    use crate::stats::stats_all;
+    use std::time::Duration;
+
+    #[test]
+    fn sample_over_budget() {
+        mock_clock_reset();
+        let bucket = AtomicBucket::new();
+        bucket.stats(stats_all);
+        let metrics = InputAdaptiveSamplingScope::wrap(bucket.clone(), 100.0);
+        let busy = metrics.counter("busy");
+        let quiet = metrics.counter("quiet");
+
+        // first window is unsampled and measures 1000 writes/s
+        for _ in 0..1000 {
+            busy.write(1, labels![]);
+        }
+        mock_clock_advance(Duration::from_secs(1));
+        busy.write(1, labels![]);
+        quiet.write(1, labels![]);
+
+        // next window samples at 10%, each accepted value counting for 10
+        for _ in 0..10_000 {
+            busy.write(1, labels![]);
+        }
+
+        let map = StatsMapScope::default();
+        bucket.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(1, map["quiet.count"]);
+        // weighted values keep estimating the actual count and sum
+        let count = map["busy.count"];
+        assert!(count > 8000 && count < 14000, "{}", count);
+        assert_eq!(count, map["busy.sum"]);
+    }
+}
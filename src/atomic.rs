@@ -1,21 +1,29 @@
 //! Maintain aggregated metrics for deferred reporting,
 
-use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
-use crate::clock::TimeHandle;
+use crate::attributes::{
+    Attributes, ClockedTimers, FlushTally, MetricId, OnFlush, Prefixed, ReportFlush, WithAttributes,
+};
+use crate::clock::{Clock, TimeHandle};
+use crate::hll::{AtomicHyperLogLog, HyperLogLog, DEFAULT_PRECISION};
 use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::label::Labels;
 use crate::name::MetricName;
+use crate::output::map::StatsMapScope;
 use crate::stats::ScoreType::*;
 use crate::stats::{stats_summary, ScoreType};
+use crate::tdigest::TDigest;
+use crate::topk::SpaceSaving;
 use crate::{Flush, MetricValue, Void};
 
 use std::borrow::Borrow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
 use std::isize;
 use std::mem;
-use std::sync::atomic::AtomicIsize;
 use std::sync::atomic::Ordering::*;
-use std::sync::Arc;
-use std::{fmt, io};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, AtomicUsize};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError, Weak};
+use std::time::{Duration, Instant};
+use std::{fmt, io, thread};
 
 #[cfg(not(feature = "parking_lot"))]
 use std::sync::RwLock;
@@ -40,14 +48,65 @@ lazy_static! {
         RwLock::new(Arc::new(initial_stats()));
     static ref DEFAULT_AGGREGATE_INPUT: RwLock<Arc<dyn InputDyn + Send + Sync>> =
         RwLock::new(initial_drain());
+
+    /// Buckets given a drain, to be flushed one last time upon shutdown.
+    static ref LIVE_BUCKETS: Mutex<Vec<LiveBucket>> = Mutex::new(Vec::new());
+}
+
+/// A bucket given a drain, held weakly.
+struct LiveBucket {
+    attributes: Attributes,
+    inner: Weak<RwLock<InnerAtomicBucket>>,
+    publishing: Weak<RwLock<()>>,
+}
+
+/// Buckets given a drain that are still alive, to be flushed upon shutdown.
+pub(crate) fn live_buckets() -> Vec<AtomicBucket> {
+    LIVE_BUCKETS
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .filter_map(|live| {
+            Some(AtomicBucket {
+                attributes: live.attributes.clone(),
+                inner: live.inner.upgrade()?,
+                publishing: live.publishing.upgrade()?,
+            })
+        })
+        .collect()
 }
 
+/// How labeled variants of a metric are ranked by `AtomicBucket::top_k`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TopBy {
+    /// Number of values written
+    Count,
+    /// Sum of values written, negative values are ignored
+    Sum,
+}
+
+/// What is published in place of rates computed over a period shorter than `AtomicBucket::min_rate_period`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateGuard {
+    /// Publish no rate
+    Skip,
+    /// Compute the rate over the minimum period instead
+    Clamp,
+    /// Publish the period's raw count (or sum) as the rate
+    RawCount,
+}
+
+/// Minimum period in seconds and what to publish for shorter periods.
+type MinRatePeriod = Option<(f64, RateGuard)>;
+
 /// Central aggregation structure.
 /// Maintains a list of metrics for enumeration when used as source.
 #[derive(Debug, Clone, Default)]
 pub struct AtomicBucket {
     attributes: Attributes,
     inner: Arc<RwLock<InnerAtomicBucket>>,
+    /// Keeps concurrent flushes publishing in the order their snapshots were taken
+    publishing: Arc<RwLock<()>>,
 }
 
 #[derive(Default)]
@@ -57,6 +116,73 @@ struct InnerAtomicBucket {
     stats: Option<Arc<StatsFn>>,
     drain: Option<Arc<dyn InputDyn + Send + Sync + 'static>>,
     publish_metadata: bool,
+    window: Option<Window>,
+    ewma: bool,
+    deltas: bool,
+    digest_compression: Option<f64>,
+    quantiles: Vec<f64>,
+    gauge_max_age: Option<Duration>,
+    idle_ttl: Option<Duration>,
+    repeat_min_max: bool,
+    top_k: Option<(usize, TopBy)>,
+    time_weighted: bool,
+    min_rate_period: MinRatePeriod,
+    max_variants: Option<usize>,
+    sharded_metrics: BTreeSet<MetricName>,
+    auto_shard: Option<usize>,
+    cumulative: bool,
+    cumulative_metrics: BTreeMap<MetricName, bool>,
+    tally: Arc<FlushTally>,
+}
+
+/// Statistics taken from a bucket, published after its lock has been released.
+/// Slow outputs then do not block the definition of metrics while publishing.
+struct Published {
+    snapshot: Vec<(MetricName, InputKind, Vec<ScoreType>)>,
+    /// Heaviest labeled variants of metrics, published as is
+    top: Vec<(MetricName, MetricValue)>,
+    /// Separately aggregated labeled variants of metrics, published with their labels
+    labeled: Vec<(MetricName, InputKind, Labels, Vec<ScoreType>)>,
+    stats_fn: Arc<StatsFn>,
+    tally: Arc<FlushTally>,
+}
+
+impl Published {
+    fn publish_to(&self, target: &dyn InputScope) -> io::Result<()> {
+        if self.snapshot.is_empty() && self.top.is_empty() && self.labeled.is_empty() {
+            // no data was collected for this period
+            // TODO update some canary metric ?
+            return Ok(());
+        }
+        for (name, kind, scores) in &self.snapshot {
+            for score in scores {
+                if let Some(stat) = (self.stats_fn)(*kind, name.clone(), *score) {
+                    // TODO provide some stats context through labels?
+                    write_stat(target, stat, *score, scores, labels![]);
+                    self.tally.sent(1, 0);
+                }
+            }
+        }
+        for (name, kind, labels, scores) in &self.labeled {
+            for score in scores {
+                if let Some(stat) = (self.stats_fn)(*kind, name.clone(), *score) {
+                    write_stat(target, stat, *score, scores, labels.clone());
+                    self.tally.sent(1, 0);
+                }
+            }
+        }
+        for (name, value) in &self.top {
+            target
+                .new_metric(name.clone(), InputKind::Gauge)
+                .write(*value, labels![]);
+            self.tally.sent(1, 0);
+        }
+        let result = target.flush();
+        if result.is_err() {
+            self.tally.failed();
+        }
+        result
+    }
 }
 
 impl fmt::Debug for InnerAtomicBucket {
@@ -71,81 +197,232 @@ lazy_static! {
 }
 
 impl InnerAtomicBucket {
-    fn flush(&mut self) -> io::Result<()> {
+    /// Take the period's statistics for publication to the drain, then purge unused metrics.
+    fn flush(&mut self) -> (Arc<dyn InputScope>, Published) {
         let pub_scope: Arc<dyn InputScope> = match self.drain {
             Some(ref out) => out.input_dyn(),
             None => read_lock!(DEFAULT_AGGREGATE_INPUT).input_dyn(),
         };
 
-        self.flush_to(pub_scope.borrow())?;
+        let published = self.take();
 
-        // all metrics published!
+        // all metrics taken!
         // purge: if stats is the last owner of the metric, remove it
+        // unless it still has values in the sliding window or a retained gauge value
+        // cumulative counters are kept, their totals would otherwise restart from zero
         // TODO parameterize whether to keep ad-hoc metrics after publish
-        let mut purged = self.metrics.clone();
-        self.metrics
+        // metrics idle for longer than their time to live are expired even if they still have handles
+        // names are collected first, cloning the map would hold an extra reference to every metric
+        let window = &self.window;
+        let gauge_max_age = self.gauge_max_age;
+        let idle_ttl = self.idle_ttl;
+        let purged: Vec<MetricName> = self
+            .metrics
             .iter()
-            .filter(|&(_k, v)| Arc::strong_count(v) == 1)
-            .map(|(k, _v)| k)
-            .for_each(|k| {
-                purged.remove(k);
-            });
-        self.metrics = purged;
+            .filter(|&(k, v)| {
+                let unused = Arc::strong_count(v) == 1
+                    && !matches!(window, Some(w) if w.has_values(k))
+                    && !matches!(gauge_max_age, Some(age) if v.is_retained(age))
+                    && !self.is_cumulative(k, v.kind);
+                unused || matches!(idle_ttl, Some(ttl) if v.is_idle(ttl))
+            })
+            .map(|(k, _v)| k.clone())
+            .collect();
+        for k in purged {
+            self.metrics.remove(&k);
+        }
 
-        Ok(())
+        (pub_scope, published)
     }
 
     /// Take a snapshot of aggregated values and reset them.
-    /// Compute stats on captured values using assigned or default stats function.
-    /// Write stats to assigned or default output.
-    fn flush_to(&mut self, target: &dyn InputScope) -> io::Result<()> {
+    /// Stats are computed on captured values using assigned or default stats function upon publication.
+    fn take(&mut self) -> Published {
         let now = TimeHandle::now();
         let duration_seconds = self.period_start.elapsed_us() as f64 / 1_000_000.0;
         self.period_start = now;
 
-        let mut snapshot: Vec<(&MetricName, InputKind, Vec<ScoreType>)> = self
-            .metrics
-            .iter()
-            .flat_map(|(name, scores)| {
-                if let Some(values) = scores.reset(duration_seconds) {
-                    Some((name, scores.metric_kind(), values))
-                } else {
-                    None
+        let mut snapshot: Vec<(&MetricName, InputKind, Vec<ScoreType>)> = match self.window {
+            Some(ref mut window) => window.slide(
+                &self.metrics,
+                duration_seconds,
+                self.ewma,
+                self.deltas,
+                &self.quantiles,
+                self.min_rate_period,
+            ),
+            None => {
+                let mut snapshot = Vec::new();
+                for (name, scores) in &self.metrics {
+                    // untouched metrics are skipped without swapping their scores,
+                    // unless moving averages or held gauge values still change with time
+                    if !scores.take_dirty() && !self.ewma && scores.time_weight.is_none() {
+                        continue;
+                    }
+                    let digest = scores.take_digest();
+                    if let Some(mut values) = scores.reset(
+                        duration_seconds,
+                        self.ewma,
+                        self.deltas,
+                        self.min_rate_period,
+                    ) {
+                        AtomicScores::push_quantiles(&mut values, digest.as_ref(), &self.quantiles);
+                        snapshot.push((name, scores.metric_kind(), values))
+                    }
                 }
-            })
-            .collect();
+                snapshot
+            }
+        };
 
-        if snapshot.is_empty() {
-            // no data was collected for this period
-            // TODO repeat previous frame min/max ?
-            // TODO update some canary metric ?
-            Ok(())
-        } else {
-            // TODO add switch for metadata such as PERIOD_LENGTH
-            if self.publish_metadata {
-                snapshot.push((
-                    &PERIOD_LENGTH,
-                    InputKind::Timer,
-                    vec![Sum((duration_seconds * 1000.0) as isize)],
-                ));
-            }
-
-            let stats_fn = match self.stats {
-                Some(ref stats_fn) => stats_fn.clone(),
-                None => read_lock!(DEFAULT_AGGREGATE_STATS).clone(),
-            };
+        if let Some(max_age) = self.gauge_max_age {
+            let published: BTreeSet<&MetricName> = snapshot.iter().map(|m| m.0).collect();
+            for (name, scores) in &self.metrics {
+                if !published.contains(name) {
+                    if let Some(values) = scores.retained(max_age) {
+                        snapshot.push((name, InputKind::Gauge, values))
+                    }
+                }
+            }
+        }
+
+        // cumulative counters publish their totals since the first value, even in empty periods
+        let published: BTreeMap<&MetricName, usize> =
+            snapshot.iter().enumerate().map(|(i, m)| (m.0, i)).collect();
+        let mut idle = Vec::new();
+        for (name, scores) in &self.metrics {
+            if self.is_cumulative(name, scores.kind) {
+                match published.get(name) {
+                    Some(i) => scores.cumulate(&mut snapshot[*i].2),
+                    None => {
+                        if let Some(values) = scores.totals() {
+                            idle.push((name, scores.kind, values))
+                        }
+                    }
+                }
+            }
+        }
+        snapshot.append(&mut idle);
+
+        // metrics without values in this period repeat the min, max and mean of their last period with values
+        if self.repeat_min_max {
+            let published: BTreeMap<&MetricName, usize> =
+                snapshot.iter().enumerate().map(|(i, m)| (m.0, i)).collect();
+            let mut repeated = Vec::new();
+            for (name, scores) in &self.metrics {
+                if let Some(values) = scores.repeated() {
+                    match published.get(name) {
+                        None => repeated.push((name, scores.kind, values)),
+                        // idle cumulative counters only have their totals
+                        Some(i)
+                            if !snapshot[*i]
+                                .2
+                                .iter()
+                                .any(|s| matches!(s, Max(_) | FloatMax(_))) =>
+                        {
+                            snapshot[*i].2.extend(values)
+                        }
+                        Some(_) => {}
+                    }
+                }
+            }
+            snapshot.append(&mut repeated);
+        }
+
+        // TODO add switch for metadata such as PERIOD_LENGTH
+        if self.publish_metadata && !snapshot.is_empty() {
+            snapshot.push((
+                &PERIOD_LENGTH,
+                InputKind::Timer,
+                vec![Sum((duration_seconds * 1000.0) as isize)],
+            ));
+        }
+
+        let mut top = Vec::new();
+        for (name, scores) in &self.metrics {
+            for (parts, value) in scores.take_top() {
+                let mut variant = name.clone();
+                variant.push_back("top".to_string());
+                variant.extend(parts.split('.').map(String::from));
+                top.push((variant, value));
+            }
+        }
+
+        // metrics updated concurrently too often during the period are sharded from now on
+        for scores in self.metrics.values() {
+            let races = scores.races.swap(0, Relaxed);
+            if matches!(self.auto_shard, Some(threshold) if races > threshold) {
+                scores.shard();
+            }
+        }
+
+        // labeled variants are published per period, even with a sliding window
+        let mut labeled = Vec::new();
+        for (name, scores) in &self.metrics {
+            for (labels, values) in
+                scores.reset_variants(duration_seconds, &self.quantiles, self.min_rate_period)
+            {
+                labeled.push((name.clone(), scores.kind, labels, values));
+            }
+        }
+
+        Published {
+            snapshot: snapshot
+                .into_iter()
+                .map(|(name, kind, scores)| (name.clone(), kind, scores))
+                .collect(),
+            top,
+            labeled,
+            stats_fn: self.stats_fn(),
+            tally: self.tally.clone(),
+        }
+    }
 
-            for metric in snapshot {
-                for score in metric.2 {
-                    let filtered = stats_fn(metric.1, metric.0.clone(), score);
-                    if let Some((kind, name, value)) = filtered {
-                        let metric: InputMetric = target.new_metric(name, kind);
-                        // TODO provide some stats context through labels?
-                        metric.write(value, labels![])
+    /// Compute stats on values aggregated so far in the current period, without resetting them.
+    /// Write stats to the target scope.
+    fn peek_to(&self, target: &dyn InputScope) -> io::Result<()> {
+        let duration_seconds = self.period_start.elapsed_us() as f64 / 1_000_000.0;
+        let stats_fn = self.stats_fn();
+        for (name, scores) in &self.metrics {
+            if let Some(values) = scores.peek(
+                duration_seconds,
+                self.ewma,
+                &self.quantiles,
+                self.min_rate_period,
+            ) {
+                for &score in &values {
+                    if let Some(stat) = stats_fn(scores.metric_kind(), name.clone(), score) {
+                        write_stat(target, stat, score, &values, labels![]);
+                    }
+                }
+            }
+            for (labels, values) in
+                scores.peek_variants(duration_seconds, &self.quantiles, self.min_rate_period)
+            {
+                for &score in &values {
+                    if let Some(stat) = stats_fn(scores.metric_kind(), name.clone(), score) {
+                        write_stat(target, stat, score, &values, labels.clone());
                     }
                 }
             }
-            target.flush()
+        }
+        target.flush()
+    }
+
+    /// Returns true if the metric's counts and sums are published as totals instead of per period.
+    fn is_cumulative(&self, name: &MetricName, kind: InputKind) -> bool {
+        match kind {
+            InputKind::Counter | InputKind::Marker => *self
+                .cumulative_metrics
+                .get(name)
+                .unwrap_or(&self.cumulative),
+            _ => false,
+        }
+    }
+
+    fn stats_fn(&self) -> Arc<StatsFn> {
+        match self.stats {
+            Some(ref stats_fn) => stats_fn.clone(),
+            None => read_lock!(DEFAULT_AGGREGATE_STATS).clone(),
         }
     }
 }
@@ -168,7 +445,25 @@ impl AtomicBucket {
                 drain: None,
                 // TODO add API toggle for metadata publish
                 publish_metadata: false,
+                window: None,
+                ewma: false,
+                deltas: false,
+                digest_compression: None,
+                quantiles: Vec::new(),
+                gauge_max_age: None,
+                idle_ttl: None,
+                repeat_min_max: false,
+                top_k: None,
+                time_weighted: false,
+                min_rate_period: None,
+                max_variants: None,
+                sharded_metrics: BTreeSet::new(),
+                auto_shard: None,
+                cumulative: false,
+                cumulative_metrics: BTreeMap::new(),
+                tally: Arc::new(FlushTally::default()),
             })),
+            publishing: Arc::new(RwLock::new(())),
         }
     }
 
@@ -233,8 +528,21 @@ impl AtomicBucket {
     }
 
     /// Set this stats's aggregated metrics flush output.
+    /// The bucket is then flushed one last time upon `shutdown()`.
     pub fn drain(&self, new_drain: impl Input + Send + Sync + 'static) {
-        write_lock!(self.inner).drain = Some(Arc::new(new_drain))
+        write_lock!(self.inner).drain = Some(Arc::new(new_drain));
+        let mut live = LIVE_BUCKETS.lock().unwrap_or_else(PoisonError::into_inner);
+        live.retain(|live| live.inner.strong_count() > 0);
+        if !live
+            .iter()
+            .any(|live| live.inner.as_ptr() == Arc::as_ptr(&self.inner))
+        {
+            live.push(LiveBucket {
+                attributes: self.attributes.clone(),
+                inner: Arc::downgrade(&self.inner),
+                publishing: Arc::downgrade(&self.publishing),
+            })
+        }
     }
 
     /// Revert this stats's flush target to the default output.
@@ -242,23 +550,351 @@ impl AtomicBucket {
         write_lock!(self.inner).drain = None
     }
 
+    /// Publish statistics over a rolling window of the last `slices` flush periods
+    /// instead of resetting them on every flush.
+    /// e.g. flushing every 5 seconds over 12 slices reports on the last minute.
+    /// Any previously accumulated window is discarded.
+    pub fn sliding_window(&self, slices: usize) {
+        assert!(slices > 0, "Sliding window needs at least one slice");
+        write_lock!(self.inner).window = Some(Window::new(slices))
+    }
+
+    /// Revert this bucket to publishing statistics for the last flush period only.
+    pub fn unset_sliding_window(&self) {
+        write_lock!(self.inner).window = None
+    }
+
+    /// Track exponentially weighted moving average rates over 1, 5 and 15 minutes.
+    /// Averages are updated on every flush and published as `Ewma1m`, `Ewma5m` and `Ewma15m` scores.
+    /// Markers and timers average their hit count, counters and levels average the sum of their values.
+    pub fn ewma_rates(&self, enabled: bool) {
+        write_lock!(self.inner).ewma = enabled
+    }
+
+    /// Publish the change of gauges' last value since their previous period with values as `Delta` scores,
+    /// e.g. for monotonically increasing OS counters sampled as gauges.
+    /// No delta is published for the first period with values.
+    pub fn gauge_deltas(&self, enabled: bool) {
+        write_lock!(self.inner).deltas = enabled
+    }
+
+    /// Estimate quantiles of counter, timer and gauge values using t-digest sketches.
+    /// See `TDigest` for the meaning of `compression`.
+    /// Requested quantiles are published as `Quantile` scores, e.g. `0.99` becomes `p99` with `stats_all`.
+    /// Only applies to metrics defined afterwards.
+    /// Unlike other scores, updating a digest requires taking a lock.
+    pub fn tdigest(&self, compression: f64, quantiles: &[f64]) {
+        let mut inner = write_lock!(self.inner);
+        inner.digest_compression = Some(compression);
+        inner.quantiles = quantiles.to_vec();
+    }
+
+    /// Stop estimating quantiles for metrics defined afterwards.
+    pub fn unset_tdigest(&self) {
+        let mut inner = write_lock!(self.inner);
+        inner.digest_compression = None;
+        inner.quantiles.clear();
+    }
+
+    /// Track the `k` labeled variants of each metric with the highest count or sum of values in a period,
+    /// to find which of them dominate without publishing every variant.
+    /// Variants are published upon flush as gauges named after the metric and their labels,
+    /// e.g. `requests.top.method.GET`. Unlabeled values are not ranked.
+    /// Uses a space-saving sketch of `4 * k` entries per metric, values of rare variants may be overestimated.
+    /// Only applies to metrics defined afterwards. Ranking a value requires resolving its labels and taking a lock.
+    /// Ranking zero variants is rejected, stopping ranking as `unset_top_k` would.
+    pub fn top_k(&self, k: usize, by: TopBy) {
+        if k == 0 {
+            warn!("Top zero variants requested, not ranking variants");
+            self.unset_top_k();
+            return;
+        }
+        write_lock!(self.inner).top_k = Some((k, by))
+    }
+
+    /// Stop ranking labeled variants of metrics defined afterwards.
+    pub fn unset_top_k(&self) {
+        write_lock!(self.inner).top_k = None
+    }
+
+    /// Aggregate the labeled variants of each metric separately, up to `max_variants` per metric,
+    /// publishing their stats with their labels next to the stats of the metric's unlabeled values.
+    /// Values of new variants beyond the maximum are aggregated with the unlabeled values, bounding cardinality.
+    /// Variants without values in a period are forgotten, freeing their place for others.
+    /// Variants publish the stats of their period only, without moving averages, deltas or sliding window.
+    /// Only applies to metrics defined afterwards. Aggregating a value requires resolving its labels.
+    pub fn labeled_variants(&self, max_variants: usize) {
+        write_lock!(self.inner).max_variants = Some(max_variants)
+    }
+
+    /// Merge the labeled variants of metrics defined afterwards.
+    pub fn unset_labeled_variants(&self) {
+        write_lock!(self.inner).max_variants = None
+    }
+
+    /// Returns true if metrics defined now aggregate or rank their labeled variants.
+    pub(crate) fn keeps_labels(&self) -> bool {
+        let inner = read_lock!(self.inner);
+        inner.max_variants.is_some() || inner.top_k.is_some()
+    }
+
+    /// Spread the updates of a metric over per-thread stripes of scores, each on its own cache line,
+    /// merged when the metric is flushed or peeked. Relieves contention on metrics updated by many threads at once,
+    /// at the cost of memory and of merging all stripes on flush. Levels are never sharded.
+    /// The name is relative to this bucket, as when defining the metric. Applies to an already defined metric.
+    pub fn shard_metric(&self, name: &str) {
+        let name = self.prefix_append(name);
+        let mut inner = write_lock!(self.inner);
+        if let Some(scores) = inner.metrics.get(&name) {
+            scores.shard();
+        }
+        inner.sharded_metrics.insert(name);
+    }
+
+    /// Shard metrics whose concurrent updates of min and max raced more than `threshold` times in a period.
+    /// Sharded metrics stay sharded for as long as they are defined.
+    pub fn auto_shard(&self, threshold: usize) {
+        write_lock!(self.inner).auto_shard = Some(threshold)
+    }
+
+    /// Stop sharding metrics automatically. Metrics already sharded stay so.
+    pub fn unset_auto_shard(&self) {
+        write_lock!(self.inner).auto_shard = None
+    }
+
+    /// Publish the mean of gauges weighted by how long each value was held, instead of the mean of values written.
+    /// A value is held from when it is written until the next value is written or the period ends,
+    /// which suits gauges written at irregular intervals, e.g. a queue depth written on every change.
+    /// Only applies to gauges defined afterwards.
+    pub fn time_weighted_gauges(&self, enabled: bool) {
+        write_lock!(self.inner).time_weighted = enabled
+    }
+
+    /// Guard rates against periods shorter than `min_period`, e.g. back-to-back flushes,
+    /// over which they would explode to absurd values.
+    pub fn min_rate_period(&self, min_period: Duration, guard: RateGuard) {
+        write_lock!(self.inner).min_rate_period = Some((min_period.as_secs_f64(), guard))
+    }
+
+    /// Compute rates over any period, however short.
+    pub fn unset_min_rate_period(&self) {
+        write_lock!(self.inner).min_rate_period = None
+    }
+
+    /// Republish the last known value of gauges that were not updated during a period,
+    /// instead of leaving a gap until the next update.
+    /// Values older than `max_age` (if any) are no longer published.
+    pub fn retain_gauges(&self, max_age: Option<Duration>) {
+        let mut inner = write_lock!(self.inner);
+        inner.gauge_max_age = Some(max_age.unwrap_or(Duration::MAX));
+    }
+
+    /// Stop republishing gauges not updated during a period.
+    pub fn unset_retain_gauges(&self) {
+        let mut inner = write_lock!(self.inner);
+        inner.gauge_max_age = None;
+    }
+
+    /// Forget metrics without any values written for longer than the time to live, upon flush.
+    /// Prevents unbounded growth of buckets where metric names are derived from requests,
+    /// e.g. from user input or URL paths.
+    /// Metrics are expired even if handles to them remain; values written through such handles are lost.
+    /// Defining the metric again restores it.
+    pub fn expire_idle(&self, ttl: Duration) {
+        write_lock!(self.inner).idle_ttl = Some(ttl)
+    }
+
+    /// Keep metrics with handles, regardless of their last write.
+    pub fn unset_expire_idle(&self) {
+        write_lock!(self.inner).idle_ttl = None
+    }
+
+    /// Publish the min, max and mean of the last period with values for metrics without values
+    /// in the current period, for downstream systems that can not tell missing data from a gap.
+    /// Counts, sums and rates are not repeated. Markers and sets have no min or max to repeat.
+    pub fn repeat_min_max(&self, enabled: bool) {
+        write_lock!(self.inner).repeat_min_max = enabled
+    }
+
+    /// Publish the count and sum of counters and markers as running totals since their first value,
+    /// instead of resetting them on every flush. Totals are also published for periods without values.
+    /// Use this with backends expecting monotonically increasing counters, such as Prometheus.
+    /// Other statistics (rate, mean, min, max) still apply to the last period only.
+    pub fn cumulative_counters(&self, enabled: bool) {
+        write_lock!(self.inner).cumulative = enabled
+    }
+
+    /// Override the bucket's cumulative counters setting for a single counter or marker.
+    /// The name is relative to this bucket, as when defining the metric.
+    pub fn cumulative_counter(&self, name: &str, enabled: bool) {
+        let name = self.prefix_append(name);
+        write_lock!(self.inner)
+            .cumulative_metrics
+            .insert(name, enabled);
+    }
+
+    /// Revert a single counter or marker to the bucket's cumulative counters setting.
+    pub fn unset_cumulative_counter(&self, name: &str) {
+        let name = self.prefix_append(name);
+        write_lock!(self.inner).cumulative_metrics.remove(&name);
+    }
+
     /// Immediately flush the stats's metrics to the specified scope and stats.
     pub fn flush_to(&self, publish_scope: &dyn InputScope) -> io::Result<()> {
+        let start = Instant::now();
+        let _publishing = write_lock!(self.publishing);
+        let published = write_lock!(self.inner).take();
+        let result = published.publish_to(publish_scope);
+        self.attributes
+            .report_flush("bucket", start, &published.tally);
+        result
+    }
+
+    /// Write stats of the values aggregated so far in the current period to the specified scope.
+    /// Unlike `flush_to`, values are not reset, moving averages and sliding window are left untouched
+    /// and the flush is not reported. Stats reflect the current period only, even with a sliding window.
+    pub fn peek_to(&self, publish_scope: &dyn InputScope) -> io::Result<()> {
+        read_lock!(self.inner).peek_to(publish_scope)
+    }
+
+    /// Take the raw values aggregated so far and reset them, without publishing anything.
+    /// The snapshot can then be `absorb`ed by another bucket, e.g. to combine per-thread buckets.
+    pub fn snapshot(&self) -> BucketSnapshot {
+        let mut inner = write_lock!(self.inner);
+        let duration_seconds = inner.period_start.elapsed_us() as f64 / 1_000_000.0;
+        inner.period_start = TimeHandle::now();
+        let mut metrics = BTreeMap::new();
+        for (name, scores) in &inner.metrics {
+            if !scores.take_dirty() && scores.time_weight.is_none() {
+                continue;
+            }
+            let slice = scores.take_slice(duration_seconds);
+            if slice.scores[HIT] > 0 {
+                metrics.insert(name.clone(), (scores.kind, slice));
+            }
+        }
+        BucketSnapshot { metrics }
+    }
+
+    /// Merge the values of a snapshot taken from another bucket into this bucket's current period.
+    /// Metric names are taken as is from the snapshot, this bucket's prefix is not applied.
+    /// Metrics already defined in this bucket keep their own kind.
+    pub fn absorb(&self, snapshot: BucketSnapshot) {
+        let mut inner = write_lock!(self.inner);
+        let digest_compression = inner.digest_compression;
+        let top_k = inner.top_k;
+        let time_weighted = inner.time_weighted;
+        let max_variants = inner.max_variants;
+        for (name, (kind, slice)) in snapshot.metrics {
+            inner
+                .metrics
+                .entry(name)
+                .or_insert_with(|| {
+                    Arc::new(
+                        AtomicScores::new(kind, digest_compression, top_k, time_weighted)
+                            .with_variants(max_variants),
+                    )
+                })
+                .absorb(&slice);
+        }
+    }
+
+    /// A new bucket recording raw values as this one does, to be `absorb`ed by it later.
+    pub(crate) fn sibling(&self) -> AtomicBucket {
+        let sibling = AtomicBucket::new();
+        let inner = read_lock!(self.inner);
+        if let Some(compression) = inner.digest_compression {
+            sibling.tdigest(compression, &inner.quantiles);
+        }
+        sibling.time_weighted_gauges(inner.time_weighted);
+        sibling
+    }
+
+    /// Returns stats of the values aggregated so far in the current period, without resetting them.
+    /// Use this to serve instantaneous values (e.g. from a health check endpoint) between flushes.
+    pub fn peek(&self) -> BTreeMap<String, MetricValue> {
+        let map = StatsMapScope::default();
+        // writing to a map can not fail
+        let _ = self.peek_to(&map);
+        map.into()
+    }
+}
+
+impl AtomicBucket {
+    /// Lookup or create scores for the requested metric, holding the write lock.
+    fn define_scores(&self, full_name: MetricName, kind: InputKind) -> Arc<AtomicScores> {
         let mut inner = write_lock!(self.inner);
-        inner.flush_to(publish_scope)
+        let digest_compression = inner.digest_compression;
+        let top_k = inner.top_k;
+        let time_weighted = inner.time_weighted;
+        let max_variants = inner.max_variants;
+        let sharded = inner.sharded_metrics.contains(&full_name);
+        let scores = inner
+            .metrics
+            .entry(full_name)
+            .or_insert_with(|| {
+                Arc::new(
+                    AtomicScores::new(kind, digest_compression, top_k, time_weighted)
+                        .with_variants(max_variants),
+                )
+            })
+            .clone();
+        if sharded {
+            scores.shard();
+        }
+        scores
     }
 }
 
 impl InputScope for AtomicBucket {
+    fn clock(&self) -> Clock {
+        self.get_timer_clock()
+    }
+
     /// Lookup or create scores for the requested metric.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
-        let scores = write_lock!(self.inner)
-            .metrics
-            .entry(self.prefix_append(name.clone()))
-            .or_insert_with(|| Arc::new(AtomicScores::new(kind)))
-            .clone();
-        InputMetric::new(MetricId::forge("stats", name), move |value, _labels| {
-            scores.update(value)
+        if kind == InputKind::Text {
+            // text annotations can't be aggregated, they are written to outputs directly
+            return InputMetric::new(MetricId::forge("stats", name), |_value, _labels| {});
+        }
+        let full_name = self.prefix_append(name.clone());
+        // metrics looked up ad-hoc on hot paths are usually defined already, concurrent lookups share the read lock
+        let defined = read_lock!(self.inner).metrics.get(&full_name).cloned();
+        let scores = match defined {
+            Some(scores) => scores,
+            None => self.define_scores(full_name, kind),
+        };
+        let weighted = scores.clone();
+        let float = scores.clone();
+        let incr = (!scores.keeps_labels()).then(|| scores.clone());
+        let metric = InputMetric::new(MetricId::forge("stats", name), move |value, labels| {
+            match scores.variant(&labels) {
+                Some(variant) => variant.update(value),
+                None => scores.update(value),
+            }
+            scores.rank(value, 1, labels)
+        })
+        .with_weighted(move |value, weight, labels| {
+            match weighted.variant(&labels) {
+                Some(variant) => variant.update_n(value, weight),
+                None => weighted.update_n(value, weight),
+            }
+            weighted.rank(value, weight, labels)
+        });
+        // unlabeled values of one skip label resolution, unless variants are aggregated or ranked
+        let metric = match incr {
+            Some(incr) => metric.with_incr(move || incr.update(1)),
+            None => metric,
+        };
+        if kind != InputKind::Gauge {
+            return metric;
+        }
+        metric.with_float(move |value, labels| {
+            match float.variant(&labels) {
+                Some(variant) => variant.update_f64(value),
+                None => float.update_f64(value),
+            }
+            float.rank(value.round() as MetricValue, 1, labels)
         })
     }
 }
@@ -268,8 +904,13 @@ impl Flush for AtomicBucket {
     /// Publish statistics
     fn flush(&self) -> io::Result<()> {
         self.notify_flush_listeners();
-        let mut inner = write_lock!(self.inner);
-        inner.flush()
+        let start = Instant::now();
+        let _publishing = write_lock!(self.publishing);
+        let (pub_scope, published) = write_lock!(self.inner).flush();
+        let result = published.publish_to(pub_scope.borrow());
+        self.attributes
+            .report_flush("bucket", start, &published.tally);
+        result
     }
 }
 
@@ -282,12 +923,22 @@ impl WithAttributes for AtomicBucket {
     }
 }
 
+impl ClockedTimers for AtomicBucket {}
+
+impl ReportFlush for AtomicBucket {}
+
 const HIT: usize = 0;
 const SUM: usize = 1;
 const MAX: usize = 2;
 const MIN: usize = 3;
 const SCORES_LEN: usize = 4;
 
+/// Integer scores of a period, with those of its floating point values if any.
+type RawScores = ([isize; SCORES_LEN], Option<[f64; SCORES_LEN]>);
+
+/// Time constants of the moving average rates, in seconds.
+const EWMA_PERIODS: [f64; 3] = [60.0, 300.0, 900.0];
+
 /// A metric that holds aggregated values.
 /// Some fields are kept public to ease publishing.
 #[derive(Debug)]
@@ -296,170 +947,1185 @@ struct AtomicScores {
     kind: InputKind,
     /// The actual recorded metric scores
     scores: [AtomicIsize; SCORES_LEN],
+    /// Moving average rates, only updated upon flush
+    ewma: RwLock<Option<[f64; 3]>>,
+    /// Last value of the previous period with values, for gauge deltas only
+    period_last: RwLock<Option<isize>>,
+    /// Distribution of values, if quantiles are tracked
+    digest: Option<RwLock<TDigest>>,
+    /// Distinct values, for sets only
+    distinct: Option<AtomicHyperLogLog>,
+    /// Last value written, for gauges only
+    last_value: AtomicIsize,
+    /// End of the last period a value was written in
+    last_seen: RwLock<Option<TimeHandle>>,
+    /// When the metric was first defined
+    defined: TimeHandle,
+    /// Count and sum of all flushed periods, for cumulative counters
+    totals: [AtomicIsize; 2],
+    /// Raw scores of the last period with values
+    previous: RwLock<Option<RawScores>>,
+    /// Heaviest labeled variants, if ranked
+    top: Option<TopK>,
+    /// Values integrated over the time they were held, for time-weighted gauges only
+    time_weight: Option<RwLock<TimeWeight>>,
+    /// Separately aggregated labeled variants, if any
+    variants: Option<Variants>,
+    /// Per-thread stripes of scores, once the metric is sharded
+    stripes: OnceLock<Box<[Stripe]>>,
+    /// Scores of floating point values, once a gauge is written one
+    floats: OnceLock<FloatScores>,
+    /// Concurrent updates of min or max detected since the last flush
+    races: AtomicUsize,
+    /// Set by writes, cleared when the scores are reset
+    dirty: AtomicBool,
 }
 
-impl AtomicScores {
-    /// Create new scores to track summary values of a metric
-    pub fn new(kind: InputKind) -> Self {
-        AtomicScores {
-            kind,
-            scores: unsafe { mem::transmute(AtomicScores::blank()) },
+/// Scores updated by some of the threads, alone on its cache line.
+#[derive(Debug)]
+#[repr(align(64))]
+struct Stripe([AtomicIsize; SCORES_LEN]);
+
+/// Scores of the floating point values of a gauge, stored as `f64` bits.
+/// The count of floating point values is also included in the gauge's own hit count.
+#[derive(Debug)]
+struct FloatScores {
+    scores: [AtomicU64; SCORES_LEN],
+    /// Last value written, NaN if the gauge's last value was an integer
+    last: AtomicU64,
+}
+
+impl FloatScores {
+    fn new() -> Self {
+        FloatScores {
+            scores: FloatScores::blank().map(|score| AtomicU64::new(score.to_bits())),
+            last: AtomicU64::new(f64::NAN.to_bits()),
         }
     }
 
-    /// Returns the metric's kind.
-    pub fn metric_kind(&self) -> InputKind {
-        self.kind
+    #[inline]
+    fn blank() -> [f64; SCORES_LEN] {
+        [0.0, 0.0, f64::NEG_INFINITY, f64::INFINITY]
     }
 
-    #[inline]
-    fn blank() -> [isize; SCORES_LEN] {
-        [0, 0, isize::MIN, isize::MAX]
+    fn update(&self, value: f64) {
+        update_f64(&self.scores[HIT], |hits| hits + 1.0);
+        update_f64(&self.scores[SUM], |sum| sum + value);
+        update_f64(&self.scores[MAX], |max| max.max(value));
+        update_f64(&self.scores[MIN], |min| min.min(value));
+        self.last.store(value.to_bits(), Relaxed);
     }
 
-    /// Update scores with new value
-    pub fn update(&self, value: MetricValue) {
-        // TODO detect & report any concurrent updates / resets for measurement of contention
-        // Count is tracked for all metrics
-        self.scores[HIT].fetch_add(1, Relaxed);
-        match self.kind {
-            InputKind::Marker => {}
-            InputKind::Level => {
-                // Level min & max apply to the _sum_ of values
-                // fetch_add only returns the previous sum, so min & max trail behind by one operation
-                // instead, pickup the slack by comparing again with the final sum upon `snapshot`
-                // this is to avoid making an extra load() on every value
-                let prev_sum = self.scores[SUM].fetch_add(value, Relaxed);
-                swap_if(&self.scores[MAX], prev_sum, |new, current| new > current);
-                swap_if(&self.scores[MIN], prev_sum, |new, current| new < current);
-            }
-            InputKind::Counter | InputKind::Timer | InputKind::Gauge => {
-                // gauges are non cumulative, but we keep the sum to compute the mean
-                // TODO use #![feature(atomic_min_max)] when stabilized
-                self.scores[SUM].fetch_add(value, Relaxed);
-                swap_if(&self.scores[MAX], value, |new, current| new > current);
-                swap_if(&self.scores[MIN], value, |new, current| new < current);
-            }
+    /// Add the scores of another period to the current scores.
+    fn absorb(&self, floats: &[f64; SCORES_LEN]) {
+        update_f64(&self.scores[HIT], |hits| hits + floats[HIT]);
+        update_f64(&self.scores[SUM], |sum| sum + floats[SUM]);
+        update_f64(&self.scores[MAX], |max| max.max(floats[MAX]));
+        update_f64(&self.scores[MIN], |min| min.min(floats[MIN]));
+    }
+
+    /// Returns the current scores if any value was recorded, resetting them if specified.
+    fn scores(&self, reset: bool) -> Option<[f64; SCORES_LEN]> {
+        let mut scores = FloatScores::blank();
+        for ((score, current), blank) in scores
+            .iter_mut()
+            .zip(self.scores.iter())
+            .zip(FloatScores::blank())
+        {
+            let bits = if reset {
+                current.swap(blank.to_bits(), AcqRel)
+            } else {
+                current.load(Acquire)
+            };
+            *score = f64::from_bits(bits);
+        }
+        if scores[HIT] > 0.0 {
+            Some(scores)
+        } else {
+            None
         }
     }
 
-    /// Reset scores to zero, return previous values
-    fn snapshot(&self, scores: &mut [isize; 4]) -> bool {
-        // NOTE copy timestamp, count AND sum _before_ testing for data to reduce concurrent discrepancies
-        scores[HIT] = self.scores[HIT].swap(0, AcqRel);
-        scores[SUM] = self.scores[SUM].swap(0, AcqRel);
+    /// Returns the gauge's last value, if it was a floating point value.
+    fn last(&self) -> Option<f64> {
+        let last = f64::from_bits(self.last.load(Acquire));
+        if last.is_nan() {
+            None
+        } else {
+            Some(last)
+        }
+    }
 
-        // if hit count is zero, no values were recorded.
-        if scores[HIT] == 0 {
-            return false;
+    /// Combine the integer and floating point scores of the same period,
+    /// the integer hit count including the floating point values.
+    fn combine(scores: &[isize; SCORES_LEN], floats: &[f64; SCORES_LEN]) -> [f64; SCORES_LEN] {
+        let hits = (scores[HIT] as f64).max(floats[HIT]);
+        let mut combined = [
+            hits,
+            scores[SUM] as f64 + floats[SUM],
+            floats[MAX],
+            floats[MIN],
+        ];
+        if hits > floats[HIT] {
+            combined[MAX] = combined[MAX].max(scores[MAX] as f64);
+            combined[MIN] = combined[MIN].min(scores[MIN] as f64);
         }
+        combined
+    }
+}
 
-        scores[MAX] = self.scores[MAX].swap(isize::MIN, AcqRel);
-        scores[MIN] = self.scores[MIN].swap(isize::MAX, AcqRel);
+/// Source of the stripe indexes of threads.
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
 
-        if self.kind == InputKind::Level {
-            // fetch_add only returns the previous sum, so min & max trail behind by one operation
-            // pickup the slack by comparing one last time against the final sum
-            if scores[SUM] > scores[MAX] {
-                scores[MAX] = scores[SUM];
-            }
-            if scores[SUM] < scores[MIN] {
-                scores[MIN] = scores[SUM];
-            }
-        }
+thread_local! {
+    /// The current thread's stripe index, modulo the number of stripes.
+    static STRIPE: usize = NEXT_STRIPE.fetch_add(1, Relaxed);
+}
 
-        true
-    }
+/// Hash and number of the label pairs identifying a variant, see `Labels::digest`.
+type VariantKey = (u64, usize);
 
-    /// Map raw scores (if any) to applicable statistics
-    pub fn reset(&self, duration_seconds: f64) -> Option<Vec<ScoreType>> {
-        let mut scores = AtomicScores::blank();
-        if self.snapshot(&mut scores) {
-            let mut snapshot = Vec::new();
-            match self.kind {
-                InputKind::Marker => {
-                    snapshot.push(Count(scores[HIT]));
-                    snapshot.push(Rate(scores[HIT] as f64 / duration_seconds))
-                }
-                InputKind::Gauge => {
-                    snapshot.push(Max(scores[MAX]));
-                    snapshot.push(Min(scores[MIN]));
-                    snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
+/// Labeled variants of a metric, each aggregated in its own scores.
+#[derive(Debug)]
+struct Variants {
+    max: usize,
+    scores: RwLock<HashMap<VariantKey, (Labels, Arc<AtomicScores>)>>,
+}
+
+/// Ranking of a metric's labeled variants.
+#[derive(Debug)]
+struct TopK {
+    k: usize,
+    by: TopBy,
+    sketch: RwLock<SpaceSaving>,
+}
+
+/// Integral of a gauge's values over the time each was held.
+#[derive(Debug, Default)]
+struct TimeWeight {
+    /// The value currently held and since when, if any value was ever written
+    held: Option<(f64, TimeHandle)>,
+    /// Sum of values multiplied by the seconds they were held, since the start of the period
+    area: f64,
+    /// Seconds during which a value was held, since the start of the period
+    seconds: f64,
+}
+
+impl TimeWeight {
+    /// Returns the period's area and held seconds, up to now.
+    fn peek(&self) -> (f64, f64) {
+        match self.held {
+            Some((value, since)) => {
+                let seconds = since.elapsed_us() as f64 / 1_000_000.0;
+                (self.area + value * seconds, self.seconds + seconds)
+            }
+            None => (self.area, self.seconds),
+        }
+    }
+
+    /// Hold the new value from now on.
+    fn hold(&mut self, value: f64) {
+        let (area, seconds) = self.peek();
+        self.area = area;
+        self.seconds = seconds;
+        self.held = Some((value, TimeHandle::now()));
+    }
+
+    /// Returns the period's area and held seconds, start a new period still holding the current value.
+    fn take(&mut self) -> (f64, f64) {
+        let taken = self.peek();
+        self.area = 0.0;
+        self.seconds = 0.0;
+        if let Some((_, since)) = &mut self.held {
+            *since = TimeHandle::now();
+        }
+        taken
+    }
+}
+
+impl AtomicScores {
+    /// Create new scores to track summary values of a metric
+    /// Levels and markers values are not worth a digest, even if compression is specified.
+    /// Only gauges can be time-weighted.
+    pub fn new(
+        kind: InputKind,
+        digest_compression: Option<f64>,
+        top_k: Option<(usize, TopBy)>,
+        time_weighted: bool,
+    ) -> Self {
+        let digest = match kind {
+            InputKind::Counter | InputKind::Timer | InputKind::Gauge | InputKind::Custom(_) => {
+                digest_compression.map(|compression| RwLock::new(TDigest::new(compression)))
+            }
+            InputKind::Marker | InputKind::Level | InputKind::Set | InputKind::Text => None,
+        };
+        let distinct = match kind {
+            InputKind::Set => Some(AtomicHyperLogLog::new(DEFAULT_PRECISION)),
+            _ => None,
+        };
+        AtomicScores {
+            kind,
+            scores: unsafe { mem::transmute(AtomicScores::blank()) },
+            ewma: RwLock::new(None),
+            period_last: RwLock::new(None),
+            digest,
+            distinct,
+            last_value: AtomicIsize::new(0),
+            last_seen: RwLock::new(None),
+            defined: TimeHandle::now(),
+            totals: [AtomicIsize::new(0), AtomicIsize::new(0)],
+            previous: RwLock::new(None),
+            top: top_k.map(|(k, by)| TopK {
+                k,
+                by,
+                sketch: RwLock::new(SpaceSaving::new(k * 4)),
+            }),
+            time_weight: if time_weighted && kind == InputKind::Gauge {
+                Some(RwLock::new(TimeWeight::default()))
+            } else {
+                None
+            },
+            variants: None,
+            stripes: OnceLock::new(),
+            floats: OnceLock::new(),
+            races: AtomicUsize::new(0),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Spread updates over one stripe of scores per available core, if not already done.
+    /// Levels are not sharded, their min and max depend on the order of updates.
+    fn shard(&self) {
+        if self.kind == InputKind::Level {
+            return;
+        }
+        self.stripes.get_or_init(|| {
+            let stripes = thread::available_parallelism().map_or(4, |cores| cores.get());
+            (0..stripes)
+                .map(|_| Stripe(AtomicScores::blank().map(AtomicIsize::new)))
+                .collect()
+        });
+    }
+
+    /// Move the values of the stripes (if any) to the metric's own scores.
+    fn fold_stripes(&self) {
+        if let Some(stripes) = self.stripes.get() {
+            for Stripe(stripe) in stripes.iter() {
+                let hits = stripe[HIT].swap(0, AcqRel);
+                if hits == 0 {
+                    continue;
                 }
-                InputKind::Timer => {
-                    snapshot.push(Count(scores[HIT]));
-                    snapshot.push(Sum(scores[SUM]));
-
-                    snapshot.push(Max(scores[MAX]));
-                    snapshot.push(Min(scores[MIN]));
-                    snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
-                    // timer rate uses the COUNT of timer calls per second (not SUM)
-                    snapshot.push(Rate(scores[HIT] as f64 / duration_seconds))
+                self.scores[SUM].fetch_add(stripe[SUM].swap(0, AcqRel), AcqRel);
+                let max = stripe[MAX].swap(isize::MIN, AcqRel);
+                swap_if(&self.scores[MAX], max, |new, current| new > current);
+                let min = stripe[MIN].swap(isize::MAX, AcqRel);
+                swap_if(&self.scores[MIN], min, |new, current| new < current);
+                self.scores[HIT].fetch_add(hits, AcqRel);
+            }
+        }
+    }
+
+    /// Aggregate up to `max_variants` labeled variants separately from unlabeled values, if specified.
+    fn with_variants(mut self, max_variants: Option<usize>) -> Self {
+        self.variants = max_variants.map(|max| Variants {
+            max,
+            scores: RwLock::new(HashMap::new()),
+        });
+        self
+    }
+
+    /// Returns the scores of the labels' variant, created if there is room for it.
+    /// Returns `None` if variants are not aggregated separately, for values without labels
+    /// and for new variants beyond the maximum, whose values go to the metric's own scores.
+    /// Returns true if values are aggregated or ranked by their labels.
+    fn keeps_labels(&self) -> bool {
+        self.variants.is_some() || self.top.is_some()
+    }
+
+    fn variant(&self, labels: &Labels) -> Option<Arc<AtomicScores>> {
+        let variants = self.variants.as_ref()?;
+        let key: VariantKey = labels.digest();
+        if key.1 == 0 {
+            return None;
+        }
+        if let Some((_, scores)) = read_lock!(variants.scores).get(&key) {
+            return Some(scores.clone());
+        }
+        let mut scores = write_lock!(variants.scores);
+        if scores.len() >= variants.max && !scores.contains_key(&key) {
+            return None;
+        }
+        let digest_compression = self
+            .digest
+            .as_ref()
+            .map(|digest| read_lock!(digest).compression());
+        let (_, variant) = scores.entry(key).or_insert_with(|| {
+            let variant = AtomicScores::new(
+                self.kind,
+                digest_compression,
+                None,
+                self.time_weight.is_some(),
+            );
+            (Labels::from(labels.clone().into_map()), Arc::new(variant))
+        });
+        Some(variant.clone())
+    }
+
+    /// Reset the labeled variants, returning the stats of those with values. Variants without values are forgotten.
+    fn reset_variants(
+        &self,
+        duration_seconds: f64,
+        quantiles: &[f64],
+        min_rate_period: MinRatePeriod,
+    ) -> Vec<(Labels, Vec<ScoreType>)> {
+        let mut reset = Vec::new();
+        if let Some(variants) = &self.variants {
+            write_lock!(variants.scores).retain(|_, (labels, variant)| {
+                let digest = variant.take_digest();
+                match variant.reset(duration_seconds, false, false, min_rate_period) {
+                    Some(mut values) => {
+                        AtomicScores::push_quantiles(&mut values, digest.as_ref(), quantiles);
+                        reset.push((labels.clone(), values));
+                        true
+                    }
+                    None => false,
                 }
-                InputKind::Counter => {
-                    snapshot.push(Count(scores[HIT]));
-                    snapshot.push(Sum(scores[SUM]));
-
-                    snapshot.push(Max(scores[MAX]));
-                    snapshot.push(Min(scores[MIN]));
-                    snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
-                    // counter rate uses the SUM of values per second (e.g. to get bytes/s)
-                    snapshot.push(Rate(scores[SUM] as f64 / duration_seconds))
+            });
+        }
+        reset
+    }
+
+    /// Returns the stats of the labeled variants with values, without resetting anything.
+    fn peek_variants(
+        &self,
+        duration_seconds: f64,
+        quantiles: &[f64],
+        min_rate_period: MinRatePeriod,
+    ) -> Vec<(Labels, Vec<ScoreType>)> {
+        match &self.variants {
+            Some(variants) => read_lock!(variants.scores)
+                .values()
+                .filter_map(|(labels, variant)| {
+                    variant
+                        .peek(duration_seconds, false, quantiles, min_rate_period)
+                        .map(|values| (labels.clone(), values))
+                })
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the metric's kind.
+    pub fn metric_kind(&self) -> InputKind {
+        self.kind
+    }
+
+    #[inline]
+    fn blank() -> [isize; SCORES_LEN] {
+        [0, 0, isize::MIN, isize::MAX]
+    }
+
+    /// Update scores with new value
+    pub fn update(&self, value: MetricValue) {
+        self.update_n(value, 1)
+    }
+
+    /// Update scores with a value observed `weight` times
+    pub fn update_n(&self, value: MetricValue, weight: usize) {
+        if weight == 0 {
+            return;
+        }
+        let total = value.saturating_mul(weight as isize);
+        // sharded metrics update the current thread's stripe
+        let scores = match self.stripes.get() {
+            Some(stripes) => &stripes[STRIPE.with(|stripe| *stripe) % stripes.len()].0,
+            None => &self.scores,
+        };
+        // Count is tracked for all metrics
+        scores[HIT].fetch_add(weight as isize, Relaxed);
+        match self.kind {
+            InputKind::Marker | InputKind::Text => {}
+            InputKind::Set => {
+                if let Some(distinct) = &self.distinct {
+                    distinct.insert(value)
                 }
-                InputKind::Level => {
-                    snapshot.push(Count(scores[HIT]));
-                    snapshot.push(Sum(scores[SUM]));
-
-                    snapshot.push(Max(scores[MAX]));
-                    snapshot.push(Min(scores[MIN]));
-                    snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
-                    // counter rate uses the SUM of values per second (e.g. to get bytes/s)
-                    snapshot.push(Rate(scores[SUM] as f64 / duration_seconds))
+            }
+            InputKind::Level => {
+                // Level min & max apply to the _sum_ of values
+                // fetch_add only returns the previous sum, so min & max trail behind by one operation
+                // instead, pickup the slack by comparing again with the final sum upon `snapshot`
+                // this is to avoid making an extra load() on every value
+                let prev_sum = scores[SUM].fetch_add(total, Relaxed);
+                let raced_max = swap_if(&scores[MAX], prev_sum, |new, current| new > current);
+                let raced_min = swap_if(&scores[MIN], prev_sum, |new, current| new < current);
+                self.count_race(raced_max || raced_min);
+            }
+            InputKind::Gauge => {
+                // gauges are non cumulative, but we keep the sum to compute the mean
+                scores[SUM].fetch_add(total, Relaxed);
+                let raced_max = swap_if(&scores[MAX], value, |new, current| new > current);
+                let raced_min = swap_if(&scores[MIN], value, |new, current| new < current);
+                self.count_race(raced_max || raced_min);
+                self.last_value.store(value, Relaxed);
+                if let Some(floats) = self.floats.get() {
+                    floats.last.store(f64::NAN.to_bits(), Relaxed);
+                }
+                if let Some(time_weight) = &self.time_weight {
+                    write_lock!(time_weight).hold(value as f64);
                 }
             }
+            InputKind::Counter | InputKind::Timer | InputKind::Custom(_) => {
+                // TODO use #![feature(atomic_min_max)] when stabilized
+                scores[SUM].fetch_add(total, Relaxed);
+                let raced_max = swap_if(&scores[MAX], value, |new, current| new > current);
+                let raced_min = swap_if(&scores[MIN], value, |new, current| new < current);
+                self.count_race(raced_max || raced_min);
+            }
+        }
+        if let Some(digest) = &self.digest {
+            write_lock!(digest).add_weighted(value as f64, weight as f64);
+        }
+        // set last, a flush clearing the flag concurrently leaves the value for the next period
+        self.mark_dirty();
+    }
+
+    /// Update the gauge's scores with a floating point value, aggregated unrounded.
+    fn update_f64(&self, value: f64) {
+        // floats are counted after their scores are updated, a period with their count has their values
+        self.floats.get_or_init(FloatScores::new).update(value);
+        self.scores[HIT].fetch_add(1, Relaxed);
+        self.last_value.store(value.round() as MetricValue, Relaxed);
+        if let Some(time_weight) = &self.time_weight {
+            write_lock!(time_weight).hold(value);
+        }
+        if let Some(digest) = &self.digest {
+            write_lock!(digest).add_weighted(value, 1.0);
+        }
+        self.mark_dirty();
+    }
+
+    /// Flag new values, storing only if not already flagged
+    /// to keep the flag's cache line shared between writers.
+    #[inline]
+    fn mark_dirty(&self) {
+        if !self.dirty.load(Relaxed) {
+            self.dirty.store(true, Release)
+        }
+    }
+
+    /// Returns true if values were written since the last call, clearing the flag.
+    fn take_dirty(&self) -> bool {
+        self.dirty.swap(false, AcqRel)
+    }
+
+    /// Count a concurrent update, if one was detected.
+    #[inline]
+    fn count_race(&self, raced: bool) {
+        if raced {
+            self.races.fetch_add(1, Relaxed);
+        }
+    }
+
+    /// Add the value to the ranking of its labeled variant, if variants are ranked.
+    fn rank(&self, value: MetricValue, weight: usize, labels: Labels) {
+        if let Some(top) = &self.top {
+            let parts = labels.name_parts();
+            if parts.is_empty() {
+                return;
+            }
+            let weight = match top.by {
+                TopBy::Count => weight as isize,
+                TopBy::Sum => value.saturating_mul(weight as isize),
+            };
+            write_lock!(top.sketch).insert(&parts.join("."), weight);
+        }
+    }
+
+    /// Return the heaviest labeled variants of the period as name parts joined by `.`, forget the others.
+    fn take_top(&self) -> Vec<(String, MetricValue)> {
+        match &self.top {
+            Some(top) => {
+                let mut sketch = write_lock!(top.sketch);
+                let variants = sketch.top(top.k);
+                sketch.clear();
+                variants
+                    .into_iter()
+                    .map(|(key, value, _error)| (key, value))
+                    .collect()
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Reset scores to zero, return previous values
+    fn snapshot(&self, scores: &mut [isize; 4], floats: &mut Option<[f64; SCORES_LEN]>) -> bool {
+        self.fold_stripes();
+        // NOTE copy timestamp, count AND sum _before_ testing for data to reduce concurrent discrepancies
+        scores[HIT] = self.scores[HIT].swap(0, AcqRel);
+        scores[SUM] = self.scores[SUM].swap(0, AcqRel);
+
+        // if hit count is zero, no values were recorded.
+        if scores[HIT] == 0 {
+            return false;
+        }
+
+        *write_lock!(self.last_seen) = Some(TimeHandle::now());
+
+        scores[MAX] = self.scores[MAX].swap(isize::MIN, AcqRel);
+        scores[MIN] = self.scores[MIN].swap(isize::MAX, AcqRel);
+        *floats = self.floats.get().and_then(|floats| floats.scores(true));
+
+        if self.kind == InputKind::Level {
+            // fetch_add only returns the previous sum, so min & max trail behind by one operation
+            // pickup the slack by comparing one last time against the final sum
+            if scores[SUM] > scores[MAX] {
+                scores[MAX] = scores[SUM];
+            }
+            if scores[SUM] < scores[MIN] {
+                scores[MIN] = scores[SUM];
+            }
+        }
+
+        *write_lock!(self.previous) = Some((*scores, *floats));
+        true
+    }
+
+    /// Reset current scores into a slice of the period.
+    fn take_slice(&self, duration_seconds: f64) -> Slice {
+        let mut slice = Slice {
+            scores: AtomicScores::blank(),
+            floats: None,
+            duration_seconds,
+            digest: self.take_digest(),
+            distinct: self.distinct.as_ref().map(AtomicHyperLogLog::take),
+            time_weight: self.take_time_weight(),
+        };
+        self.snapshot(&mut slice.scores, &mut slice.floats);
+        slice
+    }
+
+    /// Add raw scores of another period to the current scores.
+    fn absorb(&self, slice: &Slice) {
+        let scores = &slice.scores;
+        // Level min & max are relative to the sum at the start of their own period
+        let offset = if self.kind == InputKind::Level {
+            self.scores[SUM].load(Acquire)
+        } else {
+            0
+        };
+        self.scores[HIT].fetch_add(scores[HIT], AcqRel);
+        self.scores[SUM].fetch_add(scores[SUM], AcqRel);
+        swap_if(&self.scores[MAX], offset + scores[MAX], |new, current| {
+            new > current
+        });
+        swap_if(&self.scores[MIN], offset + scores[MIN], |new, current| {
+            new < current
+        });
+        if let Some(floats) = &slice.floats {
+            self.floats.get_or_init(FloatScores::new).absorb(floats);
+        }
+        if let (Some(into), Some(digest)) = (&self.digest, &slice.digest) {
+            write_lock!(into).merge(digest);
+        }
+        if let (Some(into), Some(distinct)) = (&self.distinct, &slice.distinct) {
+            into.merge(distinct);
+        }
+        if let (Some(into), Some((area, seconds))) = (&self.time_weight, slice.time_weight) {
+            let mut into = write_lock!(into);
+            into.area += area;
+            into.seconds += seconds;
+        }
+        self.mark_dirty();
+    }
+
+    /// Map current raw scores (if any) to applicable statistics, without resetting anything.
+    fn peek(
+        &self,
+        duration_seconds: f64,
+        ewma: bool,
+        quantiles: &[f64],
+        min_rate_period: MinRatePeriod,
+    ) -> Option<Vec<ScoreType>> {
+        self.fold_stripes();
+        let mut scores = AtomicScores::blank();
+        for (score, current) in scores.iter_mut().zip(self.scores.iter()) {
+            *score = current.load(Acquire);
+        }
+        if scores[HIT] == 0 {
+            return None;
+        }
+        if self.kind == InputKind::Level {
+            scores[MAX] = scores[MAX].max(scores[SUM]);
+            scores[MIN] = scores[MIN].min(scores[SUM]);
+        }
+        let floats = self.floats.get().and_then(|floats| floats.scores(false));
+        let mut snapshot = AtomicScores::score_types(
+            self.kind,
+            &scores,
+            floats.as_ref(),
+            duration_seconds,
+            min_rate_period,
+        );
+        if let Some(time_weight) = &self.time_weight {
+            AtomicScores::weigh_mean(&mut snapshot, Some(read_lock!(time_weight).peek()));
+        }
+        if let Some(distinct) = &self.distinct {
+            AtomicScores::push_unique(&mut snapshot, Some(&distinct.load()));
+        }
+        if let Some(digest) = &self.digest {
+            let digest = read_lock!(digest);
+            AtomicScores::push_quantiles(&mut snapshot, Some(&digest), quantiles);
+        }
+        if ewma {
+            AtomicScores::push_ewma(&mut snapshot, *read_lock!(self.ewma));
+        }
+        Some(snapshot)
+    }
+
+    /// Map raw scores (if any) to applicable statistics
+    pub fn reset(
+        &self,
+        duration_seconds: f64,
+        ewma: bool,
+        deltas: bool,
+        min_rate_period: MinRatePeriod,
+    ) -> Option<Vec<ScoreType>> {
+        let mut scores = AtomicScores::blank();
+        let distinct = self.distinct.as_ref().map(AtomicHyperLogLog::take);
+        // held values are weighted up to the end of the period, even if no values were recorded
+        let time_weight = self.take_time_weight();
+        let mut floats = None;
+        let has_values = self.snapshot(&mut scores, &mut floats);
+        self.accumulate(&scores);
+        // moving averages decay even if no values were recorded
+        let averages = if ewma {
+            self.update_ewma(&scores, duration_seconds)
+        } else {
+            None
+        };
+        if has_values {
+            let mut snapshot = AtomicScores::score_types(
+                self.kind,
+                &scores,
+                floats.as_ref(),
+                duration_seconds,
+                min_rate_period,
+            );
+            AtomicScores::weigh_mean(&mut snapshot, time_weight);
+            AtomicScores::push_unique(&mut snapshot, distinct.as_ref());
+            AtomicScores::push_ewma(&mut snapshot, averages);
+            if deltas {
+                AtomicScores::push_delta(&mut snapshot, self.update_delta());
+            }
             Some(snapshot)
         } else {
             None
         }
     }
+
+    /// Move the gauge's last value to the period's, return its change since the previous period.
+    /// Only call for periods with values.
+    fn update_delta(&self) -> Option<isize> {
+        if self.kind != InputKind::Gauge {
+            return None;
+        }
+        let last = self.last_value.load(Acquire);
+        write_lock!(self.period_last)
+            .replace(last)
+            .map(|previous| last - previous)
+    }
+
+    /// Fold the period's rate into the moving averages, return the updated averages.
+    /// Gauges have no rate and thus no moving averages.
+    fn update_ewma(&self, scores: &[isize; SCORES_LEN], duration_seconds: f64) -> Option<[f64; 3]> {
+        let amount = match self.kind {
+            InputKind::Marker | InputKind::Timer | InputKind::Set | InputKind::Text => scores[HIT],
+            InputKind::Counter | InputKind::Level | InputKind::Custom(_) => scores[SUM],
+            InputKind::Gauge => return None,
+        };
+        let mut ewma = write_lock!(self.ewma);
+        if duration_seconds <= 0.0 {
+            return *ewma;
+        }
+        let rate = amount as f64 / duration_seconds;
+        let averages = match *ewma {
+            // first period seeds the averages
+            None => [rate; 3],
+            Some(mut averages) => {
+                for (average, period) in averages.iter_mut().zip(EWMA_PERIODS.iter()) {
+                    let alpha = 1.0 - (-duration_seconds / period).exp();
+                    *average += alpha * (rate - *average);
+                }
+                averages
+            }
+        };
+        *ewma = Some(averages);
+        Some(averages)
+    }
+
+    /// Add a flushed period's count and sum to the totals.
+    fn accumulate(&self, scores: &[isize; SCORES_LEN]) {
+        if scores[HIT] > 0 {
+            self.totals[HIT].fetch_add(scores[HIT], AcqRel);
+            self.totals[SUM].fetch_add(scores[SUM], AcqRel);
+        }
+    }
+
+    /// Replace the period's count and sum with the totals.
+    fn cumulate(&self, snapshot: &mut [ScoreType]) {
+        for score in snapshot.iter_mut() {
+            match score {
+                Count(count) => *count = self.totals[HIT].load(Acquire),
+                Sum(sum) => *sum = self.totals[SUM].load(Acquire),
+                _ => {}
+            }
+        }
+    }
+
+    /// Map the totals (if any values were ever flushed) to applicable statistics.
+    fn totals(&self) -> Option<Vec<ScoreType>> {
+        let hits = self.totals[HIT].load(Acquire);
+        if hits == 0 {
+            return None;
+        }
+        Some(match self.kind {
+            InputKind::Counter => vec![Count(hits), Sum(self.totals[SUM].load(Acquire))],
+            _ => vec![Count(hits)],
+        })
+    }
+
+    /// Returns true if no values were written since the metric was defined or last flushed with values,
+    /// for longer than the time to live. Values of labeled variants keep the metric alive.
+    fn is_idle(&self, ttl: Duration) -> bool {
+        let seen = read_lock!(self.last_seen).unwrap_or(self.defined);
+        if Duration::from_micros(seen.elapsed_us()) <= ttl {
+            return false;
+        }
+        match &self.variants {
+            Some(variants) => read_lock!(variants.scores)
+                .values()
+                .all(|(_, variant)| variant.is_idle(ttl)),
+            None => true,
+        }
+    }
+
+    /// Returns true if the gauge's last value is more recent than the max age.
+    fn is_retained(&self, max_age: Duration) -> bool {
+        if self.kind != InputKind::Gauge {
+            return false;
+        }
+        match *read_lock!(self.last_seen) {
+            Some(seen) => Duration::from_micros(seen.elapsed_us()) <= max_age,
+            None => false,
+        }
+    }
+
+    /// Map the gauge's last value (if retained) to applicable statistics, as if it was just written.
+    fn retained(&self, max_age: Duration) -> Option<Vec<ScoreType>> {
+        if !self.is_retained(max_age) {
+            return None;
+        }
+        if let Some(last) = self.floats.get().and_then(FloatScores::last) {
+            return Some(vec![FloatMax(last), FloatMin(last), Mean(last)]);
+        }
+        let last = self.last_value.load(Acquire);
+        Some(vec![Max(last), Min(last), Mean(last as f64)])
+    }
+
+    /// Map the min, max and mean of the last period with values (if any) to applicable statistics.
+    fn repeated(&self) -> Option<Vec<ScoreType>> {
+        match self.kind {
+            InputKind::Marker | InputKind::Set => None,
+            _ => read_lock!(self.previous)
+                .map(|(scores, floats)| AtomicScores::extremes(&scores, floats.as_ref()).to_vec()),
+        }
+    }
+
+    /// Swap the current digest for an empty one, return the current digest (if any)
+    fn take_digest(&self) -> Option<TDigest> {
+        self.digest.as_ref().map(|digest| {
+            let mut digest = write_lock!(digest);
+            let empty = TDigest::new(digest.compression());
+            mem::replace(&mut *digest, empty)
+        })
+    }
+
+    /// Return the period's time-weighted area and held seconds, if the gauge is time-weighted.
+    fn take_time_weight(&self) -> Option<(f64, f64)> {
+        self.time_weight
+            .as_ref()
+            .map(|time_weight| write_lock!(time_weight).take())
+    }
+
+    /// Replace the mean of values with their time-weighted mean, if any value was held for some time.
+    fn weigh_mean(snapshot: &mut [ScoreType], time_weight: Option<(f64, f64)>) {
+        if let Some((area, seconds)) = time_weight {
+            if seconds > 0.0 {
+                for score in snapshot.iter_mut() {
+                    if let Mean(mean) = score {
+                        *mean = area / seconds
+                    }
+                }
+            }
+        }
+    }
+
+    fn push_quantiles(snapshot: &mut Vec<ScoreType>, digest: Option<&TDigest>, quantiles: &[f64]) {
+        if let Some(digest) = digest {
+            for &quantile in quantiles {
+                if let Some(value) = digest.quantile(quantile) {
+                    snapshot.push(Quantile(quantile, value));
+                }
+            }
+        }
+    }
+
+    fn push_unique(snapshot: &mut Vec<ScoreType>, distinct: Option<&HyperLogLog>) {
+        if let Some(distinct) = distinct {
+            snapshot.push(Unique(distinct.count().round() as isize));
+        }
+    }
+
+    fn push_delta(snapshot: &mut Vec<ScoreType>, delta: Option<isize>) {
+        if let Some(delta) = delta {
+            snapshot.push(Delta(delta));
+        }
+    }
+
+    fn push_ewma(snapshot: &mut Vec<ScoreType>, averages: Option<[f64; 3]>) {
+        if let Some([m1, m5, m15]) = averages {
+            snapshot.push(Ewma1m(m1));
+            snapshot.push(Ewma5m(m5));
+            snapshot.push(Ewma15m(m15));
+        }
+    }
+
+    /// Combine raw scores of consecutive periods, oldest first.
+    /// Returns `None` if no values were recorded in any of the periods.
+    fn merge<'a>(
+        kind: InputKind,
+        slices: impl Iterator<Item = &'a Slice>,
+    ) -> Option<(RawScores, f64)> {
+        let mut merged = AtomicScores::blank();
+        let mut merged_floats: Option<[f64; SCORES_LEN]> = None;
+        let mut duration_seconds = 0.0;
+        for slice in slices {
+            duration_seconds += slice.duration_seconds;
+            if slice.scores[HIT] == 0 {
+                continue;
+            }
+            // Level min & max are relative to the sum at the start of their own period
+            let offset = if kind == InputKind::Level {
+                merged[SUM]
+            } else {
+                0
+            };
+            merged[HIT] += slice.scores[HIT];
+            merged[SUM] += slice.scores[SUM];
+            merged[MAX] = merged[MAX].max(offset + slice.scores[MAX]);
+            merged[MIN] = merged[MIN].min(offset + slice.scores[MIN]);
+            if let Some(floats) = &slice.floats {
+                let into = merged_floats.get_or_insert_with(FloatScores::blank);
+                into[HIT] += floats[HIT];
+                into[SUM] += floats[SUM];
+                into[MAX] = into[MAX].max(floats[MAX]);
+                into[MIN] = into[MIN].min(floats[MIN]);
+            }
+        }
+        if merged[HIT] == 0 {
+            None
+        } else {
+            Some(((merged, merged_floats), duration_seconds))
+        }
+    }
+
+    /// Returns the max, min and mean of the values, unrounded if any was a floating point value.
+    fn extremes(
+        scores: &[isize; SCORES_LEN],
+        floats: Option<&[f64; SCORES_LEN]>,
+    ) -> [ScoreType; 3] {
+        match floats {
+            Some(floats) => {
+                let combined = FloatScores::combine(scores, floats);
+                [
+                    FloatMax(combined[MAX]),
+                    FloatMin(combined[MIN]),
+                    Mean(combined[SUM] / combined[HIT]),
+                ]
+            }
+            None => [
+                Max(scores[MAX]),
+                Min(scores[MIN]),
+                Mean(scores[SUM] as f64 / scores[HIT] as f64),
+            ],
+        }
+    }
+
+    fn score_types(
+        kind: InputKind,
+        scores: &[isize; SCORES_LEN],
+        floats: Option<&[f64; SCORES_LEN]>,
+        duration_seconds: f64,
+        min_rate_period: MinRatePeriod,
+    ) -> Vec<ScoreType> {
+        let mut snapshot = Vec::new();
+        let push_rate = |snapshot: &mut Vec<ScoreType>, amount: isize| {
+            if let Some(rate) = rate(amount, duration_seconds, min_rate_period) {
+                snapshot.push(Rate(rate))
+            }
+        };
+        match kind {
+            InputKind::Marker | InputKind::Set | InputKind::Text => {
+                snapshot.push(Count(scores[HIT]));
+                push_rate(&mut snapshot, scores[HIT])
+            }
+            InputKind::Gauge => snapshot.extend(AtomicScores::extremes(scores, floats)),
+            InputKind::Timer => {
+                snapshot.push(Count(scores[HIT]));
+                snapshot.push(Sum(scores[SUM]));
+
+                snapshot.push(Max(scores[MAX]));
+                snapshot.push(Min(scores[MIN]));
+                snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
+                // timer rate uses the COUNT of timer calls per second (not SUM)
+                push_rate(&mut snapshot, scores[HIT])
+            }
+            InputKind::Counter | InputKind::Custom(_) => {
+                snapshot.push(Count(scores[HIT]));
+                snapshot.push(Sum(scores[SUM]));
+
+                snapshot.push(Max(scores[MAX]));
+                snapshot.push(Min(scores[MIN]));
+                snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
+                // counter rate uses the SUM of values per second (e.g. to get bytes/s)
+                push_rate(&mut snapshot, scores[SUM])
+            }
+            InputKind::Level => {
+                snapshot.push(Count(scores[HIT]));
+                snapshot.push(Sum(scores[SUM]));
+
+                snapshot.push(Max(scores[MAX]));
+                snapshot.push(Min(scores[MIN]));
+                snapshot.push(Mean(scores[SUM] as f64 / scores[HIT] as f64));
+                // counter rate uses the SUM of values per second (e.g. to get bytes/s)
+                push_rate(&mut snapshot, scores[SUM])
+            }
+        }
+        snapshot
+    }
+}
+
+/// Raw values taken from an `AtomicBucket`, to be merged into another bucket.
+#[derive(Debug, Clone, Default)]
+pub struct BucketSnapshot {
+    metrics: BTreeMap<MetricName, (InputKind, Slice)>,
+}
+
+impl BucketSnapshot {
+    /// Returns true if no values were recorded in the snapshot.
+    pub fn is_empty(&self) -> bool {
+        self.metrics.is_empty()
+    }
+}
+
+/// Raw scores of a single flush period.
+#[derive(Debug, Clone)]
+struct Slice {
+    scores: [isize; SCORES_LEN],
+    /// Scores of floating point values, for gauges written any
+    floats: Option<[f64; SCORES_LEN]>,
+    duration_seconds: f64,
+    digest: Option<TDigest>,
+    distinct: Option<HyperLogLog>,
+    /// Time-weighted area and held seconds, for time-weighted gauges only
+    time_weight: Option<(f64, f64)>,
+}
+
+/// Rolling history of raw scores, one slice per flush period.
+#[derive(Debug)]
+struct Window {
+    slices: usize,
+    history: BTreeMap<MetricName, VecDeque<Slice>>,
+}
+
+impl Window {
+    fn new(slices: usize) -> Self {
+        Window {
+            slices,
+            history: BTreeMap::new(),
+        }
+    }
+
+    /// Reset current scores into a new slice, dropping the oldest one if the window is full.
+    /// Map the window's combined scores (if any) to applicable statistics.
+    fn slide<'a>(
+        &mut self,
+        metrics: &'a BTreeMap<MetricName, Arc<AtomicScores>>,
+        duration_seconds: f64,
+        ewma: bool,
+        deltas: bool,
+        quantiles: &[f64],
+        min_rate_period: MinRatePeriod,
+    ) -> Vec<(&'a MetricName, InputKind, Vec<ScoreType>)> {
+        // forget about metrics purged since the last flush
+        self.history.retain(|name, _| metrics.contains_key(name));
+
+        let mut stats = Vec::new();
+        for (name, scores) in metrics {
+            let slice = scores.take_slice(duration_seconds);
+            scores.accumulate(&slice.scores);
+            // moving averages are not windowed, they only ever see the latest period
+            let averages = if ewma {
+                scores.update_ewma(&slice.scores, duration_seconds)
+            } else {
+                None
+            };
+            // deltas are not windowed either, they compare with the previous period only
+            let delta = if deltas && slice.scores[HIT] > 0 {
+                scores.update_delta()
+            } else {
+                None
+            };
+
+            let history = self.history.entry(name.clone()).or_default();
+            history.push_back(slice);
+            while history.len() > self.slices {
+                history.pop_front();
+            }
+
+            if let Some(((merged, floats), window_seconds)) =
+                AtomicScores::merge(scores.kind, history.iter())
+            {
+                let mut values = AtomicScores::score_types(
+                    scores.kind,
+                    &merged,
+                    floats.as_ref(),
+                    window_seconds,
+                    min_rate_period,
+                );
+                let time_weight = history.iter().filter_map(|slice| slice.time_weight).reduce(
+                    |(area, seconds), (more_area, more_seconds)| {
+                        (area + more_area, seconds + more_seconds)
+                    },
+                );
+                AtomicScores::weigh_mean(&mut values, time_weight);
+                let mut sketches = history.iter().filter_map(|slice| slice.distinct.as_ref());
+                if let Some(first) = sketches.next() {
+                    let mut merged = first.clone();
+                    sketches.for_each(|distinct| merged.merge(distinct));
+                    AtomicScores::push_unique(&mut values, Some(&merged));
+                }
+                AtomicScores::push_ewma(&mut values, averages);
+                AtomicScores::push_delta(&mut values, delta);
+                let mut digests = history.iter().filter_map(|slice| slice.digest.as_ref());
+                if let Some(first) = digests.next() {
+                    let mut merged = first.clone();
+                    digests.for_each(|digest| merged.merge(digest));
+                    AtomicScores::push_quantiles(&mut values, Some(&merged), quantiles);
+                }
+                stats.push((name, scores.kind, values))
+            }
+        }
+        stats
+    }
+
+    /// Returns true if any values were recorded for the metric within the window.
+    fn has_values(&self, name: &MetricName) -> bool {
+        match self.history.get(name) {
+            Some(history) => history.iter().any(|s| s.scores[HIT] > 0),
+            None => false,
+        }
+    }
+}
+
+/// Rate of an amount over a period, if any should be published.
+fn rate(amount: isize, duration_seconds: f64, min_rate_period: MinRatePeriod) -> Option<f64> {
+    match min_rate_period {
+        Some((min_seconds, guard)) if duration_seconds < min_seconds => match guard {
+            RateGuard::Skip => None,
+            RateGuard::Clamp => Some(amount as f64 / min_seconds),
+            RateGuard::RawCount => Some(amount as f64),
+        },
+        _ => Some(amount as f64 / duration_seconds),
+    }
+}
+
+/// Write a stat to the target, unrounded if taken as is from a score of floating point values.
+fn write_stat(
+    target: &dyn InputScope,
+    (kind, name, value): (InputKind, MetricName, MetricValue),
+    score: ScoreType,
+    scores: &[ScoreType],
+    labels: Labels,
+) {
+    let metric = target.new_metric(name, kind);
+    let floats = scores
+        .iter()
+        .any(|score| matches!(score, FloatMax(_) | FloatMin(_)));
+    let exact = match score {
+        FloatMax(exact) | FloatMin(exact) => Some(exact),
+        Mean(exact) | Quantile(_, exact) if floats => Some(exact),
+        _ => None,
+    };
+    match exact {
+        Some(exact) if exact.round() as MetricValue == value => metric.write_f64(exact, labels),
+        _ => metric.write(value, labels),
+    }
+}
+
+/// Replace the value stored as `f64` bits with its update.
+#[inline]
+fn update_f64(score: &AtomicU64, update: impl Fn(f64) -> f64) {
+    let _ = score.fetch_update(Release, Acquire, |bits| {
+        Some(update(f64::from_bits(bits)).to_bits())
+    });
 }
 
 /// Spinlock until success or clear loss to concurrent update.
+/// Replace the counter's value if the comparison holds, returns true if a concurrent update was detected.
 #[inline]
-fn swap_if(counter: &AtomicIsize, new_value: isize, compare: fn(isize, isize) -> bool) {
+fn swap_if(counter: &AtomicIsize, new_value: isize, compare: fn(isize, isize) -> bool) -> bool {
     let mut current = counter.load(Acquire);
+    let mut raced = false;
     while compare(new_value, current) {
-        if counter.compare_and_swap(current, new_value, Release) == new_value {
+        match counter.compare_exchange(current, new_value, Release, Acquire) {
             // update successful
-            break;
+            Ok(_) => break,
+            // race detected, retry
+            Err(actual) => {
+                raced = true;
+                current = actual
+            }
         }
-        // race detected, retry
-        current = counter.load(Acquire);
     }
+    raced
 }
 
 #[cfg(feature = "bench")]
 mod bench {
 
     use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
 
     #[bench]
     fn update_marker(b: &mut test::Bencher) {
-        let metric = AtomicScores::new(InputKind::Marker);
+        let metric = AtomicScores::new(InputKind::Marker, None, None, false);
         b.iter(|| test::black_box(metric.update(1)));
     }
 
     #[bench]
     fn update_count(b: &mut test::Bencher) {
-        let metric = AtomicScores::new(InputKind::Counter);
+        let metric = AtomicScores::new(InputKind::Counter, None, None, false);
         b.iter(|| test::black_box(metric.update(4)));
     }
 
     #[bench]
     fn empty_snapshot(b: &mut test::Bencher) {
-        let metric = AtomicScores::new(InputKind::Counter);
+        let metric = AtomicScores::new(InputKind::Counter, None, None, false);
         let scores = &mut AtomicScores::blank();
-        b.iter(|| test::black_box(metric.snapshot(scores)));
+        let floats = &mut None;
+        b.iter(|| test::black_box(metric.snapshot(scores, floats)));
     }
 
     #[bench]
@@ -469,12 +2135,65 @@ mod bench {
         b.iter(|| test::black_box(metric.write(1, labels![])));
     }
 
+    #[bench]
+    fn lookup_defined_counter(b: &mut test::Bencher) {
+        let sink = AtomicBucket::new();
+        let _defined = sink.counter("count_a");
+        b.iter(|| test::black_box(sink.counter("count_a")));
+    }
+
+    #[bench]
+    fn flush_mostly_clean(b: &mut test::Bencher) {
+        let sink = AtomicBucket::new();
+        let counters: Vec<_> = (0..1000)
+            .map(|i| sink.counter(&format!("count_{}", i)))
+            .collect();
+        b.iter(|| {
+            counters[0].count(1);
+            test::black_box(sink.flush_to(&Void::new().metrics()))
+        });
+    }
+
     #[bench]
     fn aggregate_counter(b: &mut test::Bencher) {
         let sink = AtomicBucket::new();
         let metric = sink.new_metric("count_a".into(), InputKind::Counter);
         b.iter(|| test::black_box(metric.write(1, labels![])));
     }
+
+    /// A drain taking its time to publish, e.g. a remote backend.
+    struct SlowScope;
+
+    impl InputScope for SlowScope {
+        fn new_metric(&self, name: MetricName, _kind: InputKind) -> InputMetric {
+            InputMetric::new(MetricId::forge("slow", name), |_value, _labels| {})
+        }
+    }
+
+    impl Flush for SlowScope {
+        fn flush(&self) -> io::Result<()> {
+            thread::sleep(Duration::from_millis(1));
+            Ok(())
+        }
+    }
+
+    #[bench]
+    fn define_during_flush(b: &mut test::Bencher) {
+        let sink = AtomicBucket::new();
+        let stop = Arc::new(AtomicBool::new(false));
+        let flusher = {
+            let sink = sink.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Relaxed) {
+                    sink.flush_to(&SlowScope).unwrap();
+                }
+            })
+        };
+        b.iter(|| test::black_box(sink.counter("count_a").count(1)));
+        stop.store(true, Relaxed);
+        flusher.join().unwrap();
+    }
 }
 
 #[cfg(test)]
@@ -483,7 +2202,11 @@ mod mtest {
     use crate::stats::{stats_all, stats_average, stats_summary};
 
     use crate::clock::{mock_clock_advance, mock_clock_reset};
+    use crate::input::ShareScope;
+    use crate::label::test::TEST_SEQUENCE;
+    use crate::label::AppLabel;
     use crate::output::map::StatsMapScope;
+    use crate::testing::Recorder;
 
     use std::collections::BTreeMap;
     use std::time::Duration;
@@ -578,6 +2301,737 @@ mod mtest {
         assert_eq!(map["test.marker_a"], 3);
     }
 
+    #[test]
+    fn sliding_window() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new().named("test");
+        metrics.sliding_window(2);
+        let counter = metrics.counter("counter_a");
+        let level = metrics.level("level_a");
+
+        let flush = || {
+            mock_clock_advance(Duration::from_secs(1));
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            map.into_map()
+        };
+
+        counter.count(10);
+        level.adjust(5);
+        assert_eq!(flush()["test.counter_a"], 10);
+
+        counter.count(20);
+        level.adjust(-2);
+        level.adjust(4);
+        let map = flush();
+        assert_eq!(map["test.counter_a"], 30);
+        assert_eq!(map["test.level_a"], 2);
+
+        // first slice leaves the window
+        assert_eq!(flush()["test.counter_a"], 20);
+
+        // all slices left the window
+        assert_eq!(flush().get("test.counter_a"), None);
+    }
+
+    #[test]
+    fn sliding_window_level_extremes() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new().named("test");
+        metrics.sliding_window(3);
+        metrics.stats(stats_all);
+        let level = metrics.level("level_a");
+
+        level.adjust(10);
+        metrics.flush_to(&StatsMapScope::default()).unwrap();
+        level.adjust(-15);
+        metrics.flush_to(&StatsMapScope::default()).unwrap();
+        level.adjust(8);
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["test.level_a.max"], 10);
+        assert_eq!(map["test.level_a.min"], -5);
+        assert_eq!(map["test.level_a.sum"], 3);
+    }
+
+    #[test]
+    fn ewma_rates() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new().named("test");
+        metrics.ewma_rates(true);
+        metrics.stats(stats_all);
+        let counter = metrics.counter("counter_a");
+        let gauge = metrics.gauge("gauge_a");
+
+        counter.count(600);
+        gauge.value(4);
+        mock_clock_advance(Duration::from_secs(60));
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        // first period seeds the averages
+        assert_eq!(map["test.counter_a.ewma_1m"], 10);
+        assert_eq!(map["test.counter_a.ewma_15m"], 10);
+        assert_eq!(map.get("test.gauge_a.ewma_1m"), None);
+
+        // an empty period decays the averages without publishing them
+        mock_clock_advance(Duration::from_secs(60));
+        metrics.flush_to(&StatsMapScope::default()).unwrap();
+
+        counter.count(0);
+        mock_clock_advance(Duration::from_secs(60));
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        // 10 * e^-2
+        assert_eq!(map["test.counter_a.ewma_1m"], 1);
+        // 10 * e^-(2/5)
+        assert_eq!(map["test.counter_a.ewma_5m"], 7);
+        // 10 * e^-(2/15)
+        assert_eq!(map["test.counter_a.ewma_15m"], 9);
+    }
+
+    #[test]
+    fn tdigest_quantiles() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.tdigest(100.0, &[0.5, 0.99]);
+        metrics.stats(stats_all);
+        let timer = metrics.timer("timer_a");
+        let marker = metrics.marker("marker_a");
+        for i in 1..=1000 {
+            timer.interval_us(i);
+            marker.mark();
+        }
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert!((map["test.timer_a.p50"] - 500).abs() <= 5);
+        assert!((map["test.timer_a.p99"] - 990).abs() <= 5);
+        assert_eq!(map.get("test.marker_a.p50"), None);
+    }
+
+    #[test]
+    fn flush_report_counts_series() {
+        let reports = Arc::new(RwLock::new(Vec::new()));
+        let reports_in = reports.clone();
+        let metrics = AtomicBucket::new().on_flush_report(move |report| {
+            write_lock!(reports_in).push(report.clone());
+        });
+        metrics.stats(stats_all);
+        metrics.counter("counter_a").count(4);
+        metrics.marker("marker_a").mark();
+
+        metrics.flush_to(&StatsMapScope::default()).unwrap();
+        metrics.flush_to(&StatsMapScope::default()).unwrap();
+
+        let reports = read_lock!(reports);
+        assert_eq!(reports.len(), 2);
+        assert_eq!(reports[0].output, "bucket");
+        // six counter stats + two marker stats
+        assert_eq!(reports[0].series, 8);
+        assert_eq!(reports[0].errors, 0);
+        // nothing recorded since previous flush
+        assert_eq!(reports[1].series, 0);
+    }
+
+    #[test]
+    fn absorb_snapshots() {
+        let shard_a = AtomicBucket::new();
+        let shard_b = AtomicBucket::new();
+        shard_a.counter("counter_a").count(3);
+        shard_b.counter("counter_a").count(5);
+        shard_b.gauge("gauge_a").value(7);
+
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        metrics.absorb(shard_a.snapshot());
+        metrics.absorb(shard_b.snapshot());
+        assert!(shard_a.snapshot().is_empty());
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map: BTreeMap<String, MetricValue> = map.into();
+        assert_eq!(map["counter_a.count"], 2);
+        assert_eq!(map["counter_a.sum"], 8);
+        assert_eq!(map["counter_a.min"], 3);
+        assert_eq!(map["counter_a.max"], 5);
+        assert_eq!(map["gauge_a.mean"], 7);
+    }
+
+    #[test]
+    fn set_unique_values() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        let set = metrics.set("set_a");
+        for i in 0..100 {
+            set.insert(i % 10);
+        }
+        set.insert("eleven");
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map: BTreeMap<String, MetricValue> = map.into();
+        assert_eq!(map["set_a.count"], 101);
+        assert_eq!(map["set_a.unique"], 11);
+    }
+
+    #[test]
+    fn retain_gauges() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        metrics.retain_gauges(Some(Duration::from_secs(15)));
+        metrics.gauge("gauge_a").value(5);
+
+        let flush = || {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            let map: BTreeMap<String, MetricValue> = map.into();
+            map
+        };
+        assert_eq!(flush()["gauge_a.mean"], 5);
+
+        // not updated, last value republished
+        mock_clock_advance(Duration::from_secs(10));
+        assert_eq!(flush()["gauge_a.mean"], 5);
+
+        // too old
+        mock_clock_advance(Duration::from_secs(10));
+        assert!(flush().is_empty());
+    }
+
+    #[test]
+    fn gauge_deltas() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        metrics.gauge_deltas(true);
+        let gauge = metrics.gauge("gauge_a");
+
+        let flush = || {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            let map: BTreeMap<String, MetricValue> = map.into();
+            map
+        };
+        gauge.value(100);
+        assert_eq!(flush().get("gauge_a.delta"), None);
+
+        gauge.value(110);
+        gauge.value(130);
+        assert_eq!(flush()["gauge_a.delta"], 30);
+
+        // compares with the last period with values
+        assert!(flush().is_empty());
+        gauge.value(125);
+        assert_eq!(flush()["gauge_a.delta"], -5);
+    }
+
+    #[test]
+    fn time_weighted_gauges() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        metrics.time_weighted_gauges(true);
+        let gauge = metrics.gauge("gauge_a");
+
+        let flush = || {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            let map: BTreeMap<String, MetricValue> = map.into();
+            map
+        };
+        gauge.value(10);
+        mock_clock_advance(Duration::from_secs(3));
+        gauge.value(30);
+        mock_clock_advance(Duration::from_secs(1));
+        assert_eq!(flush()["gauge_a.mean"], 15);
+
+        // the last value is held across periods
+        mock_clock_advance(Duration::from_secs(2));
+        assert!(flush().is_empty());
+        mock_clock_advance(Duration::from_secs(2));
+        gauge.value(40);
+        mock_clock_advance(Duration::from_secs(2));
+        let map = flush();
+        assert_eq!(map["gauge_a.mean"], 35);
+        assert_eq!(map["gauge_a.max"], 40);
+    }
+
+    #[test]
+    fn min_rate_period() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        let counter = metrics.counter("counter_a");
+
+        let flush = || {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            let map: BTreeMap<String, MetricValue> = map.into();
+            map
+        };
+        counter.count(10);
+        mock_clock_advance(Duration::from_millis(10));
+        assert_eq!(flush()["counter_a.rate"], 1000);
+
+        metrics.min_rate_period(Duration::from_secs(1), RateGuard::Clamp);
+        counter.count(10);
+        mock_clock_advance(Duration::from_millis(10));
+        assert_eq!(flush()["counter_a.rate"], 10);
+
+        metrics.min_rate_period(Duration::from_secs(1), RateGuard::RawCount);
+        counter.count(20);
+        mock_clock_advance(Duration::from_millis(10));
+        assert_eq!(flush()["counter_a.rate"], 20);
+
+        metrics.min_rate_period(Duration::from_secs(1), RateGuard::Skip);
+        counter.count(10);
+        mock_clock_advance(Duration::from_millis(10));
+        let map = flush();
+        assert_eq!(map.get("counter_a.rate"), None);
+        assert_eq!(map["counter_a.sum"], 10);
+
+        // periods long enough are not affected
+        counter.count(10);
+        mock_clock_advance(Duration::from_secs(2));
+        assert_eq!(flush()["counter_a.rate"], 5);
+    }
+
+    #[test]
+    fn repeat_min_max() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        metrics.repeat_min_max(true);
+        let timer = metrics.timer("timer_a");
+        let marker = metrics.marker("marker_a");
+        timer.interval_us(10);
+        timer.interval_us(20);
+        marker.mark();
+
+        let flush = || {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            let map: BTreeMap<String, MetricValue> = map.into();
+            map
+        };
+        assert_eq!(flush()["timer_a.count"], 2);
+
+        // nothing recorded, previous extremes repeated
+        let map = flush();
+        assert_eq!(map["timer_a.max"], 20);
+        assert_eq!(map["timer_a.min"], 10);
+        assert_eq!(map["timer_a.mean"], 15);
+        assert_eq!(map.get("timer_a.count"), None);
+        assert_eq!(map.get("marker_a.count"), None);
+    }
+
+    #[test]
+    fn top_k_variants() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        let metrics = AtomicBucket::new();
+        metrics.top_k(2, TopBy::Sum);
+        let counter = metrics.new_metric("bytes".into(), InputKind::Counter);
+        counter.write(10, labels!("path" => "/a"));
+        counter.write(30, labels!("path" => "/b"));
+        counter.write(5, labels!("path" => "/c"));
+        counter.write(10, labels!("path" => "/a"));
+        counter.write(100, labels![]);
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map: BTreeMap<String, MetricValue> = map.into();
+        assert_eq!(map["bytes.top.path._b"], 30);
+        assert_eq!(map["bytes.top.path._a"], 20);
+        assert_eq!(map.get("bytes.top.path._c"), None);
+    }
+
+    #[test]
+    fn top_zero_variants() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        let metrics = AtomicBucket::new();
+        metrics.top_k(0, TopBy::Sum);
+        let counter = metrics.new_metric("bytes".into(), InputKind::Counter);
+        // weighted totals saturate
+        counter.write_n(isize::MAX, 2, labels!("path" => "/a"));
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map: BTreeMap<String, MetricValue> = map.into();
+        assert_eq!(map.get("bytes.top.path._a"), None);
+        assert_eq!(map["bytes"], isize::MAX);
+    }
+
+    /// Captures values by metric name and `path` label.
+    #[derive(Clone, Default)]
+    struct PathScope(Arc<RwLock<PathValues>>);
+
+    type PathValues = BTreeMap<(String, Option<String>), MetricValue>;
+
+    impl InputScope for PathScope {
+        fn new_metric(&self, name: MetricName, _kind: InputKind) -> InputMetric {
+            let values = self.0.clone();
+            InputMetric::new(
+                MetricId::forge("path", name.clone()),
+                move |value, labels| {
+                    let path = labels.lookup("path").map(|path| path.to_string());
+                    write_lock!(values).insert((name.join("."), path), value);
+                },
+            )
+        }
+    }
+
+    impl ShareScope for PathScope {}
+
+    impl Flush for PathScope {
+        fn flush(&self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn labeled_variants() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_average);
+        metrics.labeled_variants(2);
+        let counter = metrics.new_metric("bytes".into(), InputKind::Counter);
+        counter.write(10, labels!("path" => "/a"));
+        counter.write(30, labels!("path" => "/b"));
+        counter.write(5, labels!("path" => "/c"));
+        counter.write(20, labels!("path" => "/a"));
+        counter.write(100, labels![]);
+
+        let scope = PathScope::default();
+        metrics.flush_to(&scope).unwrap();
+        let values = read_lock!(scope.0);
+        let path = |path: Option<&str>| values[&("bytes".to_string(), path.map(String::from))];
+        assert_eq!(path(Some("/a")), 15);
+        assert_eq!(path(Some("/b")), 30);
+        // beyond the maximum, merged with unlabeled values
+        assert_eq!(path(None), 53);
+        assert_eq!(values.len(), 3);
+    }
+
+    #[test]
+    fn flush_skips_clean_metrics() {
+        let metrics = AtomicBucket::new();
+        let busy = metrics.counter("busy");
+        let _idle = metrics.counter("idle");
+        let dirty = |name: &str| {
+            read_lock!(metrics.inner).metrics[&name.into()]
+                .dirty
+                .load(Relaxed)
+        };
+        busy.count(1);
+        assert!(dirty("busy"));
+        assert!(!dirty("idle"));
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["busy"], 1);
+        assert_eq!(map.get("idle"), None);
+        assert!(!dirty("busy"));
+
+        // values written after a skipped flush are still published
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        busy.count(2);
+        metrics.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["busy"], 2);
+    }
+
+    #[test]
+    fn sharded_metrics() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        metrics.shard_metric("hot");
+        metrics.auto_shard(10);
+        let hot = metrics.counter("hot");
+        let threads: Vec<_> = (1..=4)
+            .map(|i| {
+                let hot = hot.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        hot.count(i)
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let contended = metrics.new_metric("contended".into(), InputKind::Counter);
+        contended.write(1, labels![]);
+        let scores = read_lock!(metrics.inner).metrics[&"contended".into()].clone();
+        scores.races.store(11, Relaxed);
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["hot.count"], 400);
+        assert_eq!(map["hot.sum"], 1000);
+        assert_eq!(map["hot.max"], 4);
+        assert_eq!(map["hot.min"], 1);
+        assert!(scores.stripes.get().is_some());
+    }
+
+    #[test]
+    fn increments() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        let counter = metrics.counter("counter_a");
+        let level = metrics.level("level_a");
+        let marker = metrics.marker("marker_a");
+        counter.incr();
+        counter.incr();
+        level.incr();
+        level.decr();
+        level.decr();
+        marker.mark();
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(map["counter_a.sum"], 2);
+        assert_eq!(map["marker_a.count"], 1);
+        assert_eq!(map["level_a.sum"], -1);
+        assert_eq!(map["level_a.max"], 1);
+    }
+
+    #[test]
+    fn weighted_values() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        let counter = metrics.counter("counter_a");
+        counter.count_n(10, 4);
+        counter.count(20);
+        counter.count_n(30, 0);
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map: BTreeMap<String, MetricValue> = map.into();
+        assert_eq!(map["counter_a.count"], 5);
+        assert_eq!(map["counter_a.sum"], 60);
+        assert_eq!(map["counter_a.mean"], 12);
+        assert_eq!(map["counter_a.min"], 10);
+        assert_eq!(map["counter_a.max"], 20);
+    }
+
+    #[test]
+    fn float_gauges() {
+        let metrics = AtomicBucket::new();
+        let load = metrics.gauge("load");
+        load.value_f64(0.37);
+
+        let recorder = Recorder::default();
+        metrics.flush_to(&recorder).unwrap();
+        assert_eq!(recorder.floats(), vec![0.37]);
+
+        metrics.stats(stats_all);
+        metrics.sliding_window(2);
+        load.value_f64(0.5);
+        metrics.flush_to(&recorder).unwrap();
+        load.value(1);
+        let peeked = Recorder::default();
+        metrics.peek_to(&peeked).unwrap();
+        assert_eq!(peeked.values(), vec![1, 1, 1]);
+
+        let recorder = Recorder::default();
+        metrics.flush_to(&recorder).unwrap();
+        // max, min and mean of the window's two periods
+        assert_eq!(recorder.floats(), vec![1.0, 0.5, 0.75]);
+    }
+
+    #[test]
+    fn phased_timer() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        let timer = metrics.phased_timer("db");
+
+        let waiting = timer.start_wait();
+        mock_clock_advance(Duration::from_millis(3));
+        let serving = waiting.start_service();
+        mock_clock_advance(Duration::from_millis(5));
+        assert_eq!(serving.stop(), 5_000);
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map: BTreeMap<String, MetricValue> = map.into();
+        assert_eq!(map["db.wait.sum"], 3_000);
+        assert_eq!(map["db.service.sum"], 5_000);
+    }
+
+    #[test]
+    fn expire_idle_metrics() {
+        mock_clock_reset();
+        let metrics = AtomicBucket::new();
+        metrics.expire_idle(Duration::from_secs(60));
+        let active = metrics.counter("active");
+        let idle = metrics.counter("idle");
+        idle.count(1);
+
+        for _ in 0..4 {
+            mock_clock_advance(Duration::from_secs(30));
+            active.count(1);
+            metrics.flush().unwrap();
+        }
+
+        let inner = read_lock!(metrics.inner);
+        assert!(inner.metrics.contains_key(&"active".into()));
+        assert!(!inner.metrics.contains_key(&"idle".into()));
+    }
+
+    #[test]
+    fn labeled_values_keep_metric_alive() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        mock_clock_reset();
+        let metrics = AtomicBucket::new();
+        metrics.expire_idle(Duration::from_secs(60));
+        metrics.labeled_variants(4);
+        let counter = metrics.new_metric("requests".into(), InputKind::Counter);
+
+        for _ in 0..4 {
+            mock_clock_advance(Duration::from_secs(30));
+            counter.write(1, labels!("path" => "/a"));
+            metrics.flush().unwrap();
+        }
+        assert!(read_lock!(metrics.inner)
+            .metrics
+            .contains_key(&"requests".into()));
+
+        mock_clock_advance(Duration::from_secs(90));
+        metrics.flush().unwrap();
+        assert!(read_lock!(metrics.inner).metrics.is_empty());
+    }
+
+    #[test]
+    fn weak_handles_allow_purge() {
+        let metrics = AtomicBucket::new();
+        let counter = metrics.counter("counter_a");
+        let weak = counter.downgrade();
+        weak.upgrade().expect("counter alive").count(3);
+
+        drop(counter);
+        assert!(weak.upgrade().is_none());
+        metrics.flush().unwrap();
+        assert!(read_lock!(metrics.inner).metrics.is_empty());
+    }
+
+    #[test]
+    fn cumulative_counters() {
+        let metrics = AtomicBucket::new().named("test");
+        metrics.cumulative_counters(true);
+        metrics.cumulative_counter("counter_b", false);
+        let counter_a = metrics.counter("counter_a");
+        let counter_b = metrics.counter("counter_b");
+        let marker = metrics.marker("marker_a");
+
+        let flush = || {
+            let map = StatsMapScope::default();
+            metrics.flush_to(&map).unwrap();
+            let map: BTreeMap<String, MetricValue> = map.into();
+            map
+        };
+
+        counter_a.count(3);
+        counter_b.count(3);
+        marker.mark();
+        let map = flush();
+        assert_eq!(map["test.counter_a"], 3);
+        assert_eq!(map["test.counter_b"], 3);
+        assert_eq!(map["test.marker_a"], 1);
+
+        counter_a.count(4);
+        counter_b.count(4);
+        let map = flush();
+        assert_eq!(map["test.counter_a"], 7);
+        assert_eq!(map["test.counter_b"], 4);
+        // totals published even without new values
+        assert_eq!(map["test.marker_a"], 1);
+
+        let map = flush();
+        assert_eq!(map["test.counter_a"], 7);
+        assert_eq!(map.get("test.counter_b"), None);
+    }
+
+    #[test]
+    fn peek_does_not_reset() {
+        let metrics = AtomicBucket::new();
+        metrics.stats(stats_all);
+        let counter = metrics.counter("counter_a");
+        counter.count(4);
+
+        let peeked = metrics.peek();
+        assert_eq!(peeked["counter_a.count"], 1);
+        assert_eq!(peeked["counter_a.sum"], 4);
+
+        counter.count(3);
+        assert_eq!(metrics.peek()["counter_a.sum"], 7);
+
+        let map = StatsMapScope::default();
+        metrics.flush_to(&map).unwrap();
+        let map: BTreeMap<String, MetricValue> = map.into();
+        assert_eq!(map["counter_a.count"], 2);
+        assert_eq!(map["counter_a.sum"], 7);
+
+        assert!(metrics.peek().is_empty());
+    }
+
+    #[test]
+    fn concurrent_lookups_share_definitions() {
+        let metrics = AtomicBucket::new();
+        let done = Arc::new(AtomicBool::new(false));
+        // flushes swap out the scores of the period while metrics are looked up and defined
+        let flusher = {
+            let metrics = metrics.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut sums = BTreeMap::new();
+                loop {
+                    let finished = done.load(Relaxed);
+                    let map = StatsMapScope::default();
+                    metrics.flush_to(&map).unwrap();
+                    let map: BTreeMap<String, MetricValue> = map.into();
+                    for (name, value) in map {
+                        *sums.entry(name).or_insert(0) += value;
+                    }
+                    if finished {
+                        return sums;
+                    }
+                }
+            })
+        };
+        let writers: Vec<_> = (0..8)
+            .map(|_| {
+                let metrics = metrics.clone();
+                thread::spawn(move || {
+                    for i in 0..1000 {
+                        metrics.counter(&format!("counter_{}", i % 4)).count(1);
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Relaxed);
+        let sums = flusher.join().unwrap();
+
+        for i in 0..4 {
+            assert_eq!(2000, sums[&format!("counter_{}", i)]);
+        }
+    }
+
     #[test]
     fn external_aggregate_average() {
         let map = make_stats(&stats_average);
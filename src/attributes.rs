@@ -1,13 +1,17 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::default::Default;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
+use crate::clock::Clock;
+use crate::lru_cache::LRUCache;
 use crate::name::{MetricName, NameParts};
 use crate::scheduler::{Cancel, SCHEDULER};
-use crate::{CancelHandle, Flush, InputMetric, InputScope, MetricValue};
+use crate::{CancelHandle, Flush, InputKind, InputMetric, InputScope, MetricValue};
 use std::fmt;
+use std::io::Write;
+use std::sync::{Mutex, PoisonError};
 use std::time::{Duration, Instant};
 
 #[cfg(not(feature = "parking_lot"))]
@@ -59,6 +63,122 @@ impl Default for Buffering {
     }
 }
 
+/// What outputs without native label support do with labels.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum LabelStrategy {
+    /// Ignore labels.
+    #[default]
+    Drop,
+    /// Append labels to the metric's name, e.g. `requests.method.GET`.
+    FoldIntoName,
+    /// Publish the metric without labels, plus a companion series with labels folded into its name.
+    Companion,
+    /// Replace `{key}` placeholders in the metric's name with label values, e.g. `requests.{status}` as `requests.200`.
+    /// Other labels are dropped.
+    Interpolate,
+}
+
+/// Which label keys an output forwards.
+#[derive(Debug, Clone)]
+enum LabelFilter {
+    Retain(Arc<HashSet<String>>),
+    Deny(Arc<HashSet<String>>),
+}
+
+impl LabelFilter {
+    fn forwards(&self, key: &str) -> bool {
+        match self {
+            LabelFilter::Retain(keys) => keys.contains(key),
+            LabelFilter::Deny(keys) => !keys.contains(key),
+        }
+    }
+}
+
+/// Number of transformed label values remembered by an output, most recently used first.
+const LABEL_TRANSFORM_CACHE_SIZE: usize = 1024;
+
+/// Transformation of the values of some labels, e.g. hashing user IDs.
+/// Recently transformed values are cached, expensive transforms are not repeated for frequent values.
+#[derive(Clone)]
+struct LabelTransform {
+    keys: Arc<HashSet<String>>,
+    transform: Arc<dyn Fn(&str) -> String + Send + Sync + 'static>,
+    cache: Shared<LRUCache<String, Arc<String>>>,
+}
+
+impl LabelTransform {
+    fn apply(&self, value: &Arc<String>) -> Arc<String> {
+        if let Some(transformed) = write_lock!(self.cache).get(value) {
+            return transformed.clone();
+        }
+        // the lock is not held while transforming, concurrent misses may transform the same value
+        let transformed = Arc::new((self.transform)(value));
+        write_lock!(self.cache).insert(value.to_string(), transformed.clone());
+        transformed
+    }
+}
+
+/// Remove the labels not forwarded by the filter, then transform the values of others, if any.
+fn filter_labels(
+    filter: &Option<LabelFilter>,
+    transform: &Option<LabelTransform>,
+    labels: Labels,
+) -> Labels {
+    match (filter, transform) {
+        (None, None) => labels,
+        (Some(filter), None) => labels.filter_keys(|key| filter.forwards(key)),
+        (filter, Some(transform)) => {
+            let mut map = labels.into_map();
+            if let Some(filter) = filter {
+                map.retain(|key, _| filter.forwards(key));
+            }
+            for (key, value) in map.iter_mut() {
+                if transform.keys.contains(key) {
+                    *value = transform.apply(value);
+                }
+            }
+            Labels::from(map)
+        }
+    }
+}
+
+/// The unit in which timer values are published.
+/// Timers are always recorded in microseconds, values are converted upon output.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Default)]
+pub enum TimeUnit {
+    /// Nanoseconds, with microsecond accuracy.
+    Nanoseconds,
+    /// Microseconds, as recorded.
+    Microseconds,
+    /// Milliseconds, rounded down.
+    #[default]
+    Milliseconds,
+    /// Seconds, rounded down.
+    Seconds,
+}
+
+impl TimeUnit {
+    /// Convert a microsecond value to this unit.
+    pub fn scale(self, micros: MetricValue) -> MetricValue {
+        match self {
+            TimeUnit::Nanoseconds => micros * 1000,
+            TimeUnit::Microseconds => micros,
+            TimeUnit::Milliseconds => micros / 1000,
+            TimeUnit::Seconds => micros / 1_000_000,
+        }
+    }
+
+    /// Convert a floating point duration in microseconds to this unit, keeping fractions.
+    pub fn scale_f64(self, micros: f64) -> f64 {
+        match self {
+            TimeUnit::Nanoseconds => micros * 1000.0,
+            TimeUnit::Microseconds => micros,
+            TimeUnit::Milliseconds => micros / 1000.0,
+            TimeUnit::Seconds => micros / 1_000_000.0,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Hash, Eq, PartialOrd, PartialEq)]
 pub struct MetricId(String);
 
@@ -67,10 +187,61 @@ impl MetricId {
         let id: String = name.join("/");
         MetricId(format!("{}:{}", out_type, id))
     }
+
+    /// The name of the metric, its parts joined with dots.
+    pub(crate) fn name(&self) -> String {
+        let name = self
+            .0
+            .split_once(':')
+            .map_or(&*self.0, |(_type, name)| name);
+        name.replace('/', ".")
+    }
 }
 
 pub type Shared<T> = Arc<RwLock<T>>;
 
+/// A callback receiving the report of each completed flush.
+pub type FlushReporter = Arc<dyn Fn(&FlushReport) + Send + Sync + 'static>;
+
+/// Summary of the data published by a component since its previous flush.
+#[derive(Debug, Clone)]
+pub struct FlushReport {
+    /// Type of the component that was flushed, e.g. "graphite" or "bucket".
+    pub output: &'static str,
+    /// Number of metric values published.
+    pub series: usize,
+    /// Number of bytes written, if the output is byte-oriented.
+    pub bytes: usize,
+    /// Number of failed writes.
+    pub errors: usize,
+    /// Time taken by the flush operation.
+    pub duration: Duration,
+}
+
+/// Running count of values, bytes and errors published between flushes.
+#[derive(Debug, Default)]
+pub struct FlushTally {
+    series: AtomicUsize,
+    bytes: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+impl FlushTally {
+    /// Count values and bytes successfully published.
+    pub fn sent(&self, series: usize, bytes: usize) {
+        self.series.fetch_add(series, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Count a failed write.
+    pub fn failed(&self) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A callback receiving a copy of the bytes of each batch sent by a network output.
+pub type BatchPreviewer = Arc<dyn Fn(&'static str, &[u8]) + Send + Sync + 'static>;
+
 pub struct Listener {
     listener_id: usize,
     listener_fn: Arc<dyn Fn(Instant) -> () + Send + Sync + 'static>,
@@ -83,8 +254,104 @@ pub struct Attributes {
     naming: NameParts,
     sampling: Sampling,
     buffering: Buffering,
+    timer_unit: TimeUnit,
+    clock: Clock,
+    label_strategy: LabelStrategy,
+    label_filter: Option<LabelFilter>,
+    label_transform: Option<LabelTransform>,
     flush_listeners: Shared<HashMap<MetricId, Listener>>,
+    flush_reporter: Option<FlushReporter>,
+    batch_previewer: Option<BatchPreviewer>,
     tasks: Shared<Vec<CancelHandle>>,
+    shared_scope: SharedScope,
+}
+
+/// A copy of a scope made on first use, shared by the metrics it defines.
+/// Kept by the scope, dropping its metrics has no effect on it. Clones of the scope make their own copy.
+#[derive(Default)]
+struct SharedScope(OnceLock<Arc<dyn InputScope + Send + Sync>>);
+
+impl Clone for SharedScope {
+    fn clone(&self) -> Self {
+        SharedScope::default()
+    }
+}
+
+impl Attributes {
+    /// Returns the copy of the component shared with the metrics it defines, made on first call.
+    pub(crate) fn shared_scope<F>(&self, copy: F) -> Arc<dyn InputScope + Send + Sync>
+    where
+        F: FnOnce() -> Arc<dyn InputScope + Send + Sync>,
+    {
+        self.shared_scope.0.get_or_init(copy).clone()
+    }
+
+    /// Remove the labels this component does not forward and transform the values of others, if any.
+    /// Context labels are resolved once, outputs should call this once per write.
+    pub fn filter_labels(&self, labels: Labels) -> Labels {
+        filter_labels(&self.label_filter, &self.label_transform, labels)
+    }
+
+    /// Filter and transform the labels of every value written to the metric, as set by `FilterLabels`.
+    /// Outputs wrap the metrics they define, writes then get the same labels whatever the output.
+    pub fn filter_metric(&self, metric: InputMetric) -> InputMetric {
+        if self.label_filter.is_none() && self.label_transform.is_none() {
+            return metric;
+        }
+        let filter = self.label_filter.clone();
+        let transform = self.label_transform.clone();
+        metric.map_labels(move |labels| filter_labels(&filter, &transform, labels))
+    }
+
+    /// Reset the tally and send its content to the flush reporter, if any.
+    pub fn report_flush(&self, output: &'static str, start: Instant, tally: &FlushTally) {
+        let series = tally.series.swap(0, Ordering::Relaxed);
+        let bytes = tally.bytes.swap(0, Ordering::Relaxed);
+        let errors = tally.errors.swap(0, Ordering::Relaxed);
+        if let Some(reporter) = &self.flush_reporter {
+            reporter(&FlushReport {
+                output,
+                series,
+                bytes,
+                errors,
+                duration: start.elapsed(),
+            })
+        }
+    }
+
+    /// Register a listener invoked before each flush, replacing any listener of the same metric.
+    fn add_flush_listener(
+        &self,
+        metric_id: MetricId,
+        listener_fn: Arc<dyn Fn(Instant) + Send + Sync + 'static>,
+    ) -> OnFlushCancel {
+        let listener_id = ID_GENERATOR.fetch_add(1, Ordering::Relaxed);
+        write_lock!(self.flush_listeners).insert(
+            metric_id.clone(),
+            Listener {
+                listener_id,
+                listener_fn,
+            },
+        );
+
+        let flush_listeners = self.flush_listeners.clone();
+        OnFlushCancel(Arc::new(move || {
+            let mut listeners = write_lock!(flush_listeners);
+            let installed_listener_id = listeners.get(&metric_id).map(|v| v.listener_id);
+            if let Some(id) = installed_listener_id {
+                if id == listener_id {
+                    listeners.remove(&metric_id);
+                }
+            }
+        }))
+    }
+
+    /// Send a copy of the batch about to be sent to the batch previewer, if any.
+    pub fn preview_batch(&self, output: &'static str, batch: &[u8]) {
+        if let Some(previewer) = &self.batch_previewer {
+            previewer(output, batch)
+        }
+    }
 }
 
 impl fmt::Debug for Attributes {
@@ -158,26 +425,10 @@ where
         let gauge = self.metric;
         let metric_id = gauge.metric_id().clone();
         let op = self.operation;
-        let listener_id = ID_GENERATOR.fetch_add(1, Ordering::Relaxed);
-
-        write_lock!(self.target.get_attributes().flush_listeners).insert(
-            metric_id.clone(),
-            Listener {
-                listener_id,
-                listener_fn: Arc::new(move |now| gauge.write(op(now), Labels::default())),
-            },
-        );
-
-        let flush_listeners = self.target.get_attributes().flush_listeners.clone();
-        OnFlushCancel(Arc::new(move || {
-            let mut listeners = write_lock!(flush_listeners);
-            let installed_listener_id = listeners.get(&metric_id).map(|v| v.listener_id);
-            if let Some(id) = installed_listener_id {
-                if id == listener_id {
-                    listeners.remove(&metric_id);
-                }
-            }
-        }))
+        self.target.get_attributes().add_flush_listener(
+            metric_id,
+            Arc::new(move |now| gauge.write(op(now), Labels::default())),
+        )
     }
 
     /// Observe the metric's value periodically.
@@ -207,6 +458,51 @@ pub trait Observe {
     where
         F: Fn(Instant) -> MetricValue + Send + Sync + 'static,
         Self: Sized;
+
+    /// Define a gauge sampled from the callback every time the scope is flushed,
+    /// e.g. a queue depth or a count of open connections, instead of writing it on every change.
+    /// Shorthand for `observe(gauge, ..).on_flush()`.
+    fn observe_gauge<F>(&self, name: &str, operation: F) -> OnFlushCancel
+    where
+        F: Fn() -> MetricValue + Send + Sync + 'static,
+        Self: InputScope + WithAttributes + Send + Sync + Sized,
+    {
+        ObserveWhen {
+            target: self,
+            metric: self.new_metric(name.into(), InputKind::Gauge),
+            operation: Arc::new(move |_now| operation()),
+        }
+        .on_flush()
+    }
+
+    /// Define a constant info-style gauge, written as `1` with the provided labels every time the scope is flushed,
+    /// e.g. `build_info{version="1.2.3",git_sha="4f2a9c1"} 1`.
+    /// Use `build_info_labels!()` for the conventional build description labels.
+    fn observe_info(&self, name: &str, labels: Labels) -> OnFlushCancel
+    where
+        Self: InputScope + WithAttributes + Send + Sync + Sized,
+    {
+        let gauge = self.new_metric(name.into(), InputKind::Gauge);
+        self.get_attributes().add_flush_listener(
+            gauge.metric_id().clone(),
+            Arc::new(move |_now| gauge.write(1, labels.clone())),
+        )
+    }
+
+    /// Define a marker ticked at regular intervals, as a liveness signal for the service.
+    /// Ticks stop when the returned handle is canceled or when the scope is dropped.
+    /// Shorthand for `observe(marker, |_| 1).every(period)`.
+    fn heartbeat(&self, name: &str, period: Duration) -> CancelHandle
+    where
+        Self: InputScope + WithAttributes + Send + Sync + Sized,
+    {
+        ObserveWhen {
+            target: self,
+            metric: self.new_metric(name.into(), InputKind::Marker),
+            operation: Arc::new(|_now| 1),
+        }
+        .every(period)
+    }
 }
 
 impl<T: InputScope + WithAttributes> Observe for T {
@@ -222,7 +518,8 @@ impl<T: InputScope + WithAttributes> Observe for T {
     {
         ObserveWhen {
             target: self,
-            metric: (*metric).clone(),
+            // kept by the scope, whose copy kept for its siblings would keep the scope in turn
+            metric: (*metric).clone().without_siblings(),
             operation: Arc::new(operation),
         }
     }
@@ -317,6 +614,74 @@ pub trait Sampled: WithAttributes {
     }
 }
 
+/// Receive a summary of published data after each flush.
+/// Useful for meta-monitoring and for asserting on publication volume in tests.
+pub trait ReportFlush: WithAttributes {
+    /// Return a clone that invokes the callback after each flush.
+    /// Changing this only affects scopes opened afterwards.
+    fn on_flush_report<F>(&self, reporter: F) -> Self
+    where
+        F: Fn(&FlushReport) + Send + Sync + 'static,
+    {
+        let reporter: FlushReporter = Arc::new(reporter);
+        self.with_attributes(|new_attr| new_attr.flush_reporter = Some(reporter.clone()))
+    }
+}
+
+/// Tee the exact bytes of each batch sent by a network output, before it is sent.
+/// Useful to diagnose discrepancies between what was published and what the backend shows.
+/// Previews are bounded, batches longer than `max_bytes` are truncated.
+pub trait PreviewBatches: WithAttributes {
+    /// Return a clone writing each batch to the provided writer, e.g. `io::stderr()`.
+    /// Each batch is preceded by a `# <output> batch, <n> bytes` header line.
+    /// Changing this only affects scopes opened afterwards.
+    fn preview_batches_to<W: Write + Send + 'static>(&self, write: W, max_bytes: usize) -> Self {
+        let write = Mutex::new(write);
+        let previewer: BatchPreviewer = Arc::new(move |output, batch| {
+            let (preview, truncated) = truncate(batch, max_bytes);
+            let mut write = write.lock().unwrap_or_else(PoisonError::into_inner);
+            let result = writeln!(write, "# {} batch, {} bytes", output, batch.len())
+                .and_then(|_| write.write_all(preview))
+                .and_then(|_| match preview.last() {
+                    Some(b'\n') | None => Ok(()),
+                    Some(_) => writeln!(write),
+                })
+                .and_then(|_| match truncated {
+                    0 => Ok(()),
+                    _ => writeln!(write, "# {} more bytes not shown", truncated),
+                })
+                .and_then(|_| write.flush());
+            if let Err(e) = result {
+                debug!("Could not write {} batch preview: {}", output, e)
+            }
+        });
+        self.with_attributes(|new_attr| new_attr.batch_previewer = Some(previewer.clone()))
+    }
+
+    /// Return a clone logging each batch at the specified level.
+    /// Changing this only affects scopes opened afterwards.
+    fn preview_batches_to_log(&self, level: log::Level, max_bytes: usize) -> Self {
+        let previewer: BatchPreviewer = Arc::new(move |output, batch| {
+            let (preview, truncated) = truncate(batch, max_bytes);
+            log!(
+                level,
+                "{} batch, {} bytes ({} not shown):\n{}",
+                output,
+                batch.len(),
+                truncated,
+                String::from_utf8_lossy(preview)
+            )
+        });
+        self.with_attributes(|new_attr| new_attr.batch_previewer = Some(previewer.clone()))
+    }
+}
+
+/// Split a batch into the bytes previewed and the number of bytes left out.
+fn truncate(batch: &[u8], max_bytes: usize) -> (&[u8], usize) {
+    let len = batch.len().min(max_bytes);
+    (&batch[..len], batch.len() - len)
+}
+
 /// Determine scope buffering strategy, if supported by output.
 /// Changing this only affects scopes opened afterwards.
 /// Buffering is done on best effort, meaning flush will occur if buffer capacity is exceeded.
@@ -338,6 +703,82 @@ pub trait Buffered: WithAttributes {
     }
 }
 
+/// Select the unit in which timer values are published, if supported by output.
+/// Changing this only affects metrics defined afterwards.
+pub trait ScaledTimers: WithAttributes {
+    /// Return a clone publishing timer values in the specified unit.
+    fn timer_unit(&self, unit: TimeUnit) -> Self {
+        self.with_attributes(|new_attr| new_attr.timer_unit = unit)
+    }
+
+    /// Return the unit in which timer values are published, milliseconds by default.
+    fn get_timer_unit(&self) -> TimeUnit {
+        self.get_attributes().timer_unit
+    }
+}
+
+/// Select the clock read by timers, e.g. a coarse clock when timing very high volumes of operations.
+/// Changing this only affects metrics defined afterwards.
+pub trait ClockedTimers: WithAttributes {
+    /// Return a clone defining timers reading the specified clock.
+    fn timer_clock(&self, clock: Clock) -> Self {
+        self.with_attributes(|new_attr| new_attr.clock = clock)
+    }
+
+    /// Return the clock read by timers, precise by default.
+    fn get_timer_clock(&self) -> Clock {
+        self.get_attributes().clock
+    }
+}
+
+/// Select what to do with labels, for outputs that can not represent them natively.
+/// Changing this only affects metrics defined afterwards.
+pub trait RenderLabels: WithAttributes {
+    /// Return a clone rendering labels with the specified strategy.
+    fn render_labels(&self, strategy: LabelStrategy) -> Self {
+        self.with_attributes(|new_attr| new_attr.label_strategy = strategy)
+    }
+
+    /// Return the label rendering strategy, labels are dropped by default.
+    fn get_label_strategy(&self) -> LabelStrategy {
+        self.get_attributes().label_strategy
+    }
+}
+
+/// Select which labels are forwarded, e.g. to strip high-cardinality labels before a time series database.
+/// Changing this only affects metrics defined afterwards.
+pub trait FilterLabels: WithAttributes {
+    /// Return a clone forwarding only the labels with the specified keys.
+    fn retain_labels(&self, keys: &[&str]) -> Self {
+        let filter =
+            LabelFilter::Retain(Arc::new(keys.iter().map(|key| key.to_string()).collect()));
+        self.with_attributes(|new_attr| new_attr.label_filter = Some(filter.clone()))
+    }
+
+    /// Return a clone forwarding all labels but those with the specified keys.
+    fn deny_labels(&self, keys: &[&str]) -> Self {
+        let filter = LabelFilter::Deny(Arc::new(keys.iter().map(|key| key.to_string()).collect()));
+        self.with_attributes(|new_attr| new_attr.label_filter = Some(filter.clone()))
+    }
+
+    /// Return a clone replacing the values of labels with the specified keys before they are output,
+    /// e.g. to hash user IDs or redact personal information. Values are transformed after filtering.
+    /// The last 1024 transformed values are cached, the transform should always give the same result for a value.
+    fn transform_labels<F>(&self, keys: &[&str], transform: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        let transform = LabelTransform {
+            keys: Arc::new(keys.iter().map(|key| key.to_string()).collect()),
+            transform: Arc::new(transform),
+            cache: Arc::new(RwLock::new(LRUCache::with_capacity(
+                LABEL_TRANSFORM_CACHE_SIZE,
+            ))),
+        };
+        self.with_attributes(|new_attr| new_attr.label_transform = Some(transform.clone()))
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::attributes::*;
@@ -355,4 +796,35 @@ mod test {
         metrics.flush().unwrap();
         assert_eq!(Some(&4), metrics.into_map().get("my_gauge"))
     }
+
+    #[test]
+    fn observe_gauge() {
+        let metrics: StatsMapScope = StatsMap::default().metrics();
+        let depth = Arc::new(AtomicUsize::new(3));
+        let observed = depth.clone();
+        let _cancel =
+            metrics.observe_gauge("depth", move || observed.load(Ordering::Relaxed) as isize);
+        metrics.flush().unwrap();
+        assert_eq!(Some(&3), metrics.clone().into_map().get("depth"));
+        depth.store(5, Ordering::Relaxed);
+        metrics.flush().unwrap();
+        assert_eq!(Some(&5), metrics.into_map().get("depth"))
+    }
+
+    #[test]
+    fn observe_info() {
+        let metrics: StatsMapScope = StatsMap::default().metrics();
+        let _cancel = metrics.observe_info("build_info", labels!("version" => "1.2.3"));
+        metrics.flush().unwrap();
+        assert_eq!(Some(&1), metrics.into_map().get("build_info"))
+    }
+
+    #[test]
+    fn heartbeat() {
+        let metrics: StatsMapScope = StatsMap::default().metrics();
+        // dropping a clone of the scope would cancel its scheduled tasks
+        let _beat = metrics.heartbeat("alive", Duration::from_millis(5));
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(Some(&1), metrics.into_map().get("alive"))
+    }
 }
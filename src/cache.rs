@@ -1,11 +1,16 @@
 //! Metric input scope caching.
 
 use crate::attributes::{Attributes, OnFlush, Prefixed, WithAttributes};
-use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::clock::TimeHandle;
+use crate::input::{Input, InputKind, InputMetric, InputScope};
 use crate::lru_cache as lru;
+use crate::metrics;
 use crate::name::MetricName;
-use crate::Flush;
+use crate::{Flush, QueuedInput};
 
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 #[cfg(not(feature = "parking_lot"))]
@@ -29,12 +34,205 @@ pub trait CachedInput: Input + Send + Sync + 'static + Sized {
     }
 }
 
+/// Eviction rate above which a cache is considered undersized.
+#[derive(Debug, Clone, Copy, Default)]
+struct ThrashLimit {
+    evictions_per_second: Option<usize>,
+    max_size: Option<usize>,
+}
+
+/// Number of independently locked shards of large caches.
+const CACHE_SHARDS: usize = 16;
+
+/// Caches smaller than this are not sharded, their eviction order then spans all definitions.
+const MIN_SHARDED_SIZE: usize = 1024;
+
+/// Full name and kind of a metric, interned as a flat string: hashed in a single pass,
+/// shared by the shard's table and LRU list without copying.
+/// Metrics of different kinds may share a name, the kind is part of the key.
+type CacheKey = Arc<str>;
+
+/// Separates the parts of cache keys, names may contain any other character.
+const KEY_SEPARATOR: char = '\u{1f}';
+
+/// Build the cache key of a metric.
+fn cache_key(name: &MetricName, kind: InputKind) -> String {
+    let mut key = format!("{:?}", kind);
+    for part in name.iter() {
+        key.push(KEY_SEPARATOR);
+        key.push_str(part);
+    }
+    key
+}
+
+/// Start of the current eviction rate measurement.
+struct ThrashPeriod {
+    start: TimeHandle,
+    evictions: usize,
+}
+
+/// An independently locked part of a cache, alone on its cache line.
+#[repr(align(64))]
+struct Shard {
+    lru: RwLock<lru::LRUCache<CacheKey, InputMetric>>,
+    /// Lookups found in this shard, not counted in the `cache.hit` internal metric yet
+    hits: AtomicUsize,
+}
+
+/// Metric definitions shared by all scopes of a cache.
+/// Large caches spread definitions over shards by hash of their key, each shard with its own lock and LRU,
+/// so that threads defining different metrics seldom wait on each other.
+/// Lookups only take a shard's read lock. Capacity applies to all shards together:
+/// a cache holds as many definitions as it was sized for, however they hash.
+struct MetricCache {
+    shards: Box<[Shard]>,
+    capacity: AtomicUsize,
+    /// Definitions held by all shards together, including those being inserted
+    len: AtomicUsize,
+    period: RwLock<ThrashPeriod>,
+}
+
+impl MetricCache {
+    fn with_capacity(max_size: usize) -> Self {
+        let shards = if max_size >= MIN_SHARDED_SIZE {
+            CACHE_SHARDS
+        } else {
+            1
+        };
+        MetricCache {
+            shards: (0..shards)
+                .map(|_| Shard {
+                    lru: RwLock::new(lru::LRUCache::unbounded()),
+                    hits: AtomicUsize::new(0),
+                })
+                .collect(),
+            capacity: AtomicUsize::new(max_size),
+            len: AtomicUsize::new(0),
+            period: RwLock::new(ThrashPeriod {
+                start: TimeHandle::now(),
+                evictions: 0,
+            }),
+        }
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        hasher.finish() as usize % self.shards.len()
+    }
+
+    fn get(&self, key: &str) -> Option<InputMetric> {
+        let shard = &self.shards[self.shard_index(key)];
+        let metric = read_lock!(shard.lru).get_shared(key).cloned();
+        if metric.is_some() {
+            shard.hits.fetch_add(1, Ordering::Relaxed);
+        }
+        metric
+    }
+
+    fn insert(&self, key: String, metric: InputMetric) {
+        let index = self.shard_index(&key);
+        // room is made before inserting, so that the new definition is not the one evicted
+        let reserved = self.len.fetch_add(1, Ordering::AcqRel);
+        if reserved >= self.capacity.load(Ordering::Acquire) {
+            self.evict_from(index);
+        }
+        let replaced = write_lock!(self.shards[index].lru).insert(Arc::from(key), metric);
+        if replaced.is_some() {
+            self.len.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Evict the least recently used definition of the shard, or of the next shard holding any.
+    fn evict_from(&self, index: usize) {
+        for offset in 0..self.shards.len() {
+            let shard = &self.shards[(index + offset) % self.shards.len()];
+            if write_lock!(shard.lru).remove_oldest() {
+                self.len.fetch_sub(1, Ordering::AcqRel);
+                return;
+            }
+        }
+    }
+
+    /// Count the hits of all shards in the `cache.hit` internal metric.
+    fn report_hits(&self) {
+        let hits: usize = self
+            .shards
+            .iter()
+            .map(|shard| shard.hits.swap(0, Ordering::Relaxed))
+            .sum();
+        if hits > 0 {
+            metrics::CACHE_HIT.count(hits)
+        }
+    }
+
+    fn capacity(&self) -> usize {
+        self.capacity.load(Ordering::Acquire)
+    }
+
+    fn evictions(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| read_lock!(shard.lru).evictions())
+            .sum()
+    }
+
+    fn grow(&self, size: usize) {
+        self.capacity.fetch_max(size, Ordering::AcqRel);
+    }
+
+    /// Measure eviction rate at most once per second.
+    /// If above threshold, report thrashing and grow the cache if allowed.
+    fn check_thrash(&self, limit: ThrashLimit) {
+        let threshold = match limit.evictions_per_second {
+            Some(threshold) => threshold,
+            None => return,
+        };
+        // most misses fall within the period, they do not contend for the write lock
+        if read_lock!(self.period).start.elapsed_us() < 1_000_000 {
+            return;
+        }
+        let mut period = write_lock!(self.period);
+        let elapsed_us = period.start.elapsed_us();
+        if elapsed_us < 1_000_000 {
+            return;
+        }
+        let total_evictions = self.evictions();
+        let evictions = total_evictions - period.evictions;
+        period.start = TimeHandle::now();
+        period.evictions = total_evictions;
+
+        let rate = evictions as f64 * 1_000_000.0 / elapsed_us as f64;
+        if rate <= threshold as f64 {
+            return;
+        }
+        metrics::CACHE_THRASH.mark();
+        let capacity = self.capacity();
+        match limit.max_size {
+            Some(max_size) if capacity < max_size => {
+                let new_size = (capacity * 2).clamp(1, max_size);
+                warn!(
+                    "Metric cache thrashing at {:.0} evictions/s, growing from {} to {} entries",
+                    rate, capacity, new_size
+                );
+                self.grow(new_size)
+            }
+            _ => warn!(
+                "Metric cache thrashing at {:.0} evictions/s, consider a size larger than {}",
+                rate, capacity
+            ),
+        }
+    }
+}
+
 /// Output wrapper caching frequently defined metrics
+/// All scopes of the cache share a single target scope, since cached metrics may be used from any of them.
 #[derive(Clone)]
 pub struct InputCache {
     attributes: Attributes,
-    target: Arc<dyn InputDyn + Send + Sync + 'static>,
-    cache: Arc<RwLock<lru::LRUCache<MetricName, InputMetric>>>,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    cache: Arc<MetricCache>,
+    thrash: ThrashLimit,
 }
 
 impl InputCache {
@@ -42,12 +240,33 @@ impl InputCache {
     fn wrap<OUT: Input + Send + Sync + 'static>(target: OUT, max_size: usize) -> InputCache {
         InputCache {
             attributes: Attributes::default(),
-            target: Arc::new(target),
-            cache: Arc::new(RwLock::new(lru::LRUCache::with_capacity(max_size))),
+            target: target.input_dyn(),
+            cache: Arc::new(MetricCache::with_capacity(max_size)),
+            thrash: ThrashLimit::default(),
         }
     }
+
+    /// Returns a clone of the cache detecting thrashing, i.e. evictions of metric definitions
+    /// that are still in use, causing them to be rebuilt over and over.
+    /// Thrashing is reported when evictions per second exceed the threshold,
+    /// as a `DIPSTICK_CACHE_THRASH` internal metric and a log warning.
+    pub fn thrash_threshold(&self, evictions_per_second: usize) -> Self {
+        let mut cloned = self.clone();
+        cloned.thrash.evictions_per_second = Some(evictions_per_second);
+        cloned
+    }
+
+    /// Returns a clone of the cache doubling in size when thrashing is detected, up to `max_size`.
+    /// Only effective if a `thrash_threshold` is also set.
+    pub fn grow_on_thrash(&self, max_size: usize) -> Self {
+        let mut cloned = self.clone();
+        cloned.thrash.max_size = Some(max_size);
+        cloned
+    }
 }
 
+impl QueuedInput for InputCache {}
+
 impl WithAttributes for InputCache {
     fn get_attributes(&self) -> &Attributes {
         &self.attributes
@@ -61,11 +280,11 @@ impl Input for InputCache {
     type SCOPE = InputScopeCache;
 
     fn metrics(&self) -> Self::SCOPE {
-        let target = self.target.input_dyn();
         InputScopeCache {
             attributes: self.attributes.clone(),
-            target,
+            target: self.target.clone(),
             cache: self.cache.clone(),
+            thrash: self.thrash,
         }
     }
 }
@@ -75,7 +294,8 @@ impl Input for InputCache {
 pub struct InputScopeCache {
     attributes: Attributes,
     target: Arc<dyn InputScope + Send + Sync + 'static>,
-    cache: Arc<RwLock<lru::LRUCache<MetricName, InputMetric>>>,
+    cache: Arc<MetricCache>,
+    thrash: ThrashLimit,
 }
 
 impl WithAttributes for InputScopeCache {
@@ -90,19 +310,235 @@ impl WithAttributes for InputScopeCache {
 impl InputScope for InputScopeCache {
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
         let name = self.prefix_append(name);
-        let lookup = { write_lock!(self.cache).get(&name).cloned() };
-        lookup.unwrap_or_else(|| {
-            let new_metric = self.target.new_metric(name.clone(), kind);
-            // FIXME (perf) having to take another write lock for a cache miss
-            write_lock!(self.cache).insert(name, new_metric.clone());
-            new_metric
-        })
+        let key = cache_key(&name, kind);
+        if let Some(metric) = self.cache.get(&key) {
+            return metric;
+        }
+        metrics::CACHE_MISS.mark();
+        self.cache.report_hits();
+        let new_metric = self.target.new_metric(name, kind);
+        self.cache.insert(key, new_metric.clone());
+        self.cache.check_thrash(self.thrash);
+        new_metric
     }
 }
 
 impl Flush for InputScopeCache {
     fn flush(&self) -> io::Result<()> {
         self.notify_flush_listeners();
+        self.cache.report_hits();
         self.target.flush()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attributes::MetricId;
+    use crate::clock::{mock_clock_advance, mock_clock_reset};
+    use crate::output::stream::Stream;
+    use crate::{ActorScope, MetricValue, ShareScope};
+    use std::sync::Mutex;
+    use std::thread;
+    use std::time::Duration;
+
+    type Written = Arc<Mutex<Vec<(String, InputKind, MetricValue)>>>;
+
+    /// Keeps values pending until flushed, separately for every scope.
+    #[derive(Clone, Default)]
+    struct PendingInput {
+        flushed: Written,
+    }
+
+    #[derive(Clone, Default)]
+    struct PendingScope {
+        pending: Written,
+        flushed: Written,
+    }
+
+    impl Input for PendingInput {
+        type SCOPE = PendingScope;
+
+        fn metrics(&self) -> Self::SCOPE {
+            PendingScope {
+                pending: Written::default(),
+                flushed: self.flushed.clone(),
+            }
+        }
+    }
+
+    impl CachedInput for PendingInput {}
+    impl QueuedInput for PendingInput {}
+
+    impl InputScope for PendingScope {
+        fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+            let pending = self.pending.clone();
+            let key = name.join(".");
+            InputMetric::new(MetricId::forge("pending", name), move |value, _labels| {
+                pending.lock().unwrap().push((key.clone(), kind, value))
+            })
+        }
+    }
+
+    impl ShareScope for PendingScope {}
+
+    impl Flush for PendingScope {
+        fn flush(&self) -> io::Result<()> {
+            let mut pending = self.pending.lock().unwrap();
+            self.flushed.lock().unwrap().append(&mut pending);
+            Ok(())
+        }
+    }
+
+    /// Write through the scope, flush it, then wait for values to get through any queue.
+    fn flushed<S: InputScope>(
+        scope: S,
+        flushed: &Written,
+    ) -> Vec<(String, InputKind, MetricValue)> {
+        scope.counter("count_a").count(1);
+        scope.timer("count_a").interval_us(2);
+        scope.counter("count_a").count(3);
+        scope.flush().unwrap();
+        for _ in 0..1000 {
+            if flushed.lock().unwrap().len() == 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        flushed.lock().unwrap().clone()
+    }
+
+    #[test]
+    fn decorator_orderings() {
+        let expected = vec![
+            ("count_a".to_string(), InputKind::Counter, 1),
+            ("count_a".to_string(), InputKind::Timer, 2),
+            ("count_a".to_string(), InputKind::Counter, 3),
+        ];
+        let out = PendingInput::default();
+        let written = &out.flushed;
+
+        let cached_queued = out.clone().cached(8).queued(8);
+        assert_eq!(expected, flushed(cached_queued.metrics(), written));
+        written.lock().unwrap().clear();
+
+        let queued_cached = out.clone().queued(8).cached(8);
+        assert_eq!(expected, flushed(queued_cached.metrics(), written));
+        written.lock().unwrap().clear();
+
+        let cached_actor = out.clone().cached(8).metrics().actor();
+        assert_eq!(expected, flushed(cached_actor, written));
+        written.lock().unwrap().clear();
+
+        let actor_cached = out.metrics().actor().cached(8);
+        assert_eq!(expected, flushed(actor_cached.metrics(), written));
+        written.lock().unwrap().clear();
+
+        let queued_actor = out.clone().queued(8).metrics().actor();
+        assert_eq!(expected, flushed(queued_actor, written));
+        written.lock().unwrap().clear();
+
+        let actor_queued = out.metrics().actor().queued(8);
+        assert_eq!(expected, flushed(actor_queued.metrics(), written));
+    }
+
+    #[test]
+    fn scopes_share_target() {
+        let out = PendingInput::default();
+        let cache = out.clone().cached(8);
+        cache.metrics().counter("count_a").count(1);
+        // cached metric was defined by the first scope, flushing the second scope still flushes it
+        let second = cache.metrics();
+        second.counter("count_a").count(2);
+        second.flush().unwrap();
+        assert_eq!(2, out.flushed.lock().unwrap().len());
+    }
+
+    #[test]
+    fn shared_by_threads() {
+        let cache = Stream::write_to(io::sink()).cached(1024);
+        let threads: Vec<_> = (0..32)
+            .map(|_| {
+                let metrics = cache.metrics();
+                thread::spawn(move || {
+                    for i in 0..64 {
+                        metrics.counter(&format!("counter_{}", i)).count(1);
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(cache.cache.capacity(), 1024);
+        // every definition fits, all threads got the same metrics
+        assert_eq!(cache.cache.evictions(), 0);
+    }
+
+    #[test]
+    fn grow_on_thrash() {
+        mock_clock_reset();
+        let cache = Stream::write_to(io::sink())
+            .cached(2)
+            .thrash_threshold(1)
+            .grow_on_thrash(8);
+        let metrics = cache.metrics();
+        for i in 0..10 {
+            metrics.counter(&format!("counter_{}", i)).count(1);
+        }
+        assert_eq!(cache.cache.capacity(), 2);
+
+        mock_clock_advance(Duration::from_secs(1));
+        metrics.counter("counter_x").count(1);
+        assert_eq!(cache.cache.capacity(), 4);
+
+        // growth is capped
+        for _ in 0..3 {
+            for i in 0..10 {
+                metrics.counter(&format!("counter_{}", i)).count(1);
+            }
+            mock_clock_advance(Duration::from_secs(1));
+            metrics.counter("counter_x").count(1);
+        }
+        assert_eq!(cache.cache.capacity(), 8);
+    }
+
+    #[test]
+    fn sharded_capacity_holds() {
+        let cache = Stream::write_to(io::sink()).cached(MIN_SHARDED_SIZE);
+        let metrics = cache.metrics();
+        // however the names hash, as many as the cache was sized for fit
+        for _ in 0..2 {
+            for i in 0..MIN_SHARDED_SIZE {
+                metrics.counter(&format!("counter_{}", i)).count(1);
+            }
+        }
+        assert_eq!(cache.cache.evictions(), 0);
+        metrics.counter("one_more").count(1);
+        assert_eq!(cache.cache.evictions(), 1);
+    }
+
+    #[test]
+    fn kinds_and_parts_apart() {
+        let cache = Stream::write_to(io::sink()).cached(8);
+        let metrics = cache.metrics();
+        metrics.counter("a.b").count(1);
+        metrics.timer("a.b").interval_us(1);
+        metrics.add_name("a").counter("b").count(1);
+        assert_eq!(cache.cache.len.load(Ordering::Acquire), 3);
+    }
+}
+
+#[cfg(feature = "bench")]
+mod bench {
+
+    use super::*;
+    use crate::output::stream::Stream;
+
+    #[bench]
+    fn lookup_cached_counter(b: &mut test::Bencher) {
+        let cache = Stream::write_to(io::sink()).cached(64).metrics();
+        let _defined = cache.counter("count_a");
+        b.iter(|| test::black_box(cache.counter("count_a")));
+    }
+}
@@ -0,0 +1,205 @@
+//! Clamp physically impossible jumps of gauges fed by unreliable sources, e.g. glitching sensors.
+//! Each intervention is counted, so that the glitches remain visible even if their values are not.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::clock::TimeHandle;
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::label::Labels;
+use crate::name::MetricName;
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Name appended to a clamped gauge's name for the marker counting its interventions.
+pub const CLAMPED: &str = "clamped";
+
+/// Limit how fast gauge values can change.
+pub trait ClampedInput: Input + Send + Sync + 'static + Sized {
+    /// Wrap this input with a decorator limiting gauge changes to `max_delta_per_sec`.
+    fn clamped(self, max_delta_per_sec: f64) -> InputClamp {
+        InputClamp::wrap(self, max_delta_per_sec)
+    }
+}
+
+impl<T: Input + Send + Sync + 'static> ClampedInput for T {}
+
+/// Input decorator limiting how fast gauge values can change.
+#[derive(Clone)]
+pub struct InputClamp {
+    attributes: Attributes,
+    target: Arc<dyn InputDyn + Send + Sync + 'static>,
+    max_delta_per_sec: f64,
+}
+
+impl InputClamp {
+    /// Wrap an input with a decorator limiting gauge changes to `max_delta_per_sec`.
+    pub fn wrap<OUT: Input + Send + Sync + 'static>(
+        target: OUT,
+        max_delta_per_sec: f64,
+    ) -> InputClamp {
+        InputClamp {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            max_delta_per_sec,
+        }
+    }
+}
+
+impl QueuedInput for InputClamp {}
+impl CachedInput for InputClamp {}
+
+impl WithAttributes for InputClamp {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for InputClamp {
+    type SCOPE = InputClampScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        InputClampScope {
+            attributes: self.attributes.clone(),
+            target: self.target.input_dyn(),
+            max_delta_per_sec: self.max_delta_per_sec,
+        }
+    }
+}
+
+/// Scope decorator limiting how fast gauge values can change.
+/// A value further from the previous one than allowed by the time elapsed since is replaced
+/// by the furthest allowed value, and the `clamped` marker of the gauge is marked,
+/// e.g. `temperature.clamped`. The first value of a gauge is written as is.
+/// Other metric kinds are written as is.
+#[derive(Clone)]
+pub struct InputClampScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    max_delta_per_sec: f64,
+}
+
+impl InputClampScope {
+    /// Wrap a scope with a decorator limiting gauge changes to `max_delta_per_sec`.
+    pub fn wrap<SC: InputScope + Send + Sync + 'static>(
+        target: SC,
+        max_delta_per_sec: f64,
+    ) -> Self {
+        InputClampScope {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            max_delta_per_sec,
+        }
+    }
+}
+
+impl WithAttributes for InputClampScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for InputClampScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let target_metric = self.target.new_metric(name.clone(), kind);
+        if kind != InputKind::Gauge {
+            return target_metric;
+        }
+        let mut clamped_name = name.clone();
+        clamped_name.push_back(CLAMPED.to_string());
+        let clamp = Arc::new(Clamp {
+            target: target_metric,
+            clamped: self.target.new_metric(clamped_name, InputKind::Marker),
+            max_delta_per_sec: self.max_delta_per_sec,
+            previous: Mutex::new(None),
+        });
+        let float = clamp.clone();
+        InputMetric::new(MetricId::forge("clamp", name), move |value, labels| {
+            clamp.write(value as f64, false, labels)
+        })
+        .with_float(move |value, labels| float.write(value, true, labels))
+    }
+}
+
+impl Flush for InputClampScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+/// A gauge limiting how fast its value can change.
+struct Clamp {
+    target: InputMetric,
+    clamped: InputMetric,
+    max_delta_per_sec: f64,
+    /// The last value written and when
+    previous: Mutex<Option<(f64, TimeHandle)>>,
+}
+
+impl Clamp {
+    fn write(&self, value: f64, float: bool, labels: Labels) {
+        let (value, clamped) = {
+            let mut previous = self.previous.lock().unwrap_or_else(PoisonError::into_inner);
+            let (value, clamped) = match *previous {
+                Some((last, since)) => {
+                    let max_delta =
+                        self.max_delta_per_sec * since.elapsed_us() as f64 / 1_000_000.0;
+                    let allowed = value.max(last - max_delta).min(last + max_delta);
+                    (allowed, allowed != value)
+                }
+                None => (value, false),
+            };
+            *previous = Some((value, TimeHandle::now()));
+            (value, clamped)
+        };
+        if clamped {
+            self.clamped.write(1, labels.clone())
+        }
+        if float {
+            self.target.write_f64(value, labels)
+        } else {
+            self.target.write(value.round() as isize, labels)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{mock_clock_advance, mock_clock_reset};
+    use crate::output::map::StatsMapScope;
+
+    use std::time::Duration;
+
+    #[test]
+    fn clamp_jumps() {
+        mock_clock_reset();
+        let map = StatsMapScope::default();
+        let metrics = InputClampScope::wrap(map.clone(), 10.0);
+        let gauge = metrics.gauge("temperature");
+        let level = metrics.level("level");
+
+        gauge.value(20);
+        mock_clock_advance(Duration::from_secs(1));
+        gauge.value(25);
+        assert_eq!(map.clone().into_map()["temperature"], 25);
+        assert_eq!(map.clone().into_map().get("temperature.clamped"), None);
+
+        mock_clock_advance(Duration::from_millis(500));
+        gauge.value(-1000);
+        assert_eq!(map.clone().into_map()["temperature"], 20);
+        assert_eq!(map.clone().into_map()["temperature.clamped"], 1);
+
+        // other kinds are not clamped
+        level.adjust(1000);
+        assert_eq!(map.into_map()["level"], 1000);
+    }
+}
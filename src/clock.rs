@@ -1,28 +1,87 @@
 #[cfg(test)]
 use std::ops::Add;
 
-#[cfg(test)]
-use std::time::Duration;
-
-use std::time::Instant;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
 
 use crate::MetricValue;
 
+/// The clock timers read the time from.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Clock {
+    /// Read the system's monotonic clock every time. Default.
+    #[default]
+    Precise,
+    /// Read a cached time, updated every millisecond by a dedicated thread.
+    /// Much cheaper when timing very high volumes of operations, but intervals are only precise to the millisecond.
+    Coarse,
+}
+
+/// Period at which the coarse clock is updated.
+pub const COARSE_CLOCK_TICK: Duration = Duration::from_millis(1);
+
+lazy_static! {
+    static ref COARSE_EPOCH: Instant = Instant::now();
+}
+
+/// Microseconds elapsed between the coarse epoch and the last tick.
+static COARSE_US: AtomicU64 = AtomicU64::new(0);
+
+static COARSE_TICKER: Once = Once::new();
+
+fn coarse_tick(now: Instant) {
+    COARSE_US.store((now - *COARSE_EPOCH).as_micros() as u64, Relaxed)
+}
+
+/// Read the cached time, starting the ticker on first use.
+/// The ticker has its own thread, slow scheduled tasks such as flushes do not hold the time back.
+fn ticker_now() -> Instant {
+    COARSE_TICKER.call_once(|| {
+        coarse_tick(Instant::now());
+        let spawned = thread::Builder::new()
+            .name("dipstick-coarse-clock".to_string())
+            .spawn(|| loop {
+                thread::sleep(COARSE_CLOCK_TICK);
+                coarse_tick(Instant::now())
+            });
+        if let Err(e) = spawned {
+            error!("Could not start the coarse clock thread: {}", e)
+        }
+    });
+    *COARSE_EPOCH + Duration::from_micros(COARSE_US.load(Relaxed))
+}
+
 #[derive(Debug, Copy, Clone)]
 /// A handle to the start time of a counter.
 /// Wrapped so it may be changed safely later.
-pub struct TimeHandle(Instant);
+/// The elapsed time is measured with the same clock the handle was obtained from.
+pub struct TimeHandle(Instant, Clock);
 
 impl TimeHandle {
     /// Get a handle on current time.
     /// Used by the TimerMetric start_time() method.
     pub fn now() -> TimeHandle {
-        TimeHandle(now())
+        TimeHandle(now(), Clock::Precise)
+    }
+
+    /// Get a handle on current time as read from the specified clock.
+    pub fn now_from(clock: Clock) -> TimeHandle {
+        match clock {
+            Clock::Precise => TimeHandle::now(),
+            Clock::Coarse => TimeHandle(coarse_now(), Clock::Coarse),
+        }
     }
 
     /// Get the elapsed time in microseconds since TimeHandle was obtained.
     pub fn elapsed_us(self) -> u64 {
-        let duration = now() - self.0;
+        let now = match self.1 {
+            Clock::Precise => now(),
+            Clock::Coarse => coarse_now(),
+        };
+        let duration = now.saturating_duration_since(self.0);
         (duration.as_secs() * 1_000_000) + u64::from(duration.subsec_micros())
     }
 
@@ -75,6 +134,17 @@ fn now() -> Instant {
     Instant::now()
 }
 
+#[cfg(not(test))]
+fn coarse_now() -> Instant {
+    ticker_now()
+}
+
+#[cfg(test)]
+/// The coarse clock also follows the mock clock in tests.
+fn coarse_now() -> Instant {
+    now()
+}
+
 #[cfg(test)]
 /// Metrics mock_clock enabled!
 /// thread::sleep will have no effect on metrics.
@@ -82,3 +152,16 @@ fn now() -> Instant {
 fn now() -> Instant {
     MOCK_CLOCK.with(|now| *now.borrow())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn coarse_ticker_advances() {
+        let start = ticker_now();
+        thread::sleep(Duration::from_millis(20));
+        let elapsed = ticker_now() - start;
+        assert!(elapsed >= Duration::from_millis(10), "{:?}", elapsed);
+    }
+}
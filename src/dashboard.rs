@@ -0,0 +1,179 @@
+//! Embedded dashboard serving a bucket's values as a plain text table, e.g. at `/debug/metrics`.
+//! The dashboard pulls values from the bucket instead of having them pushed to a backend,
+//! keeping a short history of past periods next to the current one.
+//! No HTTP server is provided, the handler is to be mounted in the application's own.
+
+use crate::atomic::AtomicBucket;
+use crate::output::map::StatsMapScope;
+use crate::scheduler::SCHEDULER;
+use crate::{CancelHandle, MetricValue};
+
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+use std::time::Duration;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// Default path served by a dashboard.
+pub const DASHBOARD_PATH: &str = "/debug/metrics";
+
+/// Content type of the rendered dashboard.
+pub const DASHBOARD_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+/// Values published for a single period.
+type Period = BTreeMap<String, MetricValue>;
+
+/// A bucket's current values and those of its last few periods, rendered as a text table.
+/// Recording a period flushes the bucket to the dashboard's history,
+/// the bucket should not have a drain or be flushed by other means.
+#[derive(Clone)]
+pub struct Dashboard {
+    bucket: AtomicBucket,
+    path: String,
+    periods: usize,
+    history: Arc<RwLock<VecDeque<Period>>>,
+}
+
+impl Dashboard {
+    /// Serve the values of a bucket, keeping the values of its last `periods` recorded periods.
+    pub fn new(bucket: AtomicBucket, periods: usize) -> Self {
+        Dashboard {
+            bucket,
+            path: DASHBOARD_PATH.to_string(),
+            periods,
+            history: Arc::new(RwLock::new(VecDeque::with_capacity(periods + 1))),
+        }
+    }
+
+    /// Serve the dashboard at another path than `/debug/metrics`.
+    pub fn path(&self, path: &str) -> Self {
+        let mut cloned = self.clone();
+        cloned.path = path.to_string();
+        cloned
+    }
+
+    /// End the bucket's current period, moving its values to the history.
+    pub fn record(&self) -> io::Result<()> {
+        let map = StatsMapScope::default();
+        self.bucket.flush_to(&map)?;
+        let mut history = write_lock!(self.history);
+        history.push_front(map.into_map());
+        history.truncate(self.periods);
+        Ok(())
+    }
+
+    /// Record a period at regular intervals.
+    pub fn record_every(&self, period: Duration) -> CancelHandle {
+        let dashboard = self.clone();
+        SCHEDULER.schedule(period, move |_| {
+            if let Err(err) = dashboard.record() {
+                error!("Could not record dashboard period: {}", err);
+            }
+        })
+    }
+
+    /// Handle a request for a path, returning the rendered dashboard if it is served at that path.
+    /// A query string is ignored. Other paths are left to the application, e.g. to answer `404`.
+    /// Responses are of type `DASHBOARD_CONTENT_TYPE`.
+    pub fn handle(&self, path: &str) -> Option<String> {
+        let path = path.split('?').next().unwrap_or(path);
+        if path.trim_end_matches('/') == self.path.trim_end_matches('/') {
+            Some(self.render())
+        } else {
+            None
+        }
+    }
+
+    /// Render the current values and the recorded periods as a text table, one metric per row.
+    /// Columns are the current period `now`, then past periods from most recent `-1` to oldest.
+    /// Metrics without values in a period are shown as `-`.
+    pub fn render(&self) -> String {
+        let history = read_lock!(self.history);
+        let current = self.bucket.peek();
+        let periods: Vec<&Period> = Some(&current).into_iter().chain(history.iter()).collect();
+
+        let mut header = vec!["metric".to_string(), "now".to_string()];
+        header.extend((1..periods.len()).map(|age| format!("-{}", age)));
+
+        let names: BTreeSet<&String> = periods.iter().flat_map(|period| period.keys()).collect();
+        let mut rows = vec![header];
+        for name in names {
+            let mut row = vec![name.clone()];
+            row.extend(periods.iter().map(|period| match period.get(name) {
+                Some(value) => value.to_string(),
+                None => "-".to_string(),
+            }));
+            rows.push(row);
+        }
+        render_table(&rows)
+    }
+}
+
+/// Align the cells of rows in columns, names to the left and values to the right.
+fn render_table(rows: &[Vec<String>]) -> String {
+    let mut widths: Vec<usize> = Vec::new();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            match widths.get_mut(i) {
+                Some(width) => *width = (*width).max(cell.len()),
+                None => widths.push(cell.len()),
+            }
+        }
+    }
+    let mut table = String::new();
+    for row in rows {
+        let mut line = String::new();
+        for (i, cell) in row.iter().enumerate() {
+            // writing to a string can not fail
+            let _ = match i {
+                0 => write!(line, "{:<1$}", cell, widths[i]),
+                _ => write!(line, "  {:>1$}", cell, widths[i]),
+            };
+        }
+        table.push_str(line.trim_end());
+        table.push('\n');
+    }
+    table
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stats::stats_summary;
+    use crate::InputScope;
+
+    #[test]
+    fn render_history() {
+        let bucket = AtomicBucket::new();
+        bucket.stats(stats_summary);
+        let dashboard = Dashboard::new(bucket.clone(), 2);
+        let counter = bucket.counter("requests");
+        let gauge = bucket.gauge("queue_depth");
+
+        counter.count(3);
+        dashboard.record().unwrap();
+        counter.count(25);
+        gauge.value(120);
+        dashboard.record().unwrap();
+        counter.count(1);
+        dashboard.record().unwrap();
+        counter.count(4);
+
+        assert_eq!(
+            dashboard.handle("/debug/metrics?refresh=1"),
+            Some(
+                "metric       now  -1   -2\n\
+                 queue_depth    -   -  120\n\
+                 requests       4   1   25\n"
+                    .to_string()
+            )
+        );
+        assert_eq!(dashboard.handle("/other"), None);
+    }
+}
@@ -0,0 +1,123 @@
+//! Count the values dropped by queues, rate limiters and sampling decorators, per metric,
+//! in a designated "dead letter" output so operators can see what is being lost.
+
+use crate::attributes::MetricId;
+use crate::input::{Counter, InputScope};
+use crate::proxy::Proxy;
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// Reason prepended to the name of a metric whose value was dropped by a full `InputQueue`.
+pub const DEAD_LETTER_QUEUE_DROPPED: &str = "queue_dropped";
+/// Reason prepended to the name of a metric whose value was dropped by a rate limiter.
+pub const DEAD_LETTER_RATE_LIMITED: &str = "rate_limited";
+/// Reason prepended to the name of a metric whose value was left out by label or adaptive sampling.
+pub const DEAD_LETTER_SAMPLED: &str = "sampled";
+
+/// Skip looking up dead letter counters while there is no dead letter output.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref DEAD_LETTERS: Proxy = Proxy::new();
+    static ref COUNTERS: RwLock<HashMap<(&'static str, MetricId), Counter>> =
+        RwLock::new(HashMap::new());
+}
+
+thread_local! {
+    /// Set while counting a dead letter, values the dead letter output itself drops are not counted.
+    static COUNTING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Count dropped values in the target, as counters named after the reason and the metric,
+/// e.g. `rate_limited.requests`. Replaces any previous target.
+/// An aggregating target such as an `AtomicBucket` then sums up the drops of each metric.
+pub fn dead_letter_target<T: InputScope + Send + Sync + 'static>(target: T) {
+    DEAD_LETTERS.target(target);
+    ENABLED.store(true, Ordering::Release);
+}
+
+/// Stop counting dropped values.
+pub fn unset_dead_letter_target() {
+    ENABLED.store(false, Ordering::Release);
+    DEAD_LETTERS.unset_target();
+}
+
+/// Count a value of the metric dropped for the reason, if there is a dead letter output.
+/// The counter is written without holding any lock, the output may drop values (and re-enter) or block.
+pub(crate) fn dead_letter(reason: &'static str, metric: &MetricId) {
+    if !ENABLED.load(Ordering::Acquire) || COUNTING.with(Cell::get) {
+        return;
+    }
+    COUNTING.with(|counting| counting.set(true));
+    counter_of(reason, metric).count(1);
+    COUNTING.with(|counting| counting.set(false));
+}
+
+/// Returns the dead letter counter of the metric, defined on first use.
+fn counter_of(reason: &'static str, metric: &MetricId) -> Counter {
+    let key = (reason, metric.clone());
+    if let Some(counter) = read_lock!(COUNTERS).get(&key) {
+        return counter.clone();
+    }
+    // defined before locking, the output may take its time
+    let counter = DEAD_LETTERS.counter(&format!("{}.{}", reason, metric.name()));
+    write_lock!(COUNTERS).entry(key).or_insert(counter).clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::atomic::AtomicBucket;
+    use crate::output::map::StatsMapScope;
+    use crate::rate_limit::InputRateLimitScope;
+
+    use std::sync::Mutex;
+
+    lazy_static! {
+        /// Dead letter tests share the global dead letter output.
+        static ref TEST_SEQUENCE: Mutex<()> = Mutex::new(());
+    }
+
+    #[test]
+    fn count_rate_limited() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        let bucket = AtomicBucket::new();
+        dead_letter_target(bucket.clone());
+        let metrics = InputRateLimitScope::wrap(StatsMapScope::default(), 1.0);
+        let marker = metrics.marker("dead_letter_loop");
+        for _ in 0..5 {
+            marker.mark();
+        }
+        unset_dead_letter_target();
+        marker.mark();
+
+        let map = StatsMapScope::default();
+        bucket.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["rate_limited.dead_letter_loop"], 4);
+    }
+    #[test]
+    fn dropping_dead_letter_output() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        let bucket = AtomicBucket::new();
+        // the dead letter output drops values itself
+        dead_letter_target(InputRateLimitScope::wrap(bucket.clone(), 1.0));
+        let metrics = InputRateLimitScope::wrap(StatsMapScope::default(), 1.0);
+        let marker = metrics.marker("dead_letter_reentry");
+        for _ in 0..5 {
+            marker.mark();
+        }
+        unset_dead_letter_target();
+
+        let map = StatsMapScope::default();
+        bucket.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["rate_limited.dead_letter_reentry"], 1);
+    }
+}
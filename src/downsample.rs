@@ -0,0 +1,293 @@
+//! Coalesce bursts of gauge values before they reach raw outputs.
+//! A sensor reporting thousands of times per second then costs one write per window downstream.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::clock::TimeHandle;
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::label::Labels;
+use crate::name::MetricName;
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, Weak};
+use std::time::Duration;
+
+/// The value written in place of the values coalesced in a window.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Downsample {
+    /// The last value written.
+    Last,
+    /// The mean of the values written.
+    Mean,
+    /// The highest value written.
+    Max,
+}
+
+/// Coalesce gauge values written within a window into a single value.
+pub trait DownsampledInput: Input + Send + Sync + 'static + Sized {
+    /// Wrap this input with a decorator writing a single gauge value per window.
+    fn downsampled(self, window: Duration, downsample: Downsample) -> InputDownsample {
+        InputDownsample::wrap(self, window, downsample)
+    }
+}
+
+impl<T: Input + Send + Sync + 'static> DownsampledInput for T {}
+
+/// Input decorator coalescing gauge values.
+#[derive(Clone)]
+pub struct InputDownsample {
+    attributes: Attributes,
+    target: Arc<dyn InputDyn + Send + Sync + 'static>,
+    window: Duration,
+    downsample: Downsample,
+}
+
+impl InputDownsample {
+    /// Wrap an input with a decorator writing a single gauge value per window.
+    pub fn wrap<OUT: Input + Send + Sync + 'static>(
+        target: OUT,
+        window: Duration,
+        downsample: Downsample,
+    ) -> InputDownsample {
+        InputDownsample {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            window,
+            downsample,
+        }
+    }
+}
+
+impl QueuedInput for InputDownsample {}
+impl CachedInput for InputDownsample {}
+
+impl WithAttributes for InputDownsample {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for InputDownsample {
+    type SCOPE = InputDownsampleScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        InputDownsampleScope {
+            attributes: self.attributes.clone(),
+            target: self.target.input_dyn(),
+            window: self.window,
+            downsample: self.downsample,
+            pending: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+/// Scope decorator coalescing gauge values.
+/// A window starts with the first value written and ends with the first value written after it elapsed,
+/// which is then written to the target along with the rest of the window's values.
+/// Flushing writes any incomplete window first, so values are never held for longer than a flush period.
+/// The labels of the last value of a window are kept. Other metric kinds are written as is.
+#[derive(Clone)]
+pub struct InputDownsampleScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    window: Duration,
+    downsample: Downsample,
+    pending: Arc<Mutex<Vec<Weak<Coalesced>>>>,
+}
+
+impl InputDownsampleScope {
+    /// Wrap a scope with a decorator writing a single gauge value per window.
+    pub fn wrap<SC: InputScope + Send + Sync + 'static>(
+        target: SC,
+        window: Duration,
+        downsample: Downsample,
+    ) -> Self {
+        InputDownsampleScope {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            window,
+            downsample,
+            pending: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+impl WithAttributes for InputDownsampleScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for InputDownsampleScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let target_metric = self.target.new_metric(name.clone(), kind);
+        if kind != InputKind::Gauge {
+            return target_metric;
+        }
+        let coalesced = Arc::new(Coalesced {
+            target: target_metric,
+            window_us: self.window.as_micros() as u64,
+            downsample: self.downsample,
+            window: Mutex::new(None),
+        });
+        lock(&self.pending).push(Arc::downgrade(&coalesced));
+        let float = coalesced.clone();
+        InputMetric::new(MetricId::forge("downsample", name), move |value, labels| {
+            coalesced.add(value as f64, false, labels)
+        })
+        .with_float(move |value, labels| float.add(value, true, labels))
+    }
+}
+
+impl Flush for InputDownsampleScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        let live: Vec<Arc<Coalesced>> = {
+            let mut pending = lock(&self.pending);
+            pending.retain(|coalesced| coalesced.strong_count() > 0);
+            pending.iter().filter_map(Weak::upgrade).collect()
+        };
+        for coalesced in live {
+            coalesced.write_window();
+        }
+        self.target.flush()
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Values of a gauge written since the start of its current window.
+struct Window {
+    start: TimeHandle,
+    count: usize,
+    sum: f64,
+    max: f64,
+    last: f64,
+    /// Any value written as floating point, the window's value is then written as floating point
+    float: bool,
+    labels: Labels,
+}
+
+/// A gauge coalescing its values.
+struct Coalesced {
+    target: InputMetric,
+    window_us: u64,
+    downsample: Downsample,
+    window: Mutex<Option<Window>>,
+}
+
+impl Coalesced {
+    fn add(&self, value: f64, float: bool, mut labels: Labels) {
+        // the window's value may be written from the flushing thread
+        labels.save_context();
+        let ended = {
+            let mut current = lock(&self.window);
+            let window = current.get_or_insert_with(|| Window {
+                start: TimeHandle::now(),
+                count: 0,
+                sum: 0.0,
+                max: value,
+                last: value,
+                float: false,
+                labels: labels![],
+            });
+            window.count += 1;
+            window.sum += value;
+            window.max = window.max.max(value);
+            window.last = value;
+            window.float |= float;
+            window.labels = labels;
+            if window.start.elapsed_us() >= self.window_us {
+                current.take()
+            } else {
+                None
+            }
+        };
+        if let Some(window) = ended {
+            self.write(window)
+        }
+    }
+
+    /// Write the current window's value (if any) and start a new window.
+    fn write_window(&self) {
+        let current = lock(&self.window).take();
+        if let Some(window) = current {
+            self.write(window)
+        }
+    }
+
+    fn write(&self, window: Window) {
+        let value = match self.downsample {
+            Downsample::Last => window.last,
+            Downsample::Max => window.max,
+            Downsample::Mean => window.sum / window.count as f64,
+        };
+        if window.float || self.downsample == Downsample::Mean {
+            self.target.write_f64(value, window.labels)
+        } else {
+            self.target.write(value as isize, window.labels)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{mock_clock_advance, mock_clock_reset};
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn coalesce_gauges() {
+        mock_clock_reset();
+        let map = StatsMapScope::default();
+        let metrics =
+            InputDownsampleScope::wrap(map.clone(), Duration::from_millis(10), Downsample::Max);
+        let gauge = metrics.gauge("gauge_a");
+        let counter = metrics.counter("counter_a");
+
+        gauge.value(5);
+        gauge.value(9);
+        counter.count(3);
+        // window still open
+        assert_eq!(map.clone().into_map().get("gauge_a"), None);
+        assert_eq!(map.clone().into_map()["counter_a"], 3);
+
+        mock_clock_advance(Duration::from_millis(10));
+        gauge.value(7);
+        assert_eq!(map.clone().into_map()["gauge_a"], 9);
+
+        // incomplete windows are written on flush
+        gauge.value(4);
+        metrics.flush().unwrap();
+        assert_eq!(map.into_map()["gauge_a"], 4);
+    }
+
+    #[test]
+    fn representative_values() {
+        mock_clock_reset();
+        for (downsample, expected) in &[
+            (Downsample::Last, 2),
+            (Downsample::Mean, 5),
+            (Downsample::Max, 9),
+        ] {
+            let map = StatsMapScope::default();
+            let metrics =
+                InputDownsampleScope::wrap(map.clone(), Duration::from_secs(1), *downsample);
+            let gauge = metrics.gauge("gauge_a");
+            gauge.value(4);
+            gauge.value(9);
+            gauge.value(2);
+            metrics.flush().unwrap();
+            assert_eq!(map.into_map()["gauge_a"], *expected);
+        }
+    }
+}
@@ -0,0 +1,352 @@
+//! Switch metrics to a secondary output while the primary output fails.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::label::Labels;
+use crate::name::MetricName;
+use crate::{CachedInput, Flush, MetricValue, QueuedInput};
+
+use std::io;
+use std::mem;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::SystemTime;
+
+/// Default number of consecutive primary flush errors after which metrics go to the secondary output.
+pub const DEFAULT_FAILOVER_AFTER: usize = 3;
+
+/// Maximum number of values held until the next flush tells if the primary delivered them.
+/// Beyond this, values written while failed over go straight to the secondary,
+/// and values written to the primary are not handed to the secondary if it fails.
+const MAX_UNDELIVERED: usize = 100_000;
+
+/// Send metrics to a primary output, or to a secondary output while the primary fails.
+#[derive(Clone)]
+pub struct Failover {
+    attributes: Attributes,
+    primary: Arc<dyn InputDyn + Send + Sync + 'static>,
+    secondary: Arc<dyn InputDyn + Send + Sync + 'static>,
+    failover_after: usize,
+}
+
+impl Failover {
+    /// Send metrics to the primary output, switching to the secondary output
+    /// after `DEFAULT_FAILOVER_AFTER` consecutive primary flush errors.
+    pub fn new<P, S>(primary: P, secondary: S) -> Self
+    where
+        P: Input + Send + Sync + 'static,
+        S: Input + Send + Sync + 'static,
+    {
+        Failover {
+            attributes: Attributes::default(),
+            primary: Arc::new(primary),
+            secondary: Arc::new(secondary),
+            failover_after: DEFAULT_FAILOVER_AFTER,
+        }
+    }
+
+    /// Returns a clone switching to the secondary output after `errors` consecutive primary flush errors.
+    pub fn failover_after(&self, errors: usize) -> Self {
+        let mut cloned = self.clone();
+        cloned.failover_after = errors.max(1);
+        cloned
+    }
+}
+
+impl QueuedInput for Failover {}
+impl CachedInput for Failover {}
+
+impl WithAttributes for Failover {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for Failover {
+    type SCOPE = FailoverScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        FailoverScope {
+            attributes: self.attributes.clone(),
+            primary: self.primary.input_dyn(),
+            secondary: self.secondary.input_dyn(),
+            state: Arc::new(FailoverState::new(self.failover_after)),
+        }
+    }
+}
+
+struct FailoverState {
+    failover_after: usize,
+    errors: AtomicUsize,
+    failed_over: AtomicBool,
+    /// Values written since the last successful flush of the primary, oldest first
+    undelivered: Mutex<Vec<Undelivered>>,
+}
+
+impl FailoverState {
+    fn new(failover_after: usize) -> Self {
+        FailoverState {
+            failover_after,
+            errors: AtomicUsize::new(0),
+            failed_over: AtomicBool::new(false),
+            undelivered: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn undelivered(&self) -> MutexGuard<'_, Vec<Undelivered>> {
+        self.undelivered
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Write the value to the active output, holding it until a flush tells if the primary delivered it.
+    fn write(&self, metric: &Arc<FailoverMetric>, value: Write, labels: Labels) {
+        if !self.failed_over.load(Ordering::Acquire) {
+            value.to(&metric.primary, labels.clone());
+            let mut undelivered = self.undelivered();
+            if undelivered.len() < MAX_UNDELIVERED {
+                undelivered.push(Undelivered::new(metric, value, labels))
+            }
+            return;
+        }
+        let mut undelivered = self.undelivered();
+        if undelivered.len() < MAX_UNDELIVERED {
+            // tried on the primary first upon the next flush
+            undelivered.push(Undelivered::new(metric, value, labels))
+        } else {
+            drop(undelivered);
+            value.to(&metric.secondary, labels)
+        }
+    }
+}
+
+/// A metric defined in both outputs.
+struct FailoverMetric {
+    primary: InputMetric,
+    secondary: InputMetric,
+}
+
+/// A value written to a metric, in any of the ways a metric can be written to.
+#[derive(Clone, Copy)]
+enum Write {
+    Value(MetricValue),
+    Weighted(MetricValue, usize),
+    Float(f64),
+    Timestamped(SystemTime, MetricValue),
+}
+
+impl Write {
+    fn to(self, metric: &InputMetric, labels: Labels) {
+        match self {
+            Write::Value(value) => metric.write(value, labels),
+            Write::Weighted(value, weight) => metric.write_n(value, weight, labels),
+            Write::Float(value) => metric.write_f64(value, labels),
+            Write::Timestamped(timestamp, value) => metric.write_at(timestamp, value, labels),
+        }
+    }
+}
+
+/// A value not known to be delivered by the primary yet.
+struct Undelivered {
+    metric: Arc<FailoverMetric>,
+    value: Write,
+    /// Resolved when written, replayed from the flushing thread
+    labels: Labels,
+}
+
+impl Undelivered {
+    fn new(metric: &Arc<FailoverMetric>, value: Write, labels: Labels) -> Self {
+        Undelivered {
+            metric: metric.clone(),
+            value,
+            labels: Labels::from(labels.into_map()),
+        }
+    }
+}
+
+/// Send metrics to a primary scope, or to a secondary scope while the primary fails.
+/// Since write errors only surface when flushing, the scope switches to the secondary
+/// after a number of consecutive primary flush errors. Values written since the primary's last
+/// successful flush are then handed to the secondary.
+/// While failed over, values are held until the next flush, which tries them on the primary first.
+/// If the primary delivers them, the scope switches back to it, otherwise they go to the secondary.
+/// Values written to the primary before a failed flush may also have been delivered by it.
+#[derive(Clone)]
+pub struct FailoverScope {
+    attributes: Attributes,
+    primary: Arc<dyn InputScope + Send + Sync + 'static>,
+    secondary: Arc<dyn InputScope + Send + Sync + 'static>,
+    state: Arc<FailoverState>,
+}
+
+impl FailoverScope {
+    /// Send metrics to the primary scope, switching to the secondary scope
+    /// after `DEFAULT_FAILOVER_AFTER` consecutive primary flush errors.
+    pub fn new<P, S>(primary: P, secondary: S) -> Self
+    where
+        P: InputScope + Send + Sync + 'static,
+        S: InputScope + Send + Sync + 'static,
+    {
+        FailoverScope {
+            attributes: Attributes::default(),
+            primary: Arc::new(primary),
+            secondary: Arc::new(secondary),
+            state: Arc::new(FailoverState::new(DEFAULT_FAILOVER_AFTER)),
+        }
+    }
+
+    /// Returns a clone switching to the secondary scope after `errors` consecutive primary flush errors.
+    /// The clone does not share the failover state of the original.
+    pub fn failover_after(&self, errors: usize) -> Self {
+        let mut cloned = self.clone();
+        cloned.state = Arc::new(FailoverState::new(errors.max(1)));
+        cloned
+    }
+
+    /// True if metrics currently go to the secondary scope.
+    pub fn is_failed_over(&self) -> bool {
+        self.state.failed_over.load(Ordering::Acquire)
+    }
+
+    /// Write values the primary did not deliver to the secondary scope, then flush it.
+    fn deliver_to_secondary(&self, undelivered: Vec<Undelivered>) -> io::Result<()> {
+        for held in undelivered {
+            held.value.to(&held.metric.secondary, held.labels)
+        }
+        self.secondary.flush()
+    }
+}
+
+impl WithAttributes for FailoverScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for FailoverScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let metric = Arc::new(FailoverMetric {
+            primary: self.primary.new_metric(name.clone(), kind),
+            secondary: self.secondary.new_metric(name.clone(), kind),
+        });
+        let state = self.state.clone();
+        let weighted = metric.clone();
+        let weighted_state = self.state.clone();
+        let floats = metric.clone();
+        let float_state = self.state.clone();
+        let timestamped = metric.clone();
+        let timestamped_state = self.state.clone();
+        InputMetric::new(MetricId::forge("failover", name), move |value, labels| {
+            state.write(&metric, Write::Value(value), labels)
+        })
+        .with_weighted(move |value, weight, labels| {
+            weighted_state.write(&weighted, Write::Weighted(value, weight), labels)
+        })
+        .with_float(move |value, labels| float_state.write(&floats, Write::Float(value), labels))
+        .with_timestamped(move |timestamp, value, labels| {
+            timestamped_state.write(&timestamped, Write::Timestamped(timestamp, value), labels)
+        })
+    }
+}
+
+impl Flush for FailoverScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        let failed_over = self.is_failed_over();
+        let mut undelivered = mem::take(&mut *self.state.undelivered());
+        if failed_over {
+            // held values probe the primary
+            for held in &undelivered {
+                held.value.to(&held.metric.primary, held.labels.clone())
+            }
+        }
+        match self.primary.flush() {
+            Ok(()) => {
+                self.state.errors.store(0, Ordering::Release);
+                if failed_over {
+                    self.state.failed_over.store(false, Ordering::Release);
+                    info!("Primary metrics output recovered, switching back from secondary");
+                }
+                Ok(())
+            }
+            Err(e) if !failed_over => {
+                let errors = self.state.errors.fetch_add(1, Ordering::AcqRel) + 1;
+                if errors < self.state.failover_after {
+                    // handed to the secondary if the primary keeps failing, values written since come after
+                    let mut held = self.state.undelivered();
+                    undelivered.append(&mut held);
+                    undelivered.truncate(MAX_UNDELIVERED);
+                    *held = undelivered;
+                    return Err(e);
+                }
+                self.state.failed_over.store(true, Ordering::Release);
+                warn!(
+                    "Primary metrics output failed {} times, switching to secondary: {}",
+                    errors, e
+                );
+                self.deliver_to_secondary(undelivered)
+            }
+            Err(_) => self.deliver_to_secondary(undelivered),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+    use crate::testing::{FlakyScope, Recorder};
+
+    #[test]
+    fn failover_and_back() {
+        let primary = FlakyScope::default();
+        let secondary = StatsMapScope::default();
+        let metrics = FailoverScope::new(primary.clone(), secondary.clone()).failover_after(2);
+        let gauge = metrics.gauge("gauge");
+
+        primary.set_failing(true);
+        gauge.value(1);
+        assert!(metrics.flush().is_err());
+        assert!(!metrics.is_failed_over());
+        assert!(metrics.flush().is_ok());
+        assert!(metrics.is_failed_over());
+        // handed over when switching
+        assert_eq!(secondary.clone().into_map()["gauge"], 1);
+
+        gauge.value(2);
+        // only written to the active output, the primary gets it as a probe upon flush
+        assert_eq!(primary.map().into_map()["gauge"], 1);
+        assert!(metrics.flush().is_ok());
+        assert_eq!(secondary.clone().into_map()["gauge"], 2);
+
+        primary.set_failing(false);
+        gauge.value(3);
+        assert!(metrics.flush().is_ok());
+        assert!(!metrics.is_failed_over());
+        assert_eq!(primary.map().into_map()["gauge"], 3);
+        assert_eq!(secondary.clone().into_map()["gauge"], 2);
+        gauge.value(4);
+        assert_eq!(primary.map().into_map()["gauge"], 4);
+        assert_eq!(secondary.into_map()["gauge"], 2);
+    }
+
+    #[test]
+    fn weighted_values() {
+        let primary = FlakyScope::default();
+        let secondary = Recorder::default();
+        let metrics = FailoverScope::new(primary.clone(), secondary.clone()).failover_after(1);
+        let counter = metrics.new_metric("hits".into(), InputKind::Counter);
+
+        primary.set_failing(true);
+        counter.write_n(5, 10, labels![]);
+        assert!(metrics.flush().is_ok());
+        assert_eq!(vec![5; 10], secondary.values());
+    }
+}
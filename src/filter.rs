@@ -0,0 +1,211 @@
+//! Keep or discard metrics by name, deciding once when a metric is defined.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::rewrite::{matches, segments};
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::io;
+use std::sync::Arc;
+
+/// Discard metrics by name before they reach the output.
+pub trait FilteredInput: Input + Send + Sync + 'static + Sized {
+    /// Wrap this input with a decorator discarding metrics by name.
+    /// All metrics are kept until patterns are added with `allow()` or `deny()`.
+    fn filtered(self) -> InputFilter {
+        InputFilter::wrap(self)
+    }
+}
+
+impl<T: Input + Send + Sync + 'static> FilteredInput for T {}
+
+/// Allowed and denied name patterns.
+/// Patterns are dot-separated names where `*` matches any one segment
+/// and `**` matches any number of segments, including none.
+#[derive(Debug, Clone, Default)]
+struct NameFilter {
+    allow: Vec<Vec<String>>,
+    deny: Vec<Vec<String>>,
+}
+
+impl NameFilter {
+    /// A metric is kept if it matches no deny pattern and either there are no allow patterns or it matches one.
+    fn keeps(&self, name: &MetricName) -> bool {
+        let joined = name.join(".");
+        let parts: Vec<&str> = joined.split('.').collect();
+        let allowed =
+            self.allow.is_empty() || self.allow.iter().any(|pattern| matches(pattern, &parts));
+        allowed && !self.deny.iter().any(|pattern| matches(pattern, &parts))
+    }
+}
+
+/// Input decorator discarding metrics by name.
+#[derive(Clone)]
+pub struct InputFilter {
+    attributes: Attributes,
+    target: Arc<dyn InputDyn + Send + Sync + 'static>,
+    filter: Arc<NameFilter>,
+}
+
+impl InputFilter {
+    /// Wrap an input with a name filtering decorator.
+    /// All metrics are kept until patterns are added.
+    pub fn wrap<OUT: Input + Send + Sync + 'static>(target: OUT) -> InputFilter {
+        InputFilter {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            filter: Arc::new(NameFilter::default()),
+        }
+    }
+
+    /// Returns a clone of the decorator keeping only metrics matching this or any other allow pattern,
+    /// e.g. `http.**`.
+    pub fn allow(&self, pattern: &str) -> Self {
+        let mut cloned = self.clone();
+        Arc::make_mut(&mut cloned.filter)
+            .allow
+            .push(segments(pattern));
+        cloned
+    }
+
+    /// Returns a clone of the decorator discarding metrics matching the pattern, e.g. `*.debug.**`,
+    /// even if they match an allow pattern.
+    pub fn deny(&self, pattern: &str) -> Self {
+        let mut cloned = self.clone();
+        Arc::make_mut(&mut cloned.filter)
+            .deny
+            .push(segments(pattern));
+        cloned
+    }
+}
+
+impl QueuedInput for InputFilter {}
+impl CachedInput for InputFilter {}
+
+impl WithAttributes for InputFilter {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for InputFilter {
+    type SCOPE = InputFilterScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        InputFilterScope {
+            attributes: self.attributes.clone(),
+            target: self.target.input_dyn(),
+            filter: self.filter.clone(),
+        }
+    }
+}
+
+/// Scope decorator discarding metrics by name.
+/// Metrics are kept or discarded once when they are defined, writing values costs nothing more.
+#[derive(Clone)]
+pub struct InputFilterScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    filter: Arc<NameFilter>,
+}
+
+impl InputFilterScope {
+    /// Wrap a scope with a name filtering decorator.
+    pub fn wrap<SC: InputScope + Send + Sync + 'static>(target: SC) -> Self {
+        InputFilterScope {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            filter: Arc::new(NameFilter::default()),
+        }
+    }
+
+    /// Returns a clone of the decorator keeping only metrics matching this or any other allow pattern,
+    /// e.g. `http.**`.
+    pub fn allow(&self, pattern: &str) -> Self {
+        let mut cloned = self.clone();
+        Arc::make_mut(&mut cloned.filter)
+            .allow
+            .push(segments(pattern));
+        cloned
+    }
+
+    /// Returns a clone of the decorator discarding metrics matching the pattern, e.g. `*.debug.**`,
+    /// even if they match an allow pattern.
+    pub fn deny(&self, pattern: &str) -> Self {
+        let mut cloned = self.clone();
+        Arc::make_mut(&mut cloned.filter)
+            .deny
+            .push(segments(pattern));
+        cloned
+    }
+}
+
+impl WithAttributes for InputFilterScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for InputFilterScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        if self.filter.keeps(&name) {
+            // kept, no indirection
+            self.target.new_metric(name, kind)
+        } else {
+            InputMetric::new(MetricId::forge("filter", name), |_value, _labels| {})
+        }
+    }
+}
+
+impl Flush for InputFilterScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn allow_and_deny() {
+        let map = StatsMapScope::default();
+        let metrics = InputFilterScope::wrap(map.clone())
+            .allow("http.**")
+            .allow("db.queries")
+            .deny("*.debug.**");
+
+        metrics.counter("http.requests").count(1);
+        metrics.counter("http.debug.polls").count(2);
+        metrics.counter("db.queries").count(3);
+        metrics.counter("db.connections").count(4);
+
+        let map = map.into_map();
+        assert_eq!(map["http.requests"], 1);
+        assert_eq!(map["db.queries"], 3);
+        assert_eq!(map.len(), 2);
+    }
+
+    #[test]
+    fn deny_only() {
+        let map = StatsMapScope::default();
+        let metrics = InputFilterScope::wrap(map.clone()).deny("internal.**");
+
+        metrics.counter("requests").count(1);
+        metrics.named("internal").counter("polls").count(2);
+
+        let map = map.into_map();
+        assert_eq!(map["requests"], 1);
+        assert_eq!(map.len(), 1);
+    }
+}
@@ -0,0 +1,198 @@
+//! Check output formats against golden files, to detect wire format changes upon upgrade.
+//! A canonical set of metrics is rendered and compared with the expected output.
+
+use crate::input::{InputKind, TEXT_LABEL};
+use crate::label::Labels;
+use crate::name::MetricName;
+use crate::output::format::LineFormat;
+use crate::MetricValue;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Set this environment variable to rewrite golden files with the current output instead of comparing.
+pub const GOLDEN_BLESS_VAR: &str = "DIPSTICK_BLESS";
+
+/// A canonical value, written as an integer or as a float.
+enum Canonical {
+    Int(MetricValue),
+    Float(f64),
+}
+
+/// Metrics of every kind, with nested names, extreme values and labels.
+/// Changing this list requires blessing every golden file again.
+fn canonical_metrics() -> Vec<(MetricName, InputKind, Canonical, Labels)> {
+    use self::Canonical::*;
+    let nested = MetricName::from("latency").prepend("api").prepend("app");
+    vec![
+        ("events".into(), InputKind::Marker, Int(1), labels![]),
+        (
+            "bytes_sent".into(),
+            InputKind::Counter,
+            Int(1024),
+            labels![],
+        ),
+        ("queue_depth".into(), InputKind::Level, Int(-3), labels![]),
+        ("temperature".into(), InputKind::Gauge, Int(0), labels![]),
+        (
+            "max_gauge".into(),
+            InputKind::Gauge,
+            Int(MetricValue::MAX),
+            labels![],
+        ),
+        (
+            "min_gauge".into(),
+            InputKind::Gauge,
+            Int(MetricValue::MIN),
+            labels![],
+        ),
+        (nested, InputKind::Timer, Int(123_456), labels![]),
+        ("visitors".into(), InputKind::Set, Int(42), labels![]),
+        (
+            "requests".into(),
+            InputKind::Counter,
+            Int(7),
+            labels!("method" => "GET", "status" => "200"),
+        ),
+        ("load".into(), InputKind::Gauge, Float(0.75), labels![]),
+        ("drift".into(), InputKind::Gauge, Float(-1.5e-9), labels![]),
+        (
+            "ratio_nan".into(),
+            InputKind::Gauge,
+            Float(f64::NAN),
+            labels![],
+        ),
+        (
+            "ratio_inf".into(),
+            InputKind::Gauge,
+            Float(f64::INFINITY),
+            labels![],
+        ),
+        (
+            "version".into(),
+            InputKind::Text,
+            Int(1),
+            labels!(TEXT_LABEL => "1.2.3 \"beta\""),
+        ),
+        (
+            "distribution".into(),
+            InputKind::Custom("d"),
+            Int(250),
+            labels![],
+        ),
+    ]
+}
+
+/// Render the canonical set of metrics through the format, one template per metric.
+/// Thread and app labels are also looked up, these should be left unset when rendering.
+pub fn render_canonical(format: &dyn LineFormat) -> io::Result<String> {
+    let mut buffer = Vec::new();
+    for (name, kind, value, labels) in canonical_metrics() {
+        let template = format.template(&name, kind);
+        match value {
+            Canonical::Int(value) => template.print_labels(&mut buffer, value, &labels)?,
+            Canonical::Float(value) => template.print_f64_labels(&mut buffer, value, &labels)?,
+        }
+    }
+    String::from_utf8(buffer).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Compare the canonical rendering of the format with the golden file.
+/// The golden file is written instead if `DIPSTICK_BLESS` is set.
+/// Returns a `NotFound` error if the golden file does not exist yet,
+/// or an `InvalidData` error describing the first difference if the output changed.
+pub fn check_golden<P: AsRef<Path>>(format: &dyn LineFormat, golden: P) -> io::Result<()> {
+    check_or_bless(
+        format,
+        golden.as_ref(),
+        env::var_os(GOLDEN_BLESS_VAR).is_some(),
+    )
+}
+
+fn check_or_bless(format: &dyn LineFormat, golden: &Path, bless: bool) -> io::Result<()> {
+    let rendered = render_canonical(format)?;
+    if bless {
+        return fs::write(golden, rendered);
+    }
+    let expected = fs::read_to_string(golden).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!(
+                "{}: {}, set {} to create it",
+                golden.display(),
+                e,
+                GOLDEN_BLESS_VAR
+            ),
+        )
+    })?;
+    if expected == rendered {
+        return Ok(());
+    }
+    let expected: Vec<&str> = expected.lines().collect();
+    let rendered: Vec<&str> = rendered.lines().collect();
+    let difference = match (0..=expected.len().max(rendered.len()))
+        .find(|i| expected.get(*i) != rendered.get(*i))
+    {
+        Some(i) => format!(
+            "line {}: expected {:?} but got {:?}",
+            i + 1,
+            expected.get(i).unwrap_or(&"<end>"),
+            rendered.get(i).unwrap_or(&"<end>")
+        ),
+        None => "line endings differ".to_string(),
+    };
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{} {}", golden.display(), difference),
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::format::test::TestFormat;
+    use crate::output::format::{JsonFormat, SimpleFormat, TemplateFormat};
+
+    #[test]
+    fn simple_format_golden() {
+        let golden = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/golden/simple_format.txt"
+        );
+        check_golden(&SimpleFormat::default(), golden).unwrap();
+    }
+
+    #[test]
+    fn labeled_format_golden() {
+        let golden = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/test_format.txt");
+        check_golden(&TestFormat, golden).unwrap();
+    }
+
+    #[test]
+    fn json_format_golden() {
+        let golden = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/json_format.txt");
+        let format = JsonFormat::new().label("method").label("status");
+        check_golden(&format, golden).unwrap();
+    }
+
+    #[test]
+    fn template_format_golden() {
+        let golden = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/golden/template_format.txt"
+        );
+        let format =
+            TemplateFormat::parse("[{name}] {value} {label:method}{label:status}").unwrap();
+        check_golden(&format, golden).unwrap();
+    }
+
+    #[test]
+    fn missing_golden() {
+        let golden = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden/missing.txt");
+        let err = check_or_bless(&SimpleFormat::default(), Path::new(golden), false).unwrap_err();
+        assert_eq!(io::ErrorKind::NotFound, err.kind());
+        assert!(!Path::new(golden).exists());
+    }
+}
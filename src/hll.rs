@@ -0,0 +1,216 @@
+//! A HyperLogLog sketch for estimating the number of distinct values.
+//! Kept here for low dependency count.
+//! See Flajolet et al., "HyperLogLog: the analysis of a near-optimal cardinality estimation algorithm".
+
+use crate::MetricValue;
+
+use std::hash::Hasher;
+use std::sync::atomic::AtomicU8;
+use std::sync::atomic::Ordering::*;
+
+/// Default number of index bits, for 4096 registers and a standard error of about 1.6%.
+pub const DEFAULT_PRECISION: u8 = 12;
+
+/// A compact, mergeable estimator of the number of distinct values.
+/// Sketches built separately (other threads, other processes) can be merged without loss of accuracy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HyperLogLog {
+    precision: u8,
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    /// Create an empty sketch with `2^precision` registers.
+    /// Higher precision means more accuracy and more memory.
+    /// Precision must be between 4 and 16.
+    pub fn new(precision: u8) -> Self {
+        assert!(
+            (4..=16).contains(&precision),
+            "HyperLogLog precision must be between 4 and 16"
+        );
+        HyperLogLog {
+            precision,
+            registers: vec![0; 1 << precision],
+        }
+    }
+
+    /// Returns the precision of this sketch.
+    pub fn precision(&self) -> u8 {
+        self.precision
+    }
+
+    /// Add a value to the sketch.
+    pub fn insert(&mut self, value: MetricValue) {
+        let (index, rank) = index_rank(self.precision, value);
+        if rank > self.registers[index] {
+            self.registers[index] = rank
+        }
+    }
+
+    /// Add all values of the other sketch to this sketch.
+    /// Panics if the sketches have different precisions.
+    pub fn merge(&mut self, other: &HyperLogLog) {
+        assert_eq!(
+            self.precision, other.precision,
+            "Can not merge HyperLogLog of different precisions"
+        );
+        for (register, other) in self.registers.iter_mut().zip(other.registers.iter()) {
+            *register = (*register).max(*other)
+        }
+    }
+
+    /// Returns true if no values were added to the sketch.
+    pub fn is_empty(&self) -> bool {
+        self.registers.iter().all(|r| *r == 0)
+    }
+
+    /// Estimate the number of distinct values added to the sketch.
+    pub fn count(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let mut sum = 0.0;
+        let mut zeros = 0;
+        for register in &self.registers {
+            sum += 2f64.powi(-i32::from(*register));
+            if *register == 0 {
+                zeros += 1;
+            }
+        }
+        let estimate = alpha * m * m / sum;
+        if estimate <= 2.5 * m && zeros > 0 {
+            // small range correction
+            m * (m / f64::from(zeros)).ln()
+        } else {
+            estimate
+        }
+    }
+}
+
+/// A HyperLogLog sketch that can be updated concurrently.
+#[derive(Debug)]
+pub struct AtomicHyperLogLog {
+    precision: u8,
+    registers: Vec<AtomicU8>,
+}
+
+impl AtomicHyperLogLog {
+    /// Create an empty sketch with `2^precision` registers.
+    pub fn new(precision: u8) -> Self {
+        AtomicHyperLogLog {
+            precision,
+            registers: (0..1 << precision).map(|_| AtomicU8::new(0)).collect(),
+        }
+    }
+
+    /// Add a value to the sketch.
+    pub fn insert(&self, value: MetricValue) {
+        let (index, rank) = index_rank(self.precision, value);
+        self.registers[index].fetch_max(rank, Relaxed);
+    }
+
+    /// Add all values of the other sketch to this sketch.
+    pub fn merge(&self, other: &HyperLogLog) {
+        for (register, other) in self.registers.iter().zip(other.registers.iter()) {
+            register.fetch_max(*other, Relaxed);
+        }
+    }
+
+    /// Returns a copy of the current sketch.
+    pub fn load(&self) -> HyperLogLog {
+        HyperLogLog {
+            precision: self.precision,
+            registers: self.registers.iter().map(|r| r.load(Acquire)).collect(),
+        }
+    }
+
+    /// Returns the current sketch, leaving an empty sketch in its place.
+    pub fn take(&self) -> HyperLogLog {
+        HyperLogLog {
+            precision: self.precision,
+            registers: self.registers.iter().map(|r| r.swap(0, AcqRel)).collect(),
+        }
+    }
+}
+
+/// Hash the value, returning the index of its register and the rank of its remaining bits.
+fn index_rank(precision: u8, value: MetricValue) -> (usize, u8) {
+    let hash = mix(value as u64);
+    let index = (hash >> (64 - precision)) as usize;
+    // guard bit keeps rank in range if remaining bits are all zero
+    let rest = (hash << precision) | (1 << (precision - 1));
+    (index, rest.leading_zeros() as u8 + 1)
+}
+
+/// A 64 bit FNV-1a hasher, hashing set values the same in every process and release,
+/// for sketches built separately to be merged. Unlike `DefaultHasher`, whose algorithm may change.
+/// Its weak low bits are spread by the finalizer before use.
+pub(crate) struct FixedHasher(u64);
+
+impl Default for FixedHasher {
+    fn default() -> Self {
+        FixedHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for FixedHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// The splitmix64 finalizer, spreading even sequential values over all bits.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_near(expected: f64, actual: f64) {
+        let error = (expected - actual).abs() / expected;
+        assert!(error < 0.05, "expected {} but got {}", expected, actual);
+    }
+
+    #[test]
+    fn distinct_count() {
+        let mut sketch = HyperLogLog::new(DEFAULT_PRECISION);
+        assert!(sketch.is_empty());
+        assert_eq!(0.0, sketch.count());
+        for i in 0..10_000 {
+            // every value twice
+            sketch.insert(i % 5_000);
+        }
+        assert_near(5_000.0, sketch.count());
+    }
+
+    #[test]
+    fn fixed_hash() {
+        let mut hasher = FixedHasher::default();
+        assert_eq!(0xcbf2_9ce4_8422_2325, hasher.finish());
+        hasher.write(b"a");
+        assert_eq!(0xaf63_dc4c_8601_ec8c, hasher.finish());
+    }
+
+    #[test]
+    fn merged_sketches() {
+        let atomic = AtomicHyperLogLog::new(DEFAULT_PRECISION);
+        let mut other = HyperLogLog::new(DEFAULT_PRECISION);
+        for i in 0..50_000 {
+            atomic.insert(i);
+            other.insert(i + 25_000);
+        }
+        atomic.merge(&other);
+        assert_near(75_000.0, atomic.load().count());
+        assert_near(75_000.0, atomic.take().count());
+        assert!(atomic.load().is_empty());
+    }
+}
@@ -1,11 +1,26 @@
-use crate::attributes::MetricId;
-use crate::clock::TimeHandle;
+use crate::attributes::{MetricId, WithAttributes};
+use crate::clock::{Clock, TimeHandle};
+use crate::hll::FixedHasher;
 use crate::label::Labels;
 use crate::name::MetricName;
+use crate::template::MetricTemplate;
 use crate::{Flush, MetricValue};
 
+use std::any::Any;
+use std::collections::HashMap;
 use std::fmt;
-use std::sync::Arc;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::sync::atomic::AtomicIsize;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, Weak};
+use std::time::SystemTime;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
 
 // TODO maybe define an 'AsValue' trait + impl for supported number types, then drop 'num' crate
 pub use num::integer;
@@ -48,44 +63,150 @@ impl<T: Input + Send + Sync + 'static> InputDyn for T {
 
 /// InputScope
 /// Define metrics, write values and flush them.
-pub trait InputScope: Flush {
+pub trait InputScope: Flush + ShareScope {
     /// Define a generic metric of the specified type.
     /// It is preferable to use counter() / marker() / timer() / gauge() methods.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric;
 
     /// Define a Counter.
     fn counter(&self, name: &str) -> Counter {
-        self.new_metric(name.into(), InputKind::Counter).into()
+        define(self, name, InputKind::Counter).into()
     }
 
     /// Define a Marker.
     fn marker(&self, name: &str) -> Marker {
-        self.new_metric(name.into(), InputKind::Marker).into()
+        define(self, name, InputKind::Marker).into()
     }
 
     /// Define a Timer.
     fn timer(&self, name: &str) -> Timer {
-        self.new_metric(name.into(), InputKind::Timer).into()
+        let mut timer: Timer = define(self, name, InputKind::Timer).into();
+        timer.clock = self.clock();
+        timer
+    }
+
+    /// The clock read by timers defined in this scope, precise unless the scope supports `ClockedTimers`.
+    fn clock(&self) -> Clock {
+        Clock::Precise
     }
 
     /// Define a Gauge.
     fn gauge(&self, name: &str) -> Gauge {
-        self.new_metric(name.into(), InputKind::Gauge).into()
+        define(self, name, InputKind::Gauge).into()
     }
 
     /// Define a Level.
     fn level(&self, name: &str) -> Level {
-        self.new_metric(name.into(), InputKind::Level).into()
+        define(self, name, InputKind::Level).into()
+    }
+
+    /// Define a Set.
+    fn set(&self, name: &str) -> Set {
+        define(self, name, InputKind::Set).into()
+    }
+
+    /// Define a Text annotation.
+    fn text(&self, name: &str) -> Text {
+        define(self, name, InputKind::Text).into()
+    }
+
+    /// Define a metric template, with placeholders in braces, e.g. `worker_{id}.latency`.
+    /// Metrics are defined when binding the template to placeholder values, then reused.
+    fn template<M: From<InputMetric> + Clone>(
+        &self,
+        template: &str,
+        kind: InputKind,
+    ) -> MetricTemplate<M>
+    where
+        Self: Clone + Send + Sync + 'static + Sized,
+    {
+        MetricTemplate::new(self.clone(), template, kind)
+    }
+
+    /// Define a pair of timers recording the time spent waiting (`<name>.wait`)
+    /// and the time spent being served (`<name>.service`).
+    fn phased_timer(&self, name: &str) -> PhasedTimer {
+        PhasedTimer {
+            wait: self.timer(&format!("{}.wait", name)),
+            service: self.timer(&format!("{}.service", name)),
+        }
+    }
+
+    /// Define a timer recording the total time of its stopwatches (`<name>`)
+    /// and the time of each of their laps (`<name>.<lap>`).
+    fn lap_timer(&self, name: &str) -> LapTimer
+    where
+        Self: Clone + Send + Sync + 'static + Sized,
+    {
+        LapTimer {
+            total: self.timer(name),
+            laps: MetricTemplate::new(self.clone(), &format!("{}.{{lap}}", name), InputKind::Timer),
+        }
     }
 }
 
+/// Shares a scope with the metrics it defines, for them to derive siblings, see `Counter::with_suffix()`.
+/// Implemented by the scopes of this crate, other scopes implement it with the default:
+/// their metrics have no siblings.
+pub trait ShareScope {
+    /// Returns a copy of this scope made once, to define siblings in.
+    fn shared_scope(&self) -> Option<Arc<dyn InputScope + Send + Sync>> {
+        None
+    }
+}
+
+impl<T: InputScope + WithAttributes + Send + Sync + 'static> ShareScope for T {
+    fn shared_scope(&self) -> Option<Arc<dyn InputScope + Send + Sync>> {
+        Some(
+            self.get_attributes()
+                .shared_scope(|| Arc::new(self.clone())),
+        )
+    }
+}
+
+/// Define a metric in the scope, able to derive siblings if the scope is shared.
+pub(crate) fn define<S: InputScope + ?Sized>(
+    scope: &S,
+    name: &str,
+    kind: InputKind,
+) -> InputMetric {
+    let metric = scope.new_metric(name.into(), kind);
+    match scope.shared_scope() {
+        Some(shared) => metric.with_siblings(shared, name.to_string(), kind),
+        None => metric,
+    }
+}
+
+/// Maximum number of siblings cached by a metric, others are defined again on each use.
+const MAX_SIBLINGS: usize = 256;
+
 /// A metric is actually a function that knows to write a metric value to a metric output.
 #[derive(Clone)]
 pub struct InputMetric {
     identifier: MetricId,
     inner: Arc<dyn Fn(MetricValue, Labels) + Send + Sync>,
+    weighted: Option<Arc<WeightedFn>>,
+    float: Option<Arc<FloatFn>>,
+    timestamped: Option<Arc<TimestampedFn>>,
+    incr: Option<Arc<IncrFn>>,
+    siblings: Option<Arc<Siblings>>,
+}
+
+/// Metrics named with an extra segment, defined in the same scope as the metric they derive from.
+struct Siblings {
+    scope: Arc<dyn InputScope + Send + Sync>,
+    /// Name of the metric, before the scope's prefix
+    name: String,
+    kind: InputKind,
+    /// Metric handles by suffix
+    defined: RwLock<HashMap<String, Box<dyn Any + Send + Sync>>>,
 }
 
+type WeightedFn = dyn Fn(MetricValue, usize, Labels) + Send + Sync;
+type FloatFn = dyn Fn(f64, Labels) + Send + Sync;
+type TimestampedFn = dyn Fn(SystemTime, MetricValue, Labels) + Send + Sync;
+type IncrFn = dyn Fn() + Send + Sync;
+
 impl fmt::Debug for InputMetric {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "InputMetric")
@@ -101,23 +222,313 @@ impl InputMetric {
         InputMetric {
             identifier,
             inner: Arc::new(metric),
+            weighted: None,
+            float: None,
+            timestamped: None,
+            incr: None,
+            siblings: None,
         }
     }
 
+    fn with_siblings(
+        mut self,
+        scope: Arc<dyn InputScope + Send + Sync>,
+        name: String,
+        kind: InputKind,
+    ) -> InputMetric {
+        self.siblings = Some(Arc::new(Siblings {
+            scope,
+            name,
+            kind,
+            defined: RwLock::new(HashMap::new()),
+        }));
+        self
+    }
+
+    /// Returns the metric unable to derive siblings, not keeping the scope that defined it.
+    pub(crate) fn without_siblings(mut self) -> InputMetric {
+        self.siblings = None;
+        self
+    }
+
+    /// Return the sibling metric named with the suffix appended, defining it on first use.
+    /// Metrics that were not defined by a shared scope have no siblings, the metric itself is returned.
+    fn sibling<M: From<InputMetric> + Clone + Send + Sync + 'static>(&self, suffix: &str) -> M {
+        let siblings = match self.siblings.as_ref() {
+            Some(siblings) => siblings,
+            None => return self.no_sibling(suffix),
+        };
+        if let Some(sibling) = find_sibling(read_lock!(siblings.defined).get(suffix)) {
+            return sibling;
+        }
+        let mut defined = write_lock!(siblings.defined);
+        // may have been defined concurrently
+        if let Some(sibling) = find_sibling(defined.get(suffix)) {
+            return sibling;
+        }
+        let name = format!("{}.{}", siblings.name, suffix);
+        let sibling: M = siblings
+            .scope
+            .new_metric(name.as_str().into(), siblings.kind)
+            .with_siblings(siblings.scope.clone(), name, siblings.kind)
+            .into();
+        if defined.len() < MAX_SIBLINGS {
+            defined.insert(suffix.to_string(), Box::new(sibling.clone()));
+        }
+        sibling
+    }
+
+    fn no_sibling<M: From<InputMetric>>(&self, suffix: &str) -> M {
+        warn!(
+            "Metric {:?} has no siblings, ignoring suffix {}",
+            self.identifier, suffix
+        );
+        self.clone().into()
+    }
+
+    /// Returns the metric, recording weighted values with the provided function
+    /// instead of writing them repeatedly.
+    pub fn with_weighted<F: Fn(MetricValue, usize, Labels) + Send + Sync + 'static>(
+        mut self,
+        weighted: F,
+    ) -> InputMetric {
+        self.weighted = Some(Arc::new(weighted));
+        self
+    }
+
+    /// Returns the metric, recording floating point values with the provided function
+    /// instead of rounding them to integers.
+    pub fn with_float<F: Fn(f64, Labels) + Send + Sync + 'static>(
+        mut self,
+        float: F,
+    ) -> InputMetric {
+        self.float = Some(Arc::new(float));
+        self
+    }
+
+    /// Returns a metric writing to the target in whichever way it is written, if the gate lets the values through.
+    /// The gate is called with the weight of each write, one unless written with `write_n`.
+    pub(crate) fn gated<G: Fn(usize) -> bool + Send + Sync + 'static>(
+        identifier: MetricId,
+        target: InputMetric,
+        open: G,
+    ) -> InputMetric {
+        let gated = Arc::new((target, open));
+        let weighted = gated.clone();
+        let float = gated.clone();
+        let timestamped = gated.clone();
+        let incr = gated.clone();
+        InputMetric::new(identifier, move |value, labels| {
+            if (gated.1)(1) {
+                gated.0.write(value, labels)
+            }
+        })
+        .with_weighted(move |value, weight, labels| {
+            if (weighted.1)(weight) {
+                weighted.0.write_n(value, weight, labels)
+            }
+        })
+        .with_float(move |value, labels| {
+            if (float.1)(1) {
+                float.0.write_f64(value, labels)
+            }
+        })
+        .with_timestamped(move |timestamp, value, labels| {
+            if (timestamped.1)(1) {
+                timestamped.0.write_at(timestamp, value, labels)
+            }
+        })
+        .with_incr(move || {
+            if (incr.1)(1) {
+                incr.0.incr()
+            }
+        })
+    }
+
+    /// Returns the metric, passing the labels of every value written through the mapping first.
+    pub(crate) fn map_labels<F: Fn(Labels) -> Labels + Send + Sync + 'static>(
+        mut self,
+        map: F,
+    ) -> InputMetric {
+        let map = Arc::new(map);
+        if let Some(weighted) = self.weighted.take() {
+            let map = map.clone();
+            self = self
+                .with_weighted(move |value, weight, labels| weighted(value, weight, map(labels)));
+        }
+        if let Some(float) = self.float.take() {
+            let map = map.clone();
+            self = self.with_float(move |value, labels| float(value, map(labels)));
+        }
+        if let Some(timestamped) = self.timestamped.take() {
+            let map = map.clone();
+            self = self.with_timestamped(move |timestamp, value, labels| {
+                timestamped(timestamp, value, map(labels))
+            });
+        }
+        let inner = self.inner;
+        self.inner = Arc::new(move |value, labels| inner(value, map(labels)));
+        // mapped labels may not be empty
+        self.incr = None;
+        self
+    }
+
+    /// Returns the metric, recording values written with an explicit timestamp with the provided function
+    /// instead of stamping them itself.
+    pub fn with_timestamped<F: Fn(SystemTime, MetricValue, Labels) + Send + Sync + 'static>(
+        mut self,
+        timestamped: F,
+    ) -> InputMetric {
+        self.timestamped = Some(Arc::new(timestamped));
+        self
+    }
+
+    /// Returns the metric, recording unlabeled values of one with the provided function,
+    /// without building labels for them.
+    pub fn with_incr<F: Fn() + Send + Sync + 'static>(mut self, incr: F) -> InputMetric {
+        self.incr = Some(Arc::new(incr));
+        self
+    }
+
     /// Collect a new value for this metric.
     #[inline]
     pub fn write(&self, value: MetricValue, labels: Labels) {
         (self.inner)(value, labels)
     }
 
+    /// Collect an unlabeled value of one, the most common write of counters and markers.
+    #[inline]
+    pub fn incr(&self) {
+        match &self.incr {
+            Some(incr) => incr(),
+            None => (self.inner)(1, Labels::default()),
+        }
+    }
+
+    /// Collect a value observed `weight` times, e.g. a batch of identical observations.
+    /// Outputs that can not record weights natively get the value written `weight` times,
+    /// statsd sends it once with a sample rate of `1 / weight`.
+    pub fn write_n(&self, value: MetricValue, weight: usize, labels: Labels) {
+        match &self.weighted {
+            Some(weighted) => weighted(value, weight, labels),
+            None => {
+                for _ in 0..weight {
+                    (self.inner)(value, labels.clone())
+                }
+            }
+        }
+    }
+
+    /// Collect a floating point value, e.g. a ratio or a temperature.
+    /// Outputs that can not record floating point values get the value rounded to the nearest integer.
+    pub fn write_f64(&self, value: f64, labels: Labels) {
+        match &self.float {
+            Some(float) => float(value, labels),
+            None => (self.inner)(value.round() as MetricValue, labels),
+        }
+    }
+
+    /// Collect a value observed at the specified time, e.g. when backfilling or replaying buffered data.
+    /// Time-aware outputs (graphite, Prometheus remote write) publish the value with this timestamp,
+    /// others write it as if it had just been observed.
+    pub fn write_at(&self, timestamp: SystemTime, value: MetricValue, labels: Labels) {
+        match &self.timestamped {
+            Some(timestamped) => timestamped(timestamp, value, labels),
+            None => (self.inner)(value, labels),
+        }
+    }
+
     /// Returns the unique identifier of this metric.
     pub fn metric_id(&self) -> &MetricId {
         &self.identifier
     }
+
+    /// Create a weak reference to this metric.
+    pub fn downgrade(&self) -> WeakMetric<InputMetric> {
+        WeakMetric::new(self)
+    }
+}
+
+fn find_sibling<M: Clone + 'static>(sibling: Option<&Box<dyn Any + Send + Sync>>) -> Option<M> {
+    sibling?.downcast_ref::<M>().cloned()
+}
+
+/// A weak reference to a metric, not preventing its cleanup once all other handles are dropped.
+/// e.g. aggregated metrics are purged from their bucket upon flush.
+/// Long-lived registries can hold these without keeping alive metrics owned by short-lived components.
+pub struct WeakMetric<M> {
+    identifier: MetricId,
+    inner: Weak<dyn Fn(MetricValue, Labels) + Send + Sync>,
+    weighted: Option<Weak<WeightedFn>>,
+    float: Option<Weak<FloatFn>>,
+    timestamped: Option<Weak<TimestampedFn>>,
+    incr: Option<Weak<IncrFn>>,
+    siblings: Option<Weak<Siblings>>,
+    kind: PhantomData<fn() -> M>,
+}
+
+impl<M> WeakMetric<M> {
+    fn new(metric: &InputMetric) -> Self {
+        WeakMetric {
+            identifier: metric.identifier.clone(),
+            inner: Arc::downgrade(&metric.inner),
+            weighted: metric.weighted.as_ref().map(Arc::downgrade),
+            float: metric.float.as_ref().map(Arc::downgrade),
+            timestamped: metric.timestamped.as_ref().map(Arc::downgrade),
+            incr: metric.incr.as_ref().map(Arc::downgrade),
+            siblings: metric.siblings.as_ref().map(Arc::downgrade),
+            kind: PhantomData,
+        }
+    }
+
+    /// Returns the unique identifier of the referenced metric.
+    pub fn metric_id(&self) -> &MetricId {
+        &self.identifier
+    }
+}
+
+impl<M: From<InputMetric>> WeakMetric<M> {
+    /// Returns a strong handle to the metric, or `None` if every strong handle was dropped.
+    pub fn upgrade(&self) -> Option<M> {
+        self.inner.upgrade().map(|inner| {
+            M::from(InputMetric {
+                identifier: self.identifier.clone(),
+                inner,
+                weighted: self.weighted.as_ref().and_then(Weak::upgrade),
+                float: self.float.as_ref().and_then(Weak::upgrade),
+                timestamped: self.timestamped.as_ref().and_then(Weak::upgrade),
+                incr: self.incr.as_ref().and_then(Weak::upgrade),
+                siblings: self.siblings.as_ref().and_then(Weak::upgrade),
+            })
+        })
+    }
+}
+
+impl<M> Clone for WeakMetric<M> {
+    fn clone(&self) -> Self {
+        WeakMetric {
+            identifier: self.identifier.clone(),
+            inner: self.inner.clone(),
+            weighted: self.weighted.clone(),
+            float: self.float.clone(),
+            timestamped: self.timestamped.clone(),
+            incr: self.incr.clone(),
+            siblings: self.siblings.clone(),
+            kind: PhantomData,
+        }
+    }
+}
+
+impl<M> fmt::Debug for WeakMetric<M> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WeakMetric")
+    }
 }
 
 /// Used to differentiate between metric kinds in the backend.
+/// More kinds may be added, matches on kinds should have a catch-all arm.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[non_exhaustive]
 pub enum InputKind {
     /// Monotonic counter
     Marker,
@@ -129,6 +540,15 @@ pub enum InputKind {
     Gauge,
     /// Time interval, internal to the app or provided by an external source
     Timer,
+    /// Distinct values, counted per period
+    Set,
+    /// String-valued annotation (e.g. version or build hash), written as a constant `1`
+    /// with its text in the `value` label
+    Text,
+    /// Domain kind defined by a downstream crate (e.g. "distribution").
+    /// Aggregated like a counter. Outputs with typed wire formats publish its name as the type,
+    /// e.g. `Custom("d")` for statsd distributions.
+    Custom(&'static str),
 }
 
 /// Used by the metrics! macro to obtain the InputKind from the stringified type.
@@ -140,6 +560,8 @@ impl<'a> From<&'a str> for InputKind {
             "Gauge" => InputKind::Gauge,
             "Timer" => InputKind::Timer,
             "Level" => InputKind::Level,
+            "Set" => InputKind::Set,
+            "Text" => InputKind::Text,
             _ => panic!("No InputKind '{}' defined", s),
         }
     }
@@ -154,9 +576,21 @@ pub struct Marker {
 }
 
 impl Marker {
+    /// Create a weak reference to this marker.
+    pub fn downgrade(&self) -> WeakMetric<Marker> {
+        WeakMetric::new(&self.inner)
+    }
+
+    /// Returns the sibling marker named with an extra segment, e.g. `errors.timeout` for `errors`.
+    /// Defined on first use then cached, the marker itself if its scope can not be shared.
+    pub fn with_suffix(&self, suffix: &str) -> Marker {
+        self.inner.sibling(suffix)
+    }
+
     /// Record a single event occurence.
+    #[inline]
     pub fn mark(&self) {
-        self.inner.write(1, labels![])
+        self.inner.incr()
     }
 }
 
@@ -173,10 +607,34 @@ pub struct Counter {
 }
 
 impl Counter {
+    /// Create a weak reference to this counter.
+    pub fn downgrade(&self) -> WeakMetric<Counter> {
+        WeakMetric::new(&self.inner)
+    }
+
+    /// Returns the sibling counter named with an extra segment, e.g. `bytes.gzip` for `bytes`.
+    /// Defined on first use then cached, the counter itself if its scope can not be shared.
+    pub fn with_suffix(&self, suffix: &str) -> Counter {
+        self.inner.sibling(suffix)
+    }
+
     /// Record a value count.
     pub fn count(&self, count: usize) {
         self.inner.write(count as isize, labels![])
     }
+
+    /// Record a value count observed `weight` times, in a single call.
+    /// e.g. `count_n(512, 10)` records ten observations of 512, for a sum of 5120.
+    pub fn count_n(&self, count: usize, weight: usize) {
+        self.inner.write_n(count as isize, weight, labels![])
+    }
+
+    /// Record a count of one, the most common case.
+    /// Counters can not be decremented, use a `Level` for amounts that can decrease.
+    #[inline]
+    pub fn incr(&self) {
+        self.inner.incr()
+    }
 }
 
 /// A counter of fluctuating resources accepting positive and negative values.
@@ -190,22 +648,144 @@ pub struct Level {
 }
 
 impl Level {
+    /// Create a weak reference to this level.
+    pub fn downgrade(&self) -> WeakMetric<Level> {
+        WeakMetric::new(&self.inner)
+    }
+
+    /// Returns the sibling level named with an extra segment.
+    /// Defined on first use then cached, the level itself if its scope can not be shared.
+    pub fn with_suffix(&self, suffix: &str) -> Level {
+        self.inner.sibling(suffix)
+    }
+
     /// Record a positive or negative value count
     pub fn adjust<V: ToPrimitive>(&self, count: V) {
         self.inner.write(count.to_isize().unwrap(), labels![])
     }
+
+    /// Record a count of one.
+    #[inline]
+    pub fn incr(&self) {
+        self.inner.incr()
+    }
+
+    /// Record a count of minus one.
+    #[inline]
+    pub fn decr(&self) {
+        self.inner.write(-1, Labels::default())
+    }
 }
 
 /// A gauge that sends values to the metrics backend
+/// The gauge keeps its current value, shared by its clones, to be increased or decreased.
 #[derive(Debug, Clone)]
 pub struct Gauge {
     inner: InputMetric,
+    current: Arc<AtomicIsize>,
 }
 
 impl Gauge {
+    /// Create a weak reference to this gauge.
+    pub fn downgrade(&self) -> WeakMetric<Gauge> {
+        WeakMetric::new(&self.inner)
+    }
+
+    /// Returns the sibling gauge named with an extra segment, with its own current value.
+    /// Defined on first use then cached, the gauge itself if its scope can not be shared.
+    pub fn with_suffix(&self, suffix: &str) -> Gauge {
+        self.inner.sibling(suffix)
+    }
+
     /// Record a value point for this gauge.
     pub fn value<V: ToPrimitive>(&self, value: V) {
-        self.inner.write(value.to_isize().unwrap(), labels![])
+        let value = value.to_isize().unwrap();
+        self.current.store(value, Relaxed);
+        self.inner.write(value, labels![])
+    }
+
+    /// Record a floating point value for this gauge, e.g. a ratio or a temperature.
+    /// Outputs without floating point support round the value to the nearest integer.
+    pub fn value_f64(&self, value: f64) {
+        self.current.store(value.round() as isize, Relaxed);
+        self.inner.write_f64(value, labels![])
+    }
+
+    /// Add to the gauge's current value and record the result.
+    /// Concurrent updates may be recorded out of order, the last recorded value may not be the current one.
+    pub fn increase<V: ToPrimitive>(&self, amount: V) {
+        let amount = amount.to_isize().unwrap();
+        let value = self.current.fetch_add(amount, Relaxed) + amount;
+        self.inner.write(value, labels![])
+    }
+
+    /// Subtract from the gauge's current value and record the result.
+    pub fn decrease<V: ToPrimitive>(&self, amount: V) {
+        let amount = amount.to_isize().unwrap();
+        let value = self.current.fetch_sub(amount, Relaxed) - amount;
+        self.inner.write(value, labels![])
+    }
+
+    /// The gauge's current value, as last recorded, increased or decreased.
+    pub fn current(&self) -> MetricValue {
+        self.current.load(Relaxed)
+    }
+}
+
+/// A count of distinct values, e.g. unique users or unique IPs.
+/// Values are hashed the same in every process, only their hash is sent to the metrics backend.
+/// If aggregated, the number of distinct values per period is estimated using a HyperLogLog sketch.
+#[derive(Debug, Clone)]
+pub struct Set {
+    inner: InputMetric,
+}
+
+impl Set {
+    /// Create a weak reference to this set.
+    pub fn downgrade(&self) -> WeakMetric<Set> {
+        WeakMetric::new(&self.inner)
+    }
+
+    /// Returns the sibling set named with an extra segment.
+    /// Defined on first use then cached, the set itself if its scope can not be shared.
+    pub fn with_suffix(&self, suffix: &str) -> Set {
+        self.inner.sibling(suffix)
+    }
+
+    /// Record a value occurrence. Values seen before in the same period are not counted again.
+    pub fn insert<V: Hash>(&self, value: V) {
+        let mut hasher = FixedHasher::default();
+        value.hash(&mut hasher);
+        self.inner.write(hasher.finish() as isize, labels![])
+    }
+}
+
+/// Label holding the text of a Text annotation.
+pub const TEXT_LABEL: &str = "value";
+
+/// A string-valued annotation, e.g. a version, a build hash or a configuration fingerprint.
+/// The text is written in the `value` label of a constant `1` value, Prometheus info-style.
+/// Outputs without label support (graphite, statsd) and buckets ignore text annotations.
+#[derive(Debug, Clone)]
+pub struct Text {
+    inner: InputMetric,
+}
+
+impl Text {
+    /// Create a weak reference to this text annotation.
+    pub fn downgrade(&self) -> WeakMetric<Text> {
+        WeakMetric::new(&self.inner)
+    }
+
+    /// Returns the sibling text annotation named with an extra segment.
+    /// Defined on first use then cached, the annotation itself if its scope can not be shared.
+    pub fn with_suffix(&self, suffix: &str) -> Text {
+        self.inner.sibling(suffix)
+    }
+
+    /// Record the current text of this annotation.
+    pub fn set<S: Into<String>>(&self, text: S) {
+        self.inner.write(1, labels![TEXT_LABEL => text.into()])
     }
 }
 
@@ -218,9 +798,23 @@ impl Gauge {
 #[derive(Debug, Clone)]
 pub struct Timer {
     inner: InputMetric,
+    clock: Clock,
 }
 
 impl Timer {
+    /// Create a weak reference to this timer.
+    pub fn downgrade(&self) -> WeakMetric<Timer> {
+        WeakMetric::new(&self.inner)
+    }
+
+    /// Returns the sibling timer named with an extra segment, e.g. `request.cached` for `request`, reading the same clock.
+    /// Defined on first use then cached, the timer itself if its scope can not be shared.
+    pub fn with_suffix(&self, suffix: &str) -> Timer {
+        let mut timer: Timer = self.inner.sibling(suffix);
+        timer.clock = self.clock;
+        timer
+    }
+
     /// Record a microsecond interval for this timer
     /// Can be used in place of start()/stop() if an external time interval source is used
     pub fn interval_us(&self, interval_us: u64) -> u64 {
@@ -228,6 +822,12 @@ impl Timer {
         interval_us
     }
 
+    /// Record a microsecond interval observed `weight` times, in a single call.
+    pub fn interval_us_n(&self, interval_us: u64, weight: usize) -> u64 {
+        self.inner.write_n(interval_us as isize, weight, labels![]);
+        interval_us
+    }
+
     /// Obtain a opaque handle to the current time.
     /// The handle is passed back to the stop() method to record a time interval.
     /// This is actually a convenience method to the TimeHandle::now()
@@ -235,7 +835,7 @@ impl Timer {
     /// _for now_ but might be in the future for safety.
     /// If you require safe multi-timer handles, get them through TimeType::now()
     pub fn start(&self) -> TimeHandle {
-        TimeHandle::now()
+        TimeHandle::now_from(self.clock)
     }
 
     /// Record the time elapsed since the start_time handle was obtained.
@@ -256,15 +856,158 @@ impl Timer {
     }
 }
 
+/// A timer splitting the time of multi-stage operations (e.g. request handling) in laps.
+/// Each lap is recorded in its own timer, named after the lap under the timer's name.
+#[derive(Clone)]
+pub struct LapTimer {
+    total: Timer,
+    laps: MetricTemplate<Timer>,
+}
+
+impl LapTimer {
+    /// Start a stopwatch. Its total time is recorded when it is stopped or dropped.
+    pub fn stopwatch(&self) -> Stopwatch {
+        let now = self.total.start();
+        Stopwatch {
+            timer: self.clone(),
+            start: Some(now),
+            lap_start: now,
+        }
+    }
+}
+
+/// A started stopwatch, recording laps as they complete.
+/// The total time is recorded once, when the stopwatch is stopped or goes out of scope.
+pub struct Stopwatch {
+    timer: LapTimer,
+    start: Option<TimeHandle>,
+    lap_start: TimeHandle,
+}
+
+impl Stopwatch {
+    /// End the current lap, recording its duration in the lap's timer, and start the next one.
+    /// Returns the microsecond interval of the lap.
+    pub fn lap(&mut self, name: &str) -> MetricValue {
+        let now = self.timer.total.start();
+        let lap_us = self.lap_start.elapsed_us();
+        self.lap_start = now;
+        self.timer.laps.bind(&[name]).interval_us(lap_us) as MetricValue
+    }
+
+    /// Stop the stopwatch, recording its total time. Time since the last lap is not recorded as a lap.
+    /// Returns the microsecond interval since the stopwatch was started.
+    pub fn stop(mut self) -> MetricValue {
+        self.record_total()
+    }
+
+    fn record_total(&mut self) -> MetricValue {
+        match self.start.take() {
+            Some(start) => self.timer.total.stop(start),
+            None => 0,
+        }
+    }
+}
+
+impl Drop for Stopwatch {
+    fn drop(&mut self) {
+        self.record_total();
+    }
+}
+
+/// A two-phase timer separating time spent waiting, e.g. in a queue or for a pool connection,
+/// from time spent doing the actual work.
+/// Each phase is recorded in its own timer, with the same labels.
+#[derive(Debug, Clone)]
+pub struct PhasedTimer {
+    wait: Timer,
+    service: Timer,
+}
+
+impl PhasedTimer {
+    /// Start the wait phase.
+    pub fn start_wait(&self) -> WaitHandle {
+        self.start_wait_labels(labels![])
+    }
+
+    /// Start the wait phase, both phases will be recorded with the provided labels.
+    pub fn start_wait_labels(&self, labels: Labels) -> WaitHandle {
+        WaitHandle {
+            timer: self.clone(),
+            labels,
+            start: self.wait.start(),
+        }
+    }
+
+    /// Returns the timer recording the wait phase.
+    pub fn wait_timer(&self) -> &Timer {
+        &self.wait
+    }
+
+    /// Returns the timer recording the service phase.
+    pub fn service_timer(&self) -> &Timer {
+        &self.service
+    }
+}
+
+/// A started wait phase.
+/// Dropping the handle without starting the service phase records nothing.
+#[derive(Debug)]
+#[must_use = "the wait phase is only recorded once the service phase is started"]
+pub struct WaitHandle {
+    timer: PhasedTimer,
+    labels: Labels,
+    start: TimeHandle,
+}
+
+impl WaitHandle {
+    /// End the wait phase, recording its duration, and start the service phase.
+    pub fn start_service(self) -> ServiceHandle {
+        let wait_us = self.start.elapsed_us() as isize;
+        let start = self.timer.service.start();
+        self.timer.wait.write(wait_us, self.labels.clone());
+        ServiceHandle {
+            timer: self.timer,
+            labels: self.labels,
+            start,
+        }
+    }
+}
+
+/// A started service phase.
+/// Dropping the handle without stopping it records nothing.
+#[derive(Debug)]
+#[must_use = "the service phase is only recorded once stopped"]
+pub struct ServiceHandle {
+    timer: PhasedTimer,
+    labels: Labels,
+    start: TimeHandle,
+}
+
+impl ServiceHandle {
+    /// End the service phase, recording its duration.
+    /// Returns the microsecond interval of the service phase.
+    pub fn stop(self) -> MetricValue {
+        let service_us = self.start.elapsed_us() as isize;
+        self.timer.service.write(service_us, self.labels);
+        service_us
+    }
+}
+
 impl From<InputMetric> for Gauge {
     fn from(metric: InputMetric) -> Gauge {
-        Gauge { inner: metric }
+        Gauge {
+            inner: metric,
+            current: Arc::new(AtomicIsize::new(0)),
+        }
     }
 }
 
 impl From<InputMetric> for Timer {
     fn from(metric: InputMetric) -> Timer {
-        Timer { inner: metric }
+        Timer {
+            inner: metric,
+            clock: Clock::Precise,
+        }
     }
 }
 
@@ -286,6 +1029,18 @@ impl From<InputMetric> for Level {
     }
 }
 
+impl From<InputMetric> for Set {
+    fn from(metric: InputMetric) -> Set {
+        Set { inner: metric }
+    }
+}
+
+impl From<InputMetric> for Text {
+    fn from(metric: InputMetric) -> Text {
+        Text { inner: metric }
+    }
+}
+
 impl Deref for Counter {
     type Target = InputMetric;
 
@@ -325,3 +1080,137 @@ impl Deref for Level {
         &self.inner
     }
 }
+
+impl Deref for Set {
+    type Target = InputMetric;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl Deref for Text {
+    type Target = InputMetric;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attributes::Prefixed;
+    use crate::clock::{mock_clock_advance, mock_clock_reset};
+    use crate::output::map::StatsMapScope;
+    use crate::testing::Recorder;
+
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::time::Duration;
+
+    #[test]
+    fn coarse_timer_clock() {
+        use crate::{AtomicBucket, ClockedTimers};
+        mock_clock_reset();
+        let bucket = AtomicBucket::new().timer_clock(Clock::Coarse);
+        let timer = bucket.timer("coarse");
+        assert_eq!(Clock::Coarse, timer.clock);
+        assert_eq!(Clock::Precise, AtomicBucket::new().timer("precise").clock);
+
+        let start = timer.start();
+        mock_clock_advance(Duration::from_millis(5));
+        assert_eq!(5000, timer.stop(start));
+    }
+
+    #[test]
+    fn incr_fast_path() {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let incrs = Arc::new(AtomicUsize::new(0));
+        let (w, i) = (writes.clone(), incrs.clone());
+        let metric = InputMetric::new(MetricId::forge("test", "count_a".into()), move |_, _| {
+            w.fetch_add(1, SeqCst);
+        })
+        .with_incr(move || {
+            i.fetch_add(1, SeqCst);
+        });
+
+        Counter::from(metric.clone()).incr();
+        Marker::from(metric.clone()).mark();
+        assert_eq!((0, 2), (writes.load(SeqCst), incrs.load(SeqCst)));
+
+        // labels mapped from empty ones may not be empty
+        Counter::from(metric.map_labels(|labels| labels)).incr();
+        assert_eq!((1, 2), (writes.load(SeqCst), incrs.load(SeqCst)));
+    }
+
+    #[test]
+    fn gauge_arithmetic() {
+        let map = StatsMapScope::default();
+        let gauge = map.gauge("connections");
+        gauge.value(10);
+        gauge.increase(5);
+        gauge.clone().decrease(3);
+        assert_eq!(12, gauge.current());
+        assert_eq!(map.into_map()["connections"], 12);
+    }
+
+    #[test]
+    fn set_fixed_hash() {
+        let recorder = Recorder::default();
+        recorder.set("users").insert("a");
+        assert_eq!(vec![0x089b_c907_b544_c769], recorder.values());
+    }
+
+    #[test]
+    fn sibling_metrics() {
+        let map = StatsMapScope::default();
+        let requests = map.named("app").counter("requests");
+        requests.with_suffix("get").count(1);
+        requests.with_suffix("get").count(2);
+        let post = requests.with_suffix("post");
+        post.with_suffix("json").count(3);
+        assert_eq!(
+            requests.with_suffix("get").metric_id(),
+            requests.with_suffix("get").metric_id()
+        );
+
+        let gauge = map.gauge("pool");
+        gauge.with_suffix("idle").increase(2);
+        gauge.with_suffix("idle").increase(3);
+
+        let plain = InputMetric::new(MetricId::forge("test", "plain".into()), |_, _| {});
+        let plain = Counter::from(plain);
+        assert_eq!(plain.with_suffix("get").metric_id(), plain.metric_id());
+
+        for i in 0..MAX_SIBLINGS {
+            requests.with_suffix(&i.to_string()).count(1);
+        }
+        let siblings = requests.inner.siblings.as_ref().unwrap();
+        assert_eq!(MAX_SIBLINGS, read_lock!(siblings.defined).len());
+
+        let map = map.into_map();
+        assert_eq!(map["app.requests.get"], 2);
+        assert_eq!(map["app.requests.post.json"], 3);
+        assert_eq!(map["pool.idle"], 5);
+    }
+
+    #[test]
+    fn stopwatch_laps() {
+        mock_clock_reset();
+        let map = StatsMapScope::default();
+        let timer = map.lap_timer("request");
+        {
+            let mut stopwatch = timer.stopwatch();
+            mock_clock_advance(Duration::from_millis(2));
+            assert_eq!(2000, stopwatch.lap("parse"));
+            mock_clock_advance(Duration::from_millis(5));
+            stopwatch.lap("handle");
+            mock_clock_advance(Duration::from_millis(1));
+        }
+        let map = map.into_map();
+        assert_eq!(map["request.parse"], 2000);
+        assert_eq!(map["request.handle"], 5000);
+        assert_eq!(map["request"], 8000);
+    }
+}
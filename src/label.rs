@@ -1,6 +1,17 @@
 use std::cell::RefCell;
+use std::collections::hash_map::DefaultHasher;
 use std::collections::HashMap;
-
+use std::env;
+use std::fs;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::{Acquire, Release};
 use std::sync::Arc;
 
 #[cfg(not(feature = "parking_lot"))]
@@ -12,20 +23,108 @@ use parking_lot::RwLock;
 /// Label values are immutable but can move around a lot.
 type LabelValue = Arc<String>;
 
+/// Up to this many labels are kept inline and searched linearly, sparing the hashing and the table allocation.
+const FEW_LABELS: usize = 4;
+
+/// Label key / value pairs.
+#[derive(Debug, Clone)]
+enum Pairs {
+    Few([Option<(String, LabelValue)>; FEW_LABELS]),
+    Many(HashMap<String, LabelValue>),
+}
+
+impl Default for Pairs {
+    fn default() -> Self {
+        Pairs::Few(Default::default())
+    }
+}
+
+impl Pairs {
+    /// Later values of a key replace earlier ones, as in a map.
+    fn collect<I: IntoIterator<Item = (String, LabelValue)>>(pairs: I) -> Self {
+        let mut collected = Pairs::default();
+        for (key, value) in pairs {
+            collected.insert(key, value)
+        }
+        collected
+    }
+
+    fn insert(&mut self, key: String, value: LabelValue) {
+        match self {
+            Pairs::Few(few) => {
+                if let Some(pair) = few.iter_mut().flatten().find(|(k, _)| *k == key) {
+                    pair.1 = value
+                } else if let Some(free) = few.iter_mut().find(|pair| pair.is_none()) {
+                    *free = Some((key, value))
+                } else {
+                    let mut many: HashMap<String, LabelValue> =
+                        few.iter_mut().filter_map(Option::take).collect();
+                    many.insert(key, value);
+                    *self = Pairs::Many(many)
+                }
+            }
+            Pairs::Many(many) => {
+                many.insert(key, value);
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<LabelValue> {
+        match self {
+            Pairs::Few(few) => few
+                .iter_mut()
+                .find(|pair| matches!(pair, Some((k, _)) if k == key))
+                .and_then(Option::take)
+                .map(|(_, value)| value),
+            Pairs::Many(many) => many.remove(key),
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&LabelValue> {
+        match self {
+            Pairs::Few(few) => few
+                .iter()
+                .flatten()
+                .find(|(k, _)| k == key)
+                .map(|(_, value)| value),
+            Pairs::Many(many) => many.get(key),
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        match self {
+            Pairs::Few(few) => few.iter().all(Option::is_none),
+            Pairs::Many(many) => many.is_empty(),
+        }
+    }
+}
+
 /// A reference table of key / value string pairs that may be used on output for additional metric context.
 ///
 /// For concurrency reasons, labels are immutable.
 /// All write operations return a mutated clone of the original.
 #[derive(Debug, Clone, Default)]
-struct LabelScope {
-    pairs: Option<Arc<HashMap<String, LabelValue>>>,
+pub(crate) struct LabelScope {
+    pairs: Option<Arc<Pairs>>,
 }
 
 impl LabelScope {
+    /// Later values of a key replace earlier ones, as in a map.
+    pub(crate) fn collect_pairs<I: IntoIterator<Item = (String, LabelValue)>>(pairs: I) -> Self {
+        let pairs = Pairs::collect(pairs);
+        if pairs.is_empty() {
+            LabelScope::default()
+        } else {
+            LabelScope {
+                pairs: Some(Arc::new(pairs)),
+            }
+        }
+    }
+
     /// Sets the value on a new copy of the map, then returns that copy.
-    fn set(&self, key: String, value: LabelValue) -> Self {
+    pub(crate) fn set(&self, key: String, value: LabelValue) -> Self {
         let mut new_pairs = match self.pairs {
-            None => HashMap::new(),
+            None => Pairs::default(),
             Some(ref old_pairs) => old_pairs.as_ref().clone(),
         };
 
@@ -56,27 +155,71 @@ impl LabelScope {
         }
     }
 
-    fn get(&self, key: &str) -> Option<LabelValue> {
-        // FIXME should use .and_then(), how?
-        match &self.pairs {
-            None => None,
-            Some(pairs) => pairs.get(key).cloned(),
-        }
+    pub(crate) fn get(&self, key: &str) -> Option<LabelValue> {
+        self.pairs.as_ref()?.get(key).cloned()
+    }
+
+    fn get_ref(&self, key: &str) -> Option<&str> {
+        self.pairs.as_ref()?.get(key).map(|value| value.as_str())
     }
 
     fn collect(&self, map: &mut HashMap<String, LabelValue>) {
-        if let Some(pairs) = &self.pairs {
-            map.extend(pairs.as_ref().clone().into_iter())
+        match self.pairs.as_deref() {
+            None => {}
+            Some(Pairs::Few(few)) => map.extend(few.iter().flatten().cloned()),
+            Some(Pairs::Many(many)) => map.extend(many.clone().into_iter()),
+        }
+    }
+
+    fn for_each<F: FnMut(&str, &str)>(&self, mut f: F) {
+        match self.pairs.as_deref() {
+            None => {}
+            Some(Pairs::Few(few)) => few.iter().flatten().for_each(|(k, v)| f(k, v)),
+            Some(Pairs::Many(many)) => many.iter().for_each(|(k, v)| f(k, v)),
         }
     }
 }
 
+/// Order independent hash and number of the pairs of scopes, skipping keys found in a preceding scope.
+fn digest_scopes(scopes: &[LabelScope]) -> (u64, usize) {
+    let mut digest = 0u64;
+    let mut len = 0;
+    for (i, scope) in scopes.iter().enumerate() {
+        scope.for_each(|key, value| {
+            if scopes[..i]
+                .iter()
+                .any(|preceding| preceding.get_ref(key).is_some())
+            {
+                return;
+            }
+            let mut hasher = DefaultHasher::new();
+            (key, value).hash(&mut hasher);
+            digest = digest.wrapping_add(hasher.finish());
+            len += 1;
+        });
+    }
+    (digest, len)
+}
+
+/// Labels read from the current execution context, as key / value pairs.
+pub type LabelProvider = Arc<dyn Fn() -> Vec<(String, String)> + Send + Sync + 'static>;
+
+/// Labels of the current execution context, as a scope that can be shared until the context changes.
+pub(crate) type ScopeProvider = Arc<dyn Fn() -> LabelScope + Send + Sync + 'static>;
+
 lazy_static! {
     static ref APP_LABELS: RwLock<LabelScope> = RwLock::new(LabelScope::default());
+    static ref LABEL_PROVIDER: RwLock<Option<ScopeProvider>> = RwLock::new(None);
 }
 
+/// Set while a context label provider is installed, sparing lookups the provider lock otherwise.
+static HAS_LABEL_PROVIDER: AtomicBool = AtomicBool::new(false);
+
 thread_local! {
     static THREAD_LABELS: RefCell<LabelScope> = RefCell::new(LabelScope::default());
+
+    /// Labels of the task being polled on this thread, if any.
+    static TASK_LABELS: RefCell<Option<LabelScope>> = const { RefCell::new(None) };
 }
 
 /// Handle metric labels for the current thread.
@@ -107,11 +250,166 @@ impl ThreadLabel {
         });
     }
 
+    /// Set a new value for the thread scope until the returned guard is dropped.
+    /// The previous value of the key, if any, is then restored.
+    pub fn set_scoped<S: Into<String>>(key: S, value: S) -> LabelGuard {
+        let key = key.into();
+        let previous = ThreadLabel::get(&key);
+        ThreadLabel::set(key.clone(), value.into());
+        LabelGuard::new(GuardedScope::Thread, key, previous)
+    }
+
     fn collect(map: &mut HashMap<String, LabelValue>) {
         THREAD_LABELS.with(|mop| mop.borrow().collect(map));
     }
 }
 
+/// Handle metric labels for the current async task.
+/// Unlike thread labels, task labels follow the task across `.await` points,
+/// even if it is resumed on another thread of a work-stealing executor.
+/// Tasks only have labels within `TaskLabel::scope()`, which works with any executor.
+/// Task scope labels have a higher lookup priority than thread and app scope labels.
+pub struct TaskLabel;
+
+impl TaskLabel {
+    /// Run a future in a task label scope, starting with the labels of the current task, if any.
+    pub fn scope<F: Future>(future: F) -> LabelledTask<F> {
+        let labels = TASK_LABELS.with(|task| task.borrow().clone().unwrap_or_default());
+        LabelledTask {
+            labels,
+            future: Box::pin(future),
+        }
+    }
+
+    /// Retrieve a value from the task scope.
+    pub fn get(key: &str) -> Option<Arc<String>> {
+        TASK_LABELS.with(|task| task.borrow().as_ref().and_then(|labels| labels.get(key)))
+    }
+
+    /// Set a new value for the task scope.
+    /// Replaces any previous value for the key.
+    /// Has no effect outside of a task label scope.
+    pub fn set<S: Into<String>>(key: S, value: S) {
+        TASK_LABELS.with(|task| {
+            let new = {
+                task.borrow()
+                    .as_ref()
+                    .map(|labels| labels.set(key.into(), Arc::new(value.into())))
+            };
+            if new.is_some() {
+                *task.borrow_mut() = new;
+            }
+        });
+    }
+
+    /// Unset a value for the task scope.
+    /// Has no effect if key was not set.
+    pub fn unset(key: &str) {
+        TASK_LABELS.with(|task| {
+            let new = { task.borrow().as_ref().map(|labels| labels.unset(key)) };
+            if new.is_some() {
+                *task.borrow_mut() = new;
+            }
+        });
+    }
+
+    fn current() -> LabelScope {
+        TASK_LABELS.with(|task| task.borrow().clone().unwrap_or_default())
+    }
+
+    fn collect(map: &mut HashMap<String, LabelValue>) {
+        TASK_LABELS.with(|task| {
+            if let Some(labels) = task.borrow().as_ref() {
+                labels.collect(map)
+            }
+        });
+    }
+}
+
+/// A future running in a task label scope, obtained from `TaskLabel::scope()`.
+/// Its labels are installed for the current thread while it is polled, then saved back.
+pub struct LabelledTask<F> {
+    labels: LabelScope,
+    future: Pin<Box<F>>,
+}
+
+/// Restores the labels of the enclosing task, if any, even if the polled future panics.
+struct RestoreTask<'a> {
+    labels: &'a mut LabelScope,
+    enclosing: Option<LabelScope>,
+}
+
+impl Drop for RestoreTask<'_> {
+    fn drop(&mut self) {
+        let polled = TASK_LABELS.with(|task| task.replace(self.enclosing.take()));
+        *self.labels = polled.unwrap_or_default();
+    }
+}
+
+impl<F: Future> Future for LabelledTask<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        let this = self.get_mut();
+        let enclosing = TASK_LABELS.with(|task| task.replace(Some(this.labels.clone())));
+        let _restore = RestoreTask {
+            labels: &mut this.labels,
+            enclosing,
+        };
+        this.future.as_mut().poll(cx)
+    }
+}
+
+/// Handle metric labels read from the execution context by a provider,
+/// e.g. the fields of the current `tracing` span, aligning metrics with distributed traces.
+/// The provider is invoked whenever labels are looked up, it should be cheap.
+/// Context labels have a lower lookup priority than task and thread labels, but higher than app labels.
+pub struct ContextLabel;
+
+impl ContextLabel {
+    /// Retrieve a value from the context.
+    pub fn get(key: &str) -> Option<Arc<String>> {
+        ContextLabel::current().get(key)
+    }
+
+    /// Set the provider of context labels, replacing any previous provider.
+    /// The returned labels are copied upon every lookup and every queued write,
+    /// providers able to share their labels are cheaper, such as the `tracing` span layer.
+    pub fn set_provider<F: Fn() -> Vec<(String, String)> + Send + Sync + 'static>(provider: F) {
+        ContextLabel::set_scope_provider(Arc::new(move || {
+            LabelScope::collect_pairs(
+                provider()
+                    .into_iter()
+                    .map(|(key, value)| (key, Arc::new(value))),
+            )
+        }))
+    }
+
+    /// Set a provider returning shared labels, replacing any previous provider.
+    pub(crate) fn set_scope_provider(provider: ScopeProvider) {
+        *write_lock!(LABEL_PROVIDER) = Some(provider);
+        HAS_LABEL_PROVIDER.store(true, Release);
+    }
+
+    /// Remove the provider of context labels.
+    pub fn unset_provider() {
+        HAS_LABEL_PROVIDER.store(false, Release);
+        *write_lock!(LABEL_PROVIDER) = None;
+    }
+
+    fn current() -> LabelScope {
+        if !HAS_LABEL_PROVIDER.load(Acquire) {
+            return LabelScope::default();
+        }
+        let provider = read_lock!(LABEL_PROVIDER).clone();
+        provider.map_or_else(LabelScope::default, |provider| provider())
+    }
+
+    fn collect(map: &mut HashMap<String, LabelValue>) {
+        ContextLabel::current().collect(map)
+    }
+}
+
 /// Handle metric labels for the whole application (globals).
 /// App scope labels have the lowest lookup priority and serve as a fallback to other scopes.
 pub struct AppLabel;
@@ -136,11 +434,68 @@ impl AppLabel {
         *write_lock!(APP_LABELS) = b;
     }
 
+    /// Set a new value for the app scope until the returned guard is dropped.
+    /// The previous value of the key, if any, is then restored.
+    pub fn set_scoped<S: Into<String>>(key: S, value: S) -> LabelGuard {
+        let key = key.into();
+        let previous = AppLabel::get(&key);
+        AppLabel::set(key.clone(), value.into());
+        LabelGuard::new(GuardedScope::App, key, previous)
+    }
+
     fn collect(map: &mut HashMap<String, LabelValue>) {
         read_lock!(APP_LABELS).collect(map)
     }
 }
 
+/// The scope of a label set by a guard.
+#[derive(Debug, Clone, Copy)]
+enum GuardedScope {
+    Thread,
+    App,
+}
+
+/// Restores the previous value of a label when dropped, obtained from `set_scoped()`.
+/// Guards restoring thread labels must be dropped on the thread they were obtained from.
+#[derive(Debug)]
+#[must_use = "the label is restored as soon as the guard is dropped"]
+pub struct LabelGuard {
+    scope: GuardedScope,
+    key: String,
+    previous: Option<LabelValue>,
+    /// thread labels must be restored on their own thread
+    _not_send: PhantomData<Rc<()>>,
+}
+
+impl LabelGuard {
+    fn new(scope: GuardedScope, key: String, previous: Option<LabelValue>) -> Self {
+        LabelGuard {
+            scope,
+            key,
+            previous,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Drop for LabelGuard {
+    fn drop(&mut self) {
+        let key = self.key.clone();
+        match (self.scope, self.previous.take()) {
+            (GuardedScope::Thread, Some(value)) => THREAD_LABELS.with(|map| {
+                let new = { map.borrow().set(key, value) };
+                *map.borrow_mut() = new;
+            }),
+            (GuardedScope::Thread, None) => ThreadLabel::unset(&key),
+            (GuardedScope::App, Some(value)) => {
+                let b = { read_lock!(APP_LABELS).set(key, value) };
+                *write_lock!(APP_LABELS) = b;
+            }
+            (GuardedScope::App, None) => AppLabel::unset(&key),
+        }
+    }
+}
+
 /// Base structure to carry metric labels from the application to the metric backend(s).
 /// Can carry both one-off labels and exported context labels (if async metrics are enabled).
 /// Used in applications through the labels!() macro.
@@ -153,7 +508,21 @@ impl From<HashMap<String, LabelValue>> for Labels {
     fn from(map: HashMap<String, LabelValue>) -> Self {
         Labels {
             scopes: vec![LabelScope {
-                pairs: Some(Arc::new(map)),
+                pairs: Some(Arc::new(Pairs::Many(map))),
+            }],
+        }
+    }
+}
+
+impl<const N: usize> From<[(String, LabelValue); N]> for Labels {
+    /// Later values of a key replace earlier ones. Few labels are kept inline.
+    fn from(pairs: [(String, LabelValue); N]) -> Self {
+        if N == 0 {
+            return Labels::default();
+        }
+        Labels {
+            scopes: vec![LabelScope {
+                pairs: Some(Arc::new(Pairs::collect(pairs))),
             }],
         }
     }
@@ -161,7 +530,7 @@ impl From<HashMap<String, LabelValue>> for Labels {
 
 impl Default for Labels {
     /// Create empty labels.
-    /// Only Thread and App labels will be used for lookups.
+    /// Only Task, Thread, Context and App labels will be used for lookups.
     #[inline]
     fn default() -> Self {
         Labels { scopes: vec![] }
@@ -169,10 +538,34 @@ impl Default for Labels {
 }
 
 impl Labels {
+    /// Labels describing an application's build and the host it runs on, usually obtained from `build_info_labels!()`.
+    /// Sets `version`, plus `git_sha`, `build_date` and `hostname` if known.
+    /// The hostname is taken from the `HOSTNAME` environment variable, else from `/etc/hostname`.
+    pub fn build_info(version: &str, git_sha: Option<&str>, build_date: Option<&str>) -> Labels {
+        let hostname = env::var("HOSTNAME")
+            .ok()
+            .or_else(|| fs::read_to_string("/etc/hostname").ok())
+            .map(|hostname| hostname.trim().to_string())
+            .filter(|hostname| !hostname.is_empty());
+        let mut map = HashMap::new();
+        map.insert("version".to_string(), Arc::new(version.to_string()));
+        for (key, value) in &[("git_sha", git_sha), ("build_date", build_date)] {
+            if let Some(value) = value {
+                map.insert(key.to_string(), Arc::new(value.to_string()));
+            }
+        }
+        if let Some(hostname) = hostname {
+            map.insert("hostname".to_string(), Arc::new(hostname));
+        }
+        Labels::from(map)
+    }
+
     /// Used to save metric context before enqueuing value for async output.
     pub fn save_context(&mut self) {
+        self.scopes.push(TaskLabel::current());
         self.scopes
             .push(THREAD_LABELS.with(|map| map.borrow().clone()));
+        self.scopes.push(ContextLabel::current());
         self.scopes.push(read_lock!(APP_LABELS).clone());
     }
 
@@ -181,7 +574,10 @@ impl Labels {
     // TODO needs less magic, add checks?
     pub fn lookup(&self, key: &str) -> Option<LabelValue> {
         fn lookup_current_context(key: &str) -> Option<LabelValue> {
-            ThreadLabel::get(key).or_else(|| AppLabel::get(key))
+            TaskLabel::get(key)
+                .or_else(|| ThreadLabel::get(key))
+                .or_else(|| ContextLabel::get(key))
+                .or_else(|| AppLabel::get(key))
         }
 
         match self.scopes.len() {
@@ -208,6 +604,50 @@ impl Labels {
         }
     }
 
+    /// Label lookup passing the value to the closure without copying it.
+    /// Searches in the same order as `lookup()`.
+    /// Returns `None` if the label is not found, the closure's result otherwise.
+    pub fn lookup_with<R, F: FnOnce(&str) -> R>(&self, key: &str, f: F) -> Option<R> {
+        // the closure is handed back if the label is not in the task or thread scope
+        fn with_current_context<R, F: FnOnce(&str) -> R>(key: &str, f: F) -> Option<R> {
+            let f = TASK_LABELS.with(|task| {
+                match task
+                    .borrow()
+                    .as_ref()
+                    .and_then(|labels| labels.get_ref(key))
+                {
+                    Some(value) => Ok(f(value)),
+                    None => Err(f),
+                }
+            });
+            let f = match f {
+                Ok(found) => return Some(found),
+                Err(f) => f,
+            };
+            let f = THREAD_LABELS.with(|map| match map.borrow().get_ref(key) {
+                Some(value) => Ok(f(value)),
+                None => Err(f),
+            });
+            let f = match f {
+                Ok(found) => return Some(found),
+                Err(f) => f,
+            };
+            if let Some(value) = ContextLabel::current().get_ref(key) {
+                return Some(f(value));
+            }
+            read_lock!(APP_LABELS).get_ref(key).map(f)
+        }
+
+        match self.scopes.len() {
+            0 => with_current_context(key, f),
+            1 => match self.scopes[0].get_ref(key) {
+                Some(value) => Some(f(value)),
+                None => with_current_context(key, f),
+            },
+            _ => self.scopes.iter().find_map(|src| src.get_ref(key)).map(f),
+        }
+    }
+
     /// Export current state of labels to a map.
     /// Note: An iterator would still need to allocate to check for uniqueness of keys.
     ///
@@ -218,14 +658,18 @@ impl Labels {
             // just lookup implicit context
             0 => {
                 AppLabel::collect(&mut map);
+                ContextLabel::collect(&mut map);
                 ThreadLabel::collect(&mut map);
+                TaskLabel::collect(&mut map);
             }
 
             // some value labels, no saved context labels
             // lookup value label, then lookup implicit context
             1 => {
                 AppLabel::collect(&mut map);
+                ContextLabel::collect(&mut map);
                 ThreadLabel::collect(&mut map);
+                TaskLabel::collect(&mut map);
                 self.scopes[0].collect(&mut map);
             }
 
@@ -240,6 +684,94 @@ impl Labels {
         }
         map
     }
+
+    /// Order independent hash and number of the label pairs `into_map` would collect, without collecting them.
+    /// Equal label sets have equal digests, whatever scopes they come from.
+    pub(crate) fn digest(&self) -> (u64, usize) {
+        match self.scopes.len() {
+            // value labels (if any) then implicit context, by lookup priority
+            0 | 1 => digest_scopes(&[
+                self.scopes.first().cloned().unwrap_or_default(),
+                TaskLabel::current(),
+                THREAD_LABELS.with(|map| map.borrow().clone()),
+                ContextLabel::current(),
+                read_lock!(APP_LABELS).clone(),
+            ]),
+            _ => digest_scopes(&self.scopes),
+        }
+    }
+
+    /// Keep only the labels whose key is accepted, resolving context labels now.
+    pub fn filter_keys<F: Fn(&str) -> bool>(self, accept: F) -> Labels {
+        let mut map = self.into_map();
+        map.retain(|key, _| accept(key));
+        Labels::from(map)
+    }
+
+    /// Label keys and values as name parts, ordered by key, for outputs without label support.
+    /// Characters other than alphanumerics, `-` and `_` are replaced with `_` in keys and values.
+    pub fn name_parts(self) -> Vec<String> {
+        let mut pairs: Vec<(String, LabelValue)> = self.into_map().into_iter().collect();
+        pairs.sort();
+        let mut parts = Vec::with_capacity(pairs.len() * 2);
+        for (key, value) in &pairs {
+            for part in &[key.as_str(), value.as_str()] {
+                parts.push(name_part(part));
+            }
+        }
+        parts
+    }
+
+    /// Replace `{key}` placeholders in the name with label values, for outputs without label support.
+    /// Values are sanitized as in `name_parts`, placeholders of missing labels are replaced with `none`.
+    /// Returns `None` if the name has no placeholders.
+    pub fn interpolate_name(&self, name: &str) -> Option<String> {
+        let mut rest = name;
+        let mut interpolated = String::with_capacity(name.len());
+        while let Some(open) = rest.find('{') {
+            let close = match rest[open..].find('}') {
+                Some(close) => open + close,
+                None => break,
+            };
+            interpolated.push_str(&rest[..open]);
+            let key = &rest[open + 1..close];
+            match self.lookup_with(key, name_part) {
+                Some(value) => interpolated.push_str(&value),
+                None => interpolated.push_str("none"),
+            }
+            rest = &rest[close + 1..];
+        }
+        if rest.len() == name.len() {
+            return None;
+        }
+        interpolated.push_str(rest);
+        Some(interpolated)
+    }
+
+    /// Append labels to the name as key and value parts, see `name_parts`.
+    /// Returns `None` if there are no labels.
+    pub fn fold_into_name(self, name: &str, separator: char) -> Option<String> {
+        let parts = self.name_parts();
+        if parts.is_empty() {
+            return None;
+        }
+        let mut folded = name.to_string();
+        for part in &parts {
+            folded.push(separator);
+            folded.push_str(part);
+        }
+        Some(folded)
+    }
+}
+
+/// Replace characters other than alphanumerics, `-` and `_` with `_`.
+fn name_part(part: &str) -> String {
+    part.chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+            _ => '_',
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -252,7 +784,46 @@ pub mod test {
         /// Label tests use the globally shared AppLabels which may make them interfere as tests are run concurrently.
         /// We do not want to mandate usage of `RUST_TEST_THREADS=1` which would penalize the whole test suite.
         /// Instead we use a local mutex to make sure the label tests run in sequence.
-        static ref TEST_SEQUENCE: Mutex<()> = Mutex::new(());
+        pub static ref TEST_SEQUENCE: Mutex<()> = Mutex::new(());
+    }
+
+    #[test]
+    fn fold_into_name() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        assert_eq!(None, labels!().fold_into_name("req", '.'));
+        assert_eq!(
+            Some("req.method.GET.path._a_b".to_string()),
+            labels!("path" => "/a b", "method" => "GET").fold_into_name("req", '.')
+        );
+    }
+
+    #[test]
+    fn interpolate_name() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        let labels = labels!("status" => "200", "path" => "/a b");
+        assert_eq!(None, labels.interpolate_name("requests"));
+        assert_eq!(
+            Some("requests.200._a_b.none".to_string()),
+            labels.interpolate_name("requests.{status}.{path}.{abc}")
+        );
+    }
+
+    #[test]
+    fn build_info() {
+        let labels = Labels::build_info("1.2.3", Some("4f2a9c1"), None);
+        assert_eq!(Some(Arc::new("1.2.3".into())), labels.lookup("version"));
+        assert_eq!(Some(Arc::new("4f2a9c1".into())), labels.lookup("git_sha"));
+        assert_eq!(None, labels.lookup("build_date"));
+
+        let labels = build_info_labels!();
+        assert_eq!(
+            Some(Arc::new(env!("CARGO_PKG_VERSION").into())),
+            labels.lookup("version")
+        );
     }
 
     #[test]
@@ -277,6 +848,106 @@ pub mod test {
         assert_eq!(true, labels!().lookup("abc").is_none());
     }
 
+    /// Yields once, as if waiting for I/O.
+    struct YieldNow(bool);
+
+    impl Future for YieldNow {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            if self.0 {
+                return Poll::Ready(());
+            }
+            self.0 = true;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    struct NoopWake;
+
+    impl std::task::Wake for NoopWake {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    #[test]
+    fn task_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+
+        let task = |value: &'static str| {
+            TaskLabel::scope(async move {
+                TaskLabel::set("abc", value);
+                YieldNow(false).await;
+                labels!().lookup("abc")
+            })
+        };
+        let mut first = task("123");
+        let mut second = task("456");
+
+        let waker = std::task::Waker::from(Arc::new(NoopWake));
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+        assert!(Pin::new(&mut second).poll(&mut cx).is_pending());
+        // labels do not leak out of tasks
+        assert_eq!(None, labels!().lookup("abc"));
+        assert_eq!(
+            Poll::Ready(Some(Arc::new("123".into()))),
+            Pin::new(&mut first).poll(&mut cx)
+        );
+        assert_eq!(
+            Poll::Ready(Some(Arc::new("456".into()))),
+            Pin::new(&mut second).poll(&mut cx)
+        );
+
+        // without a task scope
+        TaskLabel::set("abc", "789");
+        assert_eq!(None, TaskLabel::get("abc"));
+    }
+
+    #[test]
+    fn context_provider() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+
+        AppLabel::set("abc", "456");
+        // other tests run concurrently, only provide labels to this one
+        let test_thread = std::thread::current().id();
+        ContextLabel::set_provider(move || match std::thread::current().id() {
+            id if id == test_thread => vec![("abc".to_string(), "4bf92f35".to_string())],
+            _ => vec![],
+        });
+        assert_eq!(Some(Arc::new("4bf92f35".into())), labels!().lookup("abc"));
+        assert_eq!(
+            Some(4),
+            labels!().lookup_with("abc", |value| value.len() / 2)
+        );
+
+        ThreadLabel::set("abc", "123");
+        assert_eq!(Some(Arc::new("123".into())), labels!().lookup("abc"));
+        ThreadLabel::unset("abc");
+
+        ContextLabel::unset_provider();
+        assert_eq!(Some(Arc::new("456".into())), labels!().lookup("abc"));
+        AppLabel::unset("abc");
+    }
+
+    #[test]
+    fn scoped_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+
+        AppLabel::set("abc", "456");
+        {
+            let _guard = AppLabel::set_scoped("abc", "789");
+            assert_eq!(Some(Arc::new("789".into())), AppLabel::get("abc"));
+            {
+                let _guard = ThreadLabel::set_scoped("abc", "123");
+                assert_eq!(Some(Arc::new("123".into())), labels!().lookup("abc"));
+            }
+            assert_eq!(None, ThreadLabel::get("abc"));
+        }
+        assert_eq!(Some(Arc::new("456".into())), AppLabel::get("abc"));
+        AppLabel::unset("abc");
+    }
+
     #[test]
     fn labels_macro() {
         let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
@@ -317,4 +988,28 @@ pub mod test {
             labels.lookup("abc").expect("Label Value")
         );
     }
+
+    #[test]
+    fn digest_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+
+        let labels = labels! { "abc" => "789", "xyz" => "123" };
+        assert_eq!(
+            labels.digest(),
+            labels! { "xyz" => "123", "abc" => "789" }.digest()
+        );
+        assert_ne!(
+            labels.digest(),
+            labels! { "abc" => "789", "xyz" => "456" }.digest()
+        );
+
+        let _guard = AppLabel::set_scoped("abc", "456");
+        assert_eq!(labels.digest().1, 2);
+        let mut saved = labels! { "xyz" => "123" };
+        saved.save_context();
+        assert_eq!(
+            saved.digest(),
+            labels! { "xyz" => "123", "abc" => "456" }.digest()
+        );
+    }
 }
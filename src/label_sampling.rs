@@ -0,0 +1,280 @@
+//! Sample values at rates depending on a label's value, e.g. successful requests at 1% but errors at 100%.
+//! Accepted values are weighted by the inverse of their rate, so that aggregated counts and sums remain estimates
+//! of the actual ones.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::dead_letter::{dead_letter, DEAD_LETTER_SAMPLED};
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::label::Labels;
+use crate::name::MetricName;
+use crate::pcg32;
+use crate::{CachedInput, Flush, MetricValue, QueuedInput};
+
+use std::io;
+use std::sync::Arc;
+
+/// Sampling rates by value of a label.
+/// Values are matched exactly, or by prefix if the pattern ends with `*`, e.g. `5*` for server errors.
+/// The first matching pattern applies. Values without a matching pattern, or without the label, use the default rate.
+#[derive(Debug, Clone)]
+pub struct LabelSampling {
+    key: String,
+    rates: Vec<(String, f64)>,
+    default_rate: f64,
+}
+
+impl LabelSampling {
+    /// Sample by value of the label `key`, at `default_rate` unless specified otherwise.
+    /// Rates range from 0.0 (no values) to 1.0 (all values).
+    pub fn new(key: &str, default_rate: f64) -> Self {
+        assert!((0.0..=1.0).contains(&default_rate));
+        LabelSampling {
+            key: key.to_string(),
+            rates: Vec::new(),
+            default_rate,
+        }
+    }
+
+    /// Return a clone sampling values matching the pattern at the specified rate.
+    pub fn rate(&self, pattern: &str, rate: f64) -> Self {
+        assert!((0.0..=1.0).contains(&rate));
+        let mut cloned = self.clone();
+        cloned.rates.push((pattern.to_string(), rate));
+        cloned
+    }
+
+    fn rate_of(&self, labels: &Labels) -> f64 {
+        labels
+            .lookup_with(&self.key, |value| {
+                self.rates
+                    .iter()
+                    .find(|(pattern, _)| match pattern.strip_suffix('*') {
+                        Some(prefix) => value.starts_with(prefix),
+                        None => value == pattern,
+                    })
+                    .map(|(_, rate)| *rate)
+            })
+            .flatten()
+            .unwrap_or(self.default_rate)
+    }
+}
+
+/// Sample values by label.
+pub trait LabelSampledInput: Input + Send + Sync + 'static + Sized {
+    /// Wrap this input with a decorator sampling values at rates depending on a label's value.
+    fn sampled_by_label(self, sampling: LabelSampling) -> InputLabelSampling {
+        InputLabelSampling::wrap(self, sampling)
+    }
+}
+
+impl<T: Input + Send + Sync + 'static> LabelSampledInput for T {}
+
+/// Input decorator sampling values by label.
+#[derive(Clone)]
+pub struct InputLabelSampling {
+    attributes: Attributes,
+    target: Arc<dyn InputDyn + Send + Sync + 'static>,
+    sampling: Arc<LabelSampling>,
+}
+
+impl InputLabelSampling {
+    /// Wrap an input with a decorator sampling values at rates depending on a label's value.
+    pub fn wrap<OUT: Input + Send + Sync + 'static>(
+        target: OUT,
+        sampling: LabelSampling,
+    ) -> InputLabelSampling {
+        InputLabelSampling {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            sampling: Arc::new(sampling),
+        }
+    }
+}
+
+impl QueuedInput for InputLabelSampling {}
+impl CachedInput for InputLabelSampling {}
+
+impl WithAttributes for InputLabelSampling {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for InputLabelSampling {
+    type SCOPE = InputLabelSamplingScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        InputLabelSamplingScope {
+            attributes: self.attributes.clone(),
+            target: self.target.input_dyn(),
+            sampling: self.sampling.clone(),
+        }
+    }
+}
+
+/// Scope decorator sampling values at rates depending on a label's value.
+/// Accepted values of counting kinds (markers, counters, levels, timers) are written with a weight of
+/// the inverse of their rate, e.g. 100 for 1%. Buckets record weights natively and statsd sends them
+/// as sample rates, other outputs sum them up or write the value once per weight.
+/// Gauges, sets and texts are sampled without weights.
+#[derive(Clone)]
+pub struct InputLabelSamplingScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    sampling: Arc<LabelSampling>,
+}
+
+impl InputLabelSamplingScope {
+    /// Wrap a scope with a decorator sampling values at rates depending on a label's value.
+    pub fn wrap<SC: InputScope + Send + Sync + 'static>(
+        target: SC,
+        sampling: LabelSampling,
+    ) -> Self {
+        InputLabelSamplingScope {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            sampling: Arc::new(sampling),
+        }
+    }
+}
+
+impl WithAttributes for InputLabelSamplingScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for InputLabelSamplingScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let target = self.target.new_metric(name.clone(), kind);
+        let sampling = self.sampling.clone();
+        sampled_metric(
+            MetricId::forge("label_sampling", name),
+            target,
+            kind,
+            move |labels| pcg32::sample_weight(sampling.rate_of(labels)),
+        )
+    }
+}
+
+impl Flush for InputLabelSamplingScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+/// A metric writing the values accepted by `sample`, which returns the weight of accepted values.
+/// Values of counting kinds are written with their weight, those of other kinds as is.
+/// Rejected values are counted as dead letters.
+pub(crate) fn sampled_metric<S: Fn(&Labels) -> Option<usize> + Send + Sync + 'static>(
+    identifier: MetricId,
+    target: InputMetric,
+    kind: InputKind,
+    sample: S,
+) -> InputMetric {
+    let sampled = Arc::new(Sampled {
+        target,
+        counting: !matches!(kind, InputKind::Gauge | InputKind::Set | InputKind::Text),
+        sample,
+    });
+    let weighted = sampled.clone();
+    let float = sampled.clone();
+    InputMetric::new(identifier, move |value, labels| {
+        sampled.write(value, 1, labels)
+    })
+    .with_weighted(move |value, weight, labels| weighted.write(value, weight, labels))
+    .with_float(move |value, labels| float.write_f64(value, labels))
+}
+
+/// The target of a sampled metric.
+struct Sampled<S> {
+    target: InputMetric,
+    counting: bool,
+    sample: S,
+}
+
+impl<S: Fn(&Labels) -> Option<usize>> Sampled<S> {
+    fn write(&self, value: MetricValue, weight: usize, labels: Labels) {
+        match (self.sample)(&labels) {
+            Some(sampled) if self.counting => {
+                self.target
+                    .write_n(value, sampled.saturating_mul(weight), labels)
+            }
+            Some(_) => self.target.write(value, labels),
+            None => dead_letter(DEAD_LETTER_SAMPLED, self.target.metric_id()),
+        }
+    }
+
+    fn write_f64(&self, value: f64, labels: Labels) {
+        match (self.sample)(&labels) {
+            Some(_) => self.target.write_f64(value, labels),
+            None => dead_letter(DEAD_LETTER_SAMPLED, self.target.metric_id()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+    use crate::stats::stats_all;
+    use crate::AtomicBucket;
+
+    #[test]
+    fn sample_by_status() {
+        let map = StatsMapScope::default();
+        let sampling = LabelSampling::new("status", 1.0)
+            .rate("200", 0.0)
+            .rate("5*", 1.0);
+        let metrics = InputLabelSamplingScope::wrap(map.clone(), sampling);
+        let ok = metrics.gauge("ok");
+        let error = metrics.gauge("error");
+        let other = metrics.gauge("other");
+
+        ok.write(1, labels!["status" => "200"]);
+        error.write(2, labels!["status" => "503"]);
+        other.write(3, labels![]);
+
+        let map = map.into_map();
+        assert_eq!(None, map.get("ok"));
+        assert_eq!(2, map["error"]);
+        assert_eq!(3, map["other"]);
+    }
+
+    #[test]
+    fn weighted_by_rate() {
+        let bucket = AtomicBucket::new();
+        bucket.stats(stats_all);
+        let sampling = LabelSampling::new("status", 1.0).rate("200", 0.25);
+        let metrics = InputLabelSamplingScope::wrap(bucket.clone(), sampling);
+        let ok = metrics.counter("ok");
+        let error = metrics.counter("error");
+
+        for _ in 0..4000 {
+            ok.write(2, labels!["status" => "200"]);
+        }
+        for _ in 0..10 {
+            error.write(2, labels!["status" => "503"]);
+        }
+
+        let map = StatsMapScope::default();
+        bucket.flush_to(&map).unwrap();
+        let map = map.into_map();
+        // each accepted value stands for 4
+        let ok_count = map["ok.count"];
+        assert_eq!(0, ok_count % 4);
+        assert!((3400..=4600).contains(&ok_count), "{}", ok_count);
+        assert_eq!(ok_count * 2, map["ok.sum"]);
+        // values sampled at 100% are not weighted
+        assert_eq!(10, map["error.count"]);
+        assert_eq!(20, map["error.sum"]);
+    }
+}
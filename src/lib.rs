@@ -8,7 +8,7 @@
     unused_extern_crates,
     unused_qualifications
 )]
-#![recursion_limit = "32"]
+#![recursion_limit = "64"]
 
 #[cfg(feature = "bench")]
 extern crate test;
@@ -59,52 +59,148 @@ mod metrics;
 mod name;
 mod pcg32;
 mod proxy;
+mod registry;
 mod scheduler;
+mod shutdown;
+#[cfg(all(unix, feature = "signal-hook"))]
+mod signal;
+#[cfg(feature = "tracing")]
+mod span_label;
+#[cfg(test)]
+mod testing;
 
 mod atomic;
+mod hll;
+mod snappy;
 mod stats;
+mod tdigest;
+mod tiered;
+mod topk;
 
+mod actor;
+mod adaptive_sampling;
 mod cache;
+mod clamp;
+#[cfg(feature = "dashboard")]
+mod dashboard;
+mod dead_letter;
+mod downsample;
+mod failover;
+mod filter;
+mod golden;
+mod label_sampling;
 mod lru_cache;
 
 mod multi;
+mod pipeline;
+mod quantize;
 mod queue;
+mod rate_limit;
+mod rewrite;
+mod scoped;
+mod split;
+mod switch;
+mod template;
+mod thread_buffer;
 
 pub use crate::attributes::{
-    Buffered, Buffering, Observe, ObserveWhen, OnFlush, OnFlushCancel, Prefixed, Sampled, Sampling,
+    Buffered, Buffering, ClockedTimers, FilterLabels, FlushReport, LabelStrategy, Observe,
+    ObserveWhen, OnFlush, OnFlushCancel, Prefixed, PreviewBatches, RenderLabels, ReportFlush,
+    Sampled, Sampling, ScaledTimers, TimeUnit,
 };
-pub use crate::clock::TimeHandle;
+pub use crate::clock::{Clock, TimeHandle, COARSE_CLOCK_TICK};
 pub use crate::input::{
-    Counter, Gauge, Input, InputDyn, InputKind, InputMetric, InputScope, Level, Marker, Timer,
+    Counter, Gauge, Input, InputDyn, InputKind, InputMetric, InputScope, LapTimer, Level, Marker,
+    PhasedTimer, ServiceHandle, Set, ShareScope, Stopwatch, Text, Timer, WaitHandle, WeakMetric,
+    TEXT_LABEL,
+};
+pub use crate::label::{
+    AppLabel, ContextLabel, LabelGuard, LabelProvider, LabelledTask, Labels, TaskLabel, ThreadLabel,
+};
+pub use crate::metrics::{
+    dipstick_metrics_target, dipstick_metrics_target_prefixed, unset_dipstick_metrics_target,
+    DIPSTICK_CACHE_HIT, DIPSTICK_CACHE_MISS, DIPSTICK_CACHE_THRASH, DIPSTICK_GRAPHITE_BUF_OVERFLOW,
+    DIPSTICK_GRAPHITE_RETRY_DROPPED, DIPSTICK_GRAPHITE_SEND_FAILED, DIPSTICK_GRAPHITE_SENT_BYTES,
+    DIPSTICK_PREFIX, DIPSTICK_PROMETHEUS_BUF_OVERFLOW, DIPSTICK_PROMETHEUS_SEND_FAILED,
+    DIPSTICK_PROMETHEUS_SENT_BYTES, DIPSTICK_QUEUE_DROPPED, DIPSTICK_QUEUE_FULL,
+    DIPSTICK_QUEUE_SEND_FAILED, DIPSTICK_RATE_LIMITED, DIPSTICK_REMOTE_WRITE_SEND_FAILED,
+    DIPSTICK_REMOTE_WRITE_SENT_BYTES, DIPSTICK_STATSD_RETRY_DROPPED, DIPSTICK_STATSD_SEND_FAILED,
+    DIPSTICK_STATSD_SENT_BYTES,
 };
-pub use crate::label::{AppLabel, Labels, ThreadLabel};
 pub use crate::name::{MetricName, NameParts};
 pub use crate::output::void::Void;
 pub use crate::scheduler::{Cancel, CancelGuard, CancelHandle, ScheduleFlush};
+pub use crate::shutdown::shutdown;
+
+#[cfg(feature = "tokio")]
+pub use crate::scheduler::TokioScheduler;
+#[cfg(all(unix, feature = "signal-hook"))]
+pub use crate::signal::{SignalFlush, DEFAULT_SHUTDOWN_TIMEOUT};
+#[cfg(feature = "tracing")]
+pub use crate::span_label::{SpanLabelLayer, SPAN_LABEL};
 
 #[cfg(test)]
 pub use crate::clock::{mock_clock_advance, mock_clock_reset};
 
 pub use crate::proxy::Proxy;
+pub use crate::registry::{registry, Registry};
 
 mod output;
 pub use crate::output::format::{
-    Formatting, LabelOp, LineFormat, LineOp, LineTemplate, SimpleFormat,
+    Formatting, JsonFormat, LabelOp, LineFormat, LineOp, LineTemplate, SimpleFormat, TemplateFormat,
 };
 pub use crate::output::graphite::{Graphite, GraphiteMetric, GraphiteScope};
 pub use crate::output::log::{Log, LogScope};
 pub use crate::output::map::StatsMapScope;
+pub use crate::output::protobuf::{Protobuf, ProtobufScope, PROTOBUF_CONTENT_TYPE};
+pub use crate::output::remote_write::{
+    RemoteWrite, RemoteWriteScope, DEFAULT_REMOTE_WRITE_BACKOFF, DEFAULT_REMOTE_WRITE_RETRIES,
+};
+pub use crate::output::retry::{DEFAULT_RETRY_BACKOFF, DEFAULT_RETRY_QUEUE_BYTES};
 pub use crate::output::statsd::{Statsd, StatsdMetric, StatsdScope};
 pub use crate::output::stream::{Stream, TextScope};
 
 //#[cfg(feature="prometheus")]
 pub use crate::output::prometheus::{Prometheus, PrometheusScope};
 
-pub use crate::atomic::AtomicBucket;
-pub use crate::cache::CachedInput;
-pub use crate::multi::{MultiInput, MultiInputScope};
-pub use crate::queue::{InputQueue, InputQueueScope, QueuedInput};
-pub use crate::stats::{stats_all, stats_average, stats_summary, ScoreType};
+pub use crate::actor::{ActorScope, InputActorScope, DEFAULT_ACTOR_QUEUE_LENGTH};
+pub use crate::adaptive_sampling::{
+    AdaptiveSampledInput, InputAdaptiveSampling, InputAdaptiveSamplingScope,
+};
+pub use crate::atomic::{AtomicBucket, BucketSnapshot, RateGuard, TopBy};
+pub use crate::cache::{CachedInput, InputCache, InputScopeCache};
+pub use crate::clamp::{ClampedInput, InputClamp, InputClampScope, CLAMPED};
+#[cfg(feature = "dashboard")]
+pub use crate::dashboard::{Dashboard, DASHBOARD_CONTENT_TYPE, DASHBOARD_PATH};
+pub use crate::dead_letter::{
+    dead_letter_target, unset_dead_letter_target, DEAD_LETTER_QUEUE_DROPPED,
+    DEAD_LETTER_RATE_LIMITED, DEAD_LETTER_SAMPLED,
+};
+pub use crate::downsample::{Downsample, DownsampledInput, InputDownsample, InputDownsampleScope};
+pub use crate::failover::{Failover, FailoverScope, DEFAULT_FAILOVER_AFTER};
+pub use crate::filter::{FilteredInput, InputFilter, InputFilterScope};
+pub use crate::golden::{check_golden, render_canonical, GOLDEN_BLESS_VAR};
+pub use crate::hll::HyperLogLog;
+pub use crate::label_sampling::{
+    InputLabelSampling, InputLabelSamplingScope, LabelSampledInput, LabelSampling,
+};
+pub use crate::multi::{MultiFlushError, MultiInput, MultiInputScope};
+pub use crate::pipeline::{Pipeline, PipelineState};
+pub use crate::quantize::{InputQuantize, InputQuantizeScope, Quantize, QuantizedInput};
+pub use crate::queue::{
+    InputQueue, InputQueueScope, OverflowPolicy, QueueError, QueueFlush, QueuedInput,
+};
+pub use crate::rate_limit::{InputRateLimit, InputRateLimitScope, RateLimitedInput};
+pub use crate::rewrite::{InputRewrite, InputRewriteScope, RewrittenInput};
+pub use crate::scoped::{scoped_input, scoped_write, ScopedInput, ScopedWrite};
+pub use crate::split::{SplitInput, SplitInputScope};
+pub use crate::stats::{quantile_name, stats_all, stats_average, stats_summary, ScoreType};
+pub use crate::switch::{Switch, SwitchScope};
+pub use crate::tdigest::{Centroid, TDigest};
+pub use crate::template::MetricTemplate;
+pub use crate::thread_buffer::ThreadBufferScope;
+pub use crate::tiered::TieredBucket;
+pub use crate::topk::SpaceSaving;
 
 use std::io;
 
@@ -115,6 +211,12 @@ pub type MetricValue = isize;
 pub trait Flush {
     /// Flush does nothing by default.
     fn flush(&self) -> io::Result<()>;
+
+    /// Flush one last time, e.g. before the process exits.
+    /// Queued scopes also wait for their pending writes to be executed.
+    fn close(&self) -> io::Result<()> {
+        self.flush()
+    }
 }
 
 #[cfg(feature = "bench")]
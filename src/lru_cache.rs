@@ -2,23 +2,31 @@
 //! Stored values will be held onto as long as there is space.
 //! When space runs out, the oldest unused value will get evicted to make room for a new value.
 
+use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::hash::Hash;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
 
 struct CacheEntry<K, V> {
     key: K,
     value: Option<V>,
     next: Option<usize>,
     prev: Option<usize>,
+    /// Set by shared lookups, which can not promote the entry themselves
+    used: AtomicBool,
 }
 
 /// A fixed-size cache.
 pub struct LRUCache<K, V> {
     table: HashMap<K, usize>,
     entries: Vec<CacheEntry<K, V>>,
+    /// Slots of removed entries, to be reused
+    free: Vec<usize>,
     first: Option<usize>,
     last: Option<usize>,
     capacity: usize,
+    evictions: usize,
 }
 
 impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
@@ -27,9 +35,24 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
         LRUCache {
             table: HashMap::with_capacity(size),
             entries: Vec::with_capacity(size),
+            free: Vec::new(),
             first: None,
             last: None,
             capacity: size,
+            evictions: 0,
+        }
+    }
+
+    /// Creates a new cache that never evicts on its own, its owner removes the oldest entries as needed.
+    pub fn unbounded() -> Self {
+        LRUCache {
+            table: HashMap::new(),
+            entries: Vec::new(),
+            free: Vec::new(),
+            first: None,
+            last: None,
+            capacity: usize::MAX,
+            evictions: 0,
         }
     }
 
@@ -44,22 +67,25 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
             entry.value = Some(value);
             old
         } else {
-            self.ensure_room();
-            // Update old head
-            let idx = self.entries.len();
-            if let Some(e) = self.first {
-                let prev = Some(idx);
-                self.entries[e].prev = prev;
-            }
-            // This is the new head
-            self.entries.push(CacheEntry {
+            let entry = CacheEntry {
                 key: key.clone(),
                 value: Some(value),
-                next: self.first,
+                next: None,
                 prev: None,
-            });
-            self.first = Some(idx);
-            self.last = self.last.or(self.first);
+                used: AtomicBool::new(false),
+            };
+            // reuse the slot of an evicted entry, if any
+            let idx = match self.ensure_room().or_else(|| self.free.pop()) {
+                Some(idx) => {
+                    self.entries[idx] = entry;
+                    idx
+                }
+                None => {
+                    self.entries.push(entry);
+                    self.entries.len() - 1
+                }
+            };
+            self.push_front(idx);
             self.table.insert(key, idx);
             None
         }
@@ -74,6 +100,34 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
             .and_then(move |i| entries[*i].value.as_ref())
     }
 
+    /// Retrieves a reference to the item associated with `key` from the cache, through a shared reference.
+    /// The item is marked as used rather than promoted, it is promoted if it is about to be evicted instead.
+    pub fn get_shared<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let entry = &self.entries[*self.table.get(key)?];
+        if !entry.used.load(Relaxed) {
+            // only written once per period of use, keeping the entry's cache line shared between readers
+            entry.used.store(true, Relaxed);
+        }
+        entry.value.as_ref()
+    }
+
+    /// Removes the least recently used item, giving items used since their last promotion a second chance.
+    /// Returns false if the cache is empty.
+    pub fn remove_oldest(&mut self) -> bool {
+        match self.remove_last() {
+            Some(idx) => {
+                self.entries[idx].value = None;
+                self.free.push(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
     /// Retrieves a reference to the item associated with `key` from the cache.
     pub fn get(&mut self, key: &K) -> Option<&V> {
         if self.contains_key(key) {
@@ -87,60 +141,77 @@ impl<K: Clone + Hash + Eq, V> LRUCache<K, V> {
         self.table.len()
     }
 
+    /// Returns the number of elements evicted to make room for new elements since creation.
+    pub fn evictions(&self) -> usize {
+        self.evictions
+    }
+
     /// Promotes the specified key to the top of the cache.
     fn access(&mut self, key: &K) {
         let i = self.table[key];
-        self.remove_from_list(i);
-        self.first = Some(i);
+        if self.first != Some(i) {
+            self.remove_from_list(i);
+            self.push_front(i);
+        }
     }
 
     pub fn contains_key(&mut self, key: &K) -> bool {
         self.table.contains_key(key)
     }
 
+    /// Links an item at the front of the list.
+    fn push_front(&mut self, i: usize) {
+        self.entries[i].prev = None;
+        self.entries[i].next = self.first;
+        if let Some(first) = self.first {
+            self.entries[first].prev = Some(i);
+        }
+        self.first = Some(i);
+        self.last = self.last.or(self.first);
+    }
+
     /// Removes an item from the linked list.
     fn remove_from_list(&mut self, i: usize) {
         let (prev, next) = {
             let entry = &mut self.entries[i];
-            (entry.prev, entry.next)
+            (entry.prev.take(), entry.next.take())
         };
-        match (prev, next) {
-            // Item was in the middle of the list
-            (Some(j), Some(k)) => {
-                {
-                    let first = &mut self.entries[j];
-                    first.next = next;
-                }
-                let second = &mut self.entries[k];
-                second.prev = prev;
-            }
-            // Item was at the end of the list
-            (Some(j), None) => {
-                let first = &mut self.entries[j];
-                first.next = None;
-                self.last = prev;
-            }
-            // Item was at front
-            _ => (),
+        match prev {
+            Some(j) => self.entries[j].next = next,
+            None => self.first = next,
+        }
+        match next {
+            Some(k) => self.entries[k].prev = prev,
+            None => self.last = prev,
         }
     }
 
-    fn ensure_room(&mut self) {
-        if self.capacity == self.len() {
-            self.remove_last();
+    /// Evicts the oldest item if the cache is full, returning its now free slot.
+    fn ensure_room(&mut self) -> Option<usize> {
+        if self.len() >= self.capacity {
+            self.remove_last()
+        } else {
+            None
         }
     }
 
-    /// Removes the oldest item in the cache.
-    fn remove_last(&mut self) {
-        if let Some(idx) = self.last {
+    /// Removes the oldest item in the cache, promoting items marked as used on the way.
+    fn remove_last(&mut self) -> Option<usize> {
+        let mut idx = self.last?;
+        // every item is passed over at most once, its mark being cleared
+        for _ in 0..self.table.len() {
+            if !self.entries[idx].used.swap(false, Relaxed) {
+                break;
+            }
             self.remove_from_list(idx);
-            let key = &self.entries[idx].key;
-            self.table.remove(key);
-        }
-        if self.last.is_none() {
-            self.first = None;
+            self.push_front(idx);
+            idx = self.last?;
         }
+        self.remove_from_list(idx);
+        let key = &self.entries[idx].key;
+        self.table.remove(key);
+        self.evictions += 1;
+        Some(idx)
     }
 }
 
@@ -168,4 +239,35 @@ mod tests {
         assert!(cache.contains_key(&"qux"));
         assert!(!cache.contains_key(&"baz"));
     }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache: LRUCache<usize, _> = LRUCache::with_capacity(3);
+        for i in 0..100 {
+            cache.insert(i % 5, i);
+            cache.get(&0);
+        }
+        // the most recently accessed key stays, the cache never exceeds its capacity
+        assert_eq!(cache.len(), 3);
+        assert!(cache.contains_key(&0));
+        assert_eq!(cache.entries.len(), 3);
+    }
+    #[test]
+    fn shared_lookups_get_a_second_chance() {
+        let mut cache: LRUCache<String, _> = LRUCache::unbounded();
+        cache.insert("foo".to_string(), 1);
+        cache.insert("bar".to_string(), 2);
+        assert_eq!(Some(&1), cache.get_shared("foo"));
+
+        assert!(cache.remove_oldest());
+        assert!(cache.contains_key(&"foo".to_string()));
+        assert!(!cache.contains_key(&"bar".to_string()));
+
+        // the slot of the removed item is reused
+        cache.insert("baz".to_string(), 3);
+        assert_eq!(cache.entries.len(), 2);
+        assert!(cache.remove_oldest());
+        assert!(cache.remove_oldest());
+        assert!(!cache.remove_oldest());
+    }
 }
@@ -17,6 +17,21 @@ macro_rules! time {
     }};
 }
 
+/// Create **Labels** describing the application's build, for `observe_info()`:
+/// `version` from the calling crate's `CARGO_PKG_VERSION`, `git_sha` and `build_date` from the
+/// `GIT_SHA` and `BUILD_DATE` environment variables when compiling (e.g. set by a build script), if any,
+/// and the `hostname`. See `Labels::build_info()`.
+#[macro_export]
+macro_rules! build_info_labels {
+    () => {
+        $crate::Labels::build_info(
+            env!("CARGO_PKG_VERSION"),
+            option_env!("GIT_SHA"),
+            option_env!("BUILD_DATE"),
+        )
+    };
+}
+
 /// Create **Labels** from a list of key-value pairs
 /// Adapted from the hashmap!() macro in the *maplit* crate.
 ///
@@ -40,23 +55,10 @@ macro_rules! time {
 /// ```
 #[macro_export]
 macro_rules! labels {
-    (@single $($x:tt)*) => (());
-    (@count $($rest:expr),*) => (<[()]>::len(&[$(labels!(@single $rest)),*]));
-
     ($($key:expr => $value:expr,)+) => { labels!($($key => $value),+) };
     ($($key:expr => $value:expr),*) => {
-        {
-            let _cap = labels!(@count $($key),*);
-            let mut _map: ::std::collections::HashMap<String, ::std::sync::Arc<String>> = ::std::collections::HashMap::with_capacity(_cap);
-            $(
-                let _ = _map.insert($key.into(), ::std::sync::Arc::new($value.into()));
-            )*
-            crate::Labels::from(_map)
-        }
+        crate::Labels::from([$(($key.into(), ::std::sync::Arc::new($value.into()))),*])
     };
-    () => {
-        ::Labels::default()
-    }
 }
 
 /// Metrics can be used from anywhere (public), does not need to declare metrics in this block.
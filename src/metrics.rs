@@ -1,34 +1,129 @@
 //! Internal Dipstick runtime metrics.
 //! Because the possibly high volume of data, this is pre-set to use aggregation.
-//! This is also kept in a separate module because it is not to be exposed outside of the crate.
+//! The metrics themselves are kept private to the crate,
+//! their names and a way to publish them are exposed so operators can alert on them.
 
-use crate::attributes::Prefixed;
+use crate::attributes::{Prefixed, WithAttributes};
 use crate::input::{Counter, InputScope, Marker};
 use crate::proxy::Proxy;
 
+/// Default prefix of dipstick's own metrics names when published.
+pub const DIPSTICK_PREFIX: &str = "dipstick";
+
+/// Marker, a value could not be passed to an `InputQueue` worker thread and was dropped.
+/// Only happens if the worker thread is gone.
+pub const DIPSTICK_QUEUE_SEND_FAILED: &str = "queue.send_failed";
+/// Marker, an `InputQueue` was full and a value had to wait for room, slowing down the caller.
+pub const DIPSTICK_QUEUE_FULL: &str = "queue.full";
+/// Marker, an `InputQueue` was full and a value was dropped as per its overflow policy.
+pub const DIPSTICK_QUEUE_DROPPED: &str = "queue.dropped";
+
+/// Marker, a metric definition cache evicted entries faster than its thrash threshold allows.
+pub const DIPSTICK_CACHE_THRASH: &str = "cache.thrash";
+/// Counter, metric definitions found in a cache, counted upon misses and flushes of the cache.
+pub const DIPSTICK_CACHE_HIT: &str = "cache.hit";
+/// Marker, a metric definition was not found in a cache and had to be defined by its target.
+pub const DIPSTICK_CACHE_MISS: &str = "cache.miss";
+
+/// Marker, a value was dropped because its metric was written more often than allowed by a rate limiter.
+pub const DIPSTICK_RATE_LIMITED: &str = "rate_limit.dropped";
+
+/// Marker, a prometheus push failed and the buffered metrics were dropped.
+pub const DIPSTICK_PROMETHEUS_SEND_FAILED: &str = "prometheus.send_failed";
+/// Marker, a value could not be buffered because the prometheus buffer was full.
+pub const DIPSTICK_PROMETHEUS_BUF_OVERFLOW: &str = "prometheus.buf_overflow";
+/// Counter, bytes successfully pushed to prometheus.
+pub const DIPSTICK_PROMETHEUS_SENT_BYTES: &str = "prometheus.sent_bytes";
+
+/// Marker, a remote write request still failed after retries and its samples were dropped.
+pub const DIPSTICK_REMOTE_WRITE_SEND_FAILED: &str = "remote_write.send_failed";
+/// Counter, compressed bytes successfully sent to a remote write endpoint.
+pub const DIPSTICK_REMOTE_WRITE_SENT_BYTES: &str = "remote_write.sent_bytes";
+
+/// Marker, a graphite send failed, the metrics not sent are kept for retry.
+pub const DIPSTICK_GRAPHITE_SEND_FAILED: &str = "graphite.send_failed";
+/// Marker, a value could not be buffered because the graphite buffer was full.
+pub const DIPSTICK_GRAPHITE_BUF_OVERFLOW: &str = "graphite.buf_overflow";
+/// Counter, bytes successfully sent to graphite.
+pub const DIPSTICK_GRAPHITE_SENT_BYTES: &str = "graphite.sent_bytes";
+/// Counter, bytes of failed graphite sends dropped from the retry queue to make room for newer ones.
+pub const DIPSTICK_GRAPHITE_RETRY_DROPPED: &str = "graphite.retry_dropped_bytes";
+
+/// Marker, a statsd packet could not be sent and is kept for retry.
+pub const DIPSTICK_STATSD_SEND_FAILED: &str = "statsd.send_failed";
+/// Counter, bytes successfully sent to statsd.
+pub const DIPSTICK_STATSD_SENT_BYTES: &str = "statsd.sent_bytes";
+/// Counter, bytes of failed statsd packets dropped from the retry queue to make room for newer ones.
+pub const DIPSTICK_STATSD_RETRY_DROPPED: &str = "statsd.retry_dropped_bytes";
+
 metrics! {
     /// Dipstick's own internal metrics.
-    pub DIPSTICK_METRICS = "dipstick" => {
-
-        "queue" => {
-            pub SEND_FAILED: Marker = "send_failed";
-        }
-
-        "prometheus" => {
-            pub PROMETHEUS_SEND_ERR: Marker = "send_failed";
-            pub PROMETHEUS_OVERFLOW: Marker = "buf_overflow";
-            pub PROMETHEUS_SENT_BYTES: Counter = "sent_bytes";
-        }
-
-        "graphite" => {
-            pub GRAPHITE_SEND_ERR: Marker = "send_failed";
-            pub GRAPHITE_OVERFLOW: Marker = "buf_overflow";
-            pub GRAPHITE_SENT_BYTES: Counter = "sent_bytes";
-        }
-
-        "statsd" => {
-            pub STATSD_SEND_ERR: Marker ="send_failed";
-            pub STATSD_SENT_BYTES: Counter = "sent_bytes";
-        }
+    pub DIPSTICK_METRICS = DIPSTICK_PREFIX => {
+        pub SEND_FAILED: Marker = DIPSTICK_QUEUE_SEND_FAILED;
+        pub QUEUE_FULL: Marker = DIPSTICK_QUEUE_FULL;
+        pub QUEUE_DROPPED: Marker = DIPSTICK_QUEUE_DROPPED;
+
+        pub CACHE_THRASH: Marker = DIPSTICK_CACHE_THRASH;
+        pub CACHE_HIT: Counter = DIPSTICK_CACHE_HIT;
+        pub CACHE_MISS: Marker = DIPSTICK_CACHE_MISS;
+
+        pub RATE_LIMITED: Marker = DIPSTICK_RATE_LIMITED;
+
+        pub PROMETHEUS_SEND_ERR: Marker = DIPSTICK_PROMETHEUS_SEND_FAILED;
+        pub PROMETHEUS_OVERFLOW: Marker = DIPSTICK_PROMETHEUS_BUF_OVERFLOW;
+        pub PROMETHEUS_SENT_BYTES: Counter = DIPSTICK_PROMETHEUS_SENT_BYTES;
+
+        pub REMOTE_WRITE_SEND_ERR: Marker = DIPSTICK_REMOTE_WRITE_SEND_FAILED;
+        pub REMOTE_WRITE_SENT_BYTES: Counter = DIPSTICK_REMOTE_WRITE_SENT_BYTES;
+
+        pub GRAPHITE_SEND_ERR: Marker = DIPSTICK_GRAPHITE_SEND_FAILED;
+        pub GRAPHITE_OVERFLOW: Marker = DIPSTICK_GRAPHITE_BUF_OVERFLOW;
+        pub GRAPHITE_SENT_BYTES: Counter = DIPSTICK_GRAPHITE_SENT_BYTES;
+        pub GRAPHITE_RETRY_DROPPED: Counter = DIPSTICK_GRAPHITE_RETRY_DROPPED;
+
+        pub STATSD_SEND_ERR: Marker = DIPSTICK_STATSD_SEND_FAILED;
+        pub STATSD_SENT_BYTES: Counter = DIPSTICK_STATSD_SENT_BYTES;
+        pub STATSD_RETRY_DROPPED: Counter = DIPSTICK_STATSD_RETRY_DROPPED;
+    }
+}
+
+/// Publish dipstick's own metrics to the target, names prefixed with `DIPSTICK_PREFIX`,
+/// e.g. `dipstick.queue.send_failed`. Replaces any previous target.
+pub fn dipstick_metrics_target<T>(target: T)
+where
+    T: InputScope + WithAttributes + Send + Sync + 'static,
+{
+    dipstick_metrics_target_prefixed(target, DIPSTICK_PREFIX)
+}
+
+/// Publish dipstick's own metrics to the target, names prefixed with the specified prefix
+/// instead of `DIPSTICK_PREFIX`. Replaces any previous target.
+pub fn dipstick_metrics_target_prefixed<T>(target: T, prefix: &str)
+where
+    T: InputScope + WithAttributes + Send + Sync + 'static,
+{
+    DIPSTICK_METRICS.target(target.add_name(prefix))
+}
+
+/// Stop publishing dipstick's own metrics.
+pub fn unset_dipstick_metrics_target() {
+    DIPSTICK_METRICS.unset_target()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn prefixed_internal_metrics() {
+        // not a shared self-metric, which other tests may mark concurrently
+        let marker = DIPSTICK_METRICS.marker("test_prefixed");
+        let map = StatsMapScope::default();
+        dipstick_metrics_target_prefixed(map.clone(), "my_app");
+        marker.mark();
+        unset_dipstick_metrics_target();
+        marker.mark();
+        assert_eq!(map.into_map()["my_app.test_prefixed"], 1);
     }
 }
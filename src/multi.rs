@@ -3,27 +3,127 @@
 use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
 use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
 use crate::name::MetricName;
-use crate::Flush;
+use crate::pcg32;
+use crate::{CachedInput, Flush, QueuedInput};
 
-use std::io;
 use std::sync::Arc;
+use std::{error, fmt, io, thread};
+
+/// Which metrics and values a target receives.
+#[derive(Clone, Default)]
+struct TargetFilter {
+    /// The kinds of metrics sent to the target, all kinds if `None`.
+    kinds: Option<Arc<[InputKind]>>,
+    /// The integer sampling rate of values sent to the target, all values if `None`.
+    sample_rate: Option<u32>,
+}
+
+impl TargetFilter {
+    fn kinds(kinds: &[InputKind]) -> Self {
+        TargetFilter {
+            kinds: Some(kinds.into()),
+            sample_rate: None,
+        }
+    }
+
+    fn sampled(rate: f64) -> Self {
+        TargetFilter {
+            kinds: None,
+            sample_rate: Some(pcg32::to_int_rate(rate)),
+        }
+    }
+
+    fn accepts(&self, kind: InputKind) -> bool {
+        self.kinds
+            .as_ref()
+            .is_none_or(|kinds| kinds.contains(&kind))
+    }
+}
+
+/// A target's metric, with the sampling rate of the values written to it.
+struct TargetMetric(InputMetric, Option<u32>);
+
+impl TargetMetric {
+    fn sample(&self) -> Option<&InputMetric> {
+        match self.1 {
+            Some(rate) if !pcg32::accept_sample(rate) => None,
+            _ => Some(&self.0),
+        }
+    }
+}
+
+/// The errors of the targets that failed to flush, out of all targets of a `MultiInputScope`.
+/// Returned by `MultiInputScope::flush()` wrapped in an `io::Error`, from which it can be downcast.
+#[derive(Debug)]
+pub struct MultiFlushError {
+    errors: Vec<io::Error>,
+    targets: usize,
+}
+
+impl MultiFlushError {
+    /// The errors of the targets that failed to flush, in the order the targets were added.
+    pub fn errors(&self) -> &[io::Error] {
+        &self.errors
+    }
+
+    /// The number of targets that were flushed, including those that failed.
+    pub fn targets(&self) -> usize {
+        self.targets
+    }
+}
+
+impl fmt::Display for MultiFlushError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} of {} targets failed to flush",
+            self.errors.len(),
+            self.targets
+        )?;
+        for (i, e) in self.errors.iter().enumerate() {
+            write!(f, "{} {}", if i == 0 { ":" } else { ";" }, e)?;
+        }
+        Ok(())
+    }
+}
+
+impl error::Error for MultiFlushError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        let first: &(dyn error::Error + 'static) = self.errors.first()?;
+        Some(first)
+    }
+}
+
+impl From<MultiFlushError> for io::Error {
+    fn from(e: MultiFlushError) -> Self {
+        io::Error::other(e)
+    }
+}
 
 /// Opens multiple scopes at a time from just as many outputs.
 #[derive(Clone, Default)]
 pub struct MultiInput {
     attributes: Attributes,
-    inputs: Vec<Arc<dyn InputDyn + Send + Sync>>,
+    inputs: Vec<(Arc<dyn InputDyn + Send + Sync>, TargetFilter)>,
+    concurrent_flush: bool,
 }
 
+impl QueuedInput for MultiInput {}
+impl CachedInput for MultiInput {}
+
 impl Input for MultiInput {
     type SCOPE = MultiInputScope;
 
     fn metrics(&self) -> Self::SCOPE {
-        #[allow(clippy::redundant_closure)]
-        let scopes = self.inputs.iter().map(|input| input.input_dyn()).collect();
+        let scopes = self
+            .inputs
+            .iter()
+            .map(|(input, filter)| (input.input_dyn(), filter.clone()))
+            .collect();
         MultiInputScope {
             attributes: self.attributes.clone(),
             scopes,
+            concurrent_flush: self.concurrent_flush,
         }
     }
 }
@@ -43,7 +143,45 @@ impl MultiInput {
     /// Returns a clone of the dispatch with the new target added to the list.
     pub fn add_target<OUT: Input + Send + Sync + 'static>(&self, out: OUT) -> Self {
         let mut cloned = self.clone();
-        cloned.inputs.push(Arc::new(out));
+        cloned.inputs.push((Arc::new(out), TargetFilter::default()));
+        cloned
+    }
+
+    /// Returns a clone of the dispatch with the new target added to the list,
+    /// receiving only metrics of the specified kinds, e.g. timers to graphite only.
+    pub fn add_target_filtered<OUT: Input + Send + Sync + 'static>(
+        &self,
+        out: OUT,
+        kinds: &[InputKind],
+    ) -> Self {
+        let mut cloned = self.clone();
+        cloned
+            .inputs
+            .push((Arc::new(out), TargetFilter::kinds(kinds)));
+        cloned
+    }
+
+    /// Returns a clone of the dispatch with the new target added to the list,
+    /// receiving only a random fraction of the values written, between 0.0 and 1.0,
+    /// e.g. to mirror 1% of production traffic to a staging backend with `0.01`.
+    /// Sampled values are written as is, counts are not scaled up.
+    pub fn add_target_sampled<OUT: Input + Send + Sync + 'static>(
+        &self,
+        out: OUT,
+        rate: f64,
+    ) -> Self {
+        let mut cloned = self.clone();
+        cloned
+            .inputs
+            .push((Arc::new(out), TargetFilter::sampled(rate)));
+        cloned
+    }
+
+    /// Returns a clone of the dispatch flushing its targets concurrently, each on its own thread.
+    /// See `MultiInputScope::concurrent_flush()`.
+    pub fn concurrent_flush(&self) -> Self {
+        let mut cloned = self.clone();
+        cloned.concurrent_flush = true;
         cloned
     }
 }
@@ -58,10 +196,12 @@ impl WithAttributes for MultiInput {
 }
 
 /// Dispatch metric values to a list of scopes.
+/// Flushing attempts every target even if some fail, returning a `MultiFlushError` listing the failures.
 #[derive(Clone, Default)]
 pub struct MultiInputScope {
     attributes: Attributes,
-    scopes: Vec<Arc<dyn InputScope + Send + Sync>>,
+    scopes: Vec<(Arc<dyn InputScope + Send + Sync>, TargetFilter)>,
+    concurrent_flush: bool,
 }
 
 impl MultiInputScope {
@@ -70,6 +210,7 @@ impl MultiInputScope {
         MultiInputScope {
             attributes: Attributes::default(),
             scopes: vec![],
+            concurrent_flush: false,
         }
     }
 
@@ -77,37 +218,132 @@ impl MultiInputScope {
     /// Returns a clone of the original object.
     pub fn add_target<IN: InputScope + Send + Sync + 'static>(&self, scope: IN) -> Self {
         let mut cloned = self.clone();
-        cloned.scopes.push(Arc::new(scope));
         cloned
+            .scopes
+            .push((Arc::new(scope), TargetFilter::default()));
+        cloned
+    }
+
+    /// Add a target to the dispatch list, receiving only metrics of the specified kinds,
+    /// e.g. timers to graphite only. Metrics of other kinds are not defined in the target.
+    /// Returns a clone of the original object.
+    pub fn add_target_filtered<IN: InputScope + Send + Sync + 'static>(
+        &self,
+        scope: IN,
+        kinds: &[InputKind],
+    ) -> Self {
+        let mut cloned = self.clone();
+        cloned
+            .scopes
+            .push((Arc::new(scope), TargetFilter::kinds(kinds)));
+        cloned
+    }
+
+    /// Add a target to the dispatch list, receiving only a random fraction of the values written,
+    /// between 0.0 and 1.0, e.g. to mirror 1% of production traffic to a staging backend with `0.01`.
+    /// Sampled values are written as is, counts are not scaled up.
+    /// Returns a clone of the original object.
+    pub fn add_target_sampled<IN: InputScope + Send + Sync + 'static>(
+        &self,
+        scope: IN,
+        rate: f64,
+    ) -> Self {
+        let mut cloned = self.clone();
+        cloned
+            .scopes
+            .push((Arc::new(scope), TargetFilter::sampled(rate)));
+        cloned
+    }
+
+    /// Returns a clone of the dispatch flushing its targets concurrently, each on its own thread.
+    /// A slow target then no longer delays the others; flush takes as long as the slowest target.
+    /// Every target is flushed even if some fail or panic.
+    pub fn concurrent_flush(&self) -> Self {
+        let mut cloned = self.clone();
+        cloned.concurrent_flush = true;
+        cloned
+    }
+
+    fn flush_concurrently(&self) -> Vec<io::Result<()>> {
+        thread::scope(|s| {
+            let handles: Vec<_> = self
+                .scopes
+                .iter()
+                .map(|(scope, _filter)| s.spawn(move || scope.flush()))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|_| Err(io::Error::other("target panicked during flush")))
+                })
+                .collect()
+        })
     }
 }
 
 impl InputScope for MultiInputScope {
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
         let name = &self.prefix_append(name);
-        let metrics: Vec<InputMetric> = self
+        let metrics: Vec<TargetMetric> = self
             .scopes
             .iter()
-            .map(move |scope| scope.new_metric(name.clone(), kind))
+            .filter(|(_scope, filter)| filter.accepts(kind))
+            .map(move |(scope, filter)| {
+                TargetMetric(scope.new_metric(name.clone(), kind), filter.sample_rate)
+            })
             .collect();
+        let metrics = Arc::new(metrics);
+        let floats = metrics.clone();
+        let timestamped = metrics.clone();
+        let weighted = metrics.clone();
         InputMetric::new(
             MetricId::forge("multi", name.clone()),
             move |value, labels| {
-                for metric in &metrics {
+                for metric in metrics.iter().filter_map(TargetMetric::sample) {
                     metric.write(value, labels.clone())
                 }
             },
         )
+        .with_float(move |value, labels| {
+            for metric in floats.iter().filter_map(TargetMetric::sample) {
+                metric.write_f64(value, labels.clone())
+            }
+        })
+        .with_timestamped(move |timestamp, value, labels| {
+            for metric in timestamped.iter().filter_map(TargetMetric::sample) {
+                metric.write_at(timestamp, value, labels.clone())
+            }
+        })
+        .with_weighted(move |value, weight, labels| {
+            for metric in weighted.iter().filter_map(TargetMetric::sample) {
+                metric.write_n(value, weight, labels.clone())
+            }
+        })
     }
 }
 
 impl Flush for MultiInputScope {
     fn flush(&self) -> io::Result<()> {
         self.notify_flush_listeners();
-        for w in &self.scopes {
-            w.flush()?;
+        let results = if self.concurrent_flush && self.scopes.len() > 1 {
+            self.flush_concurrently()
+        } else {
+            self.scopes
+                .iter()
+                .map(|(scope, _filter)| scope.flush())
+                .collect()
+        };
+        let errors: Vec<io::Error> = results.into_iter().filter_map(Result::err).collect();
+        if errors.is_empty() {
+            return Ok(());
         }
-        Ok(())
+        Err(MultiFlushError {
+            errors,
+            targets: self.scopes.len(),
+        }
+        .into())
     }
 }
 
@@ -119,3 +355,94 @@ impl WithAttributes for MultiInputScope {
         &mut self.attributes
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attributes::Observe;
+    use crate::output::map::StatsMapScope;
+    use crate::output::void::Void;
+    use crate::testing::FlakyScope;
+
+    #[test]
+    fn concurrent_flush_isolates_failures() {
+        let map = StatsMapScope::default();
+        let gauge = map.gauge("observed");
+        map.observe(gauge, |_| 4).on_flush();
+        let multi = MultiInputScope::new()
+            .add_target(FlakyScope::failing())
+            .add_target(map.clone())
+            .concurrent_flush();
+
+        assert!(multi.flush().is_err());
+        // the failing target did not prevent the other from flushing
+        assert_eq!(Some(&4), map.into_map().get("observed"));
+    }
+
+    #[test]
+    fn flush_all_targets() {
+        let map = StatsMapScope::default();
+        let gauge = map.gauge("observed");
+        map.observe(gauge, |_| 4).on_flush();
+        let multi = MultiInputScope::new()
+            .add_target(FlakyScope::failing())
+            .add_target(map.clone())
+            .add_target(FlakyScope::failing());
+
+        let err = multi.flush().unwrap_err();
+        let err = err
+            .get_ref()
+            .and_then(|e| e.downcast_ref::<MultiFlushError>())
+            .unwrap();
+        assert_eq!(2, err.errors().len());
+        assert_eq!(3, err.targets());
+        assert_eq!(
+            "2 of 3 targets failed to flush: failing; failing",
+            err.to_string()
+        );
+        // the failing targets did not prevent the other from flushing
+        assert_eq!(Some(&4), map.into_map().get("observed"));
+    }
+
+    #[test]
+    fn sampled_target() {
+        let (all, mirror, none) = (Void::counting(), Void::counting(), Void::counting());
+        let multi = MultiInputScope::new()
+            .add_target(all.metrics())
+            .add_target_sampled(mirror.metrics(), 0.1)
+            .add_target_sampled(none.metrics(), 0.0);
+
+        let marker = multi.marker("hits");
+        for _ in 0..10_000 {
+            marker.mark();
+        }
+
+        assert_eq!(10_000, all.discarded(InputKind::Marker));
+        let mirrored = mirror.discarded(InputKind::Marker);
+        assert!((700..1300).contains(&mirrored), "{}", mirrored);
+        assert_eq!(0, none.discarded(InputKind::Marker));
+    }
+
+    #[test]
+    fn route_by_kind() {
+        let (graphite, statsd, all) = (
+            StatsMapScope::default(),
+            StatsMapScope::default(),
+            StatsMapScope::default(),
+        );
+        let multi = MultiInputScope::new()
+            .add_target_filtered(graphite.clone(), &[InputKind::Timer])
+            .add_target_filtered(statsd.clone(), &[InputKind::Marker, InputKind::Counter])
+            .add_target(all.clone());
+
+        multi.timer("latency").interval_us(1000);
+        multi.marker("hits").mark();
+        multi.gauge("depth").value(3);
+
+        let graphite = graphite.into_map();
+        let statsd = statsd.into_map();
+        assert_eq!(vec!["latency"], graphite.keys().collect::<Vec<_>>());
+        assert_eq!(vec!["hits"], statsd.keys().collect::<Vec<_>>());
+        assert_eq!(3, all.into_map().len());
+    }
+}
@@ -1,11 +1,14 @@
 use self::LineOp::*;
-use crate::input::InputKind;
+use crate::input::{InputKind, TEXT_LABEL};
+use crate::label::Labels;
 use crate::name::MetricName;
 use crate::MetricValue;
 
+use std::collections::HashSet;
 use std::io;
 use std::io::Write;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 /// Print commands are steps in the execution of output templates.
 pub enum LineOp {
@@ -19,6 +22,12 @@ pub enum LineOp {
     ScaledValueAsText(f64),
     /// Print the newline character.labels.lookup(key)
     NewLine,
+    /// Print the current time, in seconds since the Unix epoch.
+    Timestamp,
+    /// Print metric value as a JSON number, or `null` if it is not finite.
+    ValueAsJson,
+    /// Lookup and print label value for key, or print the fallback if the label does not exist.
+    LabelOrElse(String, Vec<LabelOp>, Vec<u8>),
 }
 
 /// Print commands are steps in the execution of output templates.
@@ -29,6 +38,15 @@ pub enum LabelOp {
     LabelKey,
     /// Print the label value.
     LabelValue,
+    /// Print the label value, escaped for use in a JSON string.
+    JsonLabelValue,
+}
+
+/// A value to print, either an integer or a floating point number.
+#[derive(Debug, Clone, Copy)]
+enum TextValue {
+    Int(MetricValue),
+    Float(f64),
 }
 
 /// An sequence of print commands, embodying an output strategy for a single metric.
@@ -49,28 +67,86 @@ impl LineTemplate {
     }
 
     /// Template execution applies commands in turn, writing to the output.
+    /// Prefer `print_labels()`, which does not copy label values.
     pub fn print<L>(&self, output: &mut dyn Write, value: MetricValue, lookup: L) -> io::Result<()>
     where
         L: Fn(&str) -> Option<Arc<String>>,
+    {
+        self.print_with(
+            output,
+            TextValue::Int(value),
+            |key, print_label| match lookup(key) {
+                Some(label_value) => print_label(&label_value),
+                None => Ok(()),
+            },
+        )
+    }
+
+    /// Template execution applies commands in turn, writing to the output.
+    /// Label values are borrowed from where they are stored, nothing is allocated.
+    pub fn print_labels(
+        &self,
+        output: &mut dyn Write,
+        value: MetricValue,
+        labels: &Labels,
+    ) -> io::Result<()> {
+        self.print_with(output, TextValue::Int(value), |key, print_label| {
+            labels.lookup_with(key, print_label).unwrap_or(Ok(()))
+        })
+    }
+
+    /// Same as `print_labels()`, for a floating point value.
+    pub fn print_f64_labels(
+        &self,
+        output: &mut dyn Write,
+        value: f64,
+        labels: &Labels,
+    ) -> io::Result<()> {
+        self.print_with(output, TextValue::Float(value), |key, print_label| {
+            labels.lookup_with(key, print_label).unwrap_or(Ok(()))
+        })
+    }
+
+    /// The lookup function prints a label's value using the provided function, if the label exists.
+    fn print_with<L>(&self, output: &mut dyn Write, value: TextValue, lookup: L) -> io::Result<()>
+    where
+        L: Fn(&str, &mut dyn FnMut(&str) -> io::Result<()>) -> io::Result<()>,
     {
         for cmd in &self.ops {
             match cmd {
                 Literal(src) => output.write_all(src.as_ref())?,
-                ValueAsText => output.write_all(format!("{}", value).as_ref())?,
+                ValueAsText => match value {
+                    TextValue::Int(value) => write!(output, "{}", value)?,
+                    TextValue::Float(value) => write!(output, "{}", value)?,
+                },
                 ScaledValueAsText(scale) => {
-                    let scaled = value as f64 / scale;
-                    output.write_all(format!("{}", scaled).as_ref())?
+                    let scaled = match value {
+                        TextValue::Int(value) => value as f64 / scale,
+                        TextValue::Float(value) => value / scale,
+                    };
+                    write!(output, "{}", scaled)?
                 }
                 NewLine => writeln!(output)?,
-                LabelExists(label_key, print_label) => {
-                    if let Some(label_value) = lookup(label_key.as_ref()) {
-                        for label_cmd in print_label {
-                            match label_cmd {
-                                LabelOp::LabelValue => output.write_all(label_value.as_bytes())?,
-                                LabelOp::LabelKey => output.write_all(label_key.as_bytes())?,
-                                LabelOp::Literal(src) => output.write_all(src.as_ref())?,
-                            }
-                        }
+                ValueAsJson => match value {
+                    TextValue::Int(value) => write!(output, "{}", value)?,
+                    TextValue::Float(value) if value.is_finite() => write!(output, "{}", value)?,
+                    TextValue::Float(_) => output.write_all(b"null")?,
+                },
+                Timestamp => {
+                    let now = SystemTime::now().duration_since(UNIX_EPOCH);
+                    write!(output, "{}", now.map_or(0, |now| now.as_secs()))?
+                }
+                LabelExists(label_key, print_label) => lookup(label_key, &mut |label_value| {
+                    print_label_ops(output, label_key, label_value, print_label)
+                })?,
+                LabelOrElse(label_key, print_label, fallback) => {
+                    let mut found = false;
+                    lookup(label_key, &mut |label_value| {
+                        found = true;
+                        print_label_ops(output, label_key, label_value, print_label)
+                    })?;
+                    if !found {
+                        output.write_all(fallback)?
                     }
                 }
             };
@@ -79,6 +155,23 @@ impl LineTemplate {
     }
 }
 
+fn print_label_ops(
+    output: &mut dyn Write,
+    label_key: &str,
+    label_value: &str,
+    print_label: &[LabelOp],
+) -> io::Result<()> {
+    for label_cmd in print_label {
+        match label_cmd {
+            LabelOp::LabelValue => output.write_all(label_value.as_bytes())?,
+            LabelOp::JsonLabelValue => write_json_escaped(output, label_value)?,
+            LabelOp::LabelKey => output.write_all(label_key.as_bytes())?,
+            LabelOp::Literal(src) => output.write_all(src.as_ref())?,
+        }
+    }
+    Ok(())
+}
+
 /// Format output config support.
 pub trait Formatting {
     /// Specify formatting of output.
@@ -95,19 +188,234 @@ pub trait LineFormat: Send + Sync {
 #[derive(Default)]
 pub struct SimpleFormat {
     // TODO make separator configurable
-// separator: String,
+    // separator: String,
 }
 
 impl LineFormat for SimpleFormat {
-    fn template(&self, name: &MetricName, _kind: InputKind) -> LineTemplate {
+    fn template(&self, name: &MetricName, kind: InputKind) -> LineTemplate {
         let mut header = name.join(".");
         header.push(' ');
+        let value = match kind {
+            // text annotations print their text instead of their constant value
+            InputKind::Text => LabelExists(TEXT_LABEL.to_string(), vec![LabelOp::LabelValue]),
+            _ => ValueAsText,
+        };
         LineTemplate {
-            ops: vec![Literal(header.into_bytes()), ValueAsText, NewLine],
+            ops: vec![Literal(header.into_bytes()), value, NewLine],
+        }
+    }
+}
+
+/// A part of a format template.
+#[derive(Debug, Clone)]
+enum TemplatePart {
+    Literal(String),
+    Name,
+    Value,
+    Label(String),
+    Timestamp,
+}
+
+/// A metric output format compiled from a template string, e.g. `{name} {value} {label:host} {ts}`,
+/// saving the need to implement `LineFormat` for simple custom formats.
+/// `{name}` prints the metric name, `{value}` its value (or the text of a text annotation),
+/// `{label:<key>}` the value of the label if it is set, and `{ts}` the time in seconds since the Unix epoch.
+/// Literal braces are written `{{` and `}}`. Each line ends with a newline.
+#[derive(Debug, Clone)]
+pub struct TemplateFormat {
+    parts: Vec<TemplatePart>,
+}
+
+impl TemplateFormat {
+    /// Compile a template string. Fails if a placeholder is unknown or not closed.
+    pub fn parse(template: &str) -> io::Result<TemplateFormat> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+        while let Some(brace) = rest.find(['{', '}']) {
+            literal.push_str(&rest[..brace]);
+            rest = &rest[brace..];
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                literal.push_str(&rest[..1]);
+                rest = &rest[2..];
+                continue;
+            }
+            if rest.starts_with('}') {
+                return Err(invalid(format!(
+                    "Unmatched '}}' in format template '{}'",
+                    template
+                )));
+            }
+            let close = rest.find('}').ok_or_else(|| {
+                invalid(format!("Unclosed '{{' in format template '{}'", template))
+            })?;
+            let part = match &rest[1..close] {
+                "name" => TemplatePart::Name,
+                "value" => TemplatePart::Value,
+                "ts" => TemplatePart::Timestamp,
+                placeholder => match placeholder.strip_prefix("label:") {
+                    Some(key) if !key.is_empty() => TemplatePart::Label(key.to_string()),
+                    _ => {
+                        return Err(invalid(format!(
+                            "Unknown placeholder '{{{}}}' in format template '{}'",
+                            placeholder, template
+                        )))
+                    }
+                },
+            };
+            if !literal.is_empty() {
+                parts.push(TemplatePart::Literal(std::mem::take(&mut literal)));
+            }
+            parts.push(part);
+            rest = &rest[close + 1..];
         }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            parts.push(TemplatePart::Literal(literal));
+        }
+        Ok(TemplateFormat { parts })
     }
 }
 
+impl LineFormat for TemplateFormat {
+    fn template(&self, name: &MetricName, kind: InputKind) -> LineTemplate {
+        let mut ops: Vec<LineOp> = self
+            .parts
+            .iter()
+            .map(|part| match part {
+                TemplatePart::Literal(literal) => Literal(literal.clone().into_bytes()),
+                TemplatePart::Name => Literal(name.join(".").into_bytes()),
+                // text annotations print their text instead of their constant value
+                TemplatePart::Value if kind == InputKind::Text => {
+                    LabelExists(TEXT_LABEL.to_string(), vec![LabelOp::LabelValue])
+                }
+                TemplatePart::Value => ValueAsText,
+                TemplatePart::Label(key) => LabelExists(key.clone(), vec![LabelOp::LabelValue]),
+                TemplatePart::Timestamp => Timestamp,
+            })
+            .collect();
+        ops.push(NewLine);
+        LineTemplate { ops }
+    }
+}
+
+/// A metric output format of one JSON object per line, for JSON log pipelines, e.g.
+/// `{"name":"requests","kind":"counter","value":12,"host":"web1"}`.
+/// Selected labels are added as fields of the object, if they are set.
+/// Labels named `name`, `kind` or `value` are added as `label_name`, `label_kind` or `label_value`,
+/// prefixed with `label_` again as long as the field would collide with another, e.g. `label_label_name`.
+/// Text annotations have their text as value, values missing or not finite are `null`.
+#[derive(Default, Clone)]
+pub struct JsonFormat {
+    labels: Vec<String>,
+}
+
+impl JsonFormat {
+    /// A JSON format without labels.
+    pub fn new() -> Self {
+        JsonFormat::default()
+    }
+
+    /// Returns a clone of the format adding the label as a field of each object, if the label is set.
+    pub fn label(&self, key: &str) -> Self {
+        let mut cloned = self.clone();
+        if !cloned.labels.iter().any(|label| label == key) {
+            cloned.labels.push(key.to_string());
+        }
+        cloned
+    }
+}
+
+impl LineFormat for JsonFormat {
+    fn template(&self, name: &MetricName, kind: InputKind) -> LineTemplate {
+        let header = format!(
+            "{{\"name\":\"{}\",\"kind\":\"{}\",\"value\":",
+            json_escape(&name.join(".")),
+            json_escape(json_kind(kind))
+        );
+        let mut ops = vec![Literal(header.into_bytes())];
+        match kind {
+            InputKind::Text => ops.push(LabelOrElse(
+                TEXT_LABEL.to_string(),
+                vec![
+                    LabelOp::Literal(b"\"".to_vec()),
+                    LabelOp::JsonLabelValue,
+                    LabelOp::Literal(b"\"".to_vec()),
+                ],
+                b"null".to_vec(),
+            )),
+            _ => ops.push(ValueAsJson),
+        }
+        // duplicate fields would shadow the fields of the metric or each other
+        let mut fields: HashSet<String> = ["name", "kind", "value"]
+            .iter()
+            .map(|field| field.to_string())
+            .collect();
+        for key in &self.labels {
+            let mut field = key.clone();
+            while fields.contains(&field) {
+                field = format!("label_{}", field);
+            }
+            let field_op = format!(",\"{}\":\"", json_escape(&field));
+            fields.insert(field);
+            ops.push(LabelExists(
+                key.clone(),
+                vec![
+                    LabelOp::Literal(field_op.into_bytes()),
+                    LabelOp::JsonLabelValue,
+                    LabelOp::Literal(b"\"".to_vec()),
+                ],
+            ));
+        }
+        ops.push(Literal(b"}".to_vec()));
+        ops.push(NewLine);
+        LineTemplate { ops }
+    }
+}
+
+fn json_kind(kind: InputKind) -> &'static str {
+    match kind {
+        InputKind::Marker => "marker",
+        InputKind::Counter => "counter",
+        InputKind::Level => "level",
+        InputKind::Gauge => "gauge",
+        InputKind::Timer => "timer",
+        InputKind::Set => "set",
+        InputKind::Text => "text",
+        InputKind::Custom(kind) => kind,
+    }
+}
+
+/// Escape quotes, backslashes and control characters for use in a JSON string.
+fn json_escape(text: &str) -> String {
+    let mut escaped = Vec::with_capacity(text.len());
+    // writing to a vector can not fail
+    let _ = write_json_escaped(&mut escaped, text);
+    String::from_utf8(escaped).expect("escaped text is UTF-8")
+}
+
+/// Write the text escaped for use in a JSON string, copying runs of characters without escapes as is.
+fn write_json_escaped(output: &mut dyn Write, text: &str) -> io::Result<()> {
+    let mut unescaped = 0;
+    for (i, c) in text.char_indices() {
+        if !matches!(c, '"' | '\\') && !c.is_control() {
+            continue;
+        }
+        output.write_all(&text.as_bytes()[unescaped..i])?;
+        match c {
+            '"' => output.write_all(b"\\\"")?,
+            '\\' => output.write_all(b"\\\\")?,
+            '\n' => output.write_all(b"\\n")?,
+            '\r' => output.write_all(b"\\r")?,
+            '\t' => output.write_all(b"\\t")?,
+            c => write!(output, "\\u{:04x}", c as u32)?,
+        }
+        unescaped = i + c.len_utf8();
+    }
+    output.write_all(&text.as_bytes()[unescaped..])
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
@@ -159,6 +467,168 @@ pub mod test {
         );
     }
 
+    #[test]
+    fn print_borrowed_label() {
+        let labels: Labels = labels!("test_key" => "456");
+        let format = TestFormat {};
+        let template = format.template(&MetricName::from("abc"), InputKind::Counter);
+        let mut out = vec![];
+        template.print_labels(&mut out, 123000, &labels).unwrap();
+        assert_eq!(
+            "Counter/abc 123000 123 test_key=456\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn print_float_value() {
+        let labels: Labels = labels!("test_key" => "456");
+        let format = TestFormat {};
+        let template = format.template(&MetricName::from("abc"), InputKind::Gauge);
+        let mut out = vec![];
+        template.print_f64_labels(&mut out, 0.37, &labels).unwrap();
+        assert_eq!(
+            "Gauge/abc 0.37 0.00037 test_key=456\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn print_text() {
+        let labels: Labels = labels!(TEXT_LABEL => "1.2.3");
+        let template = SimpleFormat::default().template(&"version".into(), InputKind::Text);
+        let mut out = vec![];
+        template.print_labels(&mut out, 1, &labels).unwrap();
+        assert_eq!("version 1.2.3\n", String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn print_json() {
+        let format = JsonFormat::new().label("host").label("zone");
+        let template = format.template(&"requests".into(), InputKind::Counter);
+        let mut out = vec![];
+        let labels: Labels = labels!("host" => "web \"1\"");
+        template.print_labels(&mut out, 12, &labels).unwrap();
+        assert_eq!(
+            "{\"name\":\"requests\",\"kind\":\"counter\",\"value\":12,\"host\":\"web \\\"1\\\"\"}\n",
+            String::from_utf8(out).unwrap()
+        );
+
+        let template = format.template(&"version".into(), InputKind::Text);
+        let mut out = vec![];
+        let labels: Labels = labels!(TEXT_LABEL => "1.2.3");
+        template.print_labels(&mut out, 1, &labels).unwrap();
+        assert_eq!(
+            "{\"name\":\"version\",\"kind\":\"text\",\"value\":\"1.2.3\"}\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn print_json_text_without_label() {
+        let template = JsonFormat::new().template(&"version".into(), InputKind::Text);
+        let mut out = vec![];
+        template.print_labels(&mut out, 1, &labels![]).unwrap();
+        assert_eq!(
+            "{\"name\":\"version\",\"kind\":\"text\",\"value\":null}\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn print_json_non_finite() {
+        let template = JsonFormat::new().template(&"ratio".into(), InputKind::Gauge);
+        for value in &[f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let mut out = vec![];
+            template
+                .print_f64_labels(&mut out, *value, &labels![])
+                .unwrap();
+            assert_eq!(
+                "{\"name\":\"ratio\",\"kind\":\"gauge\",\"value\":null}\n",
+                String::from_utf8(out).unwrap()
+            );
+        }
+        let mut out = vec![];
+        template
+            .print_f64_labels(&mut out, 0.5, &labels![])
+            .unwrap();
+        assert_eq!(
+            "{\"name\":\"ratio\",\"kind\":\"gauge\",\"value\":0.5}\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn print_json_custom_kind() {
+        let template = JsonFormat::new().template(&"hits".into(), InputKind::Custom("a\"b"));
+        let mut out = vec![];
+        template.print_labels(&mut out, 1, &labels![]).unwrap();
+        assert_eq!(
+            "{\"name\":\"hits\",\"kind\":\"a\\\"b\",\"value\":1}\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn print_json_reserved_label() {
+        let format = JsonFormat::new().label("name").label("value").label("name");
+        let template = format.template(&"requests".into(), InputKind::Counter);
+        let mut out = vec![];
+        let labels: Labels = labels!("name" => "web", "value" => "x");
+        template.print_labels(&mut out, 12, &labels).unwrap();
+        assert_eq!(
+            "{\"name\":\"requests\",\"kind\":\"counter\",\"value\":12,\"label_name\":\"web\",\"label_value\":\"x\"}\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn print_json_colliding_labels() {
+        let format = JsonFormat::new().label("label_name").label("name");
+        let template = format.template(&"requests".into(), InputKind::Counter);
+        let mut out = vec![];
+        let labels: Labels = labels!("name" => "web", "label_name" => "x");
+        template.print_labels(&mut out, 12, &labels).unwrap();
+        assert_eq!(
+            "{\"name\":\"requests\",\"kind\":\"counter\",\"value\":12,\"label_name\":\"x\",\"label_label_name\":\"web\"}\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn json_escaped_in_place() {
+        let mut out = vec![];
+        write_json_escaped(&mut out, "é\"a\\b\n\u{1}\u{85}z").unwrap();
+        assert_eq!(
+            "é\\\"a\\\\b\\n\\u0001\\u0085z",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn print_template_format() {
+        let format = TemplateFormat::parse("{{{name}}} {value} {label:host}{label:zone}").unwrap();
+        let template = format.template(&"requests".into(), InputKind::Counter);
+        let mut out = vec![];
+        let labels: Labels = labels!("host" => "web1");
+        template.print_labels(&mut out, 12, &labels).unwrap();
+        assert_eq!("{requests} 12 web1\n", String::from_utf8(out).unwrap());
+
+        let format = TemplateFormat::parse("{ts} {name}").unwrap();
+        let template = format.template(&"requests".into(), InputKind::Counter);
+        let mut out = vec![];
+        template.print_labels(&mut out, 12, &labels![]).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let (ts, name) = out.split_once(' ').unwrap();
+        assert!(ts.parse::<u64>().unwrap() > 0);
+        assert_eq!("requests\n", name);
+
+        assert!(TemplateFormat::parse("{name").is_err());
+        assert!(TemplateFormat::parse("{unknown}").is_err());
+        assert!(TemplateFormat::parse("{label:}").is_err());
+        assert!(TemplateFormat::parse("name}").is_err());
+    }
+
     #[test]
     fn print_label_not_exists() {
         let format = TestFormat {};
@@ -1,19 +1,27 @@
 //! Send metrics to a graphite server.
 
-use crate::attributes::{Attributes, Buffered, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::attributes::{
+    Attributes, Buffered, FilterLabels, FlushTally, LabelStrategy, MetricId, OnFlush, Prefixed,
+    PreviewBatches, RenderLabels, ReportFlush, ScaledTimers, TimeUnit, WithAttributes,
+};
 use crate::input::InputKind;
 use crate::input::{Input, InputMetric, InputScope};
+use crate::label::Labels;
 use crate::metrics;
 use crate::name::MetricName;
+use crate::output::retry::{
+    RetryQueue, SendError, DEFAULT_RETRY_BACKOFF, DEFAULT_RETRY_QUEUE_BYTES,
+};
+use crate::output::scratch::with_scratch;
 use crate::output::socket::RetrySocket;
+use crate::Flush;
 use crate::{CachedInput, QueuedInput};
-use crate::{Flush, MetricValue};
 
 use std::net::ToSocketAddrs;
 
-use std::fmt::Debug;
+use std::fmt::{Debug, Write as _};
 use std::io::Write;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use std::sync::Arc;
 
@@ -30,6 +38,8 @@ use std::io;
 pub struct Graphite {
     attributes: Attributes,
     socket: Arc<RwLock<RetrySocket>>,
+    retry_bytes: usize,
+    retry_backoff: Duration,
 }
 
 impl Input for Graphite {
@@ -38,8 +48,14 @@ impl Input for Graphite {
     fn metrics(&self) -> Self::SCOPE {
         GraphiteScope {
             attributes: self.attributes.clone(),
-            buffer: Arc::new(RwLock::new(String::new())),
+            buffer: Arc::new(RwLock::new(String::with_capacity(BUFFER_INITIAL_CAPACITY))),
             socket: self.socket.clone(),
+            tally: Arc::new(FlushTally::default()),
+            retry: Arc::new(RwLock::new(RetryQueue::new(
+                self.retry_bytes,
+                self.retry_backoff,
+                metrics::GRAPHITE_RETRY_DROPPED.clone(),
+            ))),
         }
     }
 }
@@ -53,8 +69,20 @@ impl Graphite {
         Ok(Graphite {
             attributes: Attributes::default(),
             socket,
+            retry_bytes: DEFAULT_RETRY_QUEUE_BYTES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
         })
     }
+
+    /// Return a clone keeping up to `max_bytes` of metrics that could not be sent, per scope,
+    /// retried on subsequent flushes after waiting `backoff`, doubled after every following failure.
+    /// Beyond the limit, the oldest metrics are dropped. A limit of zero drops failed metrics right away.
+    pub fn retry_queue(&self, max_bytes: usize, backoff: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.retry_bytes = max_bytes;
+        cloned.retry_backoff = backoff;
+        cloned
+    }
 }
 
 impl WithAttributes for Graphite {
@@ -67,6 +95,11 @@ impl WithAttributes for Graphite {
 }
 
 impl Buffered for Graphite {}
+impl ScaledTimers for Graphite {}
+impl FilterLabels for Graphite {}
+impl RenderLabels for Graphite {}
+impl ReportFlush for Graphite {}
+impl PreviewBatches for Graphite {}
 
 /// Graphite Input
 #[derive(Debug, Clone)]
@@ -74,53 +107,146 @@ pub struct GraphiteScope {
     attributes: Attributes,
     buffer: Arc<RwLock<String>>,
     socket: Arc<RwLock<RetrySocket>>,
+    tally: Arc<FlushTally>,
+    retry: Arc<RwLock<RetryQueue>>,
 }
 
 impl InputScope for GraphiteScope {
     /// Define a metric of the specified type.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
-        let mut prefix = self.prefix_prepend(name.clone()).join(".");
-        prefix.push(' ');
+        if kind == InputKind::Text {
+            // graphite has no string values
+            return InputMetric::new(MetricId::forge("graphite", name), |_value, _labels| {});
+        }
+        let base_name = self.prefix_prepend(name.clone()).join(".");
+        let prefix = format!("{} ", base_name);
 
-        let scale = match kind {
-            // timers are in µs, but we give graphite milliseconds
-            InputKind::Timer => 1000,
-            _ => 1,
+        let unit = match kind {
+            // timers are in µs, but we give graphite milliseconds by default
+            InputKind::Timer => Some(self.get_timer_unit()),
+            _ => None,
         };
 
         let cloned = self.clone();
-        let metric = GraphiteMetric { prefix, scale };
+        let metric = GraphiteMetric {
+            name: base_name,
+            prefix,
+            unit,
+            labels: self.get_label_strategy(),
+        };
         let metric_id = MetricId::forge("graphite", name);
 
-        InputMetric::new(metric_id, move |value, _labels| {
-            cloned.print(&metric, value);
-        })
+        let metric = Arc::new(metric);
+        let float_metric = metric.clone();
+        let float_cloned = self.clone();
+        let timestamped_metric = metric.clone();
+        let timestamped_cloned = self.clone();
+        let weighted_metric = metric.clone();
+        let weighted_cloned = self.clone();
+        self.attributes.filter_metric(
+            InputMetric::new(metric_id, move |value, labels| {
+                let scaled_value = match metric.unit {
+                    Some(unit) => unit.scale(value),
+                    None => value,
+                };
+                let value_text = |text: &mut String| {
+                    let _ = write!(text, "{}", scaled_value);
+                };
+                cloned.print(&metric, &value_text, labels, SystemTime::now())
+            })
+            .with_float(move |value, labels| {
+                let scaled_value = match float_metric.unit {
+                    Some(unit) => unit.scale_f64(value),
+                    None => value,
+                };
+                let value_text = |text: &mut String| {
+                    let _ = write!(text, "{}", scaled_value);
+                };
+                float_cloned.print(&float_metric, &value_text, labels, SystemTime::now())
+            })
+            .with_timestamped(move |timestamp, value, labels| {
+                let scaled_value = match timestamped_metric.unit {
+                    Some(unit) => unit.scale(value),
+                    None => value,
+                };
+                let value_text = |text: &mut String| {
+                    let _ = write!(text, "{}", scaled_value);
+                };
+                timestamped_cloned.print(&timestamped_metric, &value_text, labels, timestamp)
+            })
+            .with_weighted(move |value, weight, labels| {
+                if weight == 0 {
+                    return;
+                }
+                // a datapoint replaces any other of the same second, counts are summed up front
+                let scaled_value = match (kind, weighted_metric.unit) {
+                    (InputKind::Marker | InputKind::Counter | InputKind::Level, _) => {
+                        value.saturating_mul(weight as isize)
+                    }
+                    (_, Some(unit)) => unit.scale(value),
+                    (_, None) => value,
+                };
+                let value_text = |text: &mut String| {
+                    let _ = write!(text, "{}", scaled_value);
+                };
+                weighted_cloned.print(&weighted_metric, &value_text, labels, SystemTime::now())
+            }),
+        )
     }
 }
 
 impl Flush for GraphiteScope {
     fn flush(&self) -> io::Result<()> {
         self.notify_flush_listeners();
+        let start = Instant::now();
         let buf = write_lock!(self.buffer);
-        self.flush_inner(buf)
+        let result = self.flush_inner(buf);
+        self.attributes.report_flush("graphite", start, &self.tally);
+        result
     }
 }
 
 impl GraphiteScope {
-    fn print(&self, metric: &GraphiteMetric, value: MetricValue) {
-        let scaled_value = value / metric.scale;
-        let value_str = scaled_value.to_string();
+    /// Format the value once, in a reused scratch buffer, however many lines it is written to.
+    fn print(
+        &self,
+        metric: &GraphiteMetric,
+        value: &dyn Fn(&mut String),
+        labels: Labels,
+        time: SystemTime,
+    ) {
+        with_scratch(|value_text| {
+            value(value_text);
+            self.print_lines(metric, value_text, labels, time)
+        })
+    }
 
-        let start = SystemTime::now();
+    fn print_lines(&self, metric: &GraphiteMetric, value: &str, labels: Labels, time: SystemTime) {
+        // prefix ends with a space
+        let folded = match metric.labels {
+            LabelStrategy::Drop => None,
+            LabelStrategy::Interpolate => {
+                labels.interpolate_name(&metric.name).map(|name| name + " ")
+            }
+            _ => labels
+                .fold_into_name(&metric.name, '.')
+                .map(|name| name + " "),
+        };
 
         let mut buffer = write_lock!(self.buffer);
-        match start.duration_since(UNIX_EPOCH) {
+        match time.duration_since(UNIX_EPOCH) {
             Ok(timestamp) => {
-                buffer.push_str(&metric.prefix);
-                buffer.push_str(&value_str);
-                buffer.push(' ');
-                buffer.push_str(&timestamp.as_secs().to_string());
-                buffer.push('\n');
+                let timestamp = timestamp.as_secs();
+                let plain = match (&folded, metric.labels) {
+                    (Some(_), LabelStrategy::FoldIntoName | LabelStrategy::Interpolate) => None,
+                    _ => Some(&metric.prefix),
+                };
+                for prefix in plain.into_iter().chain(folded.as_ref()) {
+                    buffer.push_str(prefix);
+                    // only the timestamp is formatted, the rest of the line is precomputed
+                    buffer.push_str(value);
+                    let _ = writeln!(buffer, " {}", timestamp);
+                }
 
                 if buffer.len() > BUFFER_FLUSH_THRESHOLD {
                     metrics::GRAPHITE_OVERFLOW.mark();
@@ -141,26 +267,55 @@ impl GraphiteScope {
         }
     }
 
+    /// Send the buffer after any previously failed payloads, keeping it for retry if it can not be sent.
     fn flush_inner(&self, mut buf: RwLockWriteGuard<String>) -> io::Result<()> {
-        if buf.is_empty() {
+        let mut retry = write_lock!(self.retry);
+        if buf.is_empty() && retry.pending_bytes() == 0 {
             return Ok(());
         }
 
-        let mut sock = write_lock!(self.socket);
-        match sock.write_all(buf.as_bytes()) {
-            Ok(()) => {
-                metrics::GRAPHITE_SENT_BYTES.count(buf.len());
-                trace!("Sent {} bytes to graphite", buf.len());
-                buf.clear();
-                Ok(())
-            }
-            Err(e) => {
-                metrics::GRAPHITE_SEND_ERR.mark();
-                debug!("Failed to send buffer to graphite: {}", e);
-                Err(e)
+        retry.send(&mut buf, |payload| {
+            self.attributes
+                .preview_batch("graphite", payload.as_bytes());
+            let mut sock = write_lock!(self.socket);
+            match write_lines(&mut *sock, payload) {
+                Ok(()) => {
+                    metrics::GRAPHITE_SENT_BYTES.count(payload.len());
+                    self.tally
+                        .sent(payload.matches('\n').count(), payload.len());
+                    trace!("Sent {} bytes to graphite", payload.len());
+                    Ok(())
+                }
+                Err(e) => {
+                    metrics::GRAPHITE_SEND_ERR.mark();
+                    self.tally.failed();
+                    debug!("Failed to send buffer to graphite: {}", e.error);
+                    Err(e)
+                }
             }
+        })
+    }
+}
+
+/// Write the lines of the payload. Upon failure, the connection is reset and any partially written line lost,
+/// so only whole lines are counted as sent, the rest of the payload is to be sent again.
+fn write_lines(sock: &mut dyn Write, payload: &str) -> Result<(), SendError> {
+    let bytes = payload.as_bytes();
+    let mut written = 0;
+    while written < bytes.len() {
+        match sock.write(&bytes[written..]) {
+            Ok(0) => return Err(unsent(payload, written, io::ErrorKind::WriteZero.into())),
+            Ok(n) => written += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(unsent(payload, written, e)),
         }
     }
+    Ok(())
+}
+
+fn unsent(payload: &str, written: usize, error: io::Error) -> SendError {
+    let sent = payload[..written].rfind('\n').map_or(0, |end| end + 1);
+    SendError { sent, error }
 }
 
 impl WithAttributes for GraphiteScope {
@@ -173,6 +328,11 @@ impl WithAttributes for GraphiteScope {
 }
 
 impl Buffered for GraphiteScope {}
+impl ScaledTimers for GraphiteScope {}
+impl FilterLabels for GraphiteScope {}
+impl RenderLabels for GraphiteScope {}
+impl ReportFlush for GraphiteScope {}
+impl PreviewBatches for GraphiteScope {}
 
 impl QueuedInput for Graphite {}
 impl CachedInput for Graphite {}
@@ -181,11 +341,16 @@ impl CachedInput for Graphite {}
 // TODO make configurable?
 const BUFFER_FLUSH_THRESHOLD: usize = 65_536;
 
+/// Initial capacity of scope buffers, enough for a flush of a hundred metrics without growing.
+const BUFFER_INITIAL_CAPACITY: usize = 8_192;
+
 /// Key of a graphite metric.
 #[derive(Debug, Clone)]
 pub struct GraphiteMetric {
+    name: String,
     prefix: String,
-    scale: isize,
+    unit: Option<TimeUnit>,
+    labels: LabelStrategy,
 }
 
 /// Any remaining buffered data is flushed on Drop.
@@ -197,6 +362,111 @@ impl Drop for GraphiteScope {
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attributes::LabelStrategy;
+    use crate::label::test::TEST_SEQUENCE;
+    use crate::label::AppLabel;
+
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    /// Accepts a few bytes, then fails.
+    struct Cutoff(usize);
+
+    impl Write for Cutoff {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.0 {
+                0 => Err(io::ErrorKind::ConnectionReset.into()),
+                left => {
+                    let n = left.min(buf.len()).min(3);
+                    self.0 -= n;
+                    Ok(n)
+                }
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Flush the scope, then read the lines sent to the listener, without their timestamp.
+    fn sent_lines(listener: &TcpListener, scope: &GraphiteScope, count: usize) -> Vec<String> {
+        scope.flush().unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        BufReader::new(stream)
+            .lines()
+            .take(count)
+            .map(|line| {
+                let line = line.unwrap();
+                line[..line.rfind(' ').unwrap()].to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fold_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let metrics = Graphite::send_to(listener.local_addr().unwrap())
+            .unwrap()
+            .render_labels(LabelStrategy::FoldIntoName)
+            .metrics();
+        let counter = metrics.counter("requests");
+        counter.write(1, labels!["method" => "GET"]);
+        // counts are summed up front
+        counter.write_n(5, 4, labels!["method" => "GET"]);
+
+        assert_eq!(
+            vec!["requests.method.GET 1", "requests.method.GET 20"],
+            sent_lines(&listener, &metrics, 2)
+        );
+    }
+
+    #[test]
+    fn companion_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let metrics = Graphite::send_to(listener.local_addr().unwrap())
+            .unwrap()
+            .render_labels(LabelStrategy::Companion)
+            .metrics();
+        let counter = metrics.counter("requests");
+        counter.write_n(5, 4, labels!["method" => "GET"]);
+        counter.write(1, labels![]);
+
+        // unlabeled values have no companion
+        assert_eq!(
+            vec!["requests 20", "requests.method.GET 20", "requests 1"],
+            sent_lines(&listener, &metrics, 3)
+        );
+    }
+
+    #[test]
+    fn partial_write_sends_whole_lines() {
+        let payload = "a 1 0\nb 2 0\n";
+        assert!(write_lines(&mut Cutoff(100), payload).is_ok());
+
+        // a line and a half were written
+        let e = write_lines(&mut Cutoff(9), payload).unwrap_err();
+        assert_eq!(6, e.sent);
+        assert_eq!(io::ErrorKind::ConnectionReset, e.error.kind());
+
+        let e = write_lines(&mut Cutoff(0), payload).unwrap_err();
+        assert_eq!(0, e.sent);
+    }
+}
+
 #[cfg(feature = "bench")]
 mod bench {
 
@@ -1,4 +1,7 @@
-use crate::attributes::{Attributes, Buffered, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::attributes::{
+    Attributes, Buffered, FilterLabels, FlushTally, MetricId, OnFlush, Prefixed, ReportFlush,
+    WithAttributes,
+};
 use crate::input::{Input, InputKind, InputMetric, InputScope};
 use crate::name::MetricName;
 use crate::output::format::{Formatting, LineFormat, SimpleFormat};
@@ -6,6 +9,7 @@ use crate::Flush;
 use crate::{CachedInput, QueuedInput};
 
 use std::sync::Arc;
+use std::time::Instant;
 
 #[cfg(not(feature = "parking_lot"))]
 use std::sync::RwLock;
@@ -33,6 +37,7 @@ impl Input for Log {
             attributes: self.attributes.clone(),
             entries: Arc::new(RwLock::new(Vec::new())),
             log: self.clone(),
+            tally: Arc::new(FlushTally::default()),
         }
     }
 }
@@ -47,6 +52,8 @@ impl WithAttributes for Log {
 }
 
 impl Buffered for Log {}
+impl ReportFlush for Log {}
+impl FilterLabels for Log {}
 
 impl Formatting for Log {
     fn formatting(&self, format: impl LineFormat + 'static) -> Self {
@@ -62,6 +69,7 @@ pub struct LogScope {
     attributes: Attributes,
     entries: Arc<RwLock<Vec<Vec<u8>>>>,
     log: Log,
+    tally: Arc<FlushTally>,
 }
 
 impl Log {
@@ -103,6 +111,8 @@ impl WithAttributes for LogScope {
 }
 
 impl Buffered for LogScope {}
+impl ReportFlush for LogScope {}
+impl FilterLabels for LogScope {}
 
 impl QueuedInput for Log {}
 impl CachedInput for Log {}
@@ -113,45 +123,57 @@ impl InputScope for LogScope {
         let template = self.log.format.template(&name, kind);
         let entries = self.entries.clone();
 
-        if self.is_buffered() {
+        let write: Arc<dyn Fn(Vec<u8>) + Send + Sync> = if self.is_buffered() {
             // buffered
-            InputMetric::new(MetricId::forge("log", name), move |value, labels| {
-                let mut buffer = Vec::with_capacity(32);
-                match template.print(&mut buffer, value, |key| labels.lookup(key)) {
-                    Ok(()) => {
-                        let mut entries = write_lock!(entries);
-                        entries.push(buffer)
-                    }
-                    Err(err) => debug!("Could not format buffered log metric: {}", err),
-                }
+            Arc::new(move |buffer| {
+                let mut entries = write_lock!(entries);
+                entries.push(buffer)
             })
         } else {
             // unbuffered
             let level = self.log.level;
             let target = self.log.target.clone();
+            let tally = self.tally.clone();
+            Arc::new(move |buffer| {
+                if let Some(target) = &target {
+                    log!(target: target, level, "{:?}", &buffer)
+                } else {
+                    log!(level, "{:?}", &buffer)
+                }
+                tally.sent(1, buffer.len());
+            })
+        };
+
+        let template = Arc::new(template);
+        let float_template = template.clone();
+        let float_write = write.clone();
+        self.attributes.filter_metric(
             InputMetric::new(MetricId::forge("log", name), move |value, labels| {
                 let mut buffer = Vec::with_capacity(32);
-                match template.print(&mut buffer, value, |key| labels.lookup(key)) {
-                    Ok(()) => {
-                        if let Some(target) = &target {
-                            log!(target: target, level, "{:?}", &buffer)
-                        } else {
-                            log!(level, "{:?}", &buffer)
-                        }
-                    }
-                    Err(err) => debug!("Could not format buffered log metric: {}", err),
+                match template.print_labels(&mut buffer, value, &labels) {
+                    Ok(()) => write(buffer),
+                    Err(err) => debug!("Could not format log metric: {}", err),
                 }
             })
-        }
+            .with_float(move |value, labels| {
+                let mut buffer = Vec::with_capacity(32);
+                match float_template.print_f64_labels(&mut buffer, value, &labels) {
+                    Ok(()) => float_write(buffer),
+                    Err(err) => debug!("Could not format log metric: {}", err),
+                }
+            }),
+        )
     }
 }
 
 impl Flush for LogScope {
     fn flush(&self) -> io::Result<()> {
         self.notify_flush_listeners();
+        let start = Instant::now();
         let mut entries = write_lock!(self.entries);
         if !entries.is_empty() {
             let mut buf: Vec<u8> = Vec::with_capacity(32 * entries.len());
+            let series = entries.len();
             for entry in entries.drain(..) {
                 writeln!(&mut buf, "{:?}", &entry)?;
             }
@@ -160,7 +182,9 @@ impl Flush for LogScope {
             } else {
                 log!(self.log.level, "{:?}", &buf)
             }
+            self.tally.sent(series, buf.len());
         }
+        self.attributes.report_flush("log", start, &self.tally);
         Ok(())
     }
 }
@@ -175,7 +199,9 @@ impl Drop for LogScope {
 
 #[cfg(test)]
 mod test {
+    use crate::attributes::{Buffered, Buffering, FilterLabels};
     use crate::input::*;
+    use crate::output::format::{Formatting, TemplateFormat};
 
     #[test]
     fn test_to_log() {
@@ -183,4 +209,19 @@ mod test {
         let m = c.new_metric("test".into(), InputKind::Marker);
         m.write(33, labels![]);
     }
+
+    #[test]
+    fn redacted_labels() {
+        let scope = super::Log::to_log()
+            .formatting(TemplateFormat::parse("{name} {value} {label:user}").unwrap())
+            .transform_labels(&["user"], |_| "redacted".to_string())
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        scope
+            .counter("logins")
+            .write_n(1, 2, labels!("user" => "alice"));
+        let entries = read_lock!(scope.entries);
+        assert_eq!(2, entries.len());
+        assert_eq!(b"logins 1 redacted\n", &entries[0][..]);
+    }
 }
@@ -10,9 +10,17 @@ pub mod log;
 
 pub mod socket;
 
+pub mod scratch;
+
+pub mod retry;
+
 pub mod graphite;
 
 pub mod statsd;
 
 //#[cfg(feature="prometheus")]
 pub mod prometheus;
+
+pub mod protobuf;
+
+pub mod remote_write;
@@ -1,15 +1,22 @@
 //! Send metrics to a Prometheus server.
 
-use crate::attributes::{Attributes, Buffered, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::attributes::{
+    Attributes, Buffered, FilterLabels, FlushTally, MetricId, OnFlush, Prefixed, PreviewBatches,
+    ReportFlush, ScaledTimers, TimeUnit, WithAttributes,
+};
 use crate::input::InputKind;
-use crate::input::{Input, InputMetric, InputScope};
+use crate::input::{Input, InputMetric, InputScope, Timer};
 use crate::label::Labels;
 use crate::metrics;
 use crate::name::MetricName;
 use crate::{CachedInput, QueuedInput};
 use crate::{Flush, MetricValue};
 
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicIsize, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::time::Instant;
 
 #[cfg(not(feature = "parking_lot"))]
 use std::sync::{RwLock, RwLockWriteGuard};
@@ -34,6 +41,8 @@ impl Input for Prometheus {
             attributes: self.attributes.clone(),
             buffer: Arc::new(RwLock::new(String::new())),
             push_url: self.push_url.clone(),
+            tally: Arc::new(FlushTally::default()),
+            histograms: Arc::new(RwLock::new(BTreeMap::new())),
         }
     }
 }
@@ -63,6 +72,10 @@ impl WithAttributes for Prometheus {
 }
 
 impl Buffered for Prometheus {}
+impl ScaledTimers for Prometheus {}
+impl FilterLabels for Prometheus {}
+impl ReportFlush for Prometheus {}
+impl PreviewBatches for Prometheus {}
 
 /// Prometheus Input
 #[derive(Debug, Clone)]
@@ -70,43 +83,87 @@ pub struct PrometheusScope {
     attributes: Attributes,
     buffer: Arc<RwLock<String>>,
     push_url: String,
+    tally: Arc<FlushTally>,
+    histograms: Arc<RwLock<BTreeMap<String, Arc<PrometheusHistogram>>>>,
 }
 
 impl InputScope for PrometheusScope {
     /// Define a metric of the specified type.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
-        let prefix = self.prefix_prepend(name.clone()).join("_");
+        let mut prefix = self.prefix_prepend(name.clone()).join("_");
+        if kind == InputKind::Text {
+            // text annotations are published as an "info" gauge, the text being a label
+            prefix.push_str("_info");
+        }
 
-        let scale = match kind {
-            // timers are in µs, but we give Prometheus milliseconds
-            InputKind::Timer => 1000,
-            _ => 1,
+        let unit = match kind {
+            // timers are in µs, but we give Prometheus milliseconds by default
+            InputKind::Timer => Some(self.get_timer_unit()),
+            _ => None,
         };
 
         let cloned = self.clone();
-        let metric = PrometheusMetric { prefix, scale };
+        let metric = Arc::new(PrometheusMetric { prefix, unit });
 
         let metric_id = MetricId::forge("prometheus", name);
 
-        InputMetric::new(metric_id, move |value, labels| {
-            cloned.print(&metric, value, labels);
-        })
+        let float_metric = metric.clone();
+        let float_cloned = self.clone();
+        self.attributes.filter_metric(
+            InputMetric::new(metric_id, move |value, labels| {
+                let scaled_value = match metric.unit {
+                    Some(unit) => unit.scale(value),
+                    None => value,
+                };
+                cloned.print(&metric, &scaled_value.to_string(), labels);
+            })
+            .with_float(move |value, labels| {
+                let scaled_value = match float_metric.unit {
+                    Some(unit) => unit.scale_f64(value),
+                    None => value,
+                };
+                float_cloned.print(&float_metric, &scaled_value.to_string(), labels);
+            }),
+        )
     }
 }
 
 impl Flush for PrometheusScope {
     fn flush(&self) -> io::Result<()> {
         self.notify_flush_listeners();
-        let buf = write_lock!(self.buffer);
-        self.flush_inner(buf)
+        let start = Instant::now();
+        let mut buf = write_lock!(self.buffer);
+        for (prefix, histogram) in read_lock!(self.histograms).iter() {
+            histogram.print(prefix, &mut buf);
+        }
+        let result = self.flush_inner(buf);
+        self.attributes
+            .report_flush("prometheus", start, &self.tally);
+        result
     }
 }
 
 impl PrometheusScope {
-    fn print(&self, metric: &PrometheusMetric, value: MetricValue, labels: Labels) {
-        let scaled_value = value / metric.scale;
-        let value_str = scaled_value.to_string();
+    /// Define a timer published as a cumulative prometheus histogram,
+    /// with a `_bucket` series for each upper bound (in the scope's timer unit, milliseconds by default)
+    /// plus `_sum` and `_count` series.
+    /// Histogram series are published upon flush, labels are ignored.
+    /// Defining the same timer again replaces its bucket boundaries and resets its counts.
+    pub fn timer_with_buckets(&self, name: &str, buckets: &[MetricValue]) -> Timer {
+        let name: MetricName = name.into();
+        let prefix = self.prefix_prepend(name.clone()).join("_");
+        let histogram = Arc::new(PrometheusHistogram::new(buckets));
+        write_lock!(self.histograms).insert(prefix, histogram.clone());
+
+        let metric_id = MetricId::forge("prometheus", name);
+        let unit = self.get_timer_unit();
+        InputMetric::new(metric_id, move |value, _labels| {
+            histogram.update(unit.scale(value))
+        })
+        .into()
+    }
 
+    fn print(&self, metric: &PrometheusMetric, value_str: &str, labels: Labels) {
         let mut strbuf = String::new();
         // prometheus format be like `http_requests_total{method="post",code="200"} 1027 1395066363000`
         strbuf.push_str(&metric.prefix);
@@ -119,7 +176,7 @@ impl PrometheusScope {
             while let Some((k, v)) = next {
                 strbuf.push_str(&k);
                 strbuf.push_str("=\"");
-                strbuf.push_str(&v);
+                escape_label_value(&v, &mut strbuf);
                 next = i.next();
                 if next.is_some() {
                     strbuf.push_str("\",");
@@ -131,7 +188,7 @@ impl PrometheusScope {
         } else {
             strbuf.push(' ');
         }
-        strbuf.push_str(&value_str);
+        strbuf.push_str(value_str);
         strbuf.push('\n');
 
         let mut buffer = write_lock!(self.buffer);
@@ -159,12 +216,14 @@ impl PrometheusScope {
             return Ok(());
         }
 
+        self.attributes.preview_batch("prometheus", buf.as_bytes());
         match minreq::post(self.push_url.as_str())
             .with_body(buf.as_str())
             .send()
         {
             Ok(http_result) => {
                 metrics::PROMETHEUS_SENT_BYTES.count(buf.len());
+                self.tally.sent(buf.matches('\n').count(), buf.len());
                 trace!(
                     "Sent {} bytes to Prometheus (resp status code: {})",
                     buf.len(),
@@ -175,6 +234,7 @@ impl PrometheusScope {
             }
             Err(e) => {
                 metrics::PROMETHEUS_SEND_ERR.mark();
+                self.tally.failed();
                 debug!("Failed to send buffer to Prometheus: {}", e);
                 Err(io::Error::new(io::ErrorKind::Other, e))
             }
@@ -192,10 +252,26 @@ impl WithAttributes for PrometheusScope {
 }
 
 impl Buffered for PrometheusScope {}
+impl ScaledTimers for PrometheusScope {}
+impl FilterLabels for PrometheusScope {}
+impl ReportFlush for PrometheusScope {}
+impl PreviewBatches for PrometheusScope {}
 
 impl QueuedInput for Prometheus {}
 impl CachedInput for Prometheus {}
 
+/// Escape a label value as required by the Prometheus text exposition format.
+fn escape_label_value(value: &str, buffer: &mut String) {
+    for c in value.chars() {
+        match c {
+            '\\' => buffer.push_str("\\\\"),
+            '"' => buffer.push_str("\\\""),
+            '\n' => buffer.push_str("\\n"),
+            c => buffer.push(c),
+        }
+    }
+}
+
 /// Its hard to see how a single scope could get more metrics than this.
 // TODO make configurable?
 const BUFFER_FLUSH_THRESHOLD: usize = 65_536;
@@ -204,7 +280,57 @@ const BUFFER_FLUSH_THRESHOLD: usize = 65_536;
 #[derive(Debug, Clone)]
 pub struct PrometheusMetric {
     prefix: String,
-    scale: isize,
+    unit: Option<TimeUnit>,
+}
+
+/// Cumulative counts of timer values, by upper bound.
+#[derive(Debug)]
+struct PrometheusHistogram {
+    bounds: Vec<MetricValue>,
+    // one more than bounds, for +Inf
+    counts: Vec<AtomicUsize>,
+    sum: AtomicIsize,
+}
+
+impl PrometheusHistogram {
+    fn new(buckets: &[MetricValue]) -> Self {
+        let mut bounds = buckets.to_vec();
+        bounds.sort_unstable();
+        bounds.dedup();
+        let counts = (0..=bounds.len()).map(|_| AtomicUsize::new(0)).collect();
+        PrometheusHistogram {
+            bounds,
+            counts,
+            sum: AtomicIsize::new(0),
+        }
+    }
+
+    fn update(&self, value: MetricValue) {
+        let bucket = self.bounds.partition_point(|bound| *bound < value);
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Print the histogram in prometheus text format, with cumulative bucket counts.
+    fn print(&self, prefix: &str, buffer: &mut String) {
+        // writing to a String can not fail
+        let _ = writeln!(buffer, "# TYPE {} histogram", prefix);
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let _ = match self.bounds.get(i) {
+                Some(bound) => writeln!(
+                    buffer,
+                    "{}_bucket{{le=\"{}\"}} {}",
+                    prefix, bound, cumulative
+                ),
+                None => writeln!(buffer, "{}_bucket{{le=\"+Inf\"}} {}", prefix, cumulative),
+            };
+        }
+        let sum = self.sum.load(Ordering::Relaxed);
+        let _ = writeln!(buffer, "{}_sum {}", prefix, sum);
+        let _ = writeln!(buffer, "{}_count {}", prefix, cumulative);
+    }
 }
 
 /// Any remaining buffered data is flushed on Drop.
@@ -215,3 +341,66 @@ impl Drop for PrometheusScope {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attributes::Buffering;
+
+    #[test]
+    fn timer_unit() {
+        let scope = Prometheus::push_to("http://localhost:9091/metrics/job/test")
+            .unwrap()
+            .buffered(Buffering::Unlimited)
+            .timer_unit(TimeUnit::Seconds)
+            .metrics();
+        scope.timer("latency").interval_us(2_500_000);
+        scope.counter("count").count(2_500_000);
+        let mut buffer = write_lock!(scope.buffer);
+        // ignore labels, app labels may be set by other tests
+        let values: Vec<&str> = buffer
+            .lines()
+            .filter_map(|l| l.rsplit(' ').next())
+            .collect();
+        assert_eq!(values, ["2", "2500000"]);
+        // nothing to push on drop
+        buffer.clear();
+    }
+
+    #[test]
+    fn text_info_gauge_escaped() {
+        let scope = Prometheus::push_to("http://localhost:9091/metrics/job/test")
+            .unwrap()
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        scope.text("version").set("1.2 \"beta\"\n\\");
+        let mut buffer = write_lock!(scope.buffer);
+        assert!(buffer.starts_with("version_info{"));
+        assert!(buffer.contains("value=\"1.2 \\\"beta\\\"\\n\\\\\""));
+        assert!(buffer.ends_with("} 1\n"));
+        // nothing to push on drop
+        buffer.clear();
+    }
+
+    #[test]
+    fn cumulative_histogram() {
+        let histogram = PrometheusHistogram::new(&[10, 1, 5]);
+        histogram.update(1);
+        histogram.update(3);
+        histogram.update(7);
+        histogram.update(500);
+
+        let mut buffer = String::new();
+        histogram.print("req", &mut buffer);
+        assert_eq!(
+            buffer,
+            "# TYPE req histogram\n\
+             req_bucket{le=\"1\"} 1\n\
+             req_bucket{le=\"5\"} 2\n\
+             req_bucket{le=\"10\"} 3\n\
+             req_bucket{le=\"+Inf\"} 4\n\
+             req_sum 511\n\
+             req_count 4\n"
+        );
+    }
+}
@@ -0,0 +1,305 @@
+//! Render metric values in the Prometheus protobuf exposition format.
+//! Encoded by hand to avoid depending on a protobuf library, only the messages below are supported:
+//!
+//! ```protobuf
+//! message LabelPair { string name = 1; string value = 2; }
+//! message Gauge { double value = 1; }
+//! message Untyped { double value = 1; }
+//! message Metric { repeated LabelPair label = 1; Gauge gauge = 2; Untyped untyped = 5; }
+//! message MetricFamily { string name = 1; MetricType type = 3; repeated Metric metric = 4; }
+//! ```
+
+use crate::attributes::{
+    Attributes, FilterLabels, MetricId, OnFlush, Prefixed, ScaledTimers, WithAttributes,
+};
+use crate::input::{Input, InputKind, InputMetric, InputScope};
+use crate::label::Labels;
+use crate::name::MetricName;
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::mem;
+use std::sync::Arc;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// HTTP content type of the encoded payloads.
+pub const PROTOBUF_CONTENT_TYPE: &str =
+    "application/vnd.google.protobuf; proto=io.prometheus.client.MetricFamily; encoding=delimited";
+
+/// `MetricType` enum values
+const GAUGE: u64 = 1;
+const UNTYPED: u64 = 3;
+
+/// Wire type of integer and enum fields.
+pub const VARINT: u64 = 0;
+/// Wire type of double fields.
+pub const FIXED64: u64 = 1;
+/// Wire type of string and embedded message fields.
+pub const LENGTH_DELIMITED: u64 = 2;
+
+/// Collects metric values to be rendered as length-delimited `io.prometheus.client.MetricFamily` messages.
+/// Typically the target of an `AtomicBucket`'s `flush_to`, for remote-write style integrations
+/// that prefer binary payloads to the text format.
+#[derive(Clone, Default)]
+pub struct Protobuf {
+    attributes: Attributes,
+}
+
+impl Protobuf {
+    /// Protobuf metrics builder.
+    pub fn new() -> Self {
+        Protobuf::default()
+    }
+}
+
+impl WithAttributes for Protobuf {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl ScaledTimers for Protobuf {}
+impl FilterLabels for Protobuf {}
+impl QueuedInput for Protobuf {}
+impl CachedInput for Protobuf {}
+
+impl Input for Protobuf {
+    type SCOPE = ProtobufScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        ProtobufScope {
+            attributes: self.attributes.clone(),
+            families: Arc::new(RwLock::new(BTreeMap::new())),
+        }
+    }
+}
+
+/// Values of a metric family, by label set.
+#[derive(Debug)]
+struct Family {
+    kind: InputKind,
+    samples: BTreeMap<BTreeMap<String, String>, f64>,
+}
+
+/// Collects metric values to be rendered as length-delimited `io.prometheus.client.MetricFamily` messages.
+/// Every received value for a metric and label set replaces the previous one (if any).
+/// Gauges and levels are rendered as `GAUGE` families, other kinds as `UNTYPED`
+/// because their values are not cumulative once aggregated.
+#[derive(Clone, Default)]
+pub struct ProtobufScope {
+    attributes: Attributes,
+    families: Arc<RwLock<BTreeMap<String, Family>>>,
+}
+
+impl WithAttributes for ProtobufScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl ScaledTimers for ProtobufScope {}
+impl FilterLabels for ProtobufScope {}
+
+impl InputScope for ProtobufScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let mut prefix = self.prefix_prepend(name.clone()).join("_");
+        if kind == InputKind::Text {
+            // text annotations are published as an "info" gauge, the text being a label
+            prefix.push_str("_info");
+        }
+        let unit = match kind {
+            InputKind::Timer => Some(self.get_timer_unit()),
+            _ => None,
+        };
+        let metric_id = MetricId::forge("protobuf", name);
+
+        let float_scope = self.clone();
+        let float_prefix = prefix.clone();
+        let cloned = self.clone();
+        self.attributes.filter_metric(
+            InputMetric::new(metric_id, move |value, labels| {
+                let scaled_value = match unit {
+                    Some(unit) => unit.scale(value),
+                    None => value,
+                };
+                cloned.insert(&prefix, kind, scaled_value as f64, labels)
+            })
+            .with_float(move |value, labels| {
+                let scaled_value = match unit {
+                    Some(unit) => unit.scale_f64(value),
+                    None => value,
+                };
+                float_scope.insert(&float_prefix, kind, scaled_value, labels)
+            }),
+        )
+    }
+}
+
+impl Flush for ProtobufScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        Ok(())
+    }
+}
+
+impl ProtobufScope {
+    fn insert(&self, name: &str, kind: InputKind, value: f64, labels: Labels) {
+        let labels = labels
+            .into_map()
+            .into_iter()
+            .map(|(key, value)| (key, value.to_string()))
+            .collect();
+        write_lock!(self.families)
+            .entry(name.to_string())
+            .or_insert_with(|| Family {
+                kind,
+                samples: BTreeMap::new(),
+            })
+            .samples
+            .insert(labels, value);
+    }
+
+    /// Render the values received so far, keeping them.
+    pub fn encode(&self) -> Vec<u8> {
+        encode_families(&read_lock!(self.families))
+    }
+
+    /// Render the values received so far and forget them, e.g. after each bucket flush.
+    pub fn take(&self) -> Vec<u8> {
+        let families = mem::take(&mut *write_lock!(self.families));
+        encode_families(&families)
+    }
+}
+
+fn encode_families(families: &BTreeMap<String, Family>) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (name, family) in families {
+        let message = encode_family(name, family);
+        put_varint(&mut buf, message.len() as u64);
+        buf.extend_from_slice(&message);
+    }
+    buf
+}
+
+fn encode_family(name: &str, family: &Family) -> Vec<u8> {
+    let (metric_type, value_field) = match family.kind {
+        InputKind::Gauge | InputKind::Level | InputKind::Text => (GAUGE, 2),
+        _ => (UNTYPED, 5),
+    };
+    let mut buf = Vec::new();
+    put_bytes(&mut buf, 1, name.as_bytes());
+    put_key(&mut buf, 3, VARINT);
+    put_varint(&mut buf, metric_type);
+    for (labels, value) in &family.samples {
+        let mut metric = Vec::new();
+        for (key, label_value) in labels {
+            let mut pair = Vec::new();
+            put_bytes(&mut pair, 1, key.as_bytes());
+            put_bytes(&mut pair, 2, label_value.as_bytes());
+            put_bytes(&mut metric, 1, &pair);
+        }
+        let mut value_buf = Vec::new();
+        put_key(&mut value_buf, 1, FIXED64);
+        value_buf.extend_from_slice(&value.to_le_bytes());
+        put_bytes(&mut metric, value_field, &value_buf);
+        put_bytes(&mut buf, 4, &metric);
+    }
+    buf
+}
+
+/// Append the key of a field with the specified wire type.
+pub fn put_key(buf: &mut Vec<u8>, field: u64, wire_type: u64) {
+    put_varint(buf, field << 3 | wire_type)
+}
+
+/// Append a length-delimited field, e.g. a string or an embedded message.
+pub fn put_bytes(buf: &mut Vec<u8>, field: u64, bytes: &[u8]) {
+    put_key(buf, field, LENGTH_DELIMITED);
+    put_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+/// Append a base 128 varint.
+pub fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attributes::TimeUnit;
+    use crate::label::test::TEST_SEQUENCE;
+    use crate::label::AppLabel;
+
+    #[test]
+    fn gauge_family() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+        let scope = Protobuf::new().metrics();
+        let gauge = scope.gauge("g");
+        gauge.value(2);
+        gauge.value(1);
+        #[rustfmt::skip]
+        let expected: Vec<u8> = vec![
+            18, // family length
+            0x0a, 1, b'g', // name
+            0x18, 1, // type GAUGE
+            0x22, 11, // metric
+            0x12, 9, // gauge
+            0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f, // value 1.0
+        ];
+        assert_eq!(expected, scope.take());
+        assert!(scope.encode().is_empty());
+    }
+
+    #[test]
+    fn text_info_gauge() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+        let scope = Protobuf::new().metrics();
+        scope.text("version").set("1.2.3");
+        let encoded = scope.encode();
+        let name = b"version_info";
+        assert_eq!(&[0x0a, name.len() as u8][..], &encoded[1..3]);
+        assert_eq!(&name[..], &encoded[3..3 + name.len()]);
+        // type GAUGE
+        assert_eq!(&[0x18, 1][..], &encoded[3 + name.len()..5 + name.len()]);
+    }
+
+    #[test]
+    fn labeled_timer() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+        let scope = Protobuf::new().timer_unit(TimeUnit::Seconds).metrics();
+        scope
+            .new_metric("t".into(), InputKind::Timer)
+            .write(2_000_000, labels!("k" => "v"));
+        #[rustfmt::skip]
+        let expected: Vec<u8> = vec![
+            26, // family length
+            0x0a, 1, b't', // name
+            0x18, 3, // type UNTYPED
+            0x22, 19, // metric
+            0x0a, 6, 0x0a, 1, b'k', 0x12, 1, b'v', // label pair
+            0x2a, 9, // untyped
+            0x09, 0, 0, 0, 0, 0, 0, 0, 0x40, // value 2.0
+        ];
+        assert_eq!(expected, scope.encode());
+    }
+}
@@ -0,0 +1,376 @@
+//! Send metrics to a Prometheus remote write endpoint, e.g. Mimir, Thanos or VictoriaMetrics.
+//! Samples are batched into Snappy-compressed protobuf `WriteRequest`s:
+//!
+//! ```protobuf
+//! message WriteRequest { repeated TimeSeries timeseries = 1; }
+//! message TimeSeries { repeated Label labels = 1; repeated Sample samples = 2; }
+//! message Label { string name = 1; string value = 2; }
+//! message Sample { double value = 1; int64 timestamp = 2; }
+//! ```
+
+use crate::attributes::{
+    Attributes, Buffered, Buffering, FilterLabels, FlushTally, MetricId, OnFlush, Prefixed,
+    ReportFlush, ScaledTimers, WithAttributes,
+};
+use crate::input::{Input, InputKind, InputMetric, InputScope};
+use crate::label::Labels;
+use crate::metrics;
+use crate::name::MetricName;
+use crate::output::protobuf::{put_bytes, put_key, put_varint, FIXED64, VARINT};
+use crate::snappy;
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::collections::BTreeMap;
+use std::io;
+use std::mem;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// Default number of times a failed write request is retried before its samples are dropped.
+pub const DEFAULT_REMOTE_WRITE_RETRIES: usize = 3;
+
+/// Default wait before the first retry, doubled on every following retry.
+pub const DEFAULT_REMOTE_WRITE_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Labels of a series, including its `__name__`, sorted by name.
+type SeriesLabels = BTreeMap<String, String>;
+
+/// Pending values and their timestamp in milliseconds, by series.
+type Samples = BTreeMap<SeriesLabels, Vec<(f64, i64)>>;
+
+/// Remote write Input holds the endpoint URL and retry policy.
+#[derive(Clone, Debug)]
+pub struct RemoteWrite {
+    attributes: Attributes,
+    write_url: String,
+    retries: usize,
+    backoff: Duration,
+}
+
+impl RemoteWrite {
+    /// Send metrics to the remote write endpoint at the URL provided,
+    /// for example `http://mimir.example.org/api/v1/push`.
+    pub fn write_to(url: &str) -> io::Result<RemoteWrite> {
+        debug!("Writing to Prometheus remote {:?}", url);
+
+        Ok(RemoteWrite {
+            attributes: Attributes::default(),
+            write_url: url.to_string(),
+            retries: DEFAULT_REMOTE_WRITE_RETRIES,
+            backoff: DEFAULT_REMOTE_WRITE_BACKOFF,
+        })
+    }
+
+    /// Return a clone retrying failed write requests up to the specified number of times,
+    /// waiting `backoff` before the first retry and twice as long before each of the following ones.
+    /// Requests rejected by the endpoint (4xx responses other than 429) are not retried.
+    pub fn retries(&self, retries: usize, backoff: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.retries = retries;
+        cloned.backoff = backoff;
+        cloned
+    }
+}
+
+impl WithAttributes for RemoteWrite {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Buffered for RemoteWrite {}
+impl ScaledTimers for RemoteWrite {}
+impl FilterLabels for RemoteWrite {}
+impl ReportFlush for RemoteWrite {}
+impl QueuedInput for RemoteWrite {}
+impl CachedInput for RemoteWrite {}
+
+impl Input for RemoteWrite {
+    type SCOPE = RemoteWriteScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        RemoteWriteScope {
+            attributes: self.attributes.clone(),
+            write_url: self.write_url.clone(),
+            retries: self.retries,
+            backoff: self.backoff,
+            series: Arc::new(RwLock::new(BTreeMap::new())),
+            tally: Arc::new(FlushTally::default()),
+        }
+    }
+}
+
+/// Remote write scope, samples are held until flushed.
+/// Unbuffered scopes send a write request on every flush, e.g. for every bucket snapshot.
+/// Scopes buffered with `Buffering::BufferSize(n)` only send once at least `n` samples are pending,
+/// batching several snapshots per request. `Buffering::Unlimited` is treated as unbuffered.
+#[derive(Clone, Debug)]
+pub struct RemoteWriteScope {
+    attributes: Attributes,
+    write_url: String,
+    retries: usize,
+    backoff: Duration,
+    series: Arc<RwLock<Samples>>,
+    tally: Arc<FlushTally>,
+}
+
+impl WithAttributes for RemoteWriteScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Buffered for RemoteWriteScope {}
+impl ScaledTimers for RemoteWriteScope {}
+impl FilterLabels for RemoteWriteScope {}
+impl ReportFlush for RemoteWriteScope {}
+
+impl InputScope for RemoteWriteScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let prefix = self.prefix_prepend(name.clone()).join("_");
+        let unit = match kind {
+            InputKind::Timer => Some(self.get_timer_unit()),
+            _ => None,
+        };
+        let metric_id = MetricId::forge("remote_write", name);
+
+        let float_scope = self.clone();
+        let float_prefix = prefix.clone();
+        let timestamped_scope = self.clone();
+        let timestamped_prefix = prefix.clone();
+        let cloned = self.clone();
+        self.attributes.filter_metric(
+            InputMetric::new(metric_id, move |value, labels| {
+                let scaled_value = match unit {
+                    Some(unit) => unit.scale(value),
+                    None => value,
+                };
+                cloned.push(&prefix, scaled_value as f64, labels, SystemTime::now())
+            })
+            .with_float(move |value, labels| {
+                let scaled_value = match unit {
+                    Some(unit) => unit.scale_f64(value),
+                    None => value,
+                };
+                float_scope.push(&float_prefix, scaled_value, labels, SystemTime::now())
+            })
+            .with_timestamped(move |timestamp, value, labels| {
+                let scaled_value = match unit {
+                    Some(unit) => unit.scale(value),
+                    None => value,
+                };
+                timestamped_scope.push(&timestamped_prefix, scaled_value as f64, labels, timestamp)
+            }),
+        )
+    }
+}
+
+impl Flush for RemoteWriteScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        if let Buffering::BufferSize(size) = self.get_buffering() {
+            if self.pending() < size {
+                return Ok(());
+            }
+        }
+        self.send()
+    }
+}
+
+impl RemoteWriteScope {
+    fn push(&self, name: &str, value: f64, labels: Labels, time: SystemTime) {
+        let mut series: SeriesLabels = labels
+            .into_map()
+            .into_iter()
+            .map(|(key, value)| (key, value.to_string()))
+            .collect();
+        series.insert("__name__".to_string(), name.to_string());
+        let timestamp = time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as i64);
+        write_lock!(self.series)
+            .entry(series)
+            .or_default()
+            .push((value, timestamp));
+    }
+
+    fn pending(&self) -> usize {
+        read_lock!(self.series).values().map(Vec::len).sum()
+    }
+
+    /// Send all pending samples, retrying as configured.
+    fn send(&self) -> io::Result<()> {
+        let start = Instant::now();
+        let series = mem::take(&mut *write_lock!(self.series));
+        if series.is_empty() {
+            return Ok(());
+        }
+        let samples = series.values().map(Vec::len).sum();
+        let body = snappy::compress(&encode_write_request(&series));
+
+        let mut backoff = self.backoff;
+        let mut attempt = 0;
+        let result = loop {
+            match self.post(&body) {
+                Ok(()) => break Ok(()),
+                Err((e, retryable)) if retryable && attempt < self.retries => {
+                    debug!("Remote write failed, retrying in {:?}: {}", backoff, e);
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                Err((e, _)) => break Err(e),
+            }
+        };
+
+        match &result {
+            Ok(()) => {
+                metrics::REMOTE_WRITE_SENT_BYTES.count(body.len());
+                self.tally.sent(samples, body.len());
+                trace!("Sent {} samples to Prometheus remote", samples);
+            }
+            Err(e) => {
+                metrics::REMOTE_WRITE_SEND_ERR.mark();
+                self.tally.failed();
+                debug!("Dropped {} samples, remote write failed: {}", samples, e);
+            }
+        }
+        self.attributes
+            .report_flush("remote_write", start, &self.tally);
+        result
+    }
+
+    /// Post a write request. Errors are returned with whether the request should be retried.
+    fn post(&self, body: &[u8]) -> Result<(), (io::Error, bool)> {
+        let response = minreq::post(self.write_url.as_str())
+            .with_header("Content-Encoding", "snappy")
+            .with_header("Content-Type", "application/x-protobuf")
+            .with_header("X-Prometheus-Remote-Write-Version", "0.1.0")
+            .with_body(body.to_vec())
+            .send()
+            .map_err(|e| (io::Error::other(e), true))?;
+        match response.status_code {
+            200..=299 => Ok(()),
+            status => Err((
+                io::Error::other(format!("remote write endpoint responded {}", status)),
+                status == 429 || status >= 500,
+            )),
+        }
+    }
+}
+
+/// Any pending samples are sent on Drop.
+impl Drop for RemoteWriteScope {
+    fn drop(&mut self) {
+        // scopes are cloned into their metrics, only the last one sends
+        if Arc::strong_count(&self.series) == 1 {
+            if let Err(err) = self.send() {
+                warn!("Could not send remote write samples upon Drop: {}", err)
+            }
+        }
+    }
+}
+
+fn encode_write_request(series: &Samples) -> Vec<u8> {
+    let mut buf = Vec::new();
+    for (labels, samples) in series {
+        let mut timeseries = Vec::new();
+        for (name, value) in labels {
+            let mut label = Vec::new();
+            put_bytes(&mut label, 1, name.as_bytes());
+            put_bytes(&mut label, 2, value.as_bytes());
+            put_bytes(&mut timeseries, 1, &label);
+        }
+        for (value, timestamp) in samples {
+            let mut sample = Vec::new();
+            put_key(&mut sample, 1, FIXED64);
+            sample.extend_from_slice(&value.to_le_bytes());
+            put_key(&mut sample, 2, VARINT);
+            put_varint(&mut sample, *timestamp as u64);
+            put_bytes(&mut timeseries, 2, &sample);
+        }
+        put_bytes(&mut buf, 1, &timeseries);
+    }
+    buf
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn write_request() {
+        let mut series = BTreeMap::new();
+        let mut labels = SeriesLabels::new();
+        labels.insert("__name__".to_string(), "g".to_string());
+        series.insert(labels, vec![(1.0, 2)]);
+        #[rustfmt::skip]
+        let expected: Vec<u8> = vec![
+            0x0a, 28, // timeseries
+            0x0a, 13, 0x0a, 8, b'_', b'_', b'n', b'a', b'm', b'e', b'_', b'_', 0x12, 1, b'g', // label
+            0x12, 11, // sample
+            0x09, 0, 0, 0, 0, 0, 0, 0xf0, 0x3f, // value 1.0
+            0x10, 2, // timestamp
+        ];
+        assert_eq!(expected, encode_write_request(&series));
+    }
+
+    #[test]
+    fn explicit_timestamp() {
+        let scope = RemoteWrite::write_to("http://127.0.0.1:1/api/v1/push")
+            .unwrap()
+            .retries(0, Duration::from_millis(1))
+            .metrics();
+        let gauge = scope.gauge("g");
+        gauge.write_at(UNIX_EPOCH + Duration::from_secs(5), 3, labels![]);
+        let samples: Vec<(f64, i64)> = read_lock!(scope.series)
+            .values()
+            .flatten()
+            .cloned()
+            .collect();
+        assert_eq!(vec![(3.0, 5000)], samples);
+    }
+
+    #[test]
+    fn retry_server_errors() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/api/v1/push", server.local_addr().unwrap());
+        let responder = thread::spawn(move || {
+            let mut requests = 0;
+            for status in &["503 Service Unavailable", "204 No Content"] {
+                let (mut stream, _) = server.accept().unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = stream.read(&mut buf).unwrap();
+                requests += 1;
+                let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+            requests
+        });
+
+        let scope = RemoteWrite::write_to(&url)
+            .unwrap()
+            .retries(1, Duration::from_millis(1))
+            .metrics();
+        scope.counter("count").count(3);
+        scope.flush().unwrap();
+        assert_eq!(2, responder.join().unwrap());
+        assert_eq!(0, scope.pending());
+    }
+}
@@ -0,0 +1,222 @@
+//! Keep formatted payloads that could not be sent, to retry them on subsequent flushes.
+
+use crate::input::Counter;
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+/// Default maximum size of the payloads waiting to be retried, beyond which the oldest ones are dropped.
+/// Holds a number of full graphite buffers or statsd packets.
+pub const DEFAULT_RETRY_QUEUE_BYTES: usize = 1024 * 1024;
+
+/// Default wait before the first retry, doubled after every following failure.
+pub const DEFAULT_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait between retries, however many failures there were.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(300);
+
+/// A payload that could only be sent in part, if at all.
+#[derive(Debug)]
+pub struct SendError {
+    /// Bytes at the start of the payload that need not be sent again, on a char boundary.
+    pub sent: usize,
+    /// Why the rest of the payload could not be sent.
+    pub error: io::Error,
+}
+
+impl From<io::Error> for SendError {
+    fn from(error: io::Error) -> Self {
+        SendError { sent: 0, error }
+    }
+}
+
+/// A bounded queue of payloads that could not be sent, retried in order with exponential backoff.
+#[derive(Debug)]
+pub struct RetryQueue {
+    payloads: VecDeque<String>,
+    bytes: usize,
+    max_bytes: usize,
+    backoff: Duration,
+    failures: u32,
+    next_try: Instant,
+    dropped_bytes: Counter,
+}
+
+impl RetryQueue {
+    /// Keep up to `max_bytes` of failed payloads, counting the bytes of those dropped to make room.
+    pub fn new(max_bytes: usize, backoff: Duration, dropped_bytes: Counter) -> Self {
+        RetryQueue {
+            payloads: VecDeque::new(),
+            bytes: 0,
+            max_bytes,
+            backoff,
+            failures: 0,
+            next_try: Instant::now(),
+            dropped_bytes,
+        }
+    }
+
+    /// Send the payloads waiting to be retried, then the new payload, in order.
+    /// Once sent, the new payload is cleared, keeping its capacity for reuse.
+    /// Upon failure, the part of the payload that was not sent is queued for retry.
+    /// While backing off from a previous failure, the new payload is queued without trying to send anything.
+    pub fn send<F>(&mut self, payload: &mut String, mut send: F) -> io::Result<()>
+    where
+        F: FnMut(&str) -> Result<(), SendError>,
+    {
+        if !self.payloads.is_empty() && Instant::now() < self.next_try {
+            self.enqueue(payload);
+            return Ok(());
+        }
+        while let Some(queued) = self.payloads.front_mut() {
+            if let Err(e) = send(queued) {
+                queued.drain(..e.sent);
+                self.bytes -= e.sent;
+                self.failed();
+                self.enqueue(payload);
+                return Err(e.error);
+            }
+            self.bytes -= queued.len();
+            self.payloads.pop_front();
+        }
+        if payload.is_empty() {
+            self.failures = 0;
+            return Ok(());
+        }
+        match send(payload) {
+            Ok(()) => {
+                self.failures = 0;
+                payload.clear();
+                Ok(())
+            }
+            Err(e) => {
+                payload.drain(..e.sent);
+                self.failed();
+                self.enqueue(payload);
+                Err(e.error)
+            }
+        }
+    }
+
+    /// Bytes of the payloads waiting to be retried.
+    pub fn pending_bytes(&self) -> usize {
+        self.bytes
+    }
+
+    fn failed(&mut self) {
+        let backoff = self
+            .backoff
+            .saturating_mul(1 << self.failures.min(16))
+            .min(MAX_RETRY_BACKOFF);
+        self.failures += 1;
+        self.next_try = Instant::now() + backoff;
+    }
+
+    /// Move the payload to the back of the queue, dropping the oldest payloads beyond the size limit.
+    fn enqueue(&mut self, payload: &mut String) {
+        if !payload.is_empty() {
+            self.bytes += payload.len();
+            self.payloads.push_back(payload.clone());
+            payload.clear();
+        }
+        while self.bytes > self.max_bytes {
+            match self.payloads.pop_front() {
+                Some(dropped) => {
+                    self.bytes -= dropped.len();
+                    self.dropped_bytes.count(dropped.len());
+                    warn!(
+                        "Dropped {} bytes of metrics that could not be sent",
+                        dropped.len()
+                    );
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::InputScope;
+    use crate::output::map::StatsMapScope;
+
+    fn dropped() -> Counter {
+        StatsMapScope::default().counter("dropped")
+    }
+
+    fn failing(_payload: &str) -> Result<(), SendError> {
+        Err(io::Error::from(io::ErrorKind::ConnectionRefused).into())
+    }
+
+    #[test]
+    fn retry_in_order() {
+        let mut queue = RetryQueue::new(1024, Duration::ZERO, dropped());
+        let mut payload = "a\n".to_string();
+        assert!(queue.send(&mut payload, failing).is_err());
+        assert!(payload.is_empty());
+        payload.push_str("b\n");
+        assert!(queue.send(&mut payload, failing).is_err());
+        assert_eq!(4, queue.pending_bytes());
+
+        let mut sent = Vec::new();
+        payload.push_str("c\n");
+        queue
+            .send(&mut payload, |p| {
+                sent.push(p.to_string());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(vec!["a\n", "b\n", "c\n"], sent);
+        assert_eq!(0, queue.pending_bytes());
+    }
+
+    #[test]
+    fn back_off_after_failure() {
+        let mut queue = RetryQueue::new(1024, Duration::from_secs(60), dropped());
+        let mut payload = "a\n".to_string();
+        assert!(queue.send(&mut payload, failing).is_err());
+
+        // not retried until the backoff expires, kept for later meanwhile
+        payload.push_str("b\n");
+        queue.send(&mut payload, |_| panic!("sent")).unwrap();
+        assert!(payload.is_empty());
+        assert_eq!(4, queue.pending_bytes());
+    }
+
+    #[test]
+    fn retry_unsent_part() {
+        let mut queue = RetryQueue::new(1024, Duration::ZERO, dropped());
+        let mut payload = "a\nb\n".to_string();
+        let partial = queue.send(&mut payload, |_| {
+            Err(SendError {
+                sent: 2,
+                error: io::Error::from(io::ErrorKind::BrokenPipe),
+            })
+        });
+        assert!(partial.is_err());
+        assert_eq!(2, queue.pending_bytes());
+
+        let mut sent = Vec::new();
+        queue
+            .send(&mut payload, |p| {
+                sent.push(p.to_string());
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(vec!["b\n"], sent);
+    }
+
+    #[test]
+    fn drop_oldest_beyond_limit() {
+        let map = StatsMapScope::default();
+        let mut queue = RetryQueue::new(5, Duration::ZERO, map.counter("dropped"));
+        let mut payload = "aaa\n".to_string();
+        assert!(queue.send(&mut payload, failing).is_err());
+        payload.push_str("bbb\n");
+        assert!(queue.send(&mut payload, failing).is_err());
+        assert_eq!(4, queue.pending_bytes());
+        assert_eq!(4, map.into_map()["dropped"]);
+    }
+}
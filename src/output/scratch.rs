@@ -0,0 +1,26 @@
+//! Reusable per-thread text buffers, to format values without allocating on every write.
+
+use std::cell::RefCell;
+
+/// Initial capacity of scratch buffers, enough for any formatted number.
+const SCRATCH_CAPACITY: usize = 64;
+
+thread_local! {
+    static SCRATCH: RefCell<String> = RefCell::new(String::with_capacity(SCRATCH_CAPACITY));
+}
+
+/// Run the closure with an empty scratch buffer, reused by subsequent calls on the same thread.
+/// Nested calls, e.g. from outputs writing to other outputs, get a fresh buffer instead.
+pub fn with_scratch<F: FnOnce(&mut String) -> R, R>(f: F) -> R {
+    let mut f = Some(f);
+    let reused = SCRATCH.try_with(|scratch| {
+        scratch.try_borrow_mut().ok().map(|mut scratch| {
+            scratch.clear();
+            (f.take().expect("not called yet"))(&mut scratch)
+        })
+    });
+    match reused {
+        Ok(Some(result)) => result,
+        _ => (f.take().expect("not called yet"))(&mut String::with_capacity(SCRATCH_CAPACITY)),
+    }
+}
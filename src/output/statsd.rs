@@ -1,19 +1,25 @@
 //! Send metrics to a statsd server.
 
 use crate::attributes::{
-    Attributes, Buffered, MetricId, OnFlush, Prefixed, Sampled, Sampling, WithAttributes,
+    Attributes, Buffered, FilterLabels, FlushTally, LabelStrategy, MetricId, OnFlush, Prefixed,
+    PreviewBatches, RenderLabels, ReportFlush, Sampled, Sampling, WithAttributes,
 };
 use crate::input::InputKind;
 use crate::input::{Input, InputMetric, InputScope};
+use crate::label::Labels;
 use crate::metrics;
 use crate::name::MetricName;
+use crate::output::retry::{RetryQueue, DEFAULT_RETRY_BACKOFF, DEFAULT_RETRY_QUEUE_BYTES};
+use crate::output::scratch::with_scratch;
 use crate::pcg32;
+use crate::Flush;
 use crate::{CachedInput, QueuedInput};
-use crate::{Flush, MetricValue};
 
+use std::fmt::{Display, Write};
 use std::net::ToSocketAddrs;
 use std::net::UdpSocket;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[cfg(not(feature = "parking_lot"))]
 use std::sync::{RwLock, RwLockWriteGuard};
@@ -32,6 +38,8 @@ const MAX_UDP_PAYLOAD: usize = 576;
 pub struct Statsd {
     attributes: Attributes,
     socket: Arc<UdpSocket>,
+    retry_bytes: usize,
+    retry_backoff: Duration,
 }
 
 impl Statsd {
@@ -44,12 +52,28 @@ impl Statsd {
         Ok(Statsd {
             attributes: Attributes::default(),
             socket,
+            retry_bytes: DEFAULT_RETRY_QUEUE_BYTES,
+            retry_backoff: DEFAULT_RETRY_BACKOFF,
         })
     }
+
+    /// Return a clone keeping up to `max_bytes` of packets that could not be sent, per scope,
+    /// retried on subsequent flushes after waiting `backoff`, doubled after every following failure.
+    /// Beyond the limit, the oldest packets are dropped. A limit of zero drops failed packets right away.
+    pub fn retry_queue(&self, max_bytes: usize, backoff: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.retry_bytes = max_bytes;
+        cloned.retry_backoff = backoff;
+        cloned
+    }
 }
 
 impl Buffered for Statsd {}
 impl Sampled for Statsd {}
+impl RenderLabels for Statsd {}
+impl FilterLabels for Statsd {}
+impl ReportFlush for Statsd {}
+impl PreviewBatches for Statsd {}
 
 impl QueuedInput for Statsd {}
 impl CachedInput for Statsd {}
@@ -62,6 +86,12 @@ impl Input for Statsd {
             attributes: self.attributes.clone(),
             buffer: Arc::new(RwLock::new(String::with_capacity(MAX_UDP_PAYLOAD))),
             socket: self.socket.clone(),
+            tally: Arc::new(FlushTally::default()),
+            retry: Arc::new(RwLock::new(RetryQueue::new(
+                self.retry_bytes,
+                self.retry_backoff,
+                metrics::STATSD_RETRY_DROPPED.clone(),
+            ))),
         }
     }
 }
@@ -81,15 +111,23 @@ pub struct StatsdScope {
     attributes: Attributes,
     buffer: Arc<RwLock<String>>,
     socket: Arc<UdpSocket>,
+    tally: Arc<FlushTally>,
+    retry: Arc<RwLock<RetryQueue>>,
 }
 
 impl Sampled for StatsdScope {}
+impl RenderLabels for StatsdScope {}
+impl FilterLabels for StatsdScope {}
 
 impl InputScope for StatsdScope {
     /// Define a metric of the specified type.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
-        let mut prefix = self.prefix_prepend(name.clone()).join(".");
-        prefix.push(':');
+        if kind == InputKind::Text {
+            // statsd has no string values
+            return InputMetric::new(MetricId::forge("statsd", name), |_value, _labels| {});
+        }
+        let base_name = self.prefix_prepend(name.clone()).join(".");
+        let prefix = format!("{}:", base_name);
 
         let mut suffix = String::with_capacity(16);
         suffix.push('|');
@@ -97,6 +135,10 @@ impl InputScope for StatsdScope {
             InputKind::Marker | InputKind::Counter => "c",
             InputKind::Gauge | InputKind::Level => "g",
             InputKind::Timer => "ms",
+            InputKind::Set => "s",
+            // custom kinds name their own statsd type, e.g. "d" for distributions
+            InputKind::Custom(kind) => kind,
+            InputKind::Text => unreachable!(),
         });
 
         let scale = match kind {
@@ -107,68 +149,150 @@ impl InputScope for StatsdScope {
 
         let cloned = self.clone();
         let metric_id = MetricId::forge("statsd", name);
+        let labels = self.get_label_strategy();
 
-        if let Sampling::Random(float_rate) = self.get_sampling() {
-            suffix.push_str(&format! {"|@{}\n", float_rate});
-            let int_sampling_rate = pcg32::to_int_rate(float_rate);
-            let metric = StatsdMetric {
-                prefix,
-                suffix,
-                scale,
-            };
+        // weighted values of counts and timings are sent once, at the rate they would have been sampled at
+        let type_suffix = suffix.clone();
+        let sampling_rate = match self.get_sampling() {
+            Sampling::Random(float_rate) => float_rate,
+            Sampling::Full => 1.0,
+        };
+        let int_sampling_rate = match self.get_sampling() {
+            Sampling::Random(float_rate) => {
+                suffix.push_str(&format! {"|@{}\n", float_rate});
+                Some(pcg32::to_int_rate(float_rate))
+            }
+            Sampling::Full => {
+                suffix.push_str("\n");
+                None
+            }
+        };
+        let metric = Arc::new(StatsdMetric {
+            name: base_name,
+            prefix,
+            suffix,
+            scale,
+            labels,
+            // levels are relative, an unsigned statsd gauge value would replace the current one
+            signed: kind == InputKind::Level,
+        });
 
-            InputMetric::new(metric_id, move |value, _labels| {
-                if pcg32::accept_sample(int_sampling_rate) {
-                    cloned.print(&metric, value)
+        let float_metric = metric.clone();
+        let float_cloned = self.clone();
+        let weighted_metric = metric.clone();
+        let weighted_cloned = self.clone();
+        self.attributes.filter_metric(
+            InputMetric::new(metric_id, move |value, labels| {
+                if int_sampling_rate.is_none_or(pcg32::accept_sample) {
+                    let scaled_value = value / metric.scale;
+                    cloned.print(
+                        &metric,
+                        &|text| metric.write_value(text, scaled_value),
+                        &metric.suffix,
+                        labels,
+                    )
                 }
             })
-        } else {
-            suffix.push_str("\n");
-            let metric = StatsdMetric {
-                prefix,
-                suffix,
-                scale,
-            };
-            InputMetric::new(metric_id, move |value, _labels| {
-                cloned.print(&metric, value)
+            .with_float(move |value, labels| {
+                if int_sampling_rate.is_none_or(pcg32::accept_sample) {
+                    let scaled_value = value / float_metric.scale as f64;
+                    let value_text =
+                        |text: &mut String| float_metric.write_value(text, scaled_value);
+                    float_cloned.print(&float_metric, &value_text, &float_metric.suffix, labels)
+                }
             })
-        }
+            .with_weighted(move |value, weight, labels| {
+                if weight == 0 || !int_sampling_rate.is_none_or(pcg32::accept_sample) {
+                    return;
+                }
+                let metric = &weighted_metric;
+                let scaled_value = value / metric.scale;
+                match kind {
+                    InputKind::Marker
+                    | InputKind::Counter
+                    | InputKind::Timer
+                    | InputKind::Custom(_) => {
+                        let rated_suffix =
+                            format!("{}|@{}\n", type_suffix, sampling_rate / weight as f64);
+                        let value_text = |text: &mut String| metric.write_value(text, scaled_value);
+                        weighted_cloned.print(metric, &value_text, &rated_suffix, labels)
+                    }
+                    // level deltas add up
+                    InputKind::Level => {
+                        let total = scaled_value.saturating_mul(weight as isize);
+                        let value_text = |text: &mut String| metric.write_value(text, total);
+                        weighted_cloned.print(metric, &value_text, &metric.suffix, labels)
+                    }
+                    // gauges and sets hold the same value however many times it is written
+                    _ => {
+                        let value_text = |text: &mut String| metric.write_value(text, scaled_value);
+                        weighted_cloned.print(metric, &value_text, &metric.suffix, labels)
+                    }
+                }
+            }),
+        )
     }
 }
 
 impl Flush for StatsdScope {
     fn flush(&self) -> io::Result<()> {
         self.notify_flush_listeners();
+        let start = Instant::now();
         let buf = write_lock!(self.buffer);
-        self.flush_inner(buf)
+        let result = self.flush_inner(buf);
+        self.attributes.report_flush("statsd", start, &self.tally);
+        result
     }
 }
 
 impl StatsdScope {
-    fn print(&self, metric: &StatsdMetric, value: MetricValue) {
-        let scaled_value = value / metric.scale;
-        let value_str = scaled_value.to_string();
-        let entry_len = metric.prefix.len() + value_str.len() + metric.suffix.len();
+    fn print(
+        &self,
+        metric: &StatsdMetric,
+        value: &dyn Fn(&mut String),
+        suffix: &str,
+        labels: Labels,
+    ) {
+        let folded = match metric.labels {
+            LabelStrategy::Drop => None,
+            LabelStrategy::Interpolate => {
+                labels.interpolate_name(&metric.name).map(|name| name + ":")
+            }
+            _ => labels
+                .fold_into_name(&metric.name, '.')
+                .map(|name| name + ":"),
+        };
+        let plain = match (&folded, metric.labels) {
+            (Some(_), LabelStrategy::FoldIntoName | LabelStrategy::Interpolate) => None,
+            _ => Some(&metric.prefix),
+        };
+        // the value is formatted once, in a reused scratch buffer, however many entries it is written to
+        with_scratch(|value_text| {
+            value(value_text);
+            for prefix in plain.into_iter().chain(folded.as_ref()) {
+                self.print_entry(prefix, value_text, suffix)
+            }
+        })
+    }
 
+    /// Splice the value between the metric's precomputed prefix and suffix, directly into the buffer.
+    fn print_entry(&self, prefix: &str, value: &str, suffix: &str) {
         let mut buffer = write_lock!(self.buffer);
-        if entry_len > buffer.capacity() {
-            // TODO report entry too big to fit in buffer (!?)
+        let previous_len = buffer.len();
+        let entry_len = push_entry(&mut buffer, prefix, value, suffix);
+        if entry_len > MAX_UDP_PAYLOAD {
+            // TODO report entry too big to fit in a packet (!?)
+            buffer.truncate(previous_len);
             return;
         }
 
-        let available = buffer.capacity() - buffer.len();
-        if entry_len + 1 > available {
-            // buffer is nearly full, make room
+        if buffer.len() > MAX_UDP_PAYLOAD {
+            // packet is full, send the previous entries and start the next packet with this one
+            buffer.truncate(previous_len);
+            // previous entries that can not be sent are kept for retry
             let _ = self.flush_inner(buffer);
             buffer = write_lock!(self.buffer);
-        } else {
-            if !buffer.is_empty() {
-                // separate from previous entry
-                buffer.push('\n')
-            }
-            buffer.push_str(&metric.prefix);
-            buffer.push_str(&value_str);
-            buffer.push_str(&metric.suffix);
+            push_entry(&mut buffer, prefix, value, suffix);
         }
 
         if !self.is_buffered() {
@@ -178,22 +302,42 @@ impl StatsdScope {
         }
     }
 
+    /// Send the packet after any previously failed ones, keeping it for retry if it can not be sent.
     fn flush_inner(&self, mut buffer: RwLockWriteGuard<String>) -> io::Result<()> {
-        if !buffer.is_empty() {
-            match self.socket.send(buffer.as_bytes()) {
+        let mut retry = write_lock!(self.retry);
+        if buffer.is_empty() && retry.pending_bytes() == 0 {
+            return Ok(());
+        }
+        retry.send(&mut buffer, |packet| {
+            self.attributes.preview_batch("statsd", packet.as_bytes());
+            match self.socket.send(packet.as_bytes()) {
                 Ok(size) => {
                     metrics::STATSD_SENT_BYTES.count(size);
-                    trace!("Sent {} bytes to statsd", buffer.len());
+                    self.tally.sent(packet.matches('\n').count() + 1, size);
+                    trace!("Sent {} bytes to statsd", packet.len());
+                    Ok(())
                 }
                 Err(e) => {
                     metrics::STATSD_SEND_ERR.mark();
-                    return Err(e);
+                    self.tally.failed();
+                    Err(e.into())
                 }
-            };
-            buffer.clear();
-        }
-        Ok(())
+            }
+        })
+    }
+}
+
+/// Append an entry to the buffer, separated from any previous entry. Returns the length of the entry.
+fn push_entry(buffer: &mut String, prefix: &str, value: &str, suffix: &str) -> usize {
+    if !buffer.is_empty() {
+        // separate from previous entry
+        buffer.push('\n')
     }
+    let entry_start = buffer.len();
+    buffer.push_str(prefix);
+    buffer.push_str(value);
+    buffer.push_str(suffix);
+    buffer.len() - entry_start
 }
 
 impl WithAttributes for StatsdScope {
@@ -206,13 +350,28 @@ impl WithAttributes for StatsdScope {
 }
 
 impl Buffered for StatsdScope {}
+impl ReportFlush for StatsdScope {}
+impl PreviewBatches for StatsdScope {}
 
 /// Key of a statsd metric.
 #[derive(Debug, Clone)]
 pub struct StatsdMetric {
+    name: String,
     prefix: String,
     suffix: String,
     scale: isize,
+    labels: LabelStrategy,
+    signed: bool,
+}
+
+impl StatsdMetric {
+    /// Signed values are gauge deltas, non-negative ones need an explicit `+`.
+    fn write_value<V: Display + PartialOrd + Default>(&self, text: &mut String, value: V) {
+        if self.signed && value >= V::default() {
+            text.push('+');
+        }
+        let _ = write!(text, "{}", value);
+    }
 }
 
 /// Any remaining buffered data is flushed on Drop.
@@ -286,3 +445,256 @@ mod bench {
         b.iter(|| test::black_box(timer.write(2000, labels![])));
     }
 }
+
+#[cfg(test)]
+mod mtest {
+    use super::*;
+    use crate::attributes::Buffering;
+    use crate::label::test::TEST_SEQUENCE;
+    use crate::label::AppLabel;
+
+    #[test]
+    fn level_deltas() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Statsd::send_to(server.local_addr().unwrap())
+            .unwrap()
+            .metrics();
+        let level = metrics.level("queue");
+        level.adjust(3);
+        level.adjust(-2);
+        level.adjust(0);
+
+        let mut packets = String::new();
+        let mut buf = [0u8; 64];
+        for _ in 0..3 {
+            let len = server.recv(&mut buf).unwrap();
+            packets.push_str(std::str::from_utf8(&buf[..len]).unwrap());
+        }
+        assert_eq!("queue:+3|g\nqueue:-2|g\nqueue:+0|g\n", packets);
+    }
+
+    #[test]
+    fn weighted_values() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Statsd::send_to(server.local_addr().unwrap())
+            .unwrap()
+            .metrics();
+        metrics.counter("bytes").count_n(5, 10);
+        let level = metrics.new_metric("queue".into(), InputKind::Level);
+        level.write_n(-2, 3, labels![]);
+        let gauge = metrics.new_metric("load".into(), InputKind::Gauge);
+        gauge.write_n(7, 4, labels![]);
+
+        let mut packets = String::new();
+        let mut buf = [0u8; 64];
+        for _ in 0..3 {
+            let len = server.recv(&mut buf).unwrap();
+            packets.push_str(std::str::from_utf8(&buf[..len]).unwrap());
+        }
+        assert_eq!("bytes:5|c|@0.1\nqueue:-6|g\nload:7|g\n", packets);
+    }
+
+    #[test]
+    fn full_packet_keeps_entry() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Statsd::send_to(server.local_addr().unwrap())
+            .unwrap()
+            .buffered(Buffering::Unlimited)
+            .metrics();
+        let counter = metrics.counter("counter");
+        for _ in 0..60 {
+            counter.count(1);
+        }
+        metrics.flush().unwrap();
+
+        server
+            .set_read_timeout(Some(Duration::from_secs(1)))
+            .unwrap();
+        let mut entries = 0;
+        let mut buf = [0u8; MAX_UDP_PAYLOAD];
+        while entries < 60 {
+            let len = match server.recv(&mut buf) {
+                Ok(len) => len,
+                Err(_) => break,
+            };
+            assert!(len <= MAX_UDP_PAYLOAD);
+            let packet = std::str::from_utf8(&buf[..len]).unwrap();
+            entries += packet.lines().filter(|line| !line.is_empty()).count();
+        }
+        assert_eq!(60, entries);
+    }
+
+    #[test]
+    fn custom_kind() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Statsd::send_to(server.local_addr().unwrap())
+            .unwrap()
+            .metrics();
+        let distribution = metrics.new_metric("latency".into(), InputKind::Custom("d"));
+        distribution.write(42, labels![]);
+
+        let mut buf = [0u8; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!("latency:42|d\n", std::str::from_utf8(&buf[..len]).unwrap());
+    }
+
+    #[test]
+    fn interpolate_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Statsd::send_to(server.local_addr().unwrap())
+            .unwrap()
+            .render_labels(LabelStrategy::Interpolate)
+            .metrics();
+        let marker = metrics.marker("requests.{status}");
+        marker.write(1, labels!["status" => "200", "method" => "GET"]);
+
+        let mut buf = [0u8; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            "requests.200:1|c\n",
+            std::str::from_utf8(&buf[..len]).unwrap()
+        );
+    }
+
+    #[test]
+    fn fold_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Statsd::send_to(server.local_addr().unwrap())
+            .unwrap()
+            .render_labels(LabelStrategy::FoldIntoName)
+            .metrics();
+        let counter = metrics.counter("requests");
+        counter.write(1, labels!["method" => "GET"]);
+        // weighted counts are sent once, at the rate they would have been sampled at
+        counter.write_n(5, 4, labels!["method" => "GET"]);
+
+        let mut buf = [0u8; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            "requests.method.GET:1|c\n",
+            std::str::from_utf8(&buf[..len]).unwrap()
+        );
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            "requests.method.GET:5|c|@0.25\n",
+            std::str::from_utf8(&buf[..len]).unwrap()
+        );
+    }
+
+    #[test]
+    fn companion_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Statsd::send_to(server.local_addr().unwrap())
+            .unwrap()
+            .render_labels(LabelStrategy::Companion)
+            .metrics();
+        let counter = metrics.counter("requests");
+        counter.write_n(5, 4, labels!["method" => "GET"]);
+        counter.write(1, labels![]);
+
+        let mut buf = [0u8; 64];
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            let len = server.recv(&mut buf).unwrap();
+            received.push(std::str::from_utf8(&buf[..len]).unwrap().to_string());
+        }
+        // unlabeled values have no companion
+        assert_eq!(
+            vec![
+                "requests:5|c|@0.25\n",
+                "requests.method.GET:5|c|@0.25\n",
+                "requests:1|c\n"
+            ],
+            received
+        );
+    }
+
+    #[test]
+    fn retain_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Statsd::send_to(server.local_addr().unwrap())
+            .unwrap()
+            .render_labels(LabelStrategy::FoldIntoName)
+            .retain_labels(&["status"])
+            .metrics();
+        let marker = metrics.marker("requests");
+        marker.write(1, labels!["status" => "200", "request_id" => "8f3a"]);
+
+        let mut buf = [0u8; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            "requests.status.200:1|c\n",
+            std::str::from_utf8(&buf[..len]).unwrap()
+        );
+    }
+
+    #[test]
+    fn transform_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        AppLabel::unset("abc");
+
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let metrics = Statsd::send_to(server.local_addr().unwrap())
+            .unwrap()
+            .render_labels(LabelStrategy::FoldIntoName)
+            .transform_labels(&["user"], |user| format!("{}", user.len()))
+            .metrics();
+        let marker = metrics.marker("logins");
+        marker.write(1, labels!["user" => "alice", "status" => "ok"]);
+
+        let mut buf = [0u8; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(
+            "logins.status.ok.user.5:1|c\n",
+            std::str::from_utf8(&buf[..len]).unwrap()
+        );
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn preview_batches() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let preview = SharedBuf::default();
+        let metrics = Statsd::send_to(server.local_addr().unwrap())
+            .unwrap()
+            .buffered(Buffering::BufferSize(512))
+            .preview_batches_to(preview.clone(), 12)
+            .metrics();
+        // scopes are flushed when any of their clones is dropped, keep the metrics alive
+        let (count_a, count_b) = (metrics.counter("count_a"), metrics.counter("count_b"));
+        count_a.count(3);
+        count_b.count(4);
+        metrics.flush().unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = server.recv(&mut buf).unwrap();
+        assert_eq!(b"count_a:3|c\n\ncount_b:4|c\n", &buf[..len]);
+        assert_eq!(
+            "# statsd batch, 25 bytes\ncount_a:3|c\n# 13 more bytes not shown\n",
+            String::from_utf8(preview.0.lock().unwrap().clone()).unwrap()
+        );
+    }
+}
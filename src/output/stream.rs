@@ -2,7 +2,10 @@
 
 // TODO parameterize templates
 
-use crate::attributes::{Attributes, Buffered, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::attributes::{
+    Attributes, Buffered, FilterLabels, FlushTally, MetricId, OnFlush, Prefixed, ReportFlush,
+    WithAttributes,
+};
 use crate::input::InputKind;
 use crate::name::MetricName;
 use crate::Flush;
@@ -13,6 +16,7 @@ use std::io::{self, Write};
 use std::path::Path;
 
 use std::sync::Arc;
+use std::time::Instant;
 
 #[cfg(not(feature = "parking_lot"))]
 use std::sync::RwLock;
@@ -140,6 +144,8 @@ impl<W: Write + Send + Sync + 'static> WithAttributes for Stream<W> {
 }
 
 impl<W: Write + Send + Sync + 'static> Buffered for Stream<W> {}
+impl<W: Write + Send + Sync + 'static> ReportFlush for Stream<W> {}
+impl<W: Write + Send + Sync + 'static> FilterLabels for Stream<W> {}
 
 impl<W: Write + Send + Sync + 'static> Input for Stream<W> {
     type SCOPE = TextScope<W>;
@@ -149,6 +155,7 @@ impl<W: Write + Send + Sync + 'static> Input for Stream<W> {
             attributes: self.attributes.clone(),
             entries: Arc::new(RwLock::new(Vec::new())),
             input: self.clone(),
+            tally: Arc::new(FlushTally::default()),
         }
     }
 }
@@ -158,6 +165,7 @@ pub struct TextScope<W: Write + Send + Sync + 'static> {
     attributes: Attributes,
     entries: Arc<RwLock<Vec<Vec<u8>>>>,
     input: Stream<W>,
+    tally: Arc<FlushTally>,
 }
 
 impl<W: Write + Send + Sync + 'static> Clone for TextScope<W> {
@@ -166,6 +174,7 @@ impl<W: Write + Send + Sync + 'static> Clone for TextScope<W> {
             attributes: self.attributes.clone(),
             entries: self.entries.clone(),
             input: self.input.clone(),
+            tally: self.tally.clone(),
         }
     }
 }
@@ -180,6 +189,8 @@ impl<W: Write + Send + Sync + 'static> WithAttributes for TextScope<W> {
 }
 
 impl<W: Write + Send + Sync + 'static> Buffered for TextScope<W> {}
+impl<W: Write + Send + Sync + 'static> ReportFlush for TextScope<W> {}
+impl<W: Write + Send + Sync + 'static> FilterLabels for TextScope<W> {}
 
 impl<W: Write + Send + Sync + 'static> InputScope for TextScope<W> {
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
@@ -189,44 +200,69 @@ impl<W: Write + Send + Sync + 'static> InputScope for TextScope<W> {
         let entries = self.entries.clone();
         let metric_id = MetricId::forge("stream", name);
 
-        if self.is_buffered() {
-            InputMetric::new(metric_id, move |value, labels| {
-                let mut buffer = Vec::with_capacity(32);
-                match template.print(&mut buffer, value, |key| labels.lookup(key)) {
-                    Ok(()) => {
-                        let mut entries = write_lock!(entries);
-                        entries.push(buffer)
-                    }
-                    Err(err) => debug!("{}", err),
-                }
+        let write: Arc<dyn Fn(Vec<u8>) + Send + Sync> = if self.is_buffered() {
+            Arc::new(move |buffer| {
+                let mut entries = write_lock!(entries);
+                entries.push(buffer)
             })
         } else {
             // unbuffered
             let input = self.input.clone();
+            let tally = self.tally.clone();
+            Arc::new(move |buffer| {
+                let mut input = write_lock!(input.inner);
+                if let Err(e) = input.write_all(&buffer).and_then(|_| input.flush()) {
+                    tally.failed();
+                    debug!("Could not write text metrics: {}", e)
+                } else {
+                    tally.sent(1, buffer.len());
+                }
+            })
+        };
+
+        let template = Arc::new(template);
+        let float_template = template.clone();
+        let float_write = write.clone();
+        self.attributes.filter_metric(
             InputMetric::new(metric_id, move |value, labels| {
                 let mut buffer = Vec::with_capacity(32);
-                match template.print(&mut buffer, value, |key| labels.lookup(key)) {
-                    Ok(()) => {
-                        let mut input = write_lock!(input.inner);
-                        if let Err(e) = input.write_all(&buffer).and_then(|_| input.flush()) {
-                            debug!("Could not write text metrics: {}", e)
-                        }
-                    }
+                match template.print_labels(&mut buffer, value, &labels) {
+                    Ok(()) => write(buffer),
                     Err(err) => debug!("{}", err),
                 }
             })
-        }
+            .with_float(move |value, labels| {
+                let mut buffer = Vec::with_capacity(32);
+                match float_template.print_f64_labels(&mut buffer, value, &labels) {
+                    Ok(()) => float_write(buffer),
+                    Err(err) => debug!("{}", err),
+                }
+            }),
+        )
     }
 }
 
 impl<W: Write + Send + Sync + 'static> Flush for TextScope<W> {
     fn flush(&self) -> io::Result<()> {
         self.notify_flush_listeners();
+        let start = Instant::now();
+        let result = self.flush_entries();
+        if result.is_err() {
+            self.tally.failed();
+        }
+        self.attributes.report_flush("stream", start, &self.tally);
+        result
+    }
+}
+
+impl<W: Write + Send + Sync + 'static> TextScope<W> {
+    fn flush_entries(&self) -> io::Result<()> {
         let mut entries = write_lock!(self.entries);
         if !entries.is_empty() {
             let mut input = write_lock!(self.input.inner);
             for entry in entries.drain(..) {
-                input.write_all(&entry)?
+                input.write_all(&entry)?;
+                self.tally.sent(1, entry.len());
             }
             input.flush()?;
         }
@@ -245,8 +281,11 @@ impl<W: Write + Send + Sync + 'static> Drop for TextScope<W> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::attributes::FilterLabels;
     use crate::input::InputKind;
+    use crate::output::format::TemplateFormat;
     use std::io;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
     #[test]
     fn sink_print() {
@@ -254,4 +293,33 @@ mod test {
         let m = c.new_metric("test".into(), InputKind::Marker);
         m.write(33, labels![]);
     }
+
+    #[test]
+    fn transformed_labels_cached() {
+        let transforms = Arc::new(AtomicUsize::new(0));
+        let counted = transforms.clone();
+        let stream = Stream::write_to(Vec::new())
+            .formatting(
+                TemplateFormat::parse("{name} {value} {label:user} {label:region}").unwrap(),
+            )
+            .transform_labels(&["user"], move |user| {
+                counted.fetch_add(1, Ordering::Relaxed);
+                format!("#{}", user.len())
+            });
+        let scope = stream.metrics();
+        let logins = scope.counter("logins");
+        logins.count_n(1, 2);
+        for _ in 0..3 {
+            logins.write_n(1, 1, labels!("user" => "alice", "region" => "eu"));
+        }
+        scope.gauge("load").value_f64(2.0);
+
+        let written = String::from_utf8(read_lock!(stream.inner).clone()).unwrap();
+        assert_eq!(
+            "logins 1  \nlogins 1  \nlogins 1 #5 eu\nlogins 1 #5 eu\nlogins 1 #5 eu\nload 2  \n",
+            written
+        );
+        // transformed once, then cached
+        assert_eq!(1, transforms.load(Ordering::Relaxed));
+    }
 }
@@ -2,8 +2,9 @@ use crate::name::MetricName;
 use crate::Flush;
 
 use crate::attributes::MetricId;
-use crate::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::{Input, InputDyn, InputKind, InputMetric, InputScope, ShareScope};
 use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 
 lazy_static! {
@@ -14,13 +15,36 @@ lazy_static! {
     pub static ref NO_METRIC_SCOPE: Arc<dyn InputScope + Send + Sync> = VOID_INPUT.input_dyn();
 }
 
+/// Number of discarded writes, per metric kind.
+#[derive(Default)]
+struct VoidCounts {
+    writes: [AtomicUsize; 8],
+}
+
+fn kind_index(kind: InputKind) -> usize {
+    match kind {
+        InputKind::Marker => 0,
+        InputKind::Counter => 1,
+        InputKind::Level => 2,
+        InputKind::Gauge => 3,
+        InputKind::Timer => 4,
+        InputKind::Set => 5,
+        InputKind::Text => 6,
+        InputKind::Custom(_) => 7,
+    }
+}
+
 /// Discard metrics Input.
 #[derive(Clone, Default)]
-pub struct Void {}
+pub struct Void {
+    counts: Option<Arc<VoidCounts>>,
+}
 
 /// Discard metrics Input.
 #[derive(Clone)]
-pub struct VoidInput {}
+pub struct VoidInput {
+    counts: Option<Arc<VoidCounts>>,
+}
 
 impl Void {
     /// Void metrics builder.
@@ -31,7 +55,35 @@ impl Void {
 
     /// Void metrics builder.
     pub fn new() -> Self {
-        Void {}
+        Void::default()
+    }
+
+    /// Void metrics builder that counts the writes it discards, per metric kind.
+    /// Can stand in for a real output to estimate what it would be sent ("shadow mode").
+    pub fn counting() -> Self {
+        Void {
+            counts: Some(Arc::new(VoidCounts::default())),
+        }
+    }
+
+    /// Number of values of the specified kind discarded so far, from all scopes of this output.
+    /// Always zero unless the output was built with `counting()`.
+    pub fn discarded(&self, kind: InputKind) -> usize {
+        self.counts.as_ref().map_or(0, |counts| {
+            counts.writes[kind_index(kind)].load(Ordering::Relaxed)
+        })
+    }
+
+    /// Number of values of any kind discarded so far, from all scopes of this output.
+    /// Always zero unless the output was built with `counting()`.
+    pub fn discarded_total(&self) -> usize {
+        self.counts.as_ref().map_or(0, |counts| {
+            counts
+                .writes
+                .iter()
+                .map(|writes| writes.load(Ordering::Relaxed))
+                .sum()
+        })
     }
 }
 
@@ -39,16 +91,31 @@ impl Input for Void {
     type SCOPE = VoidInput;
 
     fn metrics(&self) -> Self::SCOPE {
-        VoidInput {}
+        VoidInput {
+            counts: self.counts.clone(),
+        }
     }
 }
 
 impl InputScope for VoidInput {
-    fn new_metric(&self, name: MetricName, _kind: InputKind) -> InputMetric {
-        InputMetric::new(MetricId::forge("void", name), |_value, _labels| {})
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let id = MetricId::forge("void", name);
+        match &self.counts {
+            Some(counts) => {
+                let counts = counts.clone();
+                let index = kind_index(kind);
+                InputMetric::new(id, move |_value, _labels| {
+                    counts.writes[index].fetch_add(1, Ordering::Relaxed);
+                })
+            }
+            None => InputMetric::new(id, |_value, _labels| {}),
+        }
     }
 }
 
+/// Values are discarded, siblings would be too.
+impl ShareScope for VoidInput {}
+
 impl Flush for VoidInput {
     fn flush(&self) -> io::Result<()> {
         Ok(())
@@ -65,4 +132,18 @@ pub mod test {
         let m = c.new_metric("test".into(), InputKind::Marker);
         m.write(33, labels![]);
     }
+
+    #[test]
+    fn count_discarded() {
+        let void = Void::counting();
+        let metrics = void.metrics();
+        metrics.marker("a").mark();
+        metrics.marker("a").mark();
+        void.metrics().gauge("b").value_f64(0.5);
+        assert_eq!(2, void.discarded(InputKind::Marker));
+        assert_eq!(1, void.discarded(InputKind::Gauge));
+        assert_eq!(0, void.discarded(InputKind::Timer));
+        assert_eq!(3, void.discarded_total());
+        assert_eq!(0, Void::new().discarded_total());
+    }
 }
@@ -32,6 +32,11 @@ fn pcg32_random() -> u32 {
     })
 }
 
+/// quickly return a random fraction between 0.0 (inclusive) and 1.0 (exclusive)
+pub fn random_fraction() -> f64 {
+    f64::from(pcg32_random()) / (f64::from(u32::MAX) + 1.0)
+}
+
 /// Convert a floating point sampling rate to an integer so that a fast integer RNG can be used
 /// Float rate range is between 1.0 (send 100% of the samples) and 0.0 (_no_ samples taken)
 /// .    | float rate | int rate | percentage
@@ -47,3 +52,14 @@ pub fn to_int_rate(float_rate: f64) -> u32 {
 pub fn accept_sample(int_rate: u32) -> bool {
     pcg32_random() > int_rate
 }
+
+/// Randomly select a sample based on a float rate, returning its weight (the inverse of the rate) if selected.
+pub fn sample_weight(float_rate: f64) -> Option<usize> {
+    if float_rate >= 1.0 {
+        Some(1)
+    } else if float_rate > 0.0 && accept_sample(to_int_rate(float_rate)) {
+        Some((1.0 / float_rate).round() as usize)
+    } else {
+        None
+    }
+}
@@ -0,0 +1,283 @@
+//! Explicit lifecycle for a metrics pipeline, from its entry scope to its outputs.
+//! Starting a pipeline lets values in and schedules its publication, stopping it cancels the schedule,
+//! flushes whatever was collected and waits for queued outputs to deliver it,
+//! returning the result instead of logging it from a `Drop` impl.
+//! Pausing a pipeline silences it during maintenance without tearing it down.
+
+use crate::attributes::{Attributes, OnFlush, Prefixed, WithAttributes};
+use crate::input::{InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::queue::drain_queues;
+use crate::scheduler::{schedule_flush, CancelHandle, SCHEDULER};
+use crate::switch::SwitchScope;
+use crate::Flush;
+
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// How long stopping a pipeline waits for queued outputs to deliver its last values, by default.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Lifecycle state of a pipeline.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PipelineState {
+    /// Not started yet, or stopped. Values written are discarded and nothing is published.
+    Stopped,
+    /// Started, values are collected and publication is scheduled if a flush period was set.
+    Running,
+    /// Started but paused, values are discarded and nothing is published until resumed.
+    Paused,
+}
+
+/// A metrics pipeline with an explicit lifecycle, e.g. a bucket draining to outputs.
+/// Metrics are defined on the pipeline itself, which forwards them to its entry scope.
+/// Clones are handles to the same pipeline, as are the metrics defined by it, which keep a copy to define siblings in.
+/// If still running when the last handle is dropped, the pipeline is stopped and any error is logged.
+#[derive(Clone)]
+pub struct Pipeline {
+    attributes: Attributes,
+    /// The entry scope, switched on while running. Checked by every write, kept out of the lifecycle lock.
+    gate: SwitchScope,
+    flush_period: Option<Duration>,
+    drain_timeout: Duration,
+    lifecycle: Arc<Mutex<Lifecycle>>,
+}
+
+struct Lifecycle {
+    state: PipelineState,
+    scope: Arc<dyn InputScope + Send + Sync>,
+    scheduled: Option<CancelHandle>,
+    gate: SwitchScope,
+    drain_timeout: Duration,
+}
+
+impl Lifecycle {
+    fn schedule(&mut self, flush_period: Option<Duration>) {
+        self.scheduled = flush_period.map(|period| {
+            schedule_flush(self.scope.clone(), |flush| {
+                SCHEDULER.schedule(period, flush)
+            })
+        });
+    }
+
+    /// Cancel the scheduled publication, waiting for a flush in progress to complete.
+    fn unschedule(&mut self) {
+        if let Some(scheduled) = self.scheduled.take() {
+            scheduled.join();
+        }
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        if self.state == PipelineState::Stopped {
+            return Err(io::Error::other("metrics pipeline is not running"));
+        }
+        self.unschedule();
+        self.state = PipelineState::Stopped;
+        self.gate.pause();
+        let deadline = Instant::now() + self.drain_timeout;
+        // queues downstream of the entry scope (e.g. a bucket's output) are drained too
+        let closed = self.scope.close();
+        closed.and(drain_queues(deadline))
+    }
+}
+
+impl Drop for Lifecycle {
+    fn drop(&mut self) {
+        if self.state != PipelineState::Stopped {
+            if let Err(e) = self.stop() {
+                warn!("Could not stop metrics pipeline upon Drop: {}", e)
+            }
+        }
+    }
+}
+
+impl Pipeline {
+    /// Build a stopped pipeline around its entry scope.
+    pub fn new<S: InputScope + Send + Sync + 'static>(scope: S) -> Self {
+        let gate = SwitchScope::new(scope, Arc::new(AtomicBool::new(false)));
+        Pipeline {
+            attributes: Attributes::default(),
+            gate: gate.clone(),
+            flush_period: None,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            lifecycle: Arc::new(Mutex::new(Lifecycle {
+                state: PipelineState::Stopped,
+                scope: gate.target().clone(),
+                scheduled: None,
+                gate,
+                drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            })),
+        }
+    }
+
+    /// Returns a clone flushing the entry scope at regular intervals while running.
+    /// Applies from the next start.
+    pub fn flush_every(&self, period: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.flush_period = Some(period);
+        cloned
+    }
+
+    /// Returns a clone waiting up to `timeout` for queued outputs to deliver the last values when stopped,
+    /// five seconds by default. Applies from the next start.
+    pub fn drain_timeout(&self, timeout: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.drain_timeout = timeout;
+        cloned
+    }
+
+    /// Start the pipeline, letting values written to its metrics in
+    /// and scheduling its publication if a flush period was set.
+    /// Fails if the pipeline is already running.
+    pub fn start(&self) -> io::Result<()> {
+        let mut lifecycle = self.lock();
+        if lifecycle.state != PipelineState::Stopped {
+            return Err(io::Error::other("metrics pipeline is already running"));
+        }
+        lifecycle.schedule(self.flush_period);
+        lifecycle.drain_timeout = self.drain_timeout;
+        lifecycle.state = PipelineState::Running;
+        self.gate.resume();
+        Ok(())
+    }
+
+    /// Pause the running pipeline, e.g. during maintenance: values written to its metrics are discarded
+    /// and its scheduled publication is suspended, until resumed. Fails if the pipeline is not running.
+    pub fn pause(&self) -> io::Result<()> {
+        let mut lifecycle = self.lock();
+        if lifecycle.state != PipelineState::Running {
+            return Err(io::Error::other("metrics pipeline is not running"));
+        }
+        self.gate.pause();
+        lifecycle.unschedule();
+        lifecycle.state = PipelineState::Paused;
+        Ok(())
+    }
+
+    /// Resume the paused pipeline, rescheduling its publication. Fails if the pipeline is not paused.
+    pub fn resume(&self) -> io::Result<()> {
+        let mut lifecycle = self.lock();
+        if lifecycle.state != PipelineState::Paused {
+            return Err(io::Error::other("metrics pipeline is not paused"));
+        }
+        lifecycle.schedule(self.flush_period);
+        lifecycle.state = PipelineState::Running;
+        self.gate.resume();
+        Ok(())
+    }
+
+    /// Stop the pipeline, canceling its scheduled publication and discarding values written afterwards,
+    /// then closing the entry scope and waiting for queued outputs to deliver the last values, up to the drain timeout.
+    /// Returns the result of the final flush, or a `TimedOut` error if queued values were still pending.
+    /// Fails if the pipeline is neither running nor paused. Must not be called from a scheduled flush.
+    pub fn stop(&self) -> io::Result<()> {
+        self.lock().stop()
+    }
+
+    /// Returns the current lifecycle state of the pipeline.
+    pub fn state(&self) -> PipelineState {
+        self.lock().state
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Lifecycle> {
+        self.lifecycle
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl WithAttributes for Pipeline {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for Pipeline {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        self.gate.new_metric(self.prefix_append(name), kind)
+    }
+}
+
+impl Flush for Pipeline {
+    /// Flush the entry scope, unless the pipeline is stopped or paused.
+    fn flush(&self) -> io::Result<()> {
+        if !self.gate.enabled().load(Relaxed) {
+            return Ok(());
+        }
+        self.notify_flush_listeners();
+        self.gate.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::queue::InputQueueScope;
+    use crate::testing::Recorder;
+
+    #[test]
+    fn pause_resume() {
+        let bucket = crate::AtomicBucket::new();
+        let pipeline = Pipeline::new(bucket.clone());
+        let counter = pipeline.counter("hits");
+        assert!(pipeline.pause().is_err());
+        // discarded until started
+        counter.count(1000);
+
+        pipeline.start().unwrap();
+        counter.count(1);
+        pipeline.pause().unwrap();
+        assert_eq!(PipelineState::Paused, pipeline.state());
+        assert!(pipeline.start().is_err());
+        // discarded while paused
+        counter.count(10);
+        pipeline.resume().unwrap();
+        assert!(pipeline.resume().is_err());
+        counter.count(100);
+
+        let map = crate::StatsMapScope::default();
+        bucket.flush_to(&map).unwrap();
+        assert_eq!(101, map.into_map()["hits"]);
+        pipeline.stop().unwrap();
+    }
+
+    #[test]
+    fn start_stop() {
+        let scope = Recorder::default();
+        let pipeline = Pipeline::new(scope.clone()).flush_every(Duration::from_secs(3600));
+        assert_eq!(PipelineState::Stopped, pipeline.state());
+        assert!(pipeline.stop().is_err());
+
+        pipeline.start().unwrap();
+        assert!(pipeline.start().is_err());
+        pipeline.stop().unwrap();
+        assert_eq!(PipelineState::Stopped, pipeline.state());
+        assert_eq!(1, scope.flushes());
+
+        // stopped when the last handle is dropped
+        pipeline.start().unwrap();
+        drop(pipeline.clone());
+        assert_eq!(1, scope.flushes());
+        drop(pipeline);
+        assert_eq!(2, scope.flushes());
+    }
+    #[test]
+    fn stop_drains_queue() {
+        let target = Recorder::default();
+        let pipeline = Pipeline::new(InputQueueScope::wrap(target.clone(), 64));
+        let counter = pipeline.counter("hits");
+        pipeline.start().unwrap();
+        for i in 0..10 {
+            counter.count(i)
+        }
+        pipeline.stop().unwrap();
+        assert_eq!(10, target.values().len());
+        assert_eq!(1, target.flushes());
+    }
+}
@@ -1,9 +1,11 @@
 //! Decouple metric definition from configuration with trait objects.
 
-use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::attributes::{Attributes, ClockedTimers, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::clock::Clock;
 use crate::input::{InputKind, InputMetric, InputScope};
 use crate::name::{MetricName, NameParts};
 use crate::output::void::VOID_INPUT;
+use crate::registry::registry;
 use crate::Flush;
 
 use std::collections::{BTreeMap, HashMap};
@@ -16,8 +18,6 @@ use std::sync::RwLock;
 #[cfg(feature = "parking_lot")]
 use parking_lot::RwLock;
 
-use atomic_refcell::*;
-
 lazy_static! {
     /// Root of the default metrics proxy, usable by all libraries and apps.
     /// Libraries should create their metrics into sub subspaces of this.
@@ -36,7 +36,8 @@ struct ProxyMetric {
     // the metric trait object to proxy metric values to
     // the second part can be up to namespace.len() + 1 if this metric was individually targeted
     // 0 if no target assigned
-    target: AtomicRefCell<(InputMetric, usize)>,
+    // writes only take the read lock, retargeting waits for writes in progress
+    target: RwLock<(InputMetric, usize)>,
 
     // a reference to the the parent proxy to remove the metric from when it is dropped
     proxy: Arc<RwLock<InnerProxy>>,
@@ -53,6 +54,11 @@ impl Drop for ProxyMetric {
 /// Decouples metrics definition from backend configuration.
 /// Allows defining metrics before a concrete type is configured.
 /// Allows replacing metrics backend on the fly at runtime.
+///
+/// Different name subtrees can be routed to different targets,
+/// e.g. `proxy.named("db").target(graphite)` and `proxy.named("http").target(statsd)`.
+/// Each metric goes to the target of its longest targeted namespace,
+/// or nowhere if none of its namespaces is targeted.
 #[derive(Clone, Debug)]
 pub struct Proxy {
     attributes: Attributes,
@@ -88,29 +94,34 @@ impl InnerProxy {
         }
     }
 
+    /// Returns the retargeted metrics, which must only be dropped after releasing the proxy lock,
+    /// as dropping the last reference to a metric removes it from the proxy.
     fn set_target(
         &mut self,
         namespace: &NameParts,
         target_scope: Arc<dyn InputScope + Send + Sync>,
-    ) {
+    ) -> Vec<Arc<ProxyMetric>> {
         self.targets.insert(namespace.clone(), target_scope.clone());
 
+        let mut retargeted = Vec::new();
         for (metric_name, metric) in self.metrics.range_mut(namespace.clone()..) {
-            if let Some(metric) = metric.upgrade() {
-                // check for range end
-                if !metric_name.is_within(namespace) {
-                    break;
-                }
+            // check for range end
+            if !metric_name.is_within(namespace) {
+                break;
+            }
 
+            if let Some(metric) = metric.upgrade() {
                 // check if metric targeted by _lower_ namespace
-                if metric.target.borrow().1 > namespace.len() {
+                if read_lock!(metric.target).1 > namespace.len() {
                     continue;
                 }
 
                 let target_metric = target_scope.new_metric(metric.name.short(), metric.kind);
-                *metric.target.borrow_mut() = (target_metric, namespace.len());
+                *write_lock!(metric.target) = (target_metric, namespace.len());
+                retargeted.push(metric);
             }
         }
+        retargeted
     }
 
     fn get_effective_target(
@@ -131,10 +142,12 @@ impl InnerProxy {
         None
     }
 
-    fn unset_target(&mut self, namespace: &NameParts) {
+    /// Returns the retargeted metrics, which must only be dropped after releasing the proxy lock.
+    fn unset_target(&mut self, namespace: &NameParts) -> Vec<Arc<ProxyMetric>> {
+        let mut retargeted = Vec::new();
         if self.targets.remove(namespace).is_none() {
             // nothing to do
-            return;
+            return retargeted;
         }
 
         let (up_target, up_nslen) = self
@@ -150,14 +163,16 @@ impl InnerProxy {
 
             if let Some(metric) = metric.upgrade() {
                 // check if metric targeted by _lower_ namespace
-                if metric.target.borrow().1 > namespace.len() {
+                if read_lock!(metric.target).1 > namespace.len() {
                     continue;
                 }
 
                 let new_metric = up_target.new_metric(name.short(), metric.kind);
-                *metric.target.borrow_mut() = (new_metric, up_nslen);
+                *write_lock!(metric.target) = (new_metric, up_nslen);
+                retargeted.push(metric);
             }
         }
+        retargeted
     }
 
     fn drop_metric(&mut self, name: &NameParts) {
@@ -166,12 +181,20 @@ impl InnerProxy {
         }
     }
 
+    /// Flush the effective target of the namespace and the targets of any namespaces within it.
     fn flush(&self, namespace: &NameParts) -> io::Result<()> {
-        if let Some((target, _nslen)) = self.get_effective_target(namespace) {
-            target.flush()
-        } else {
-            Ok(())
+        let mut result = match self.get_effective_target(namespace) {
+            Some((target, _nslen)) => target.flush(),
+            None => Ok(()),
+        };
+        for (target_namespace, target) in &self.targets {
+            if target_namespace.len() > namespace.len() && target_namespace.is_within(namespace) {
+                if let Err(e) = target.flush() {
+                    result = Err(e);
+                }
+            }
         }
+        result
     }
 }
 
@@ -194,14 +217,39 @@ impl Proxy {
         self.target(target)
     }
 
-    /// Replace target for this proxy and its children.
+    /// Replace target for this proxy and its children,
+    /// except for children that have their own target.
     pub fn target<T: InputScope + Send + Sync + 'static>(&self, target: T) {
-        write_lock!(self.inner).set_target(self.get_prefixes(), Arc::new(target))
+        let _retargeted = write_lock!(self.inner).set_target(self.get_prefixes(), Arc::new(target));
     }
 
-    /// Replace target for this proxy and its children.
+    /// Replace target for this proxy and its children with the output registered under the name,
+    /// following it as it is changed in the global `registry()`, even if it is registered later.
+    pub fn target_named(&self, name: &str) {
+        self.target(registry().get(name))
+    }
+
+    /// Replace target for this proxy and its children while metrics are being written,
+    /// e.g. to switch from statsd to graphite or to change address when reloading configuration.
+    /// Existing metric handles are kept and write to the new target once it is installed.
+    /// The previous target is then flushed, so that values it buffered are not lost.
+    pub fn swap_target<T: InputScope + Send + Sync + 'static>(&self, target: T) -> io::Result<()> {
+        let (previous, _retargeted) = {
+            let mut inner = write_lock!(self.inner);
+            let previous = inner.get_effective_target(self.get_prefixes());
+            let retargeted = inner.set_target(self.get_prefixes(), Arc::new(target));
+            (previous, retargeted)
+        };
+        match previous {
+            Some((previous, _nslen)) => previous.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Remove target for this proxy and its children,
+    /// which revert to the target of the nearest targeted parent namespace.
     pub fn unset_target(&self) {
-        write_lock!(self.inner).unset_target(self.get_prefixes())
+        let _retargeted = write_lock!(self.inner).unset_target(self.get_prefixes());
     }
 
     /// Install a new default target for all proxies.
@@ -228,6 +276,10 @@ impl<S: AsRef<str>> From<S> for Proxy {
 }
 
 impl InputScope for Proxy {
+    fn clock(&self) -> Clock {
+        self.get_timer_clock()
+    }
+
     /// Lookup or create a proxy stub for the requested metric.
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
         let name: MetricName = self.prefix_append(name);
@@ -248,7 +300,7 @@ impl InputScope for Proxy {
                     let proxy = Arc::new(ProxyMetric {
                         name: namespace.clone(),
                         kind,
-                        target: AtomicRefCell::new((metric_object, target_namespace_length)),
+                        target: RwLock::new((metric_object, target_namespace_length)),
                         proxy: self.inner.clone(),
                     });
                     inner
@@ -257,8 +309,20 @@ impl InputScope for Proxy {
                     proxy
                 }
             });
+        let weighted = proxy.clone();
+        let float = proxy.clone();
+        let timestamped = proxy.clone();
         InputMetric::new(MetricId::forge("proxy", name), move |value, labels| {
-            proxy.target.borrow().0.write(value, labels)
+            read_lock!(proxy.target).0.write(value, labels)
+        })
+        .with_weighted(move |value, weight, labels| {
+            read_lock!(weighted.target).0.write_n(value, weight, labels)
+        })
+        .with_float(move |value, labels| read_lock!(float.target).0.write_f64(value, labels))
+        .with_timestamped(move |timestamp, value, labels| {
+            read_lock!(timestamped.target)
+                .0
+                .write_at(timestamp, value, labels)
         })
     }
 }
@@ -279,6 +343,113 @@ impl WithAttributes for Proxy {
     }
 }
 
+impl ClockedTimers for Proxy {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attributes::Observe;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn route_longest_prefix() {
+        let proxy = Proxy::new();
+        let (root, db, pool) = (
+            StatsMapScope::default(),
+            StatsMapScope::default(),
+            StatsMapScope::default(),
+        );
+        proxy.target(root.clone());
+        proxy.named("db").target(db.clone());
+        proxy.named("db").add_name("pool").target(pool.clone());
+
+        proxy.counter("requests").count(1);
+        proxy.named("db").counter("queries").count(2);
+        proxy
+            .named("db")
+            .add_name("pool")
+            .counter("acquired")
+            .count(3);
+        proxy.named("dbx").counter("other").count(4);
+        proxy.named("pool").counter("unrelated").count(5);
+
+        let root: BTreeMap<_, _> = root.into();
+        let db: BTreeMap<_, _> = db.into();
+        let pool: BTreeMap<_, _> = pool.into();
+        assert_eq!(Some(&1), root.get("requests"));
+        assert_eq!(Some(&4), root.get("other"));
+        assert_eq!(Some(&5), root.get("unrelated"));
+        assert_eq!(Some(&2), db.get("queries"));
+        assert_eq!(Some(&3), pool.get("acquired"));
+        assert_eq!(1, db.len());
+    }
+
+    #[test]
+    fn unset_route_reverts_to_parent() {
+        let proxy = Proxy::new();
+        let (root, db) = (StatsMapScope::default(), StatsMapScope::default());
+        proxy.target(root.clone());
+        proxy.named("db").target(db.clone());
+
+        let queries = proxy.named("db").counter("queries");
+        queries.count(1);
+        proxy.named("db").unset_target();
+        queries.count(2);
+
+        let root: BTreeMap<_, _> = root.into();
+        let db: BTreeMap<_, _> = db.into();
+        assert_eq!(Some(&1), db.get("queries"));
+        assert_eq!(Some(&2), root.get("queries"));
+    }
+
+    #[test]
+    fn flush_routed_targets() {
+        let proxy = Proxy::new();
+        let (db, http) = (StatsMapScope::default(), StatsMapScope::default());
+        let _db_flushed = db.observe_gauge("flushed", || 1);
+        let _http_flushed = http.observe_gauge("flushed", || 1);
+        proxy.named("db").target(db.clone());
+        proxy.named("http").target(http.clone());
+
+        proxy.flush().unwrap();
+
+        let db: BTreeMap<_, _> = db.into();
+        let http: BTreeMap<_, _> = http.into();
+        assert_eq!(Some(&1), db.get("flushed"));
+        assert_eq!(Some(&1), http.get("flushed"));
+    }
+
+    #[test]
+    fn swap_target_while_writing() {
+        let proxy = Proxy::new();
+        let (statsd, graphite) = (StatsMapScope::default(), StatsMapScope::default());
+        proxy.target(statsd.clone());
+        let counter = proxy.counter("requests");
+
+        let writer = {
+            let counter = counter.clone();
+            std::thread::spawn(move || {
+                for _ in 0..10_000 {
+                    counter.count(1);
+                }
+            })
+        };
+        for i in 0..100 {
+            if i % 2 == 0 {
+                proxy.swap_target(graphite.clone()).unwrap();
+            } else {
+                proxy.swap_target(statsd.clone()).unwrap();
+            }
+        }
+        writer.join().unwrap();
+
+        proxy.swap_target(graphite.clone()).unwrap();
+        counter.count(2);
+        let graphite: BTreeMap<_, _> = graphite.into();
+        assert_eq!(Some(&2), graphite.get("requests"));
+    }
+}
+
 #[cfg(feature = "bench")]
 mod bench {
 
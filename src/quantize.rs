@@ -0,0 +1,243 @@
+//! Round metric values before they reach aggregation or output.
+//! Coarser values mean less distinct values for downstream storage to keep.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::{CachedInput, Flush, MetricValue, QueuedInput};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+/// A value rounding strategy.
+/// Steps of one or less leave values unchanged.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Quantize {
+    /// Round to the nearest multiple of the step, e.g. timers to the millisecond with `Nearest(1000)`.
+    Nearest(MetricValue),
+    /// Round down to a multiple of the step, putting values in buckets of equal width.
+    Floor(MetricValue),
+    /// Round up to a multiple of the step.
+    Ceil(MetricValue),
+}
+
+impl Quantize {
+    /// Round the value according to this strategy.
+    pub fn apply(self, value: MetricValue) -> MetricValue {
+        let step = match self {
+            Quantize::Nearest(step) | Quantize::Floor(step) | Quantize::Ceil(step) => step,
+        };
+        if step <= 1 {
+            return value;
+        }
+        let remainder = value.rem_euclid(step);
+        let floor = value - remainder;
+        match self {
+            Quantize::Floor(_) => floor,
+            Quantize::Ceil(_) if remainder == 0 => floor,
+            Quantize::Ceil(_) => floor + step,
+            Quantize::Nearest(_) if remainder * 2 >= step => floor + step,
+            Quantize::Nearest(_) => floor,
+        }
+    }
+
+    /// Round the floating point value according to this strategy, halfway values up.
+    pub fn apply_f64(self, value: f64) -> f64 {
+        let step = match self {
+            Quantize::Nearest(step) | Quantize::Floor(step) | Quantize::Ceil(step) => step,
+        };
+        if step <= 1 {
+            return value;
+        }
+        let steps = value / step as f64;
+        let steps = match self {
+            Quantize::Floor(_) => steps.floor(),
+            Quantize::Ceil(_) => steps.ceil(),
+            Quantize::Nearest(_) => (steps + 0.5).floor(),
+        };
+        steps * step as f64
+    }
+}
+
+/// Round values of selected metric kinds before they are written.
+pub trait QuantizedInput: Input + Send + Sync + 'static + Sized {
+    /// Wrap this input with a decorator rounding values of the specified kind.
+    /// More kinds can be rounded using `quantize()` on the returned decorator.
+    fn quantized(self, kind: InputKind, quantize: Quantize) -> InputQuantize {
+        InputQuantize::wrap(self).quantize(kind, quantize)
+    }
+}
+
+impl<T: Input + Send + Sync + 'static> QuantizedInput for T {}
+
+/// Input decorator rounding metric values.
+#[derive(Clone)]
+pub struct InputQuantize {
+    attributes: Attributes,
+    target: Arc<dyn InputDyn + Send + Sync + 'static>,
+    rules: Arc<HashMap<InputKind, Quantize>>,
+}
+
+impl InputQuantize {
+    /// Wrap an input with a value rounding decorator.
+    /// Values are left unchanged until rules are added with `quantize()`.
+    pub fn wrap<OUT: Input + Send + Sync + 'static>(target: OUT) -> InputQuantize {
+        InputQuantize {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            rules: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a clone of the decorator rounding values of the specified kind.
+    /// Replaces any previous rule for that kind.
+    pub fn quantize(&self, kind: InputKind, quantize: Quantize) -> Self {
+        let mut cloned = self.clone();
+        Arc::make_mut(&mut cloned.rules).insert(kind, quantize);
+        cloned
+    }
+}
+
+impl QueuedInput for InputQuantize {}
+impl CachedInput for InputQuantize {}
+
+impl WithAttributes for InputQuantize {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for InputQuantize {
+    type SCOPE = InputQuantizeScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        InputQuantizeScope {
+            attributes: self.attributes.clone(),
+            target: self.target.input_dyn(),
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+/// Scope decorator rounding metric values.
+#[derive(Clone)]
+pub struct InputQuantizeScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    rules: Arc<HashMap<InputKind, Quantize>>,
+}
+
+impl InputQuantizeScope {
+    /// Wrap a scope with a value rounding decorator.
+    /// Use this to round values before they are aggregated by an `AtomicBucket`.
+    pub fn wrap<SC: InputScope + Send + Sync + 'static>(target: SC) -> Self {
+        InputQuantizeScope {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            rules: Arc::new(HashMap::new()),
+        }
+    }
+
+    /// Returns a clone of the decorator rounding values of the specified kind.
+    /// Replaces any previous rule for that kind.
+    pub fn quantize(&self, kind: InputKind, quantize: Quantize) -> Self {
+        let mut cloned = self.clone();
+        Arc::make_mut(&mut cloned.rules).insert(kind, quantize);
+        cloned
+    }
+}
+
+impl WithAttributes for InputQuantizeScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for InputQuantizeScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let target_metric = self.target.new_metric(name.clone(), kind);
+        match self.rules.get(&kind) {
+            Some(&quantize) => {
+                let float_metric = target_metric.clone();
+                InputMetric::new(MetricId::forge("quantize", name), move |value, labels| {
+                    target_metric.write(quantize.apply(value), labels)
+                })
+                .with_float(move |value, labels| {
+                    float_metric.write_f64(quantize.apply_f64(value), labels)
+                })
+            }
+            // no rounding, no indirection
+            None => target_metric,
+        }
+    }
+}
+
+impl Flush for InputQuantizeScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+    use crate::testing::Recorder;
+
+    #[test]
+    fn rounding() {
+        assert_eq!(Quantize::Nearest(1000).apply(1499), 1000);
+        assert_eq!(Quantize::Nearest(1000).apply(1500), 2000);
+        assert_eq!(Quantize::Nearest(10).apply(-14), -10);
+        assert_eq!(Quantize::Floor(10).apply(37), 30);
+        assert_eq!(Quantize::Floor(10).apply(-3), -10);
+        assert_eq!(Quantize::Ceil(10).apply(31), 40);
+        assert_eq!(Quantize::Ceil(10).apply(30), 30);
+        assert_eq!(Quantize::Floor(0).apply(37), 37);
+    }
+
+    #[test]
+    fn rounding_floats() {
+        assert_eq!(Quantize::Nearest(10).apply_f64(14.9), 10.0);
+        assert_eq!(Quantize::Nearest(10).apply_f64(15.0), 20.0);
+        assert_eq!(Quantize::Nearest(10).apply_f64(-14.9), -10.0);
+        assert_eq!(Quantize::Floor(10).apply_f64(-0.5), -10.0);
+        assert_eq!(Quantize::Ceil(10).apply_f64(30.5), 40.0);
+        assert_eq!(Quantize::Floor(1).apply_f64(0.37), 0.37);
+    }
+
+    #[test]
+    fn quantize_by_kind() {
+        let map = StatsMapScope::default();
+        let metrics = InputQuantizeScope::wrap(map.clone())
+            .quantize(InputKind::Timer, Quantize::Nearest(1000))
+            .quantize(InputKind::Gauge, Quantize::Floor(10));
+
+        metrics.timer("timer_a").interval_us(1_600);
+        metrics.gauge("gauge_a").value(37);
+        metrics.counter("counter_a").count(37);
+
+        let map = map.into_map();
+        assert_eq!(map["timer_a"], 2000);
+        assert_eq!(map["gauge_a"], 30);
+        assert_eq!(map["counter_a"], 37);
+    }
+
+    #[test]
+    fn quantize_floats() {
+        let recorder = Recorder::default();
+        let metrics = InputQuantizeScope::wrap(recorder.clone())
+            .quantize(InputKind::Gauge, Quantize::Floor(10));
+        metrics.gauge("gauge_a").value_f64(37.5);
+        assert_eq!(recorder.floats(), vec![30.0]);
+    }
+}
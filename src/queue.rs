@@ -3,20 +3,383 @@
 //! If queue size is exceeded, calling code reverts to blocking.
 
 use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::clock::TimeHandle;
+use crate::dead_letter::{dead_letter, DEAD_LETTER_QUEUE_DROPPED};
 use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
 use crate::label::Labels;
 use crate::metrics;
 use crate::name::MetricName;
+use crate::scheduler::{Cancel, CancelHandle, SCHEDULER};
 use crate::CachedInput;
 use crate::{Flush, MetricValue};
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
 #[cfg(not(feature = "crossbeam-channel"))]
 use std::sync::mpsc;
-use std::sync::Arc;
-use std::{io, thread};
+#[cfg(not(feature = "crossbeam-channel"))]
+use std::sync::mpsc::TrySendError;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, Weak};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant, SystemTime};
+use std::{error, fmt, io, mem, thread};
 
 #[cfg(feature = "crossbeam-channel")]
 use crossbeam_channel as crossbeam;
+#[cfg(feature = "crossbeam-channel")]
+use crossbeam_channel::TrySendError;
+
+#[cfg(not(feature = "crossbeam-channel"))]
+type QueueSender = mpsc::SyncSender<InputQueueCmd>;
+#[cfg(feature = "crossbeam-channel")]
+type QueueSender = crossbeam::Sender<InputQueueCmd>;
+
+/// The worker's end of the channel, also used by senders to drop the oldest command.
+#[cfg(not(feature = "crossbeam-channel"))]
+type QueueReceiver = Arc<Mutex<mpsc::Receiver<InputQueueCmd>>>;
+#[cfg(feature = "crossbeam-channel")]
+type QueueReceiver = crossbeam::Receiver<InputQueueCmd>;
+
+/// What writes do when the queue is full.
+/// Flushes always wait for room, they are never dropped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverflowPolicy {
+    /// Wait for room in the queue, slowing down the caller.
+    #[default]
+    Block,
+    /// Drop the value being written.
+    DropNewest,
+    /// Drop the oldest queued value to make room for the value being written.
+    DropOldest,
+}
+
+/// Why a command could not be passed to the queue's worker thread right away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// The queue is full, the worker thread is not keeping up with the load.
+    /// Only returned when a non-blocking overflow policy dropped a value to cope,
+    /// writes and flushes otherwise wait for room.
+    Full {
+        /// Number of commands waiting in the queue, its maximum size.
+        depth: usize,
+    },
+    /// The worker thread is gone, commands can not be sent anymore and are dropped.
+    WorkerDead,
+}
+
+impl fmt::Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueError::Full { depth } => write!(f, "metrics queue full ({} pending)", depth),
+            QueueError::WorkerDead => write!(f, "metrics queue worker thread is gone"),
+        }
+    }
+}
+
+impl error::Error for QueueError {}
+
+impl QueueError {
+    /// Count a failed send, values dropped by the overflow policy are already counted as dropped.
+    fn count(&self) {
+        if let QueueError::WorkerDead = self {
+            metrics::SEND_FAILED.mark();
+        }
+    }
+}
+
+impl From<QueueError> for io::Error {
+    fn from(e: QueueError) -> Self {
+        let kind = match e {
+            QueueError::Full { .. } => io::ErrorKind::WouldBlock,
+            QueueError::WorkerDead => io::ErrorKind::BrokenPipe,
+        };
+        io::Error::new(kind, e)
+    }
+}
+
+/// Both ends of a queue's channel.
+struct QueueChannel {
+    sender: QueueSender,
+    receiver: QueueReceiver,
+    capacity: usize,
+    /// Commands sent but not yet executed, shared with the workers.
+    pending: Arc<Pending>,
+    /// Batches of writes held by threads, sent upon flush even if their thread stopped writing.
+    batches: Mutex<Vec<Weak<Mutex<Batch>>>>,
+    /// Sends the threads' batches every max delay, if batching.
+    collector: Mutex<Option<CancelHandle>>,
+}
+
+/// How long closing a queue waits for its pending commands to be executed, by default.
+const DEFAULT_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Count of the commands sent to a queue but not yet executed, waited upon by drains.
+#[derive(Default)]
+struct Pending {
+    count: AtomicUsize,
+    lock: Mutex<()>,
+    drained: Condvar,
+}
+
+impl Pending {
+    fn add(&self) {
+        self.count.fetch_add(1, SeqCst);
+    }
+
+    /// Uncount an executed or dropped command, waking up drains once none are left.
+    fn done(&self) {
+        if self.count.fetch_sub(1, SeqCst) == 1 {
+            // a drain checks the count and starts waiting under the lock, it can not miss this wakeup
+            let _lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+            self.drained.notify_all();
+        }
+    }
+
+    /// Wait until no commands are pending, or until the deadline.
+    fn wait(&self, deadline: Instant) -> io::Result<()> {
+        let mut lock = self.lock.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let pending = self.count.load(SeqCst);
+            if pending == 0 {
+                return Ok(());
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("{} queued metrics commands not delivered", pending),
+                ));
+            }
+            lock = self
+                .drained
+                .wait_timeout(lock, deadline - now)
+                .unwrap_or_else(PoisonError::into_inner)
+                .0;
+        }
+    }
+}
+
+lazy_static! {
+    /// Channels of all live queues, to be drained upon shutdown.
+    static ref LIVE_QUEUES: Mutex<Vec<Weak<QueueChannel>>> = Mutex::new(Vec::new());
+}
+
+/// Wait until the commands of all live queues have been executed, or until the deadline.
+/// The batches of every thread are sent first.
+pub(crate) fn drain_queues(deadline: Instant) -> io::Result<()> {
+    let channels: Vec<Arc<QueueChannel>> = LIVE_QUEUES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .filter_map(Weak::upgrade)
+        .collect();
+    let mut result = Ok(());
+    for channel in &channels {
+        if let Err(e) = channel.send_batches() {
+            result = result.and(Err(e.into()));
+        }
+    }
+    for channel in &channels {
+        result = result.and(channel.drain(deadline));
+    }
+    result
+}
+
+impl QueueChannel {
+    fn new(sender: QueueSender, receiver: QueueReceiver, capacity: usize) -> Arc<Self> {
+        let channel = Arc::new(QueueChannel {
+            sender,
+            receiver,
+            capacity,
+            pending: Arc::new(Pending::default()),
+            batches: Mutex::new(Vec::new()),
+            collector: Mutex::new(None),
+        });
+        let mut live = LIVE_QUEUES.lock().unwrap_or_else(PoisonError::into_inner);
+        live.retain(|queue| queue.strong_count() > 0);
+        live.push(Arc::downgrade(&channel));
+        channel
+    }
+
+    /// Register a new batch of writes for the current thread.
+    fn new_batch(&self, max_writes: usize) -> Arc<Mutex<Batch>> {
+        let batch = Arc::new(Mutex::new(Batch {
+            overflow: OverflowPolicy::Block,
+            cmds: Vec::with_capacity(max_writes),
+            since: TimeHandle::now(),
+        }));
+        let mut batches = self.batches.lock().unwrap_or_else(PoisonError::into_inner);
+        batches.retain(|batch| batch.strong_count() > 0);
+        batches.push(Arc::downgrade(&batch));
+        batch
+    }
+
+    /// Send the pending writes of every thread, each as a batch.
+    fn send_batches(&self) -> Result<(), QueueError> {
+        let batches: Vec<Arc<Mutex<Batch>>> = self
+            .batches
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        let mut result = Ok(());
+        for batch in batches {
+            let mut batch = batch.lock().unwrap_or_else(PoisonError::into_inner);
+            result = result.and(batch.send(self));
+        }
+        result
+    }
+
+    /// Send the threads' batches at least every `max_delay`, even if their thread stopped writing.
+    fn collect_batches_every(self: &Arc<Self>, max_delay: Duration) {
+        let mut collector = self
+            .collector
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        match collector.as_ref() {
+            Some(handle) if handle.period() <= max_delay => {}
+            Some(handle) => handle.set_period(max_delay),
+            None => {
+                let channel = Arc::downgrade(self);
+                *collector = Some(SCHEDULER.schedule(max_delay, move |_| {
+                    if let Some(channel) = channel.upgrade() {
+                        if let Err(e) = channel.send_batches() {
+                            e.count();
+                            debug!("Failed to send async metrics: {}", e);
+                        }
+                    }
+                }))
+            }
+        }
+    }
+
+    /// Wait until all commands sent so far have been executed, or until the deadline.
+    fn drain(&self, deadline: Instant) -> io::Result<()> {
+        self.pending.wait(deadline)
+    }
+
+    /// Send the command to the worker thread, applying the overflow policy if the queue is full.
+    /// Flushes always wait for room.
+    fn send(&self, policy: OverflowPolicy, cmd: InputQueueCmd) -> Result<(), QueueError> {
+        // counted before it is sent, so that it can not be executed before being counted
+        self.pending.add();
+        let sent = self.send_counted(policy, cmd);
+        // dropped values were uncounted as they were dropped
+        if let Err(QueueError::WorkerDead) = sent {
+            self.pending.done();
+        }
+        sent
+    }
+
+    /// Uncount a command that will never be executed.
+    fn dropped(&self, cmd: &InputQueueCmd) {
+        metrics::QUEUE_DROPPED.mark();
+        cmd.dead_letter();
+        self.pending.done();
+    }
+
+    fn send_counted(&self, policy: OverflowPolicy, cmd: InputQueueCmd) -> Result<(), QueueError> {
+        match self.sender.try_send(cmd) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(cmd)) => {
+                metrics::QUEUE_FULL.mark();
+                match (policy, &cmd) {
+                    (_, InputQueueCmd::Flush(..)) | (OverflowPolicy::Block, _) => {
+                        debug!(
+                            "{}, waiting",
+                            QueueError::Full {
+                                depth: self.capacity
+                            }
+                        );
+                        self.sender.send(cmd).map_err(|_| QueueError::WorkerDead)
+                    }
+                    (OverflowPolicy::DropNewest, _) => {
+                        self.dropped(&cmd);
+                        Err(QueueError::Full {
+                            depth: self.capacity,
+                        })
+                    }
+                    (OverflowPolicy::DropOldest, _) => self.send_dropping_oldest(cmd),
+                }
+            }
+            Err(TrySendError::Disconnected(_)) => Err(QueueError::WorkerDead),
+        }
+    }
+
+    /// Drop the oldest queued values until the command fits in the queue, without waiting.
+    /// Flushes are never dropped, even if oldest: a flush taken from the queue is sent again ahead of the command,
+    /// as a single command taking the room it left.
+    fn send_dropping_oldest(&self, mut cmd: InputQueueCmd) -> Result<(), QueueError> {
+        let mut dropped = false;
+        loop {
+            let oldest = self.try_recv();
+            let popped = oldest.is_some();
+            match oldest {
+                Some(oldest) if oldest.holds_flush() => {
+                    cmd = InputQueueCmd::Batch(vec![oldest, cmd]);
+                    // two counted commands now sent as one
+                    self.pending.done();
+                }
+                Some(oldest) => {
+                    self.dropped(&oldest);
+                    dropped = true;
+                }
+                None => {}
+            }
+            // the worker or other writers may have taken the room, try again then
+            match self.sender.try_send(cmd) {
+                Ok(()) if dropped => {
+                    return Err(QueueError::Full {
+                        depth: self.capacity,
+                    })
+                }
+                Ok(()) => return Ok(()),
+                Err(TrySendError::Full(unsent)) => cmd = unsent,
+                Err(TrySendError::Disconnected(_)) => return Err(QueueError::WorkerDead),
+            }
+            if !popped {
+                // no room to make, e.g. a zero-sized queue
+                if cmd.holds_flush() {
+                    return self.sender.send(cmd).map_err(|_| QueueError::WorkerDead);
+                }
+                self.dropped(&cmd);
+                return Err(QueueError::Full {
+                    depth: self.capacity,
+                });
+            }
+        }
+    }
+
+    /// Take the oldest queued command, if any.
+    #[cfg(not(feature = "crossbeam-channel"))]
+    fn try_recv(&self) -> Option<InputQueueCmd> {
+        self.receiver.lock().unwrap().try_recv().ok()
+    }
+
+    /// Take the oldest queued command, if any.
+    #[cfg(feature = "crossbeam-channel")]
+    fn try_recv(&self) -> Option<InputQueueCmd> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+impl Drop for QueueChannel {
+    fn drop(&mut self) {
+        if let Some(collector) = self
+            .collector
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+        {
+            collector.cancel()
+        }
+    }
+}
 
 /// Wrap this output behind an asynchronous metrics dispatch queue.
 /// This is not strictly required for multi threading since the provided scopes
@@ -26,68 +389,219 @@ pub trait QueuedInput: Input + Send + Sync + 'static + Sized {
     fn queued(self, max_size: usize) -> InputQueue {
         InputQueue::new(self, max_size)
     }
+
+    /// Wrap this output with an asynchronous dispatch queue drained by multiple worker threads,
+    /// each writing to its own scope of this output.
+    fn queued_workers(self, max_size: usize, workers: usize) -> InputQueue {
+        InputQueue::with_workers(self, max_size, workers)
+    }
+}
+
+/// How many writes or how long a thread holds on to before sending them as a batch.
+#[derive(Debug, Clone, Copy)]
+struct Batching {
+    max_writes: usize,
+    max_delay: Duration,
+}
+
+thread_local! {
+    /// Writes waiting to be sent by this thread, by queue channel.
+    static BATCHES: RefCell<HashMap<usize, ThreadBatch>> = RefCell::new(HashMap::new());
+}
+
+/// A thread's writes to a queue, not yet sent.
+/// Locked by the writing thread, or by a flush sending the batches of every thread.
+struct Batch {
+    overflow: OverflowPolicy,
+    cmds: Vec<InputQueueCmd>,
+    since: TimeHandle,
+}
+
+impl Batch {
+    fn send(&mut self, channel: &QueueChannel) -> Result<(), QueueError> {
+        self.since = TimeHandle::now();
+        if self.cmds.is_empty() {
+            return Ok(());
+        }
+        let cmds = mem::take(&mut self.cmds);
+        channel.send(self.overflow, InputQueueCmd::Batch(cmds))
+    }
+}
+
+/// The current thread's batch of writes to a queue.
+struct ThreadBatch {
+    /// Keeps the channel's allocation, and with it the batch's key, from being reused
+    channel: Weak<QueueChannel>,
+    batch: Arc<Mutex<Batch>>,
+}
+
+impl Drop for ThreadBatch {
+    /// Send the writes left when the thread exits.
+    fn drop(&mut self) {
+        if let Some(channel) = self.channel.upgrade() {
+            let mut batch = self.batch.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Err(e) = batch.send(&channel) {
+                e.count();
+                debug!("Failed to send async metrics: {}", e);
+            }
+        }
+    }
+}
+
+/// Send a write command, or add it to the current thread's batch if batching.
+fn dispatch(
+    channel: &Arc<QueueChannel>,
+    overflow: OverflowPolicy,
+    batching: Option<Batching>,
+    cmd: InputQueueCmd,
+) {
+    let result = match batching {
+        None => channel.send(overflow, cmd),
+        Some(batching) => {
+            let mut cmd = Some(cmd);
+            let batched = BATCHES.try_with(|batches| {
+                let mut batches = batches.borrow_mut();
+                let key = Arc::as_ptr(channel) as usize;
+                if !batches.contains_key(&key) {
+                    // forget the batches of dropped queues
+                    batches.retain(|_, batch| batch.channel.strong_count() > 0);
+                    let batch = ThreadBatch {
+                        channel: Arc::downgrade(channel),
+                        batch: channel.new_batch(batching.max_writes),
+                    };
+                    batches.insert(key, batch);
+                }
+                let mut batch = batches[&key]
+                    .batch
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner);
+                batch.overflow = overflow;
+                batch.cmds.extend(cmd.take());
+                if batch.cmds.len() >= batching.max_writes
+                    || batch.since.elapsed_us() >= batching.max_delay.as_micros() as u64
+                {
+                    batch.send(channel)
+                } else {
+                    Ok(())
+                }
+            });
+            match (batched, cmd) {
+                (Ok(result), _) => result,
+                // the thread is exiting, its batches are gone
+                (Err(_), Some(cmd)) => channel.send(overflow, cmd),
+                (Err(_), None) => Ok(()),
+            }
+        }
+    };
+    if let Err(e) = result {
+        e.count();
+        debug!("Failed to send async metrics: {}", e);
+    }
 }
 
+/// A metric defined in the scope of each worker, written by whichever worker executes the command.
+type WorkerMetrics = Arc<[InputMetric]>;
+
+/// The target scopes of each worker, or a single scope shared by all workers.
+type WorkerScopes = Arc<[Arc<dyn InputScope + Send + Sync + 'static>]>;
+
+/// Execute a command on the worker thread of the specified index.
+fn execute(cmd: InputQueueCmd, worker: usize) {
+    match cmd {
+        InputQueueCmd::Write(metrics, value, labels) => {
+            metrics[worker % metrics.len()].write(value, labels)
+        }
+        InputQueueCmd::WriteFloat(metrics, value, labels) => {
+            metrics[worker % metrics.len()].write_f64(value, labels)
+        }
+        InputQueueCmd::WriteWeighted(metrics, value, weight, labels) => {
+            metrics[worker % metrics.len()].write_n(value, weight, labels)
+        }
+        InputQueueCmd::WriteAt(metrics, timestamp, value, labels) => {
+            metrics[worker % metrics.len()].write_at(timestamp, value, labels)
+        }
+        InputQueueCmd::Batch(cmds) => {
+            for cmd in cmds {
+                execute(cmd, worker)
+            }
+        }
+        InputQueueCmd::Flush(scopes, done) => {
+            let mut result = Ok(());
+            for scope in scopes.iter() {
+                if let Err(e) = scope.flush() {
+                    debug!("Could not asynchronously flush metrics: {}", e);
+                    result = result.and(Err(e));
+                }
+            }
+            if let Some(done) = done {
+                done.complete(result)
+            }
+        }
+    }
+}
+
+/// Spawn a worker thread executing received commands until the channel is closed.
+///
 /// # Panics
 ///
 /// Panics if the OS fails to create a thread.
-#[cfg(not(feature = "crossbeam-channel"))]
-fn new_async_channel(length: usize) -> Arc<mpsc::SyncSender<InputQueueCmd>> {
-    let (sender, receiver) = mpsc::sync_channel::<InputQueueCmd>(length);
-
+fn spawn_worker<R>(worker: usize, workers: usize, pending: Arc<Pending>, mut receive: R)
+where
+    R: FnMut() -> Result<InputQueueCmd, String> + Send + 'static,
+{
+    let name = match workers {
+        1 => "dipstick-queue-in".to_string(),
+        _ => format!("dipstick-queue-in-{}", worker),
+    };
     thread::Builder::new()
-        .name("dipstick-queue-in".to_string())
-        .spawn(move || {
-            let mut done = false;
-            while !done {
-                match receiver.recv() {
-                    Ok(InputQueueCmd::Write(metric, value, labels)) => metric.write(value, labels),
-                    Ok(InputQueueCmd::Flush(scope)) => {
-                        if let Err(e) = scope.flush() {
-                            debug!("Could not asynchronously flush metrics: {}", e);
-                        }
-                    }
-                    Err(e) => {
-                        debug!("Async metrics receive loop terminated: {}", e);
-                        // cannot break from within match, use safety pin instead
-                        done = true
-                    }
+        .name(name)
+        .spawn(move || loop {
+            match receive() {
+                Ok(cmd) => {
+                    execute(cmd, worker);
+                    pending.done();
+                }
+                Err(e) => {
+                    debug!("Async metrics receive loop terminated: {}", e);
+                    break;
                 }
             }
         })
         .unwrap(); // TODO: Panic, change API to return Result?
-    Arc::new(sender)
+}
+
+/// # Panics
+///
+/// Panics if the OS fails to create a thread.
+#[cfg(not(feature = "crossbeam-channel"))]
+fn new_async_channel(length: usize, workers: usize) -> Arc<QueueChannel> {
+    let (sender, receiver) = mpsc::sync_channel::<InputQueueCmd>(length);
+    let channel = QueueChannel::new(sender, Arc::new(Mutex::new(receiver)), length);
+    for worker in 0..workers {
+        let worker_receiver = channel.receiver.clone();
+        // the lock is released before executing, other workers and senders only wait for receipt
+        spawn_worker(worker, workers, channel.pending.clone(), move || {
+            let received = worker_receiver.lock().unwrap().recv();
+            received.map_err(|e| e.to_string())
+        });
+    }
+    channel
 }
 
 /// # Panics
 ///
 /// Panics if the OS fails to create a thread.
 #[cfg(feature = "crossbeam-channel")]
-fn new_async_channel(length: usize) -> Arc<crossbeam::Sender<InputQueueCmd>> {
+fn new_async_channel(length: usize, workers: usize) -> Arc<QueueChannel> {
     let (sender, receiver) = crossbeam::bounded::<InputQueueCmd>(length);
-
-    thread::Builder::new()
-        .name("dipstick-queue-in".to_string())
-        .spawn(move || {
-            let mut done = false;
-            while !done {
-                match receiver.recv() {
-                    Ok(InputQueueCmd::Write(metric, value, labels)) => metric.write(value, labels),
-                    Ok(InputQueueCmd::Flush(scope)) => {
-                        if let Err(e) = scope.flush() {
-                            debug!("Could not asynchronously flush metrics: {}", e);
-                        }
-                    }
-                    Err(e) => {
-                        debug!("Async metrics receive loop terminated: {}", e);
-                        // cannot break from within match, use safety pin instead
-                        done = true
-                    }
-                }
-            }
-        })
-        .unwrap(); // TODO: Panic, change API to return Result?
-    Arc::new(sender)
+    let channel = QueueChannel::new(sender, receiver, length);
+    for worker in 0..workers {
+        let worker_receiver = channel.receiver.clone();
+        spawn_worker(worker, workers, channel.pending.clone(), move || {
+            worker_receiver.recv().map_err(|e| e.to_string())
+        });
+    }
+    channel
 }
 
 /// Wrap new scopes with an asynchronous metric write & flush dispatcher.
@@ -95,21 +609,67 @@ fn new_async_channel(length: usize) -> Arc<crossbeam::Sender<InputQueueCmd>> {
 pub struct InputQueue {
     attributes: Attributes,
     target: Arc<dyn InputDyn + Send + Sync + 'static>,
-    #[cfg(not(feature = "crossbeam-channel"))]
-    sender: Arc<mpsc::SyncSender<InputQueueCmd>>,
-    #[cfg(feature = "crossbeam-channel")]
-    sender: Arc<crossbeam::Sender<InputQueueCmd>>,
+    channel: Arc<QueueChannel>,
+    overflow: OverflowPolicy,
+    batching: Option<Batching>,
+    close_timeout: Duration,
+    workers: usize,
 }
 
 impl InputQueue {
     /// Wrap new scopes with an asynchronous metric write & flush dispatcher.
     pub fn new<OUT: Input + Send + Sync + 'static>(target: OUT, queue_length: usize) -> Self {
+        InputQueue::with_workers(target, queue_length, 1)
+    }
+
+    /// Wrap new scopes with an asynchronous dispatcher drained by multiple worker threads,
+    /// to keep up with high write rates to slow outputs. Each worker writes to its own scope of the target,
+    /// flushing a scope flushes those of all workers. Writes are not executed in order.
+    pub fn with_workers<OUT: Input + Send + Sync + 'static>(
+        target: OUT,
+        queue_length: usize,
+        workers: usize,
+    ) -> Self {
+        assert!(workers > 0);
         InputQueue {
             attributes: Attributes::default(),
             target: Arc::new(target),
-            sender: new_async_channel(queue_length),
+            channel: new_async_channel(queue_length, workers),
+            overflow: OverflowPolicy::Block,
+            batching: None,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            workers,
         }
     }
+
+    /// Return a clone applying the specified policy to writes when the queue is full.
+    /// Writes wait for room by default.
+    pub fn overflow(&self, policy: OverflowPolicy) -> Self {
+        let mut cloned = self.clone();
+        cloned.overflow = policy;
+        cloned
+    }
+
+    /// Return a clone waiting up to `timeout` for the queue's pending commands to be executed when closed,
+    /// five seconds by default.
+    pub fn close_timeout(&self, timeout: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.close_timeout = timeout;
+        cloned
+    }
+
+    /// Return a clone sending writes in batches of up to `max_writes` per thread, instead of one by one,
+    /// cutting the channel's synchronization overhead for bursty writers. A thread's batch is sent once full,
+    /// at most `max_delay` after its first write, upon a flush of the scope or upon the thread's exit.
+    pub fn batched(&self, max_writes: usize, max_delay: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.batching = Some(Batching {
+            max_writes,
+            max_delay,
+        });
+        cloned.channel.collect_batches_every(max_delay);
+        cloned
+    }
 }
 
 impl CachedInput for InputQueue {}
@@ -128,11 +688,14 @@ impl Input for InputQueue {
 
     /// Wrap new scopes with an asynchronous metric write & flush dispatcher.
     fn metrics(&self) -> Self::SCOPE {
-        let target_scope = self.target.input_dyn();
+        let targets = (0..self.workers).map(|_| self.target.input_dyn()).collect();
         InputQueueScope {
             attributes: self.attributes.clone(),
-            sender: self.sender.clone(),
-            target: target_scope,
+            channel: self.channel.clone(),
+            overflow: self.overflow,
+            batching: self.batching,
+            close_timeout: self.close_timeout,
+            targets,
         }
     }
 }
@@ -141,21 +704,139 @@ impl Input for InputQueue {
 /// Async commands should be of no concerns to applications.
 pub enum InputQueueCmd {
     /// Send metric write
-    Write(InputMetric, MetricValue, Labels),
-    /// Send metric flush
-    Flush(Arc<dyn InputScope + Send + Sync + 'static>),
+    Write(WorkerMetrics, MetricValue, Labels),
+    /// Send floating point metric write
+    WriteFloat(WorkerMetrics, f64, Labels),
+    /// Send weighted metric write
+    WriteWeighted(WorkerMetrics, MetricValue, usize, Labels),
+    /// Send timestamped metric write
+    WriteAt(WorkerMetrics, SystemTime, MetricValue, Labels),
+    /// Send a batch of commands written by a thread
+    Batch(Vec<InputQueueCmd>),
+    /// Send metric flush, notifying its completion if awaited
+    Flush(WorkerScopes, Option<FlushNotifier>),
+}
+
+impl InputQueueCmd {
+    /// Whether the command is or contains a flush, which must not be dropped.
+    fn holds_flush(&self) -> bool {
+        match self {
+            InputQueueCmd::Flush(..) => true,
+            InputQueueCmd::Batch(cmds) => cmds.iter().any(InputQueueCmd::holds_flush),
+            _ => false,
+        }
+    }
+
+    /// Count the values of a dropped command in the dead letter output.
+    fn dead_letter(&self) {
+        match self {
+            InputQueueCmd::Write(metrics, ..)
+            | InputQueueCmd::WriteFloat(metrics, ..)
+            | InputQueueCmd::WriteWeighted(metrics, ..)
+            | InputQueueCmd::WriteAt(metrics, ..) => {
+                if let Some(metric) = metrics.first() {
+                    dead_letter(DEAD_LETTER_QUEUE_DROPPED, metric.metric_id())
+                }
+            }
+            InputQueueCmd::Batch(cmds) => cmds.iter().for_each(InputQueueCmd::dead_letter),
+            InputQueueCmd::Flush(..) => {}
+        }
+    }
+}
+
+/// Result of an awaited flush, set by the worker thread.
+#[derive(Default)]
+struct FlushSlot {
+    result: Option<io::Result<()>>,
+    waker: Option<Waker>,
+}
+
+/// Where the worker thread and the awaiting caller meet.
+#[derive(Default)]
+struct FlushState {
+    slot: Mutex<FlushSlot>,
+    done: Condvar,
+}
+
+impl FlushState {
+    fn lock(&self) -> MutexGuard<'_, FlushSlot> {
+        self.slot.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// The worker's end of an awaited flush.
+/// A flush dropped without being executed completes with an error, its caller would wait forever otherwise.
+pub struct FlushNotifier(Option<Arc<FlushState>>);
+
+impl FlushNotifier {
+    fn complete(mut self, result: io::Result<()>) {
+        self.notify(result)
+    }
+
+    fn notify(&mut self, result: io::Result<()>) {
+        if let Some(state) = self.0.take() {
+            let mut slot = state.lock();
+            slot.result = Some(result);
+            if let Some(waker) = slot.waker.take() {
+                waker.wake()
+            }
+            state.done.notify_all();
+        }
+    }
+}
+
+impl Drop for FlushNotifier {
+    fn drop(&mut self) {
+        self.notify(Err(QueueError::WorkerDead.into()))
+    }
+}
+
+/// A flush of a queued scope, resolving once the worker thread has actually written and flushed the target.
+/// Can be awaited from async code, or waited upon from any thread.
+pub struct QueueFlush(Arc<FlushState>);
+
+impl QueueFlush {
+    /// Block the current thread until the flush has completed, returning its result.
+    pub fn wait(self) -> io::Result<()> {
+        let mut slot = self.0.lock();
+        loop {
+            if let Some(result) = slot.result.take() {
+                return result;
+            }
+            slot = self
+                .0
+                .done
+                .wait(slot)
+                .unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl Future for QueueFlush {
+    type Output = io::Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.0.lock();
+        match slot.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
 }
 
 /// A metric scope wrapper that sends writes & flushes over a Rust sync channel.
-/// Commands are executed by a background thread.
+/// Commands are executed by background threads.
 #[derive(Clone)]
 pub struct InputQueueScope {
     attributes: Attributes,
-    #[cfg(not(feature = "crossbeam-channel"))]
-    sender: Arc<mpsc::SyncSender<InputQueueCmd>>,
-    #[cfg(feature = "crossbeam-channel")]
-    sender: Arc<crossbeam::Sender<InputQueueCmd>>,
-    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    channel: Arc<QueueChannel>,
+    overflow: OverflowPolicy,
+    batching: Option<Batching>,
+    close_timeout: Duration,
+    targets: WorkerScopes,
 }
 
 impl InputQueueScope {
@@ -164,12 +845,57 @@ impl InputQueueScope {
         target_scope: SC,
         queue_length: usize,
     ) -> Self {
+        InputQueueScope::with_workers(target_scope, queue_length, 1)
+    }
+
+    /// Wrap a scope with an asynchronous dispatcher drained by multiple worker threads.
+    /// All workers write to the same scope, which must tolerate concurrent writes.
+    /// Writes are not executed in order.
+    pub fn with_workers<SC: InputScope + Send + Sync + 'static>(
+        target_scope: SC,
+        queue_length: usize,
+        workers: usize,
+    ) -> Self {
+        assert!(workers > 0);
+        let target: Arc<dyn InputScope + Send + Sync + 'static> = Arc::new(target_scope);
         InputQueueScope {
             attributes: Attributes::default(),
-            sender: new_async_channel(queue_length),
-            target: Arc::new(target_scope),
+            channel: new_async_channel(queue_length, workers),
+            overflow: OverflowPolicy::Block,
+            batching: None,
+            close_timeout: DEFAULT_CLOSE_TIMEOUT,
+            targets: Arc::new([target]),
         }
     }
+
+    /// Return a clone applying the specified policy to writes when the queue is full.
+    /// Writes wait for room by default.
+    pub fn overflow(&self, policy: OverflowPolicy) -> Self {
+        let mut cloned = self.clone();
+        cloned.overflow = policy;
+        cloned
+    }
+
+    /// Return a clone waiting up to `timeout` for the queue's pending commands to be executed when closed,
+    /// five seconds by default.
+    pub fn close_timeout(&self, timeout: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.close_timeout = timeout;
+        cloned
+    }
+
+    /// Return a clone sending writes in batches of up to `max_writes` per thread, instead of one by one,
+    /// cutting the channel's synchronization overhead for bursty writers. A thread's batch is sent once full,
+    /// at most `max_delay` after its first write, upon a flush of the scope or upon the thread's exit.
+    pub fn batched(&self, max_writes: usize, max_delay: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.batching = Some(Batching {
+            max_writes,
+            max_delay,
+        });
+        cloned.channel.collect_batches_every(max_delay);
+        cloned
+    }
 }
 
 impl WithAttributes for InputQueueScope {
@@ -184,28 +910,412 @@ impl WithAttributes for InputQueueScope {
 impl InputScope for InputQueueScope {
     fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
         let name = self.prefix_append(name);
-        let target_metric = self.target.new_metric(name.clone(), kind);
-        let sender = self.sender.clone();
+        let target_metric: WorkerMetrics = self
+            .targets
+            .iter()
+            .map(|target| target.new_metric(name.clone(), kind))
+            .collect();
+        let channel = self.channel.clone();
+        let overflow = self.overflow;
+        let batching = self.batching;
+        let float_metric = target_metric.clone();
+        let float_channel = self.channel.clone();
+        let timestamped_metric = target_metric.clone();
+        let timestamped_channel = self.channel.clone();
+        let weighted_metric = target_metric.clone();
+        let weighted_channel = self.channel.clone();
         InputMetric::new(MetricId::forge("queue", name), move |value, mut labels| {
             labels.save_context();
-            if let Err(e) = sender.send(InputQueueCmd::Write(target_metric.clone(), value, labels))
-            {
-                metrics::SEND_FAILED.mark();
-                debug!("Failed to send async metrics: {}", e);
-            }
+            let cmd = InputQueueCmd::Write(target_metric.clone(), value, labels);
+            dispatch(&channel, overflow, batching, cmd)
+        })
+        .with_float(move |value, mut labels| {
+            labels.save_context();
+            let cmd = InputQueueCmd::WriteFloat(float_metric.clone(), value, labels);
+            dispatch(&float_channel, overflow, batching, cmd)
+        })
+        .with_timestamped(move |timestamp, value, mut labels| {
+            labels.save_context();
+            let cmd = InputQueueCmd::WriteAt(timestamped_metric.clone(), timestamp, value, labels);
+            dispatch(&timestamped_channel, overflow, batching, cmd)
         })
+        .with_weighted(move |value, weight, mut labels| {
+            labels.save_context();
+            let cmd = InputQueueCmd::WriteWeighted(weighted_metric.clone(), value, weight, labels);
+            dispatch(&weighted_channel, overflow, batching, cmd)
+        })
+    }
+}
+
+impl InputQueueScope {
+    /// Flush the scope asynchronously, returning a future that resolves once the worker thread
+    /// has written the values sent before and flushed the target, with the target's flush result.
+    /// With multiple workers, writes still being executed by other workers may complete after the flush.
+    pub fn flush_async(&self) -> QueueFlush {
+        self.notify_flush_listeners();
+        let state = Arc::new(FlushState::default());
+        let notifier = FlushNotifier(Some(state.clone()));
+        let cmd = InputQueueCmd::Flush(self.targets.clone(), Some(notifier));
+        // the writes batched by every thread precede the flush, which is sent even if some were dropped
+        let batched = self.channel.send_batches();
+        // the notifier of an unsent flush completes it with an error when dropped
+        if let Err(e) = self.channel.send(self.overflow, cmd).and(batched) {
+            e.count();
+            debug!("Failed to flush async metrics: {}", e);
+        }
+        QueueFlush(state)
     }
 }
 
 impl Flush for InputQueueScope {
     fn flush(&self) -> io::Result<()> {
         self.notify_flush_listeners();
-        if let Err(e) = self.sender.send(InputQueueCmd::Flush(self.target.clone())) {
-            metrics::SEND_FAILED.mark();
+        let cmd = InputQueueCmd::Flush(self.targets.clone(), None);
+        // the writes batched by every thread precede the flush, which is sent even if some were dropped
+        let batched = self.channel.send_batches();
+        if let Err(e) = self.channel.send(self.overflow, cmd).and(batched) {
+            e.count();
             debug!("Failed to flush async metrics: {}", e);
-            Err(io::Error::new(io::ErrorKind::Other, e))
+            Err(e.into())
         } else {
             Ok(())
         }
     }
+
+    /// Flush, then wait for all commands sent to the queue so far to be executed, up to the close timeout.
+    /// Returns a `TimedOut` error if commands were still pending after the timeout.
+    fn close(&self) -> io::Result<()> {
+        let deadline = Instant::now() + self.close_timeout;
+        self.flush()?;
+        self.channel.drain(deadline)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::atomic::AtomicBucket;
+    use crate::attributes::MetricId;
+    use crate::input::ShareScope;
+    use crate::output::map::StatsMapScope;
+    use crate::output::void::Void;
+    use crate::stats::stats_all;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::{mpsc, Mutex};
+
+    /// Records written values once the gate is open, counting writes started.
+    #[derive(Clone, Default)]
+    struct GatedScope {
+        gate: Arc<Mutex<()>>,
+        started: Arc<AtomicUsize>,
+        values: Arc<Mutex<Vec<MetricValue>>>,
+        /// Count of values recorded at each flush.
+        flushed: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl InputScope for GatedScope {
+        fn new_metric(&self, name: MetricName, _kind: InputKind) -> InputMetric {
+            let scope = self.clone();
+            InputMetric::new(MetricId::forge("gated", name), move |value, _labels| {
+                scope.started.fetch_add(1, SeqCst);
+                let _open = scope.gate.lock().unwrap();
+                scope.values.lock().unwrap().push(value);
+            })
+        }
+    }
+
+    impl GatedScope {
+        /// Wait up to a second for the count of values to be recorded.
+        fn wait_for(&self, count: usize) {
+            for _ in 0..1000 {
+                if self.values.lock().unwrap().len() >= count {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(1))
+            }
+        }
+    }
+
+    impl ShareScope for GatedScope {}
+
+    impl Flush for GatedScope {
+        fn flush(&self) -> io::Result<()> {
+            let recorded = self.values.lock().unwrap().len();
+            self.flushed.lock().unwrap().push(recorded);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn drop_newest_when_full() {
+        let target = GatedScope::default();
+        let closed = target.gate.lock().unwrap();
+        let queue = InputQueueScope::wrap(target.clone(), 1).overflow(OverflowPolicy::DropNewest);
+        let gauge = queue.gauge("depth");
+
+        gauge.value(1);
+        while target.started.load(SeqCst) == 0 {
+            thread::yield_now()
+        }
+        // the worker is stuck writing 1, 2 fills the queue
+        gauge.value(2);
+        gauge.value(3);
+        drop(closed);
+
+        target.wait_for(2);
+        thread::sleep(Duration::from_millis(10));
+        assert_eq!(vec![1, 2], *target.values.lock().unwrap());
+    }
+
+    #[test]
+    fn drop_oldest_keeps_flush() {
+        let target = GatedScope::default();
+        let closed = target.gate.lock().unwrap();
+        let queue = InputQueueScope::wrap(target.clone(), 1).overflow(OverflowPolicy::DropOldest);
+        let gauge = queue.gauge("depth");
+
+        gauge.value(1);
+        while target.started.load(SeqCst) == 0 {
+            thread::yield_now()
+        }
+        // the worker is stuck writing 1, the flush fills the queue
+        queue.flush().unwrap();
+        // sent along with the flush it made room from, without waiting for the worker
+        gauge.value(2);
+        drop(closed);
+
+        target.wait_for(2);
+        assert_eq!(vec![1, 2], *target.values.lock().unwrap());
+        assert_eq!(vec![1], *target.flushed.lock().unwrap());
+    }
+
+    #[test]
+    fn drop_oldest_when_full() {
+        let target = GatedScope::default();
+        let closed = target.gate.lock().unwrap();
+        let queue = InputQueueScope::wrap(target.clone(), 1).overflow(OverflowPolicy::DropOldest);
+        let gauge = queue.gauge("depth");
+
+        gauge.value(1);
+        while target.started.load(SeqCst) == 0 {
+            thread::yield_now()
+        }
+        // the worker is stuck writing 1, 2 fills the queue
+        gauge.value(2);
+        gauge.value(3);
+        drop(closed);
+
+        for _ in 0..1000 {
+            if target.values.lock().unwrap().len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1))
+        }
+        assert_eq!(vec![1, 3], *target.values.lock().unwrap());
+    }
+
+    #[test]
+    fn dropped_value_is_full() {
+        let target = GatedScope::default();
+        let closed = target.gate.lock().unwrap();
+        let queue = InputQueueScope::wrap(target.clone(), 1).overflow(OverflowPolicy::DropNewest);
+        let gauge = queue.gauge("depth");
+
+        gauge.value(1);
+        while target.started.load(SeqCst) == 0 {
+            thread::yield_now()
+        }
+        // the worker is stuck writing 1, 2 fills the queue
+        gauge.value(2);
+        let dropped = queue
+            .channel
+            .send(OverflowPolicy::DropNewest, InputQueueCmd::Batch(Vec::new()));
+        assert_eq!(Err(QueueError::Full { depth: 1 }), dropped);
+        drop(closed);
+
+        target.wait_for(2);
+        assert_eq!(vec![1, 2], *target.values.lock().unwrap());
+    }
+
+    #[test]
+    fn queue_error_kinds() {
+        let full: io::Error = QueueError::Full { depth: 8 }.into();
+        assert_eq!(io::ErrorKind::WouldBlock, full.kind());
+        assert_eq!("metrics queue full (8 pending)", full.to_string());
+
+        let dead: io::Error = QueueError::WorkerDead.into();
+        assert_eq!(io::ErrorKind::BrokenPipe, dead.kind());
+        let inner = dead.get_ref().and_then(|e| e.downcast_ref::<QueueError>());
+        assert_eq!(Some(&QueueError::WorkerDead), inner);
+    }
+
+    #[test]
+    fn concurrent_workers() {
+        let target = GatedScope::default();
+        let closed = target.gate.lock().unwrap();
+        let queue = InputQueueScope::with_workers(target.clone(), 8, 2);
+        let counter = queue.counter("hits");
+
+        counter.count(1);
+        counter.count(2);
+        // both workers are stuck writing at once
+        while target.started.load(SeqCst) < 2 {
+            thread::yield_now()
+        }
+        drop(closed);
+
+        for _ in 0..1000 {
+            if target.values.lock().unwrap().len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1))
+        }
+        let mut values = target.values.lock().unwrap().clone();
+        values.sort();
+        assert_eq!(vec![1, 2], values);
+    }
+
+    #[test]
+    fn close_waits_for_writes() {
+        let target = GatedScope::default();
+        let queue = InputQueueScope::with_workers(target.clone(), 64, 2);
+        let counter = queue.counter("hits");
+        for i in 0..50 {
+            counter.count(i)
+        }
+        queue.close().unwrap();
+        assert_eq!(50, target.values.lock().unwrap().len());
+    }
+
+    #[test]
+    fn close_times_out() {
+        let target = GatedScope::default();
+        let queue =
+            InputQueueScope::wrap(target.clone(), 64).close_timeout(Duration::from_millis(20));
+        let closed = target.gate.lock().unwrap();
+        queue.counter("hits").count(1);
+        let err = queue.close().unwrap_err();
+        assert_eq!(io::ErrorKind::TimedOut, err.kind());
+        drop(closed);
+        queue.close().unwrap();
+        assert_eq!(vec![1], *target.values.lock().unwrap());
+    }
+
+    #[test]
+    fn flush_async_wait() {
+        let target = GatedScope::default();
+        let queue = InputQueueScope::wrap(target.clone(), 64);
+        let counter = queue.counter("hits");
+        for i in 0..10 {
+            counter.count(i)
+        }
+        queue.flush_async().wait().unwrap();
+        assert_eq!(10, target.values.lock().unwrap().len());
+    }
+
+    /// Wakes up the thread polling a future.
+    struct ThreadWaker(thread::Thread);
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark()
+        }
+    }
+
+    #[test]
+    fn flush_async_future() {
+        let target = GatedScope::default();
+        let closed = target.gate.lock().unwrap();
+        let queue = InputQueueScope::wrap(target.clone(), 64);
+        queue.counter("hits").count(1);
+
+        let mut flush = Box::pin(queue.flush_async());
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        // the worker is stuck writing
+        assert!(flush.as_mut().poll(&mut cx).is_pending());
+        drop(closed);
+        loop {
+            if let Poll::Ready(result) = flush.as_mut().poll(&mut cx) {
+                result.unwrap();
+                break;
+            }
+            thread::park()
+        }
+        assert_eq!(vec![1], *target.values.lock().unwrap());
+    }
+
+    #[test]
+    fn scope_per_worker() {
+        let queue = InputQueue::with_workers(Void::new(), 8, 3);
+        assert_eq!(3, queue.metrics().targets.len());
+    }
+
+    #[test]
+    fn batched_writes() {
+        let target = GatedScope::default();
+        let queue = InputQueueScope::wrap(target.clone(), 8).batched(3, Duration::from_secs(60));
+        let counter = queue.counter("hits");
+
+        counter.count(1);
+        counter.count(2);
+        thread::sleep(Duration::from_millis(10));
+        assert!(target.values.lock().unwrap().is_empty());
+        counter.count(3);
+        target.wait_for(3);
+        counter.count(4);
+        queue.flush().unwrap();
+        target.wait_for(4);
+        assert_eq!(vec![1, 2, 3, 4], *target.values.lock().unwrap());
+    }
+
+    #[test]
+    fn flush_sends_batches_of_idle_threads() {
+        let target = GatedScope::default();
+        let queue = InputQueueScope::wrap(target.clone(), 8).batched(10, Duration::from_secs(60));
+        let counter = queue.counter("hits");
+        let (written, wait_written) = mpsc::channel();
+        let (done, wait_done) = mpsc::channel::<()>();
+        let writer = thread::spawn(move || {
+            counter.count(1);
+            counter.count(2);
+            written.send(()).unwrap();
+            // stay alive, holding the batch
+            let _ = wait_done.recv();
+        });
+
+        wait_written.recv().unwrap();
+        queue.close().unwrap();
+        assert_eq!(vec![1, 2], *target.values.lock().unwrap());
+        done.send(()).unwrap();
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn batch_sent_after_max_delay() {
+        let target = GatedScope::default();
+        let queue = InputQueueScope::wrap(target.clone(), 8).batched(10, Duration::from_millis(20));
+        queue.counter("hits").count(1);
+        target.wait_for(1);
+        assert_eq!(vec![1], *target.values.lock().unwrap());
+    }
+
+    #[test]
+    fn weighted_values_sent_once() {
+        let bucket = AtomicBucket::new();
+        bucket.stats(stats_all);
+        let queue = InputQueueScope::wrap(bucket.clone(), 8);
+        queue.counter("bytes").count_n(3, 4);
+        // wait for the write without flushing the bucket
+        queue
+            .channel
+            .drain(Instant::now() + Duration::from_secs(5))
+            .unwrap();
+
+        let map = StatsMapScope::default();
+        bucket.flush_to(&map).unwrap();
+        let map = map.into_map();
+        assert_eq!(4, map["bytes.count"]);
+        assert_eq!(12, map["bytes.sum"]);
+    }
 }
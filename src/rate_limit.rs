@@ -0,0 +1,297 @@
+//! Cap how many values of each metric are written per second, e.g. to protect log or line outputs
+//! from runaway loops. Values beyond the cap are dropped and counted by the `rate_limit.dropped` self-metric.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::clock::TimeHandle;
+use crate::dead_letter::{dead_letter, DEAD_LETTER_RATE_LIMITED};
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::metrics;
+use crate::name::MetricName;
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex, PoisonError};
+
+/// Cap how many values of each metric are written per second.
+pub trait RateLimitedInput: Input + Send + Sync + 'static + Sized {
+    /// Wrap this input with a decorator writing at most `writes_per_sec` values of each metric per second.
+    fn rate_limited(self, writes_per_sec: f64) -> InputRateLimit {
+        InputRateLimit::wrap(self, writes_per_sec)
+    }
+}
+
+impl<T: Input + Send + Sync + 'static> RateLimitedInput for T {}
+
+/// The token buckets of each metric, shared by all the scopes of a decorator.
+type Buckets = Arc<Mutex<HashMap<MetricId, Arc<TokenBucket>>>>;
+
+/// Rate and burst of a decorator, with the token buckets of its metrics.
+#[derive(Clone)]
+struct RateLimit {
+    writes_per_sec: f64,
+    burst: f64,
+    buckets: Buckets,
+}
+
+impl RateLimit {
+    /// Up to one second worth of writes can be made in a burst.
+    fn new(writes_per_sec: f64) -> Self {
+        RateLimit {
+            writes_per_sec,
+            burst: writes_per_sec.max(1.0),
+            buckets: Buckets::default(),
+        }
+    }
+
+    /// Buckets filled to the previous burst size are not reused.
+    fn burst(&self, writes: f64) -> Self {
+        RateLimit {
+            writes_per_sec: self.writes_per_sec,
+            burst: writes.max(1.0),
+            buckets: Buckets::default(),
+        }
+    }
+
+    /// Let the values of the target metric through as long as its bucket has tokens, one per value written.
+    fn limit(&self, name: MetricName, target: InputMetric) -> InputMetric {
+        let bucket = self
+            .buckets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(target.metric_id().clone())
+            .or_insert_with_key(|id| {
+                Arc::new(TokenBucket::new(
+                    id.clone(),
+                    self.writes_per_sec,
+                    self.burst,
+                ))
+            })
+            .clone();
+        InputMetric::gated(MetricId::forge("rate_limit", name), target, move |weight| {
+            bucket.acquire_n(weight)
+        })
+    }
+}
+
+/// Input decorator capping how many values of each metric are written per second.
+#[derive(Clone)]
+pub struct InputRateLimit {
+    attributes: Attributes,
+    target: Arc<dyn InputDyn + Send + Sync + 'static>,
+    limit: RateLimit,
+}
+
+impl InputRateLimit {
+    /// Wrap an input with a decorator writing at most `writes_per_sec` values of each metric per second.
+    /// Up to one second worth of writes can be made in a burst.
+    pub fn wrap<OUT: Input + Send + Sync + 'static>(
+        target: OUT,
+        writes_per_sec: f64,
+    ) -> InputRateLimit {
+        InputRateLimit {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            limit: RateLimit::new(writes_per_sec),
+        }
+    }
+
+    /// Returns a clone of the decorator allowing up to `writes` values of a metric to be written in a burst,
+    /// before being limited to its rate.
+    pub fn burst(&self, writes: f64) -> Self {
+        let mut cloned = self.clone();
+        cloned.limit = self.limit.burst(writes);
+        cloned
+    }
+}
+
+impl QueuedInput for InputRateLimit {}
+impl CachedInput for InputRateLimit {}
+
+impl WithAttributes for InputRateLimit {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for InputRateLimit {
+    type SCOPE = InputRateLimitScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        InputRateLimitScope {
+            attributes: self.attributes.clone(),
+            target: self.target.input_dyn(),
+            limit: self.limit.clone(),
+        }
+    }
+}
+
+/// Scope decorator capping how many values of each metric are written per second.
+/// Each metric has its own token bucket, shared by all definitions of that metric.
+/// A value written with `write_n` takes as many tokens as its weight.
+/// Values written beyond the rate are dropped and counted by the `rate_limit.dropped` self-metric.
+#[derive(Clone)]
+pub struct InputRateLimitScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    limit: RateLimit,
+}
+
+impl InputRateLimitScope {
+    /// Wrap a scope with a decorator writing at most `writes_per_sec` values of each metric per second.
+    /// Up to one second worth of writes can be made in a burst.
+    pub fn wrap<SC: InputScope + Send + Sync + 'static>(target: SC, writes_per_sec: f64) -> Self {
+        InputRateLimitScope {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            limit: RateLimit::new(writes_per_sec),
+        }
+    }
+
+    /// Returns a clone of the decorator allowing up to `writes` values of a metric to be written in a burst,
+    /// before being limited to its rate.
+    pub fn burst(&self, writes: f64) -> Self {
+        let mut cloned = self.clone();
+        cloned.limit = self.limit.burst(writes);
+        cloned
+    }
+}
+
+impl WithAttributes for InputRateLimitScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for InputRateLimitScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let target = self.target.new_metric(name.clone(), kind);
+        self.limit.limit(name, target)
+    }
+}
+
+impl Flush for InputRateLimitScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+/// Tokens are added at a steady rate up to the burst size, each write takes one per unit of weight.
+struct TokenBucket {
+    metric: MetricId,
+    writes_per_sec: f64,
+    burst: f64,
+    /// Tokens left and when they were counted
+    tokens: Mutex<(f64, TimeHandle)>,
+}
+
+impl TokenBucket {
+    fn new(metric: MetricId, writes_per_sec: f64, burst: f64) -> Self {
+        TokenBucket {
+            metric,
+            writes_per_sec,
+            burst,
+            tokens: Mutex::new((burst, TimeHandle::now())),
+        }
+    }
+
+    /// Take as many tokens as the weight if there are enough left, or count the dropped write.
+    /// A write weighing more than the burst goes through once the bucket is full,
+    /// leaving a debt that the rate pays back before the next write.
+    fn acquire_n(&self, weight: usize) -> bool {
+        let weight = weight as f64;
+        let acquired = {
+            let mut tokens = self.tokens.lock().unwrap_or_else(PoisonError::into_inner);
+            let (left, since) = *tokens;
+            let refill = self.writes_per_sec * since.elapsed_us() as f64 / 1_000_000.0;
+            let left = (left + refill).min(self.burst);
+            if left >= weight.min(self.burst) {
+                *tokens = (left - weight, TimeHandle::now());
+                true
+            } else {
+                *tokens = (left, TimeHandle::now());
+                false
+            }
+        };
+        if !acquired {
+            metrics::RATE_LIMITED.mark();
+            dead_letter(DEAD_LETTER_RATE_LIMITED, &self.metric);
+        }
+        acquired
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{mock_clock_advance, mock_clock_reset};
+    use crate::output::map::StatsMapScope;
+    use crate::output::void::Void;
+
+    use std::time::Duration;
+
+    #[test]
+    fn limit_writes() {
+        mock_clock_reset();
+        let void = Void::counting();
+        let metrics = InputRateLimitScope::wrap(void.metrics(), 10.0).burst(5.0);
+        let marker = metrics.marker("loop");
+
+        for _ in 0..100 {
+            marker.mark();
+        }
+        assert_eq!(5, void.discarded(InputKind::Marker));
+
+        mock_clock_advance(Duration::from_millis(500));
+        for _ in 0..100 {
+            marker.mark();
+        }
+        assert_eq!(10, void.discarded(InputKind::Marker));
+    }
+
+    #[test]
+    fn shared_by_definitions() {
+        mock_clock_reset();
+        let map = StatsMapScope::default();
+        let metrics = InputRateLimitScope::wrap(map.clone(), 1.0);
+
+        metrics.gauge("gauge").value(1);
+        metrics.gauge("gauge").value(2);
+        assert_eq!(map.into_map()["gauge"], 1);
+    }
+
+    #[test]
+    fn weighted_writes() {
+        mock_clock_reset();
+        let void = Void::counting();
+        let metrics = InputRateLimitScope::wrap(void.metrics(), 10.0).burst(5.0);
+        let marker = metrics.marker("loop");
+
+        marker.write_n(1, 3, labels![]);
+        // 2 tokens left
+        marker.write_n(1, 3, labels![]);
+        marker.write_n(1, 2, labels![]);
+        assert_eq!(5, void.discarded(InputKind::Marker));
+
+        // heavier than the burst, once full
+        mock_clock_advance(Duration::from_millis(500));
+        marker.write_n(1, 8, labels![]);
+        assert_eq!(13, void.discarded(InputKind::Marker));
+
+        // debt of 3 tokens paid back
+        mock_clock_advance(Duration::from_millis(200));
+        marker.mark();
+        assert_eq!(13, void.discarded(InputKind::Marker));
+        mock_clock_advance(Duration::from_millis(200));
+        marker.mark();
+        assert_eq!(14, void.discarded(InputKind::Marker));
+    }
+}
@@ -0,0 +1,100 @@
+//! Outputs registered by name, so that wiring can be set up far from where metrics are defined.
+
+use crate::input::InputScope;
+use crate::proxy::Proxy;
+
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::io;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+lazy_static! {
+    static ref REGISTRY: Registry = Registry::default();
+}
+
+/// Return the global registry of named outputs.
+pub fn registry() -> &'static Registry {
+    &REGISTRY
+}
+
+/// Outputs addressable by name.
+/// Each name stands for a proxy that can be targeted before an output is registered under that name,
+/// and keeps its metrics as the output registered under that name changes.
+#[derive(Debug, Default)]
+pub struct Registry {
+    outputs: RwLock<HashMap<String, Proxy>>,
+}
+
+impl Registry {
+    /// Register the output under the name, replacing any output previously registered under that name.
+    /// Metrics already sent to the name write to the new output once it is registered,
+    /// the previous output is then flushed so that the values it buffered are not lost.
+    pub fn set<T: InputScope + Send + Sync + 'static>(
+        &self,
+        name: &str,
+        output: T,
+    ) -> io::Result<()> {
+        self.get(name).swap_target(output)
+    }
+
+    /// Unregister any output registered under the name.
+    /// Metrics sent to the name are discarded until another output is registered.
+    pub fn unset(&self, name: &str) {
+        if let Some(proxy) = read_lock!(self.outputs).get(name) {
+            proxy.unset_target()
+        }
+    }
+
+    /// Return the proxy sending metrics to the output registered under the name,
+    /// or discarding them until an output is registered under that name.
+    pub fn get(&self, name: &str) -> Proxy {
+        if let Some(proxy) = read_lock!(self.outputs).get(name) {
+            return proxy.clone();
+        }
+        // not Proxy::default(), which is the shared root proxy
+        match write_lock!(self.outputs).entry(name.to_string()) {
+            Entry::Occupied(entry) => entry.get().clone(),
+            Entry::Vacant(entry) => entry.insert(Proxy::new()).clone(),
+        }
+    }
+
+    /// The names referenced so far, whether or not an output is registered under them.
+    pub fn names(&self) -> Vec<String> {
+        read_lock!(self.outputs).keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn target_by_name() {
+        let proxy = Proxy::new();
+        proxy.target_named("test_registry_main");
+        let counter = proxy.counter("requests");
+        counter.count(1);
+
+        let first = StatsMapScope::default();
+        registry().set("test_registry_main", first.clone()).unwrap();
+        counter.count(2);
+
+        let second = StatsMapScope::default();
+        registry()
+            .set("test_registry_main", second.clone())
+            .unwrap();
+        counter.count(3);
+
+        assert_eq!(first.into_map()["requests"], 2);
+        assert_eq!(second.into_map()["requests"], 3);
+        assert!(registry()
+            .names()
+            .contains(&"test_registry_main".to_string()));
+    }
+}
@@ -0,0 +1,314 @@
+//! Rename, re-prefix or discard metrics as they are defined,
+//! e.g. to adapt library-emitted names to an organization's naming convention.
+//! Names are rewritten once when a metric is defined, writing values costs nothing more.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::io;
+use std::sync::Arc;
+
+/// Rewrite metric names before they reach the output.
+pub trait RewrittenInput: Input + Send + Sync + 'static + Sized {
+    /// Wrap this input with a decorator rewriting metric names.
+    /// Names are left unchanged until rules are added with `rename()`, `replace_prefix()` or `discard()`.
+    fn rewritten(self) -> InputRewrite {
+        InputRewrite::wrap(self)
+    }
+}
+
+impl<T: Input + Send + Sync + 'static> RewrittenInput for T {}
+
+/// A name rewriting rule.
+/// Patterns are dot-separated names where `*` matches any one segment
+/// and `**` matches any number of segments, including none.
+#[derive(Debug, Clone)]
+enum Rule {
+    Rename(Vec<String>, Vec<String>),
+    ReplacePrefix(Vec<String>, Vec<String>),
+    Discard(Vec<String>),
+}
+
+/// Split a dot-separated name or pattern into its segments.
+pub(crate) fn segments(name: &str) -> Vec<String> {
+    name.split('.')
+        .filter(|segment| !segment.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// True if the name segments match the pattern segments,
+/// where `*` matches any one segment and `**` any number of segments.
+pub(crate) fn matches(pattern: &[String], name: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some((any, rest)) if any == "**" => (0..=name.len()).any(|i| matches(rest, &name[i..])),
+        Some((segment, rest)) => match name.split_first() {
+            Some((name_segment, name_rest)) => {
+                (segment == "*" || segment == name_segment) && matches(rest, name_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+fn to_name<'a>(mut segments: impl Iterator<Item = &'a str>) -> MetricName {
+    let mut name = MetricName::from(segments.next().expect("Rewritten metric name"));
+    segments.for_each(|segment| name.push_back(segment.to_string()));
+    name
+}
+
+/// The rules of a decorator, shared by its scopes.
+#[derive(Debug, Clone, Default)]
+struct Rules(Arc<Vec<Rule>>);
+
+impl Rules {
+    fn with(&self, rule: Rule) -> Self {
+        let mut cloned = self.clone();
+        Arc::make_mut(&mut cloned.0).push(rule);
+        cloned
+    }
+
+    /// A rule renaming metrics to an empty name is ignored.
+    fn rename(&self, pattern: &str, name: &str) -> Self {
+        let name = segments(name);
+        if name.is_empty() {
+            warn!(
+                "Ignoring rule renaming metrics matching {} to an empty name",
+                pattern
+            );
+            return self.clone();
+        }
+        self.with(Rule::Rename(segments(pattern), name))
+    }
+
+    fn replace_prefix(&self, prefix: &str, replacement: &str) -> Self {
+        self.with(Rule::ReplacePrefix(segments(prefix), segments(replacement)))
+    }
+
+    fn discard(&self, pattern: &str) -> Self {
+        self.with(Rule::Discard(segments(pattern)))
+    }
+
+    /// Apply the first matching rule to the name, returning `None` if the metric is discarded.
+    fn rewrite(&self, name: MetricName) -> Option<MetricName> {
+        let joined = name.join(".");
+        let parts: Vec<&str> = joined.split('.').collect();
+        for rule in self.0.iter() {
+            match rule {
+                Rule::Rename(pattern, to) if matches(pattern, &parts) => {
+                    return Some(to_name(to.iter().map(|s| &**s)))
+                }
+                Rule::ReplacePrefix(prefix, to)
+                    if parts.len() > prefix.len() && matches(prefix, &parts[..prefix.len()]) =>
+                {
+                    let rest = parts[prefix.len()..].iter().copied();
+                    return Some(to_name(to.iter().map(|s| &**s).chain(rest)));
+                }
+                Rule::Discard(pattern) if matches(pattern, &parts) => return None,
+                _ => {}
+            }
+        }
+        Some(name)
+    }
+}
+
+/// Input decorator rewriting metric names.
+#[derive(Clone)]
+pub struct InputRewrite {
+    attributes: Attributes,
+    target: Arc<dyn InputDyn + Send + Sync + 'static>,
+    rules: Rules,
+}
+
+impl InputRewrite {
+    /// Wrap an input with a name rewriting decorator.
+    /// Names are left unchanged until rules are added.
+    pub fn wrap<OUT: Input + Send + Sync + 'static>(target: OUT) -> InputRewrite {
+        InputRewrite {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            rules: Rules::default(),
+        }
+    }
+
+    /// Returns a clone of the decorator renaming metrics matching the pattern, e.g. `http.req*`.
+    /// Rules are tried in the order they were added, the first matching rule applies.
+    /// Renaming to an empty name is ignored.
+    pub fn rename(&self, pattern: &str, name: &str) -> Self {
+        let mut cloned = self.clone();
+        cloned.rules = self.rules.rename(pattern, name);
+        cloned
+    }
+
+    /// Returns a clone of the decorator replacing the leading segments of metric names
+    /// matching the prefix pattern, e.g. `hyper` with `org.web`. An empty replacement strips the prefix.
+    /// Rules are tried in the order they were added, the first matching rule applies.
+    pub fn replace_prefix(&self, prefix: &str, replacement: &str) -> Self {
+        let mut cloned = self.clone();
+        cloned.rules = self.rules.replace_prefix(prefix, replacement);
+        cloned
+    }
+
+    /// Returns a clone of the decorator discarding metrics matching the pattern, e.g. `debug.**`.
+    /// Rules are tried in the order they were added, the first matching rule applies.
+    pub fn discard(&self, pattern: &str) -> Self {
+        let mut cloned = self.clone();
+        cloned.rules = self.rules.discard(pattern);
+        cloned
+    }
+}
+
+impl QueuedInput for InputRewrite {}
+impl CachedInput for InputRewrite {}
+
+impl WithAttributes for InputRewrite {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for InputRewrite {
+    type SCOPE = InputRewriteScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        InputRewriteScope {
+            attributes: self.attributes.clone(),
+            target: self.target.input_dyn(),
+            rules: self.rules.clone(),
+        }
+    }
+}
+
+/// Scope decorator rewriting metric names.
+#[derive(Clone)]
+pub struct InputRewriteScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    rules: Rules,
+}
+
+impl InputRewriteScope {
+    /// Wrap a scope with a name rewriting decorator.
+    pub fn wrap<SC: InputScope + Send + Sync + 'static>(target: SC) -> Self {
+        InputRewriteScope {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            rules: Rules::default(),
+        }
+    }
+
+    /// Returns a clone of the decorator renaming metrics matching the pattern, e.g. `http.req*`.
+    /// Rules are tried in the order they were added, the first matching rule applies.
+    /// Renaming to an empty name is ignored.
+    pub fn rename(&self, pattern: &str, name: &str) -> Self {
+        let mut cloned = self.clone();
+        cloned.rules = self.rules.rename(pattern, name);
+        cloned
+    }
+
+    /// Returns a clone of the decorator replacing the leading segments of metric names
+    /// matching the prefix pattern, e.g. `hyper` with `org.web`. An empty replacement strips the prefix.
+    /// Rules are tried in the order they were added, the first matching rule applies.
+    pub fn replace_prefix(&self, prefix: &str, replacement: &str) -> Self {
+        let mut cloned = self.clone();
+        cloned.rules = self.rules.replace_prefix(prefix, replacement);
+        cloned
+    }
+
+    /// Returns a clone of the decorator discarding metrics matching the pattern, e.g. `debug.**`.
+    /// Rules are tried in the order they were added, the first matching rule applies.
+    pub fn discard(&self, pattern: &str) -> Self {
+        let mut cloned = self.clone();
+        cloned.rules = self.rules.discard(pattern);
+        cloned
+    }
+}
+
+impl WithAttributes for InputRewriteScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for InputRewriteScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        match self.rules.rewrite(name.clone()) {
+            // renamed once, no indirection
+            Some(rewritten) => self.target.new_metric(rewritten, kind),
+            None => InputMetric::new(MetricId::forge("rewrite", name), |_value, _labels| {}),
+        }
+    }
+}
+
+impl Flush for InputRewriteScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn pattern_matching() {
+        let name = ["http", "requests", "count"];
+        assert!(matches(&segments("http.requests.count"), &name));
+        assert!(matches(&segments("http.*.count"), &name));
+        assert!(matches(&segments("http.**"), &name));
+        assert!(matches(&segments("**.count"), &name));
+        assert!(matches(&segments("http.**.requests.count"), &name));
+        assert!(!matches(&segments("http.*"), &name));
+        assert!(!matches(&segments("db.**"), &name));
+    }
+
+    #[test]
+    fn first_matching_rule_applies() {
+        let map = StatsMapScope::default();
+        let metrics = InputRewriteScope::wrap(map.clone())
+            .discard("hyper.debug.**")
+            .rename("hyper.req_count", "http.requests")
+            .replace_prefix("hyper", "org.web")
+            .replace_prefix("legacy", "");
+
+        metrics.counter("hyper.req_count").count(1);
+        metrics.named("hyper").counter("conn_open").count(2);
+        metrics
+            .named("hyper")
+            .add_name("debug")
+            .counter("polls")
+            .count(3);
+        metrics.counter("legacy.errors").count(4);
+        metrics.counter("other").count(5);
+
+        let map = map.into_map();
+        assert_eq!(map["http.requests"], 1);
+        assert_eq!(map["org.web.conn_open"], 2);
+        assert_eq!(map["errors"], 4);
+        assert_eq!(map["other"], 5);
+        assert_eq!(map.len(), 4);
+    }
+
+    #[test]
+    fn empty_rename_ignored() {
+        let map = StatsMapScope::default();
+        let metrics = InputRewriteScope::wrap(map.clone())
+            .rename("hyper.**", "")
+            .rename("hyper.req_count", "http.requests");
+
+        metrics.counter("hyper.req_count").count(1);
+        assert_eq!(map.into_map()["http.requests"], 1);
+    }
+}
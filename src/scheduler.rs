@@ -1,14 +1,18 @@
 //! Task scheduling facilities.
 
 use crate::input::InputScope;
+use crate::pcg32;
+use crate::Flush;
 
-use std::cmp::{max, Ordering};
+use std::cmp::Ordering;
 use std::collections::BinaryHeap;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering::SeqCst;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::{Arc, Condvar, Mutex, PoisonError, Weak};
 use std::thread;
-use std::time::{Duration, Instant};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// A guard canceling the inner handle when dropped.
 ///
@@ -53,26 +57,129 @@ pub trait Cancel {
     }
 }
 
-/// A handle to cancel a scheduled task if required.
+/// A handle to cancel or reschedule a scheduled task if required.
 #[derive(Debug, Clone)]
-pub struct CancelHandle(Arc<AtomicBool>);
+pub struct CancelHandle(Arc<TaskState>);
+
+/// State of a scheduled task shared with its handles.
+#[derive(Debug)]
+struct TaskState {
+    cancelled: AtomicBool,
+    period: Mutex<Duration>,
+    /// Held while the task runs, so that cancellation can be confirmed.
+    running: Mutex<()>,
+    wakeup: TaskWakeup,
+}
+
+/// How to get a task to notice it was rescheduled or cancelled.
+#[derive(Debug)]
+enum TaskWakeup {
+    /// Tasks of the scheduler thread are reordered in its queue.
+    Queue(Weak<TaskQueue>),
+    /// Tasks spawned on a tokio runtime wait for their next run or for a notification.
+    #[cfg(feature = "tokio")]
+    Tokio(Arc<tokio::sync::Notify>),
+}
 
 impl CancelHandle {
-    fn new() -> CancelHandle {
-        CancelHandle(Arc::new(AtomicBool::new(false)))
+    fn new(period: Duration, wakeup: TaskWakeup) -> CancelHandle {
+        CancelHandle(Arc::new(TaskState {
+            cancelled: AtomicBool::new(false),
+            period: Mutex::new(period),
+            running: Mutex::new(()),
+            wakeup,
+        }))
     }
 
     fn is_cancelled(&self) -> bool {
-        self.0.load(SeqCst)
+        self.0.cancelled.load(SeqCst)
+    }
+
+    /// Run the operation unless the task was cancelled. Returns false if the task was cancelled.
+    /// A panicking operation is logged and stays scheduled, it does not take down other tasks sharing the thread.
+    fn run(&self, operation: &(dyn Fn(Instant) + Send + Sync), now: Instant) -> bool {
+        if self.is_cancelled() {
+            return false;
+        }
+        let _running = self
+            .0
+            .running
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        // the task may have been cancelled while waiting for the lock
+        if self.is_cancelled() {
+            return false;
+        }
+        if panic::catch_unwind(AssertUnwindSafe(|| operation(now))).is_err() {
+            error!("Scheduled task panicked, it will run again at its next period.");
+        }
+        true
+    }
+
+    /// Returns the current period of the task.
+    pub fn period(&self) -> Duration {
+        *self.0.period.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Change the period of the task, e.g. upon configuration reload.
+    /// The next run is rescheduled to one new period after the previous run, or right away if that is past.
+    pub fn set_period(&self, period: Duration) {
+        *self.0.period.lock().unwrap_or_else(PoisonError::into_inner) = period;
+        match &self.0.wakeup {
+            TaskWakeup::Queue(queue) => {
+                if let Some(queue) = queue.upgrade() {
+                    // a running task is out of the queue, it picks up the new period when it is done
+                    let mut tasks = queue.tasks.lock().unwrap_or_else(PoisonError::into_inner);
+                    let mut rescheduled = mem::take(&mut *tasks).into_vec();
+                    for task in &mut rescheduled {
+                        if Arc::ptr_eq(&task.handle.0, &self.0) {
+                            task.next_time = task.cadence.rescheduled(task.last_run, period);
+                        }
+                    }
+                    *tasks = BinaryHeap::from(rescheduled);
+                    queue.wakeup.notify_one();
+                }
+            }
+            #[cfg(feature = "tokio")]
+            TaskWakeup::Tokio(notify) => notify.notify_one(),
+        }
+    }
+
+    /// Cancel the task if it was not already, then wait for any run in progress to complete.
+    /// Once this returns, the task is guaranteed not to run again.
+    /// Must not be called from the task itself, which would wait for itself forever.
+    pub fn join(&self) {
+        self.0.cancelled.store(true, SeqCst);
+        self.wake_cancelled();
+        drop(
+            self.0
+                .running
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner),
+        );
     }
 }
 
 impl Cancel for CancelHandle {
     /// Signals the task to stop.
     fn cancel(&self) {
-        if self.0.swap(true, SeqCst) {
+        if self.0.cancelled.swap(true, SeqCst) {
             warn!("Scheduled task was already cancelled.")
         }
+        self.wake_cancelled();
+    }
+}
+
+impl CancelHandle {
+    /// Let a waiting tokio task end right away rather than at its next run.
+    /// Cancelled tasks of the scheduler thread are dropped from its queue when due.
+    fn wake_cancelled(&self) {
+        #[cfg(feature = "tokio")]
+        {
+            if let TaskWakeup::Tokio(notify) = &self.0.wakeup {
+                notify.notify_one()
+            }
+        }
     }
 }
 
@@ -80,31 +187,121 @@ impl Cancel for CancelHandle {
 pub trait ScheduleFlush {
     /// Flush this scope at regular intervals.
     fn flush_every(&self, period: Duration) -> CancelHandle;
+
+    /// Flush this scope at intervals randomly varied by up to `jitter` times the period, e.g. 0.1 for ±10%.
+    /// Jitter is clamped between 0 and 0.9.
+    /// The first flush happens after a random phase offset within the first period.
+    /// Use in fleets of instances, so that they do not all publish at the same instant.
+    fn flush_every_jittered(&self, period: Duration, jitter: f64) -> CancelHandle;
+
+    /// Flush this scope at every wall-clock multiple of the period, e.g. at the top of every minute,
+    /// so that published values line up with the time buckets of dashboards.
+    /// Boundaries are computed in UTC from the Unix epoch.
+    fn flush_aligned(&self, period: Duration) -> CancelHandle;
+
+    /// Flush this scope at regular intervals, from a task spawned on a tokio runtime
+    /// rather than from the dedicated scheduler thread.
+    #[cfg(feature = "tokio")]
+    fn flush_every_on(&self, scheduler: &TokioScheduler, period: Duration) -> CancelHandle;
 }
 
 impl<T: InputScope + Send + Sync + Clone + 'static> ScheduleFlush for T {
     /// Flush this scope at regular intervals.
     fn flush_every(&self, period: Duration) -> CancelHandle {
-        let scope = self.clone();
-        SCHEDULER.schedule(period, move |_| {
-            if let Err(err) = scope.flush() {
-                error!("Could not flush metrics: {}", err);
-            }
+        let scope: Arc<dyn Flush + Send + Sync> = Arc::new(self.clone());
+        schedule_flush(scope, |flush| SCHEDULER.schedule(period, flush))
+    }
+
+    /// Flush this scope at randomly varied intervals.
+    fn flush_every_jittered(&self, period: Duration, jitter: f64) -> CancelHandle {
+        let scope: Arc<dyn Flush + Send + Sync> = Arc::new(self.clone());
+        schedule_flush(scope, |flush| {
+            SCHEDULER.schedule_jittered(period, jitter, flush)
         })
     }
+
+    /// Flush this scope at every wall-clock multiple of the period.
+    fn flush_aligned(&self, period: Duration) -> CancelHandle {
+        let scope: Arc<dyn Flush + Send + Sync> = Arc::new(self.clone());
+        schedule_flush(scope, |flush| SCHEDULER.schedule_aligned(period, flush))
+    }
+
+    /// Flush this scope at regular intervals, from a task of a tokio runtime.
+    #[cfg(feature = "tokio")]
+    fn flush_every_on(&self, scheduler: &TokioScheduler, period: Duration) -> CancelHandle {
+        let scope: Arc<dyn Flush + Send + Sync> = Arc::new(self.clone());
+        schedule_flush(scope, |flush| scheduler.schedule(period, flush))
+    }
 }
 
+/// A scope flushed on a schedule.
+type ScheduledFlush = (CancelHandle, Arc<dyn Flush + Send + Sync>);
+
 lazy_static! {
+    /// The single thread running all scheduled tasks of the application.
     pub static ref SCHEDULER: Scheduler = Scheduler::new();
+
+    /// Scopes flushed on a schedule, to be flushed one last time upon shutdown.
+    static ref SCHEDULED_FLUSHES: Mutex<Vec<ScheduledFlush>> = Mutex::new(Vec::new());
+}
+
+/// Schedule flushes of the scope, recording it for a final flush upon shutdown.
+pub(crate) fn schedule_flush<S>(scope: Arc<dyn Flush + Send + Sync>, schedule: S) -> CancelHandle
+where
+    S: FnOnce(Box<dyn Fn(Instant) + Send + Sync>) -> CancelHandle,
+{
+    let flushed = scope.clone();
+    let handle = schedule(Box::new(move |_| {
+        if let Err(err) = flushed.flush() {
+            error!("Could not flush metrics: {}", err);
+        }
+    }));
+    let mut scheduled = SCHEDULED_FLUSHES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner);
+    scheduled.retain(|(handle, _)| !handle.is_cancelled());
+    scheduled.push((handle.clone(), scope));
+    handle
+}
+
+/// Cancel all scheduled flushes, waiting for those in progress to complete.
+/// Returns the scopes that were still being flushed, for a final flush.
+pub(crate) fn stop_scheduled_flushes() -> Vec<Arc<dyn Flush + Send + Sync>> {
+    let scheduled = mem::take(
+        &mut *SCHEDULED_FLUSHES
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner),
+    );
+    scheduled
+        .into_iter()
+        .filter(|(handle, _)| !handle.is_cancelled())
+        .map(|(handle, scope)| {
+            handle.join();
+            scope
+        })
+        .collect()
 }
 
 struct ScheduledTask {
     next_time: Instant,
-    period: Duration,
+    last_run: Instant,
+    cadence: Cadence,
     handle: CancelHandle,
     operation: Arc<dyn Fn(Instant) -> () + Send + Sync + 'static>,
 }
 
+impl ScheduledTask {
+    /// Run the task unless it was cancelled. Returns false if the task was cancelled.
+    fn run(&mut self, now: Instant) -> bool {
+        if !self.handle.run(&*self.operation, now) {
+            return false;
+        }
+        self.last_run = now;
+        self.next_time = self.cadence.next_time(now, self.handle.period());
+        true
+    }
+}
+
 impl Ord for ScheduledTask {
     fn cmp(&self, other: &ScheduledTask) -> Ordering {
         other.next_time.cmp(&self.next_time)
@@ -125,49 +322,52 @@ impl PartialEq for ScheduledTask {
 
 impl Eq for ScheduledTask {}
 
+/// Tasks waiting for their next run, earliest first.
+struct TaskQueue {
+    tasks: Mutex<BinaryHeap<ScheduledTask>>,
+    wakeup: Condvar,
+}
+
 pub struct Scheduler {
-    next_tasks: Arc<(Mutex<BinaryHeap<ScheduledTask>>, Condvar)>,
+    next_tasks: Arc<TaskQueue>,
 }
 
+/// Wait between checks when no tasks are scheduled.
 pub static MIN_DELAY: Duration = Duration::from_millis(50);
 
 impl Scheduler {
     /// Launch a new scheduler thread.
     fn new() -> Self {
-        let sched: Arc<(Mutex<BinaryHeap<ScheduledTask>>, Condvar)> =
-            Arc::new((Mutex::new(BinaryHeap::new()), Condvar::new()));
+        let sched = Arc::new(TaskQueue {
+            tasks: Mutex::new(BinaryHeap::new()),
+            wakeup: Condvar::new(),
+        });
         let sched1 = Arc::downgrade(&sched);
 
         thread::Builder::new()
             .name("dipstick_scheduler".to_string())
             .spawn(move || {
-                let mut wait_for = MIN_DELAY;
                 while let Some(sss) = sched1.upgrade() {
-                    let &(ref heap_mutex, ref condvar) = &*sss;
-                    let heap = heap_mutex.lock().unwrap();
-                    let (mut tasks, _timed_out) = condvar.wait_timeout(heap, wait_for).unwrap();
-                    'work: loop {
-                        let now = Instant::now();
-                        match tasks.peek() {
-                            Some(task) if task.next_time > now => {
-                                // next task is not ready yet, update schedule
-                                wait_for = max(MIN_DELAY, task.next_time - now);
-                                break 'work;
-                            }
-                            None => {
-                                // TODO no tasks left. exit thread?
-                                break 'work;
-                            }
-                            _ => {}
-                        }
-                        if let Some(mut task) = tasks.pop() {
-                            if task.handle.is_cancelled() {
-                                // do not execute, do not reinsert
-                                continue;
-                            }
-                            (task.operation)(now);
-                            task.next_time = now + task.period;
-                            tasks.push(task);
+                    // check tasks before waiting, so that tasks scheduled before the thread started are not missed
+                    let mut tasks = sss.tasks.lock().unwrap();
+                    let now = Instant::now();
+                    let wait_for = match tasks.peek() {
+                        // next task is not ready yet, update schedule
+                        Some(task) if task.next_time > now => task.next_time - now,
+                        Some(_) => Duration::ZERO,
+                        // TODO no tasks left. exit thread?
+                        None => MIN_DELAY,
+                    };
+                    if !wait_for.is_zero() {
+                        let _ = sss.wakeup.wait_timeout(tasks, wait_for).unwrap();
+                        continue;
+                    }
+                    if let Some(mut task) = tasks.pop() {
+                        // tasks run without holding the queue, so that they may (re)schedule tasks
+                        drop(tasks);
+                        // cancelled tasks are not reinserted
+                        if task.run(now) {
+                            sss.tasks.lock().unwrap().push(task);
                         }
                     }
                 }
@@ -179,7 +379,7 @@ impl Scheduler {
 
     #[cfg(test)]
     pub fn task_count(&self) -> usize {
-        self.next_tasks.0.lock().unwrap().len()
+        self.next_tasks.tasks.lock().unwrap().len()
     }
 
     /// Schedule a task to run periodically.
@@ -187,24 +387,420 @@ impl Scheduler {
     where
         F: Fn(Instant) -> () + Send + Sync + 'static,
     {
-        let handle = CancelHandle::new();
-        let new_task = ScheduledTask {
-            next_time: Instant::now() + period,
+        let cadence = Cadence::Interval { jitter: 0.0 };
+        self.push(
+            Instant::now() + period,
             period,
+            cadence,
+            Arc::new(operation),
+        )
+    }
+
+    /// Schedule a task to run at intervals randomly varied by up to `jitter` times the period.
+    /// The first run happens after a random phase offset within the period.
+    /// Jitter is clamped between 0 and 0.9, a NaN jitter being taken as none.
+    pub fn schedule_jittered<F>(&self, period: Duration, jitter: f64, operation: F) -> CancelHandle
+    where
+        F: Fn(Instant) + Send + Sync + 'static,
+    {
+        let jitter = clamp_jitter(jitter);
+        let phase = period.mul_f64(pcg32::random_fraction());
+        let cadence = Cadence::Interval { jitter };
+        self.push(Instant::now() + phase, period, cadence, Arc::new(operation))
+    }
+
+    /// Schedule a task to run at every wall-clock multiple of the period, e.g. at the top of every minute.
+    pub fn schedule_aligned<F>(&self, period: Duration, operation: F) -> CancelHandle
+    where
+        F: Fn(Instant) + Send + Sync + 'static,
+    {
+        let next_time = Instant::now() + until_aligned(period, Duration::ZERO);
+        self.push(next_time, period, Cadence::Aligned, Arc::new(operation))
+    }
+
+    fn push(
+        &self,
+        next_time: Instant,
+        period: Duration,
+        cadence: Cadence,
+        operation: Arc<dyn Fn(Instant) + Send + Sync + 'static>,
+    ) -> CancelHandle {
+        let handle = CancelHandle::new(period, TaskWakeup::Queue(Arc::downgrade(&self.next_tasks)));
+        let new_task = ScheduledTask {
+            next_time,
+            last_run: Instant::now(),
+            cadence,
             handle: handle.clone(),
-            operation: Arc::new(operation),
+            operation,
         };
-        self.next_tasks.0.lock().unwrap().push(new_task);
-        self.next_tasks.1.notify_one();
+        self.next_tasks.tasks.lock().unwrap().push(new_task);
+        self.next_tasks.wakeup.notify_one();
         handle
     }
 }
 
+/// Schedules tasks on an existing tokio runtime instead of the dedicated scheduler thread.
+/// Each task is a lightweight async task waiting for its next run.
+/// Operations block, e.g. on I/O, and thus run on the runtime's blocking thread pool.
+/// The runtime must have its time driver enabled.
+#[cfg(feature = "tokio")]
+#[derive(Debug, Clone)]
+pub struct TokioScheduler {
+    runtime: tokio::runtime::Handle,
+}
+
+#[cfg(feature = "tokio")]
+impl TokioScheduler {
+    /// Schedule tasks on the runtime of the handle.
+    pub fn new(runtime: tokio::runtime::Handle) -> Self {
+        TokioScheduler { runtime }
+    }
+
+    /// Schedule tasks on the current runtime.
+    /// Panics if not called from within a tokio runtime.
+    pub fn current() -> Self {
+        Self::new(tokio::runtime::Handle::current())
+    }
+
+    /// Schedule a task to run periodically.
+    pub fn schedule<F>(&self, period: Duration, operation: F) -> CancelHandle
+    where
+        F: Fn(Instant) + Send + Sync + 'static,
+    {
+        self.spawn(
+            period,
+            period,
+            Cadence::Interval { jitter: 0.0 },
+            Arc::new(operation),
+        )
+    }
+
+    /// Schedule a task to run at intervals randomly varied by up to `jitter` times the period.
+    /// The first run happens after a random phase offset within the period.
+    /// Jitter is clamped between 0 and 0.9, a NaN jitter being taken as none.
+    pub fn schedule_jittered<F>(&self, period: Duration, jitter: f64, operation: F) -> CancelHandle
+    where
+        F: Fn(Instant) + Send + Sync + 'static,
+    {
+        let jitter = clamp_jitter(jitter);
+        let phase = period.mul_f64(pcg32::random_fraction());
+        self.spawn(
+            phase,
+            period,
+            Cadence::Interval { jitter },
+            Arc::new(operation),
+        )
+    }
+
+    /// Schedule a task to run at every wall-clock multiple of the period, e.g. at the top of every minute.
+    pub fn schedule_aligned<F>(&self, period: Duration, operation: F) -> CancelHandle
+    where
+        F: Fn(Instant) + Send + Sync + 'static,
+    {
+        let first_delay = until_aligned(period, Duration::ZERO);
+        self.spawn(first_delay, period, Cadence::Aligned, Arc::new(operation))
+    }
+
+    fn spawn(
+        &self,
+        first_delay: Duration,
+        period: Duration,
+        cadence: Cadence,
+        operation: Arc<dyn Fn(Instant) + Send + Sync + 'static>,
+    ) -> CancelHandle {
+        let notify = Arc::new(tokio::sync::Notify::new());
+        let handle = CancelHandle::new(period, TaskWakeup::Tokio(notify.clone()));
+        let task = handle.clone();
+        self.runtime.spawn(async move {
+            let mut last_run = Instant::now();
+            let mut next_time = last_run + first_delay;
+            loop {
+                let deadline = tokio::time::Instant::from_std(next_time);
+                if tokio::time::timeout_at(deadline, notify.notified())
+                    .await
+                    .is_ok()
+                {
+                    // woken up by a new period or a cancellation
+                    if task.is_cancelled() {
+                        break;
+                    }
+                    next_time = cadence.rescheduled(last_run, task.period());
+                    continue;
+                }
+                let now = Instant::now();
+                let (run, operation) = (task.clone(), operation.clone());
+                match tokio::task::spawn_blocking(move || run.run(&*operation, now)).await {
+                    Ok(true) => {}
+                    // cancelled, or the runtime is shutting down
+                    _ => break,
+                }
+                last_run = now;
+                next_time = cadence.next_time(now, task.period());
+            }
+        });
+        handle
+    }
+}
+
+/// When a task runs again after its previous run.
+#[derive(Debug, Clone, Copy)]
+enum Cadence {
+    /// One period later, randomly varied by up to `jitter` times the period.
+    Interval { jitter: f64 },
+    /// At the next wall-clock multiple of the period.
+    Aligned,
+}
+
+impl Cadence {
+    /// Time of the run following the one started at `last_run`, once it completed.
+    fn next_time(self, last_run: Instant, period: Duration) -> Instant {
+        match self {
+            Cadence::Interval { jitter } => last_run + jittered(period, jitter),
+            // aligned from the end of the run, a run slightly ahead of its boundary must not run again right after it
+            Cadence::Aligned => Instant::now() + until_aligned(period, period / 2),
+        }
+    }
+
+    /// Time of the next run after the period changed.
+    fn rescheduled(self, last_run: Instant, period: Duration) -> Instant {
+        match self {
+            Cadence::Interval { .. } => last_run + period,
+            Cadence::Aligned => Instant::now() + until_aligned(period, Duration::ZERO),
+        }
+    }
+}
+
+/// Delay until the first wall-clock multiple of the period at least `min_delay` from now.
+fn until_aligned(period: Duration, min_delay: Duration) -> Duration {
+    let period_ns = period.as_nanos().max(1);
+    let since_epoch = (SystemTime::now() + min_delay)
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    min_delay + Duration::from_nanos((period_ns - since_epoch % period_ns) as u64)
+}
+
+/// Largest variation of intervals, as a fraction of the period, keeping runs from coming back to back.
+const MAX_JITTER: f64 = 0.9;
+
+/// Bring the jitter within `0.0..=MAX_JITTER`.
+fn clamp_jitter(jitter: f64) -> f64 {
+    if jitter.is_nan() {
+        return 0.0;
+    }
+    jitter.clamp(0.0, MAX_JITTER)
+}
+
+/// Vary the period by a random amount of up to `jitter` times the period, either way.
+fn jittered(period: Duration, jitter: f64) -> Duration {
+    if jitter == 0.0 {
+        return period;
+    }
+    period.mul_f64(1.0 + jitter * (2.0 * pcg32::random_fraction() - 1.0))
+}
+
 #[cfg(test)]
 pub mod test {
     use super::*;
     use std::sync::atomic::AtomicUsize;
 
+    /// Poll the condition until it holds, for up to five seconds.
+    /// Runs are then counted as they happen rather than guessed from elapsed time, which busy hosts stretch.
+    fn wait_until(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5))
+        }
+        true
+    }
+
+    #[test]
+    fn jitter_within_bounds() {
+        let period = Duration::from_millis(100);
+        assert_eq!(period, jittered(period, 0.0));
+        for _ in 0..1000 {
+            let delay = jittered(period, 0.1);
+            assert!(delay >= Duration::from_millis(90), "{:?}", delay);
+            assert!(delay <= Duration::from_millis(110), "{:?}", delay);
+        }
+    }
+
+    #[test]
+    fn jitter_clamped() {
+        assert_eq!(0.1, clamp_jitter(0.1));
+        assert_eq!(0.0, clamp_jitter(-0.5));
+        assert_eq!(0.0, clamp_jitter(f64::NAN));
+        assert_eq!(MAX_JITTER, clamp_jitter(1.0));
+        assert_eq!(MAX_JITTER, clamp_jitter(f64::INFINITY));
+    }
+
+    #[test]
+    fn aligned_delay() {
+        let period = Duration::from_millis(100);
+        let delay = until_aligned(period, Duration::ZERO);
+        assert!(delay > Duration::ZERO && delay <= period, "{:?}", delay);
+        let delay = until_aligned(period, period / 2);
+        assert!(
+            delay >= period / 2 && delay <= period * 3 / 2,
+            "{:?}",
+            delay
+        );
+    }
+
+    #[test]
+    fn schedule_aligned() {
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let runs1 = runs.clone();
+
+        let sched = Scheduler::new();
+
+        let handle1 = sched.schedule_aligned(Duration::from_millis(50), move |_| {
+            runs1.lock().unwrap().push(SystemTime::now());
+        });
+        assert!(wait_until(|| runs.lock().unwrap().len() >= 3));
+        handle1.join();
+        let runs = runs.lock().unwrap();
+        for run in runs.iter() {
+            let since_epoch = run.duration_since(UNIX_EPOCH).unwrap().as_millis();
+            assert!(since_epoch % 50 < 20, "{}", since_epoch % 50);
+        }
+    }
+
+    #[test]
+    fn aligned_after_slow_run() {
+        let runs = Arc::new(Mutex::new(Vec::new()));
+        let runs1 = runs.clone();
+
+        let sched = Scheduler::new();
+
+        let handle1 = sched.schedule_aligned(Duration::from_millis(50), move |_| {
+            runs1.lock().unwrap().push(SystemTime::now());
+            thread::sleep(Duration::from_millis(30));
+        });
+        assert!(wait_until(|| runs.lock().unwrap().len() >= 2));
+        handle1.join();
+        let runs = runs.lock().unwrap();
+        for run in runs.iter() {
+            let since_epoch = run.duration_since(UNIX_EPOCH).unwrap().as_millis();
+            assert!(since_epoch % 50 < 20, "{}", since_epoch % 50);
+        }
+    }
+
+    #[test]
+    fn schedule_jittered() {
+        let trig1a = Arc::new(AtomicUsize::new(0));
+        let trig1b = trig1a.clone();
+
+        let sched = Scheduler::new();
+
+        let start = Instant::now();
+        let handle1 = sched.schedule_jittered(Duration::from_millis(50), 0.1, move |_| {
+            trig1b.fetch_add(1, SeqCst);
+        });
+        // first run within the first period, then every 45 to 55 ms
+        assert!(wait_until(|| trig1a.load(SeqCst) >= 3));
+        handle1.join();
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(90), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn set_period() {
+        let trig1a = Arc::new(AtomicUsize::new(0));
+        let trig1b = trig1a.clone();
+
+        let sched = Scheduler::new();
+
+        let handle1 = sched.schedule(Duration::from_secs(10), move |_| {
+            trig1b.fetch_add(1, SeqCst);
+        });
+        // the pending run is rescheduled right away
+        handle1.set_period(Duration::from_millis(50));
+        assert_eq!(Duration::from_millis(50), handle1.period());
+        // well before the original period
+        assert!(wait_until(|| trig1a.load(SeqCst) >= 3));
+        handle1.cancel();
+    }
+
+    #[test]
+    fn join_running_task() {
+        let trig1a = Arc::new(AtomicUsize::new(0));
+        let trig1b = trig1a.clone();
+
+        let sched = Scheduler::new();
+
+        let started1a = Arc::new(AtomicBool::new(false));
+        let started1b = started1a.clone();
+
+        let handle1 = sched.schedule(Duration::from_millis(20), move |_| {
+            started1b.store(true, SeqCst);
+            thread::sleep(Duration::from_millis(50));
+            trig1b.fetch_add(1, SeqCst);
+        });
+        // wait for the first run to be in progress
+        assert!(wait_until(|| started1a.load(SeqCst)));
+        handle1.join();
+        assert_eq!(1, trig1a.load(SeqCst));
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(1, trig1a.load(SeqCst));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn schedule_on_tokio() {
+        let trig1a = Arc::new(AtomicUsize::new(0));
+        let trig1b = trig1a.clone();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let sched = TokioScheduler::new(runtime.handle().clone());
+
+        let handle1 = sched.schedule(Duration::from_millis(50), move |_| {
+            trig1b.fetch_add(1, SeqCst);
+        });
+        let deadline = Instant::now() + Duration::from_secs(5);
+        runtime.block_on(async {
+            while trig1a.load(SeqCst) < 3 && Instant::now() < deadline {
+                tokio::time::sleep(Duration::from_millis(5)).await
+            }
+        });
+        assert!(trig1a.load(SeqCst) >= 3);
+
+        handle1.join();
+        let runs = trig1a.load(SeqCst);
+        runtime.block_on(async { tokio::time::sleep(Duration::from_millis(70)).await });
+        assert_eq!(runs, trig1a.load(SeqCst));
+    }
+
+    #[test]
+    fn panicking_task_is_isolated() {
+        let trig1a = Arc::new(AtomicUsize::new(0));
+        let trig1b = trig1a.clone();
+        let trig2a = Arc::new(AtomicUsize::new(0));
+        let trig2b = trig2a.clone();
+
+        let sched = Scheduler::new();
+
+        let handle1 = sched.schedule(Duration::from_millis(50), move |_| {
+            trig1b.fetch_add(1, SeqCst);
+            panic!("scheduled task failure");
+        });
+        let handle2 = sched.schedule(Duration::from_millis(50), move |_| {
+            trig2b.fetch_add(1, SeqCst);
+        });
+        // the panicking task keeps running, and does not stop the other one
+        assert!(wait_until(
+            || trig1a.load(SeqCst) >= 2 && trig2a.load(SeqCst) >= 2
+        ));
+        handle1.cancel();
+        handle2.cancel();
+    }
+
     #[test]
     fn schedule_one_and_cancel() {
         let trig1a = Arc::new(AtomicUsize::new(0));
@@ -216,13 +812,14 @@ pub mod test {
             trig1b.fetch_add(1, SeqCst);
         });
         assert_eq!(sched.task_count(), 1);
-        thread::sleep(Duration::from_millis(170));
-        assert_eq!(3, trig1a.load(SeqCst));
+        assert!(wait_until(|| trig1a.load(SeqCst) >= 3));
 
         handle1.cancel();
+        // dropped from the queue when next due
+        assert!(wait_until(|| sched.task_count() == 0));
+        let runs = trig1a.load(SeqCst);
         thread::sleep(Duration::from_millis(70));
-        assert_eq!(sched.task_count(), 0);
-        assert_eq!(3, trig1a.load(SeqCst));
+        assert_eq!(runs, trig1a.load(SeqCst));
     }
 
     #[test]
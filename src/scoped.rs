@@ -0,0 +1,215 @@
+//! Build short-lived pipelines over lent resources, e.g. a test-local buffer or scope.
+//! Outputs and combinators require `'static` targets because metrics can outlive the scope that defined them.
+//! A scoped handle is `'static` but only reaches the lent resource until the closure returns,
+//! after which the resource is handed back and any further use fails with `BrokenPipe` (or is dropped for metric values).
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::{Input, InputKind, InputMetric, InputScope, ShareScope};
+use crate::name::MetricName;
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// A resource lent to scoped handles until taken back.
+struct Lent<T>(Mutex<Option<T>>);
+
+impl<T> Lent<T> {
+    fn lock(&self) -> MutexGuard<'_, Option<T>> {
+        // a panic while writing to the lent resource does not prevent taking it back
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn scope_ended() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::BrokenPipe,
+        "lent metrics resource is out of scope",
+    )
+}
+
+/// Takes the resource back when the scope ends, even if unwinding.
+struct TakeBack<T>(Arc<Lent<T>>);
+
+impl<T> TakeBack<T> {
+    fn take(self) -> T {
+        // waits for any write in progress to complete
+        self.0
+            .lock()
+            .take()
+            .expect("lent resource is only taken back once")
+    }
+}
+
+impl<T> Drop for TakeBack<T> {
+    fn drop(&mut self) {
+        self.0.lock().take();
+    }
+}
+
+/// Run the closure with a `'static` writer forwarding to the lent writer, e.g. to build a `Stream` on it.
+/// The writer is handed back along with the closure's result when it returns; writes made afterwards fail.
+pub fn scoped_write<W, F, R>(write: W, f: F) -> (W, R)
+where
+    W: Write + Send + 'static,
+    F: FnOnce(ScopedWrite) -> R,
+{
+    let lent = TakeBack(Arc::new(Lent(Mutex::new(Some(write)))));
+    let result = f(ScopedWrite {
+        inner: lent.0.clone(),
+    });
+    (lent.take(), result)
+}
+
+/// Writes to a lent writer, if not yet taken back.
+trait LentWrite: Send + Sync {
+    fn write(&self, buf: &[u8]) -> io::Result<usize>;
+    fn flush(&self) -> io::Result<()>;
+}
+
+impl<W: Write + Send> LentWrite for Lent<W> {
+    fn write(&self, buf: &[u8]) -> io::Result<usize> {
+        match self.lock().as_mut() {
+            Some(write) => write.write(buf),
+            None => Err(scope_ended()),
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        match self.lock().as_mut() {
+            Some(write) => write.flush(),
+            None => Err(scope_ended()),
+        }
+    }
+}
+
+/// A `'static` writer to a lent writer, valid until its `scoped_write` closure returns.
+#[derive(Clone)]
+pub struct ScopedWrite {
+    inner: Arc<dyn LentWrite>,
+}
+
+impl Write for ScopedWrite {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Run the closure with a `'static` input forwarding to the lent scope, e.g. to wrap it in a queue or a multi.
+/// The scope is handed back along with the closure's result when it returns. Metrics defined before then
+/// remain usable, metrics defined afterwards drop their values and flushing fails.
+pub fn scoped_input<S, F, R>(scope: S, f: F) -> (S, R)
+where
+    S: InputScope + Send + Sync + 'static,
+    F: FnOnce(ScopedInput) -> R,
+{
+    let lent = TakeBack(Arc::new(Lent(Mutex::new(Some(scope)))));
+    let result = f(ScopedInput {
+        attributes: Attributes::default(),
+        target: lent.0.clone(),
+    });
+    (lent.take(), result)
+}
+
+impl<S: InputScope> InputScope for Lent<S> {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        match self.lock().as_ref() {
+            Some(target) => target.new_metric(name, kind),
+            None => {
+                debug!("Lent scope ended, dropping values of metric {:?}", name);
+                InputMetric::new(MetricId::forge("scoped", name), |_, _| {})
+            }
+        }
+    }
+}
+
+/// Metrics are defined through `ScopedInput`, which can be shared.
+impl<S> ShareScope for Lent<S> {}
+
+impl<S: InputScope> Flush for Lent<S> {
+    fn flush(&self) -> io::Result<()> {
+        match self.lock().as_ref() {
+            Some(target) => target.flush(),
+            None => Err(scope_ended()),
+        }
+    }
+}
+
+/// A `'static` input and scope over a lent scope, valid until its `scoped_input` closure returns.
+#[derive(Clone)]
+pub struct ScopedInput {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync>,
+}
+
+impl QueuedInput for ScopedInput {}
+impl CachedInput for ScopedInput {}
+
+impl WithAttributes for ScopedInput {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for ScopedInput {
+    type SCOPE = ScopedInput;
+
+    fn metrics(&self) -> Self::SCOPE {
+        self.clone()
+    }
+}
+
+impl InputScope for ScopedInput {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        self.target.new_metric(self.prefix_append(name), kind)
+    }
+}
+
+impl Flush for ScopedInput {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+    use crate::{MultiInput, Stream};
+
+    #[test]
+    fn lent_buffer() {
+        let (buffer, mut write) = scoped_write(Vec::new(), |write| {
+            let stream = Stream::write_to(write.clone());
+            stream.metrics().counter("count_a").count(3);
+            write
+        });
+        assert_eq!("count_a 3\n", String::from_utf8(buffer).unwrap());
+
+        // out of scope
+        assert!(write.write(b"late").is_err());
+    }
+
+    #[test]
+    fn lent_scope() {
+        let (map, (counter, input)) = scoped_input(StatsMapScope::default(), |input| {
+            let multi = MultiInput::new().add_target(input.clone()).metrics();
+            let counter = multi.counter("count_a");
+            counter.count(3);
+            multi.flush().unwrap();
+            (counter, input)
+        });
+        // metrics defined in scope still reach the target
+        counter.count(4);
+        assert!(input.flush().is_err());
+        assert_eq!(map.into_map()["count_a"], 4);
+    }
+}
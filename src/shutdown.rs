@@ -0,0 +1,72 @@
+//! Orderly shutdown of background metrics activity,
+//! so that metrics emitted just before the process exits are not lost.
+
+use crate::atomic::live_buckets;
+use crate::queue::drain_queues;
+use crate::scheduler::stop_scheduled_flushes;
+
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Stop all scheduled flushes, flush their scopes and every live bucket given a drain one last time,
+/// then wait for the commands of all live queues to be executed, up to the timeout.
+/// Returns the first error of the final flushes, or a `TimedOut` error if queued commands
+/// were still pending after the timeout. Every scope is flushed regardless of errors.
+/// Errors of flushes executed by queue worker threads are only logged.
+/// Must not be called from a scheduled task, which would wait for itself forever.
+pub fn shutdown(timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+    let mut result = Ok(());
+    let mut scopes = stop_scheduled_flushes();
+    // buckets already flushed with their scheduled scope have nothing left to publish
+    for bucket in live_buckets() {
+        scopes.push(Arc::new(bucket));
+    }
+    for scope in scopes {
+        if let Err(err) = scope.flush() {
+            error!("Could not flush metrics upon shutdown: {}", err);
+            result = result.and(Err(err));
+        }
+    }
+    // final flushes of queued scopes are themselves queued
+    result.and(drain_queues(deadline))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::atomic::AtomicBucket;
+    use crate::input::InputScope;
+    use crate::queue::InputQueueScope;
+    use crate::scheduler::ScheduleFlush;
+    use crate::testing::Recorder;
+
+    #[test]
+    fn final_flush_drains_queue() {
+        let target = Recorder::default();
+        let queue = InputQueueScope::wrap(target.clone(), 64);
+        let handle = queue.flush_every(Duration::from_secs(3600));
+        let counter = queue.counter("hits");
+        for i in 0..10 {
+            counter.count(i)
+        }
+
+        shutdown(Duration::from_secs(5)).unwrap();
+        assert_eq!(10, target.values().len());
+        assert_eq!(1, target.flushes());
+        // no longer scheduled
+        handle.join();
+    }
+    #[test]
+    fn final_flush_of_unscheduled_bucket() {
+        let target = Recorder::default();
+        let bucket = AtomicBucket::new();
+        bucket.drain(target.clone());
+        bucket.counter("hits").count(3);
+
+        shutdown(Duration::from_secs(5)).unwrap();
+        assert_eq!(1, target.flushes());
+        assert!(!target.values().is_empty());
+    }
+}
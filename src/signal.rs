@@ -0,0 +1,129 @@
+//! Flush metrics upon Unix signals: immediately on `SIGUSR1`,
+//! one last time on `SIGTERM` or `SIGINT`, before the process exits if so requested.
+
+use crate::shutdown::shutdown;
+use crate::Flush;
+
+use signal_hook::consts::{SIGINT, SIGTERM, SIGUSR1};
+use signal_hook::iterator::Signals;
+
+use std::sync::Arc;
+use std::time::Duration;
+use std::{io, thread};
+
+/// Default time allowed for queues to drain upon `SIGTERM` or `SIGINT`.
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Opt-in signal handling flushing registered scopes.
+/// Once installed, `SIGUSR1` flushes the scopes right away, e.g. to inspect a running process,
+/// while `SIGTERM` and `SIGINT` flush them one last time and perform a `shutdown()`.
+/// The process is then left running, unless `exit_on_terminate()` or `on_terminate()` say otherwise.
+#[derive(Clone)]
+pub struct SignalFlush {
+    scopes: Vec<Arc<dyn Flush + Send + Sync>>,
+    timeout: Duration,
+    on_terminate: Option<Arc<dyn Fn(i32) + Send + Sync>>,
+}
+
+impl Default for SignalFlush {
+    fn default() -> Self {
+        SignalFlush {
+            scopes: Vec::new(),
+            timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            on_terminate: None,
+        }
+    }
+}
+
+impl SignalFlush {
+    /// Signal handling flushing no scopes but the scheduled ones upon exit.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a clone also flushing the scope upon signals.
+    pub fn add_scope<S: Flush + Send + Sync + 'static>(&self, scope: S) -> Self {
+        let mut cloned = self.clone();
+        cloned.scopes.push(Arc::new(scope));
+        cloned
+    }
+
+    /// Returns a clone allowing queues the specified time to drain upon exit.
+    pub fn shutdown_timeout(&self, timeout: Duration) -> Self {
+        let mut cloned = self.clone();
+        cloned.timeout = timeout;
+        cloned
+    }
+
+    /// Returns a clone terminating the process after the final flush, as the signal would have without handler.
+    pub fn exit_on_terminate(&self) -> Self {
+        self.on_terminate(|signal| {
+            if let Err(err) = signal_hook::low_level::emulate_default_handler(signal) {
+                error!("Could not terminate upon signal {}: {}", signal, err);
+            }
+        })
+    }
+
+    /// Returns a clone invoking the callback with the signal after the final flush upon `SIGTERM` or `SIGINT`,
+    /// e.g. to stop the application gracefully.
+    pub fn on_terminate<F: Fn(i32) + Send + Sync + 'static>(&self, callback: F) -> Self {
+        let mut cloned = self.clone();
+        cloned.on_terminate = Some(Arc::new(callback));
+        cloned
+    }
+
+    /// Install the signal handlers, listening for signals on a dedicated thread.
+    /// Handlers remain installed for the rest of the process' life.
+    pub fn install(&self) -> io::Result<()> {
+        let mut signals = Signals::new([SIGUSR1, SIGTERM, SIGINT])?;
+        let handling = self.clone();
+        thread::Builder::new()
+            .name("dipstick-signals".to_string())
+            .spawn(move || {
+                for signal in signals.forever() {
+                    handling.flush_scopes();
+                    if signal != SIGUSR1 {
+                        if let Err(err) = shutdown(handling.timeout) {
+                            error!("Could not deliver all metrics before exit: {}", err);
+                        }
+                        if let Some(on_terminate) = &handling.on_terminate {
+                            on_terminate(signal)
+                        }
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    fn flush_scopes(&self) {
+        for scope in &self.scopes {
+            if let Err(err) = scope.flush() {
+                error!("Could not flush metrics upon signal: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::testing::Recorder;
+
+    #[test]
+    fn flush_on_sigusr1() {
+        let scope = Recorder::default();
+        SignalFlush::new()
+            .add_scope(scope.clone())
+            .install()
+            .unwrap();
+
+        signal_hook::low_level::raise(SIGUSR1).unwrap();
+        for _ in 0..1000 {
+            if scope.flushes() > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1))
+        }
+        assert_eq!(1, scope.flushes());
+    }
+}
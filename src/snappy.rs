@@ -0,0 +1,150 @@
+//! Compression in the Snappy block format, as required by Prometheus remote write.
+//! Kept here for low dependency count, only compression is implemented.
+//! See https://github.com/google/snappy/blob/master/format_description.txt
+
+/// Matches are only looked up within blocks of this size, so that copy offsets always fit in two bytes.
+const BLOCK_SIZE: usize = 1 << 16;
+
+const HASH_BITS: u32 = 14;
+
+/// Element tags
+const LITERAL: u8 = 0b00;
+const COPY_2: u8 = 0b10;
+
+/// Compress the input as a single Snappy block, without framing.
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    let mut len = input.len() as u64;
+    // uncompressed length preamble, as a varint
+    while len >= 0x80 {
+        out.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+    for block in input.chunks(BLOCK_SIZE) {
+        compress_block(block, &mut out);
+    }
+    out
+}
+
+/// Greedily replace any four bytes already seen at the same hash with a copy of the longest match from there.
+fn compress_block(block: &[u8], out: &mut Vec<u8>) {
+    // positions are offset by one, zero meaning no position
+    let mut table = vec![0u32; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut pos = 0;
+    while pos + 4 <= block.len() {
+        let word = u32::from_le_bytes([block[pos], block[pos + 1], block[pos + 2], block[pos + 3]]);
+        let hash = (word.wrapping_mul(0x1e35_a7bd) >> (32 - HASH_BITS)) as usize;
+        let candidate = table[hash] as usize;
+        table[hash] = pos as u32 + 1;
+        if candidate > 0 && block[candidate - 1..candidate + 3] == block[pos..pos + 4] {
+            let from = candidate - 1;
+            let mut len = 4;
+            while pos + len < block.len() && block[from + len] == block[pos + len] {
+                len += 1;
+            }
+            emit_literal(&block[literal_start..pos], out);
+            emit_copy(pos - from, len, out);
+            pos += len;
+            literal_start = pos;
+        } else {
+            pos += 1;
+        }
+    }
+    emit_literal(&block[literal_start..], out);
+}
+
+fn emit_literal(literal: &[u8], out: &mut Vec<u8>) {
+    if literal.is_empty() {
+        return;
+    }
+    // literals never exceed the block size, their length fits in two bytes
+    let n = literal.len() - 1;
+    if n < 60 {
+        out.push((n as u8) << 2 | LITERAL);
+    } else if n < 1 << 8 {
+        out.push(60 << 2 | LITERAL);
+        out.push(n as u8);
+    } else {
+        out.push(61 << 2 | LITERAL);
+        out.extend_from_slice(&(n as u16).to_le_bytes());
+    }
+    out.extend_from_slice(literal);
+}
+
+fn emit_copy(offset: usize, mut len: usize, out: &mut Vec<u8>) {
+    // a single element copies at most 64 bytes
+    while len > 0 {
+        let n = len.min(64);
+        out.push(((n - 1) as u8) << 2 | COPY_2);
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        len -= n;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Decompress the subset of the format produced by `compress`.
+    fn decompress(input: &[u8]) -> Vec<u8> {
+        let (mut len, mut shift, mut pos) = (0usize, 0, 0);
+        loop {
+            len |= ((input[pos] & 0x7f) as usize) << shift;
+            pos += 1;
+            if input[pos - 1] < 0x80 {
+                break;
+            }
+            shift += 7;
+        }
+        let mut out = Vec::with_capacity(len);
+        while pos < input.len() {
+            let tag = input[pos];
+            pos += 1;
+            match tag & 0b11 {
+                LITERAL => {
+                    let n = match tag >> 2 {
+                        60 => {
+                            pos += 1;
+                            input[pos - 1] as usize
+                        }
+                        61 => {
+                            pos += 2;
+                            u16::from_le_bytes([input[pos - 2], input[pos - 1]]) as usize
+                        }
+                        n => n as usize,
+                    } + 1;
+                    out.extend_from_slice(&input[pos..pos + n]);
+                    pos += n;
+                }
+                COPY_2 => {
+                    let n = (tag >> 2) as usize + 1;
+                    let offset = u16::from_le_bytes([input[pos], input[pos + 1]]) as usize;
+                    pos += 2;
+                    // copies may overlap their own output
+                    for _ in 0..n {
+                        out.push(out[out.len() - offset]);
+                    }
+                }
+                _ => panic!("unexpected tag {}", tag),
+            }
+        }
+        assert_eq!(len, out.len());
+        out
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut input = Vec::new();
+        for i in 0..20_000 {
+            input.extend_from_slice(format!("series_{} {}\n", i % 300, i).as_bytes());
+        }
+        let compressed = compress(&input);
+        assert!(compressed.len() < input.len() / 2);
+        assert_eq!(input, decompress(&compressed));
+
+        assert_eq!(vec![0], compress(&[]));
+        assert_eq!(b"abc".to_vec(), decompress(&compress(b"abc")));
+    }
+}
@@ -0,0 +1,167 @@
+//! Read context labels from the fields of the current `tracing` span, aligning metrics with distributed traces.
+
+use crate::label::{ContextLabel, LabelScope};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::Subscriber;
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::Arc;
+
+/// Label holding the name of the current span.
+pub const SPAN_LABEL: &str = "span";
+
+thread_local! {
+    /// Labels of the spans entered on this thread, innermost last.
+    static ENTERED: RefCell<Vec<(Id, LabelScope)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A `tracing-subscriber` layer providing the fields of the current span as context labels,
+/// e.g. `trace_id` for `info_span!("request", trace_id = %id)`, along with the span name as `span`.
+/// Fields of enclosing spans are also provided, unless the inner span has a field of the same name.
+/// Labels of a span are collected once when it is created or recorded, then shared by every lookup.
+/// Adding the layer to a subscriber replaces any context label provider.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SpanLabelLayer;
+
+/// Labels of a span, kept in its extensions.
+struct SpanLabels(LabelScope);
+
+/// Collects field values into labels, strings as is, other values debug-formatted.
+struct LabelVisitor(LabelScope);
+
+impl Visit for LabelVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0 = self
+            .0
+            .set(field.name().to_string(), Arc::new(value.to_string()));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0 = self
+            .0
+            .set(field.name().to_string(), Arc::new(format!("{:?}", value)));
+    }
+}
+
+fn current_span_labels() -> LabelScope {
+    ENTERED.with(|entered| {
+        entered
+            .borrow()
+            .last()
+            .map(|(_, labels)| labels.clone())
+            .unwrap_or_default()
+    })
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for SpanLabelLayer {
+    fn on_layer(&mut self, _subscriber: &mut S) {
+        ContextLabel::set_scope_provider(Arc::new(current_span_labels));
+    }
+
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+        let enclosing = span
+            .parent()
+            .and_then(|parent| {
+                let extensions = parent.extensions();
+                extensions
+                    .get::<SpanLabels>()
+                    .map(|labels| labels.0.clone())
+            })
+            .unwrap_or_default();
+        let mut visitor =
+            LabelVisitor(enclosing.set(SPAN_LABEL.to_string(), Arc::new(span.name().to_string())));
+        attrs.record(&mut visitor);
+        span.extensions_mut().insert(SpanLabels(visitor.0));
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let mut extensions = span.extensions_mut();
+            if let Some(labels) = extensions.get_mut::<SpanLabels>() {
+                let mut visitor = LabelVisitor(labels.0.clone());
+                values.record(&mut visitor);
+                labels.0 = visitor.0;
+                // the span may be recorded while entered on this thread
+                ENTERED.with(|entered| {
+                    for (entered_id, entered_labels) in entered.borrow_mut().iter_mut() {
+                        if entered_id == id {
+                            *entered_labels = labels.0.clone();
+                        }
+                    }
+                });
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            let labels = span
+                .extensions()
+                .get::<SpanLabels>()
+                .map(|labels| labels.0.clone())
+                .unwrap_or_default();
+            ENTERED.with(|entered| entered.borrow_mut().push((id.clone(), labels)));
+        }
+    }
+
+    fn on_exit(&self, id: &Id, _ctx: Context<'_, S>) {
+        ENTERED.with(|entered| {
+            let mut entered = entered.borrow_mut();
+            // spans are usually exited in reverse order of entry, but not necessarily
+            if let Some(position) = entered.iter().rposition(|(entered, _)| entered == id) {
+                entered.remove(position);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::label::test::TEST_SEQUENCE;
+
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn span_fields_as_labels() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+
+        let subscriber = tracing_subscriber::registry().with(SpanLabelLayer);
+        tracing::subscriber::with_default(subscriber, || {
+            let request = tracing::info_span!("request", trace_id = "4bf92f35", user = 42);
+            let _request = request.enter();
+            assert_eq!(Some(Arc::new("request".into())), labels!().lookup("span"));
+            assert_eq!(
+                Some(Arc::new("4bf92f35".into())),
+                labels!().lookup("trace_id")
+            );
+
+            let query = tracing::info_span!("query", user = tracing::field::Empty);
+            query.record("user", 7);
+            {
+                let _query = query.enter();
+                assert_eq!(Some(Arc::new("query".into())), labels!().lookup("span"));
+                assert_eq!(Some(Arc::new("7".into())), labels!().lookup("user"));
+                assert_eq!(
+                    Some(Arc::new("4bf92f35".into())),
+                    labels!().lookup("trace_id")
+                );
+            }
+            assert_eq!(Some(Arc::new("42".into())), labels!().lookup("user"));
+
+            request.record("user", 43);
+            assert_eq!(Some(Arc::new("43".into())), labels!().lookup("user"));
+        });
+        assert_eq!(None, labels!().lookup("trace_id"));
+        ContextLabel::unset_provider();
+    }
+}
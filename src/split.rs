@@ -0,0 +1,219 @@
+//! Route metrics to different sinks according to their kind.
+
+use crate::attributes::{Attributes, OnFlush, Prefixed, WithAttributes};
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::output::void::{NO_METRIC_SCOPE, VOID_INPUT};
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+/// Opens scopes dispatching each metric to a single target chosen by the metric's kind,
+/// e.g. timers to a histogram-capable backend, counters and gauges to graphite, markers to the log.
+/// Metrics of kinds without a route go to the fallback target, which discards them by default.
+#[derive(Clone)]
+pub struct SplitInput {
+    attributes: Attributes,
+    routes: HashMap<InputKind, Arc<dyn InputDyn + Send + Sync>>,
+    fallback: Arc<dyn InputDyn + Send + Sync>,
+}
+
+impl QueuedInput for SplitInput {}
+impl CachedInput for SplitInput {}
+
+impl Default for SplitInput {
+    fn default() -> Self {
+        SplitInput {
+            attributes: Attributes::default(),
+            routes: HashMap::new(),
+            fallback: VOID_INPUT.clone(),
+        }
+    }
+}
+
+impl SplitInput {
+    /// Create a new kind splitter with no routes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a clone of the splitter sending metrics of the specified kinds to the target.
+    /// Replaces any previous route for these kinds.
+    pub fn route<OUT: Input + Send + Sync + 'static>(&self, kinds: &[InputKind], out: OUT) -> Self {
+        let mut cloned = self.clone();
+        let out: Arc<dyn InputDyn + Send + Sync> = Arc::new(out);
+        for kind in kinds {
+            cloned.routes.insert(*kind, out.clone());
+        }
+        cloned
+    }
+
+    /// Returns a clone of the splitter sending metrics of unrouted kinds to the target.
+    pub fn fallback<OUT: Input + Send + Sync + 'static>(&self, out: OUT) -> Self {
+        let mut cloned = self.clone();
+        cloned.fallback = Arc::new(out);
+        cloned
+    }
+}
+
+impl Input for SplitInput {
+    type SCOPE = SplitInputScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        // open a single scope per distinct target, even if it serves multiple kinds
+        let mut opened: Vec<(
+            Arc<dyn InputDyn + Send + Sync>,
+            Arc<dyn InputScope + Send + Sync>,
+        )> = Vec::new();
+        let mut open = |input: &Arc<dyn InputDyn + Send + Sync>| {
+            if let Some((_, scope)) = opened.iter().find(|(i, _)| Arc::ptr_eq(i, input)) {
+                return scope.clone();
+            }
+            let scope = input.input_dyn();
+            opened.push((input.clone(), scope.clone()));
+            scope
+        };
+        let routes = self
+            .routes
+            .iter()
+            .map(|(kind, input)| (*kind, open(input)))
+            .collect();
+        let fallback = open(&self.fallback);
+        SplitInputScope {
+            attributes: self.attributes.clone(),
+            routes,
+            fallback,
+        }
+    }
+}
+
+impl WithAttributes for SplitInput {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+/// Dispatch each metric to a single scope chosen by the metric's kind.
+#[derive(Clone)]
+pub struct SplitInputScope {
+    attributes: Attributes,
+    routes: HashMap<InputKind, Arc<dyn InputScope + Send + Sync>>,
+    fallback: Arc<dyn InputScope + Send + Sync>,
+}
+
+impl Default for SplitInputScope {
+    fn default() -> Self {
+        SplitInputScope {
+            attributes: Attributes::default(),
+            routes: HashMap::new(),
+            fallback: NO_METRIC_SCOPE.clone(),
+        }
+    }
+}
+
+impl SplitInputScope {
+    /// Create a new kind splitter scope with no routes.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a clone of the splitter sending metrics of the specified kinds to the scope.
+    /// Replaces any previous route for these kinds.
+    pub fn route<IN: InputScope + Send + Sync + 'static>(
+        &self,
+        kinds: &[InputKind],
+        scope: IN,
+    ) -> Self {
+        let mut cloned = self.clone();
+        let scope: Arc<dyn InputScope + Send + Sync> = Arc::new(scope);
+        for kind in kinds {
+            cloned.routes.insert(*kind, scope.clone());
+        }
+        cloned
+    }
+
+    /// Returns a clone of the splitter sending metrics of unrouted kinds to the scope.
+    pub fn fallback<IN: InputScope + Send + Sync + 'static>(&self, scope: IN) -> Self {
+        let mut cloned = self.clone();
+        cloned.fallback = Arc::new(scope);
+        cloned
+    }
+
+    /// Every distinct scope, including the fallback.
+    fn scopes(&self) -> Vec<&Arc<dyn InputScope + Send + Sync>> {
+        let mut scopes: Vec<&Arc<dyn InputScope + Send + Sync>> = vec![&self.fallback];
+        for scope in self.routes.values() {
+            if !scopes.iter().any(|s| Arc::ptr_eq(s, scope)) {
+                scopes.push(scope)
+            }
+        }
+        scopes
+    }
+}
+
+impl InputScope for SplitInputScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        self.routes
+            .get(&kind)
+            .unwrap_or(&self.fallback)
+            .new_metric(name, kind)
+    }
+}
+
+impl Flush for SplitInputScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        for scope in self.scopes() {
+            scope.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl WithAttributes for SplitInputScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn split_by_kind() {
+        let timers = StatsMapScope::default();
+        let counts = StatsMapScope::default();
+        let others = StatsMapScope::default();
+        let split = SplitInputScope::new()
+            .route(&[InputKind::Timer], timers.clone())
+            .route(&[InputKind::Counter, InputKind::Gauge], counts.clone())
+            .fallback(others.clone());
+
+        split.timer("timer_a").interval_us(5);
+        split.counter("counter_a").count(6);
+        split.gauge("gauge_a").value(7);
+        split.marker("marker_a").mark();
+
+        let timers = timers.into_map();
+        assert_eq!(timers.len(), 1);
+        assert_eq!(timers["timer_a"], 5);
+
+        let counts = counts.into_map();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts["counter_a"], 6);
+        assert_eq!(counts["gauge_a"], 7);
+
+        assert_eq!(others.into_map()["marker_a"], 1);
+    }
+}
@@ -15,10 +15,26 @@ pub enum ScoreType {
     Max(isize),
     /// Smallest value observed.
     Min(isize),
+    /// Biggest value observed, for gauges written floating point values.
+    FloatMax(f64),
+    /// Smallest value observed, for gauges written floating point values.
+    FloatMin(f64),
     /// Average value (hit count / sum, non-atomic)
     Mean(f64),
     /// Mean rate (hit count / period length in seconds, non-atomic)
     Rate(f64),
+    /// Exponentially weighted moving average rate over one minute (non-atomic)
+    Ewma1m(f64),
+    /// Exponentially weighted moving average rate over five minutes (non-atomic)
+    Ewma5m(f64),
+    /// Exponentially weighted moving average rate over fifteen minutes (non-atomic)
+    Ewma15m(f64),
+    /// Estimated value at a quantile (quantile, value), from a t-digest (non-atomic)
+    Quantile(f64, f64),
+    /// Estimated number of distinct values, from a HyperLogLog sketch (non-atomic)
+    Unique(isize),
+    /// Change of a gauge's last value since the previous period with values (non-atomic)
+    Delta(isize),
 }
 
 /// A predefined export strategy reporting all aggregated stats for all metric types.
@@ -35,16 +51,55 @@ pub fn stats_all(
         ScoreType::Mean(mean) => Some((kind, name.make_name("mean"), mean.round() as MetricValue)),
         ScoreType::Max(max) => Some((InputKind::Gauge, name.make_name("max"), max)),
         ScoreType::Min(min) => Some((InputKind::Gauge, name.make_name("min"), min)),
+        ScoreType::FloatMax(max) => Some((
+            InputKind::Gauge,
+            name.make_name("max"),
+            max.round() as MetricValue,
+        )),
+        ScoreType::FloatMin(min) => Some((
+            InputKind::Gauge,
+            name.make_name("min"),
+            min.round() as MetricValue,
+        )),
         ScoreType::Rate(rate) => Some((
             InputKind::Gauge,
             name.make_name("rate"),
             rate.round() as MetricValue,
         )),
+        ScoreType::Ewma1m(rate) => Some((
+            InputKind::Gauge,
+            name.make_name("ewma_1m"),
+            rate.round() as MetricValue,
+        )),
+        ScoreType::Ewma5m(rate) => Some((
+            InputKind::Gauge,
+            name.make_name("ewma_5m"),
+            rate.round() as MetricValue,
+        )),
+        ScoreType::Ewma15m(rate) => Some((
+            InputKind::Gauge,
+            name.make_name("ewma_15m"),
+            rate.round() as MetricValue,
+        )),
+        ScoreType::Quantile(quantile, value) => Some((
+            kind,
+            name.make_name(quantile_name(quantile)),
+            value.round() as MetricValue,
+        )),
+        ScoreType::Unique(unique) => Some((InputKind::Gauge, name.make_name("unique"), unique)),
+        ScoreType::Delta(delta) => Some((InputKind::Gauge, name.make_name("delta"), delta)),
     }
 }
 
+/// Short name of a quantile, e.g. `p50` for `0.5` or `p999` for `0.999`.
+pub fn quantile_name(quantile: f64) -> String {
+    let percent = format!("{:.4}", quantile * 100.0);
+    let percent = percent.trim_end_matches('0').trim_end_matches('.');
+    format!("p{}", percent.replace('.', ""))
+}
+
 /// A predefined export strategy reporting the average value for every non-marker metric.
-/// Marker metrics export their hit count instead.
+/// Marker metrics export their hit count instead, set metrics their number of distinct values.
 /// Since there is only one stat per metric, there is no risk of collision
 /// and so exported stats copy their metric's name.
 #[allow(dead_code)]
@@ -58,6 +113,10 @@ pub fn stats_average(
             ScoreType::Count(count) => Some((InputKind::Counter, name, count)),
             _ => None,
         },
+        InputKind::Set => match score {
+            ScoreType::Unique(unique) => Some((InputKind::Gauge, name, unique)),
+            _ => None,
+        },
         _ => match score {
             ScoreType::Mean(avg) => Some((InputKind::Gauge, name, avg.round() as MetricValue)),
             _ => None,
@@ -66,9 +125,10 @@ pub fn stats_average(
 }
 
 /// A predefined single-stat-per-metric export strategy:
-/// - Timers and Counters each export their sums
+/// - Timers, Counters and custom kinds each export their sums
 /// - Markers each export their hit count
 /// - Gauges each export their average
+/// - Sets each export their number of distinct values
 /// Since there is only one stat per metric, there is no risk of collision
 /// and so exported stats copy their metric's name.
 #[allow(dead_code)]
@@ -82,7 +142,7 @@ pub fn stats_summary(
             ScoreType::Count(count) => Some((InputKind::Counter, name, count)),
             _ => None,
         },
-        InputKind::Counter | InputKind::Timer => match score {
+        InputKind::Counter | InputKind::Timer | InputKind::Custom(_) => match score {
             ScoreType::Sum(sum) => Some((kind, name, sum)),
             _ => None,
         },
@@ -90,5 +150,23 @@ pub fn stats_summary(
             ScoreType::Mean(mean) => Some((InputKind::Gauge, name, mean.round() as MetricValue)),
             _ => None,
         },
+        InputKind::Set => match score {
+            ScoreType::Unique(unique) => Some((InputKind::Gauge, name, unique)),
+            _ => None,
+        },
+        InputKind::Text => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn quantile_names() {
+        assert_eq!("p50", quantile_name(0.5));
+        assert_eq!("p99", quantile_name(0.99));
+        assert_eq!("p999", quantile_name(0.999));
+        assert_eq!("p100", quantile_name(1.0));
     }
 }
@@ -0,0 +1,187 @@
+//! Turn an output on or off at runtime, e.g. from a feature flag or an admin endpoint.
+//! While off, writing a value costs a single atomic load.
+
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::{Input, InputDyn, InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::{CachedInput, Flush, QueuedInput};
+
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::sync::Arc;
+
+/// Send metrics to an output only while a flag toggled by the application is set.
+#[derive(Clone)]
+pub struct Switch {
+    attributes: Attributes,
+    target: Arc<dyn InputDyn + Send + Sync + 'static>,
+    enabled: Arc<AtomicBool>,
+}
+
+impl Switch {
+    /// Send metrics to the output while the flag is set.
+    pub fn new<OUT: Input + Send + Sync + 'static>(target: OUT, enabled: Arc<AtomicBool>) -> Self {
+        Switch {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            enabled,
+        }
+    }
+
+    /// The flag turning the output on or off, shared by all scopes of the switch.
+    pub fn enabled(&self) -> &Arc<AtomicBool> {
+        &self.enabled
+    }
+}
+
+impl QueuedInput for Switch {}
+impl CachedInput for Switch {}
+
+impl WithAttributes for Switch {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl Input for Switch {
+    type SCOPE = SwitchScope;
+
+    fn metrics(&self) -> Self::SCOPE {
+        SwitchScope {
+            attributes: self.attributes.clone(),
+            target: self.target.input_dyn(),
+            enabled: self.enabled.clone(),
+            flushed_enabled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+}
+
+/// Send metrics to a scope only while a flag toggled by the application is set.
+/// Values written while the flag is clear are discarded, and the scope is only flushed once after switching off.
+#[derive(Clone)]
+pub struct SwitchScope {
+    attributes: Attributes,
+    target: Arc<dyn InputScope + Send + Sync + 'static>,
+    enabled: Arc<AtomicBool>,
+    /// Whether the flag was set upon the last flush.
+    flushed_enabled: Arc<AtomicBool>,
+}
+
+impl SwitchScope {
+    /// Send metrics to the scope while the flag is set.
+    pub fn new<SC: InputScope + Send + Sync + 'static>(
+        target: SC,
+        enabled: Arc<AtomicBool>,
+    ) -> Self {
+        SwitchScope {
+            attributes: Attributes::default(),
+            target: Arc::new(target),
+            enabled,
+            flushed_enabled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// The flag turning the scope on or off.
+    pub fn enabled(&self) -> &Arc<AtomicBool> {
+        &self.enabled
+    }
+
+    /// The scope switched on and off.
+    pub(crate) fn target(&self) -> &Arc<dyn InputScope + Send + Sync + 'static> {
+        &self.target
+    }
+
+    /// Switch the scope off, discarding values written until resumed.
+    /// The values written before are flushed upon the next flush.
+    pub fn pause(&self) {
+        self.enabled.store(false, Relaxed)
+    }
+
+    /// Switch the scope back on.
+    pub fn resume(&self) {
+        self.enabled.store(true, Relaxed)
+    }
+}
+
+impl WithAttributes for SwitchScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for SwitchScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let target = self.target.new_metric(name.clone(), kind);
+        let enabled = self.enabled.clone();
+        InputMetric::gated(MetricId::forge("switch", name), target, move |_weight| {
+            enabled.load(Relaxed)
+        })
+    }
+}
+
+impl Flush for SwitchScope {
+    /// Flush the target scope, unless switched off.
+    /// The first flush after switching off still flushes the values written before.
+    fn flush(&self) -> io::Result<()> {
+        let enabled = self.enabled.load(Relaxed);
+        if !self.flushed_enabled.swap(enabled, Relaxed) && !enabled {
+            return Ok(());
+        }
+        self.notify_flush_listeners();
+        self.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+    use crate::testing::Recorder;
+
+    #[test]
+    fn toggle_output() {
+        let map = StatsMapScope::default();
+        let enabled = Arc::new(AtomicBool::new(false));
+        let metrics = SwitchScope::new(map.clone(), enabled.clone());
+        let counter = metrics.counter("requests");
+
+        counter.count(1);
+        enabled.store(true, Relaxed);
+        counter.count(2);
+        metrics.gauge("late").value(3);
+        enabled.store(false, Relaxed);
+        counter.count(4);
+        metrics.resume();
+        counter.count(8);
+        metrics.pause();
+        counter.count(16);
+
+        let map = map.into_map();
+        assert_eq!(map["requests"], 8);
+        assert_eq!(map["late"], 3);
+    }
+
+    #[test]
+    fn flush_once_switched_off() {
+        let target = Recorder::default();
+        let metrics = SwitchScope::new(target.clone(), Arc::new(AtomicBool::new(false)));
+        metrics.flush().unwrap();
+        assert_eq!(0, target.flushes());
+
+        metrics.resume();
+        metrics.flush().unwrap();
+        metrics.pause();
+        metrics.flush().unwrap();
+        assert_eq!(2, target.flushes());
+        metrics.flush().unwrap();
+        assert_eq!(2, target.flushes());
+    }
+}
@@ -0,0 +1,242 @@
+//! A t-digest sketch for estimating quantiles of a value distribution.
+//! Kept here for low dependency count.
+//! See Dunning & Ertl, "Computing Extremely Accurate Quantiles Using t-Digests".
+
+use std::cmp::Ordering;
+use std::f64::consts::PI;
+
+/// A cluster of nearby values summarized by their mean and count.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Centroid {
+    /// Mean of the values in the cluster.
+    pub mean: f64,
+    /// Number of values in the cluster.
+    pub weight: f64,
+}
+
+/// A compact, mergeable summary of a value distribution.
+/// Quantile estimates are most accurate near the extremes (e.g. p99, p999).
+/// Digests built separately (other threads, other processes) can be merged without loss of accuracy.
+#[derive(Debug, Clone)]
+pub struct TDigest {
+    compression: f64,
+    centroids: Vec<Centroid>,
+    buffer: Vec<Centroid>,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    /// Create an empty digest.
+    /// Higher compression means more centroids kept, more accuracy and more memory.
+    /// A compression of 100 is a reasonable default.
+    pub fn new(compression: f64) -> Self {
+        assert!(compression > 0.0, "TDigest compression must be positive");
+        TDigest {
+            compression,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    /// Rebuild a digest from centroids obtained from another digest, e.g. in another process.
+    pub fn from_centroids<I: IntoIterator<Item = Centroid>>(
+        compression: f64,
+        centroids: I,
+    ) -> Self {
+        let mut digest = TDigest::new(compression);
+        for centroid in centroids {
+            digest.push(centroid);
+        }
+        digest.compress();
+        digest
+    }
+
+    /// Returns the compression factor of this digest.
+    pub fn compression(&self) -> f64 {
+        self.compression
+    }
+
+    /// Add a single value to the digest.
+    pub fn add(&mut self, value: f64) {
+        self.push(Centroid {
+            mean: value,
+            weight: 1.0,
+        })
+    }
+
+    /// Add a value observed `weight` times to the digest.
+    pub fn add_weighted(&mut self, value: f64, weight: f64) {
+        self.push(Centroid {
+            mean: value,
+            weight,
+        })
+    }
+
+    /// Add all values summarized by the other digest to this digest.
+    pub fn merge(&mut self, other: &TDigest) {
+        for centroid in other.centroids.iter().chain(other.buffer.iter()) {
+            self.push(*centroid);
+        }
+    }
+
+    /// Returns the number of values added to the digest.
+    pub fn count(&self) -> f64 {
+        self.centroids
+            .iter()
+            .chain(self.buffer.iter())
+            .map(|c| c.weight)
+            .sum()
+    }
+
+    /// Returns true if no values were added to the digest.
+    pub fn is_empty(&self) -> bool {
+        self.centroids.is_empty() && self.buffer.is_empty()
+    }
+
+    /// Returns the summarized distribution as a list of centroids ordered by mean.
+    pub fn centroids(&self) -> Vec<Centroid> {
+        self.compressed().centroids
+    }
+
+    /// Estimate the value below which the requested fraction (0.0 to 1.0) of values fall.
+    /// Returns `None` if the digest is empty.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        if !self.buffer.is_empty() {
+            return self.compressed().quantile(quantile);
+        }
+        let centroids = &self.centroids;
+        match centroids.len() {
+            0 => return None,
+            1 => return Some(centroids[0].mean),
+            _ => {}
+        }
+
+        let total = self.count();
+        let target = quantile.clamp(0.0, 1.0) * total;
+
+        // each centroid's mean sits at the middle of its weight
+        let first = centroids[0];
+        if target < first.weight / 2.0 {
+            let fraction = target / (first.weight / 2.0);
+            return Some(self.min + fraction * (first.mean - self.min));
+        }
+
+        let mut cumulative = first.weight / 2.0;
+        for pair in centroids.windows(2) {
+            let span = (pair[0].weight + pair[1].weight) / 2.0;
+            if target < cumulative + span {
+                let fraction = (target - cumulative) / span;
+                return Some(pair[0].mean + fraction * (pair[1].mean - pair[0].mean));
+            }
+            cumulative += span;
+        }
+
+        let last = centroids[centroids.len() - 1];
+        let fraction = ((target - cumulative) / (last.weight / 2.0)).min(1.0);
+        Some(last.mean + fraction * (self.max - last.mean))
+    }
+
+    fn push(&mut self, centroid: Centroid) {
+        self.min = self.min.min(centroid.mean);
+        self.max = self.max.max(centroid.mean);
+        self.buffer.push(centroid);
+        if self.buffer.len() as f64 > self.compression * 5.0 {
+            self.compress();
+        }
+    }
+
+    fn compressed(&self) -> TDigest {
+        let mut digest = self.clone();
+        digest.compress();
+        digest
+    }
+
+    /// Merge buffered values into centroids, keeping centroids small near the extremes.
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut all = Vec::with_capacity(self.centroids.len() + self.buffer.len());
+        all.append(&mut self.centroids);
+        all.append(&mut self.buffer);
+        all.sort_by(|a, b| a.mean.partial_cmp(&b.mean).unwrap_or(Ordering::Equal));
+
+        let total: f64 = all.iter().map(|c| c.weight).sum();
+        let mut merged = Vec::with_capacity(all.len());
+        let mut all = all.into_iter();
+        let mut current = all.next().expect("some centroids");
+        let mut weight_before = 0.0;
+
+        for next in all {
+            let q_left = weight_before / total;
+            let q_right = (weight_before + current.weight + next.weight) / total;
+            if self.scale(q_right) - self.scale(q_left) <= 1.0 {
+                let weight = current.weight + next.weight;
+                current.mean += (next.mean - current.mean) * next.weight / weight;
+                current.weight = weight;
+            } else {
+                weight_before += current.weight;
+                merged.push(current);
+                current = next;
+            }
+        }
+        merged.push(current);
+        self.centroids = merged;
+    }
+
+    /// The k1 scale function, mapping quantiles to centroid index space.
+    fn scale(&self, quantile: f64) -> f64 {
+        self.compression / (2.0 * PI) * (2.0 * quantile - 1.0).asin()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn assert_near(expected: f64, actual: Option<f64>, tolerance: f64) {
+        let actual = actual.expect("quantile");
+        assert!(
+            (expected - actual).abs() <= tolerance,
+            "expected {} but got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn empty_digest() {
+        assert_eq!(None, TDigest::new(100.0).quantile(0.5));
+    }
+
+    #[test]
+    fn uniform_quantiles() {
+        let mut digest = TDigest::new(100.0);
+        for i in 1..=10_000 {
+            digest.add(f64::from(i));
+        }
+        assert_eq!(10_000.0, digest.count());
+        assert!(digest.centroids().len() < 200);
+        assert_near(5_000.0, digest.quantile(0.5), 50.0);
+        assert_near(9_900.0, digest.quantile(0.99), 10.0);
+        assert_near(1.0, digest.quantile(0.0), 0.0);
+        assert_near(10_000.0, digest.quantile(1.0), 0.0);
+    }
+
+    #[test]
+    fn merged_digests() {
+        let mut low = TDigest::new(100.0);
+        let mut high = TDigest::new(100.0);
+        for i in 1..=5_000 {
+            low.add(f64::from(i));
+            high.add(f64::from(i + 5_000));
+        }
+        low.merge(&TDigest::from_centroids(100.0, high.centroids()));
+        assert_eq!(10_000.0, low.count());
+        assert_near(5_000.0, low.quantile(0.5), 50.0);
+        assert_near(9_900.0, low.quantile(0.99), 10.0);
+    }
+}
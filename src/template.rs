@@ -0,0 +1,169 @@
+//! Metric names with placeholders, e.g. `worker_{id}.latency`, defined once and bound per instance.
+//! Bound metrics are cached by their placeholder values, hot paths neither format names nor redefine metrics.
+
+use crate::input::{define, InputKind, InputMetric, InputScope};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// A literal part of a template or a placeholder to fill.
+#[derive(Debug, Clone)]
+enum TemplatePart {
+    Literal(String),
+    Placeholder(String),
+}
+
+/// Bound metrics and their placeholder values, by hash of the values.
+type Bound<M> = HashMap<u64, Vec<(Vec<String>, M)>>;
+
+/// A metric name with placeholders, e.g. `worker_{id}.latency`, bound to values at runtime.
+/// Each distinct set of values defines a metric once, which is then reused.
+/// Bound metrics are kept as long as the template, there should be a bounded number of distinct values.
+#[derive(Clone)]
+pub struct MetricTemplate<M> {
+    scope: Arc<dyn InputScope + Send + Sync>,
+    kind: InputKind,
+    parts: Arc<Vec<TemplatePart>>,
+    bound: Arc<RwLock<Bound<M>>>,
+}
+
+impl<M: From<InputMetric> + Clone> MetricTemplate<M> {
+    /// Define a metric template in a scope. Placeholders are names enclosed in braces.
+    pub fn new<S: InputScope + Send + Sync + 'static>(
+        scope: S,
+        template: &str,
+        kind: InputKind,
+    ) -> Self {
+        MetricTemplate {
+            scope: Arc::new(scope),
+            kind,
+            parts: Arc::new(parse(template)),
+            bound: Arc::new(RwLock::new(HashMap::new())),
+        }
+    }
+
+    /// Names of the template's placeholders, in order of appearance.
+    pub fn placeholders(&self) -> Vec<&str> {
+        self.parts
+            .iter()
+            .filter_map(|part| match part {
+                TemplatePart::Placeholder(name) => Some(name.as_str()),
+                TemplatePart::Literal(_) => None,
+            })
+            .collect()
+    }
+
+    /// Return the metric named by filling the placeholders with the values, in order of appearance.
+    /// The metric is defined on first use of these values only.
+    /// Panics if the number of values does not match the number of placeholders.
+    pub fn bind(&self, values: &[&str]) -> M {
+        let mut hasher = DefaultHasher::new();
+        values.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        if let Some(metric) = find(read_lock!(self.bound).get(&hash), values) {
+            return metric;
+        }
+        let name = self.fill(values);
+        let mut bound = write_lock!(self.bound);
+        let same_hash = bound.entry(hash).or_default();
+        // may have been bound concurrently
+        if let Some(metric) = find(Some(same_hash), values) {
+            return metric;
+        }
+        let metric: M = define(&*self.scope, &name, self.kind).into();
+        same_hash.push((
+            values.iter().map(|value| value.to_string()).collect(),
+            metric.clone(),
+        ));
+        metric
+    }
+
+    fn fill(&self, values: &[&str]) -> String {
+        let mut values = values.iter();
+        let mut name = String::new();
+        for part in self.parts.iter() {
+            match part {
+                TemplatePart::Literal(literal) => name.push_str(literal),
+                TemplatePart::Placeholder(placeholder) => match values.next() {
+                    Some(value) => name.push_str(value),
+                    None => panic!("No value for metric template placeholder '{}'", placeholder),
+                },
+            }
+        }
+        if values.next().is_some() {
+            panic!("More values than metric template placeholders")
+        }
+        name
+    }
+}
+
+fn find<M: Clone>(same_hash: Option<&Vec<(Vec<String>, M)>>, values: &[&str]) -> Option<M> {
+    same_hash?
+        .iter()
+        .find(|(bound_values, _)| bound_values.iter().eq(values.iter().copied()))
+        .map(|(_, metric)| metric.clone())
+}
+
+/// Split a template into literals and placeholders. An unclosed brace is kept as literal.
+fn parse(template: &str) -> Vec<TemplatePart> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        match rest[open..].find('}') {
+            Some(close) => {
+                if open > 0 {
+                    parts.push(TemplatePart::Literal(rest[..open].to_string()));
+                }
+                let placeholder = &rest[open + 1..open + close];
+                parts.push(TemplatePart::Placeholder(placeholder.to_string()));
+                rest = &rest[open + close + 1..];
+            }
+            None => break,
+        }
+    }
+    if !rest.is_empty() {
+        parts.push(TemplatePart::Literal(rest.to_string()));
+    }
+    parts
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::input::Timer;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn bind_once() {
+        let map = StatsMapScope::default();
+        let template: MetricTemplate<Timer> =
+            map.template("worker_{id}.{op}_latency", InputKind::Timer);
+        assert_eq!(vec!["id", "op"], template.placeholders());
+
+        template.bind(&["3", "read"]).interval_us(10);
+        template.bind(&["3", "read"]).interval_us(20);
+        template.bind(&["4", "read"]).interval_us(30);
+        assert_eq!(2, read_lock!(template.bound).len());
+
+        let map = map.into_map();
+        assert_eq!(20, map["worker_3.read_latency"]);
+        assert_eq!(30, map["worker_4.read_latency"]);
+    }
+
+    #[test]
+    #[should_panic]
+    fn missing_value() {
+        let template: MetricTemplate<Timer> =
+            StatsMapScope::default().template("worker_{id}.latency", InputKind::Timer);
+        template.bind(&[]);
+    }
+}
@@ -0,0 +1,125 @@
+//! Scopes standing in for real outputs in tests.
+
+use crate::attributes::{Attributes, MetricId, WithAttributes};
+use crate::input::{Input, InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::output::map::StatsMapScope;
+use crate::{Flush, MetricValue};
+
+use std::io;
+use std::sync::atomic::Ordering::SeqCst;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::{Arc, Mutex};
+
+/// Records written values and counts flushes, shared by its clones.
+#[derive(Clone, Default)]
+pub(crate) struct Recorder {
+    attributes: Attributes,
+    values: Arc<Mutex<Vec<MetricValue>>>,
+    floats: Arc<Mutex<Vec<f64>>>,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl Recorder {
+    /// Values written so far, in order.
+    pub(crate) fn values(&self) -> Vec<MetricValue> {
+        self.values.lock().unwrap().clone()
+    }
+
+    /// Floating point values written so far, in order.
+    pub(crate) fn floats(&self) -> Vec<f64> {
+        self.floats.lock().unwrap().clone()
+    }
+
+    /// Number of flushes so far.
+    pub(crate) fn flushes(&self) -> usize {
+        self.flushes.load(SeqCst)
+    }
+}
+
+impl InputScope for Recorder {
+    fn new_metric(&self, name: MetricName, _kind: InputKind) -> InputMetric {
+        let values = self.values.clone();
+        let floats = self.floats.clone();
+        InputMetric::new(MetricId::forge("recorder", name), move |value, _labels| {
+            values.lock().unwrap().push(value)
+        })
+        .with_float(move |value, _labels| floats.lock().unwrap().push(value))
+    }
+}
+
+/// Scopes opened from a recorder share its values, e.g. when it drains a bucket.
+impl Input for Recorder {
+    type SCOPE = Recorder;
+
+    fn metrics(&self) -> Self::SCOPE {
+        self.clone()
+    }
+}
+
+impl Flush for Recorder {
+    fn flush(&self) -> io::Result<()> {
+        self.flushes.fetch_add(1, SeqCst);
+        Ok(())
+    }
+}
+
+impl WithAttributes for Recorder {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+/// Writes values to a map, failing to flush while set to fail.
+#[derive(Clone, Default)]
+pub(crate) struct FlakyScope {
+    attributes: Attributes,
+    map: StatsMapScope,
+    failing: Arc<AtomicBool>,
+}
+
+impl FlakyScope {
+    /// A scope failing to flush until told otherwise.
+    pub(crate) fn failing() -> Self {
+        let scope = FlakyScope::default();
+        scope.set_failing(true);
+        scope
+    }
+
+    /// Make flushes fail, or succeed again.
+    pub(crate) fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, SeqCst)
+    }
+
+    /// The map receiving written values.
+    pub(crate) fn map(&self) -> StatsMapScope {
+        self.map.clone()
+    }
+}
+
+impl InputScope for FlakyScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        self.map.new_metric(name, kind)
+    }
+}
+
+impl Flush for FlakyScope {
+    fn flush(&self) -> io::Result<()> {
+        if self.failing.load(SeqCst) {
+            return Err(io::Error::other("failing"));
+        }
+        Ok(())
+    }
+}
+
+impl WithAttributes for FlakyScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
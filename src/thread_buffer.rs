@@ -0,0 +1,347 @@
+//! Buffer writes in thread-local buckets, merged into a shared bucket every so many writes or so much time.
+//! Per-write atomic updates of the shared bucket become amortized merges, for extreme throughput cases.
+
+use crate::atomic::AtomicBucket;
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::clock::TimeHandle;
+use crate::input::{InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::scheduler::{Cancel, CancelHandle, SCHEDULER};
+use crate::{Flush, MetricValue};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, Weak};
+use std::time::Duration;
+
+#[cfg(not(feature = "parking_lot"))]
+use std::sync::RwLock;
+
+#[cfg(feature = "parking_lot")]
+use parking_lot::RwLock;
+
+/// Source of the ids of thread buffer scopes, each having its own buffer in every thread.
+static BUFFER_ID: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    /// This thread's buffers, by scope id.
+    static BUFFERS: RefCell<HashMap<usize, ThreadBuffer>> = RefCell::new(HashMap::new());
+}
+
+/// The current thread's buffer of a scope.
+struct ThreadBuffer {
+    /// Tells if the scope is gone, its buffer then being of no further use
+    shared: Weak<Shared>,
+    buffer: Arc<Mutex<LocalBuffer>>,
+}
+
+/// A thread's values of a scope, not yet merged into the target bucket.
+/// Locked by the writing thread, or by a flush merging the buffers of every thread.
+struct LocalBuffer {
+    bucket: AtomicBucket,
+    target: AtomicBucket,
+    /// Metrics of the local bucket, by index of their definition in the scope
+    metrics: Vec<Option<InputMetric>>,
+    writes: usize,
+    since: TimeHandle,
+}
+
+impl LocalBuffer {
+    fn write(
+        &mut self,
+        shared: &Shared,
+        index: usize,
+        name: &MetricName,
+        kind: InputKind,
+        value: MetricValue,
+    ) {
+        if self.metrics.len() <= index {
+            self.metrics.resize(index + 1, None);
+        }
+        let bucket = &self.bucket;
+        self.metrics[index]
+            .get_or_insert_with(|| bucket.new_metric(name.clone(), kind))
+            .write(value, labels![]);
+        self.writes += 1;
+        if self.writes >= shared.max_writes
+            || self.since.elapsed_us() >= shared.max_delay.as_micros() as u64
+        {
+            self.merge()
+        }
+    }
+
+    /// Move buffered values to the target bucket.
+    fn merge(&mut self) {
+        self.target.absorb(self.bucket.snapshot());
+        self.writes = 0;
+        self.since = TimeHandle::now();
+    }
+}
+
+impl Drop for LocalBuffer {
+    /// Merge the values left when the thread exits.
+    fn drop(&mut self) {
+        if self.writes > 0 {
+            self.merge()
+        }
+    }
+}
+
+/// State shared by clones of a scope.
+struct Shared {
+    id: usize,
+    target: AtomicBucket,
+    max_writes: usize,
+    max_delay: Duration,
+    /// Index of each metric defined, by full name and kind
+    indexes: RwLock<HashMap<(MetricName, InputKind), usize>>,
+    /// Buffers held by threads, merged upon flush even if their thread stopped writing.
+    buffers: Mutex<Vec<Weak<Mutex<LocalBuffer>>>>,
+    /// Merges the threads' buffers every max delay.
+    collector: Mutex<Option<CancelHandle>>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Some(collector) = self
+            .collector
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+        {
+            collector.cancel()
+        }
+    }
+}
+
+impl Shared {
+    /// Returns the current thread's buffer, registering a new one on first use.
+    fn thread_buffer(
+        self: &Arc<Self>,
+        buffers: &mut HashMap<usize, ThreadBuffer>,
+    ) -> Arc<Mutex<LocalBuffer>> {
+        if let Some(buffer) = buffers.get(&self.id) {
+            return buffer.buffer.clone();
+        }
+        // forget the buffers of dropped scopes, their values are merged as they drop
+        buffers.retain(|_, buffer| buffer.shared.strong_count() > 0);
+        let buffer = Arc::new(Mutex::new(LocalBuffer {
+            bucket: self.target.sibling(),
+            target: self.target.clone(),
+            metrics: Vec::new(),
+            writes: 0,
+            since: TimeHandle::now(),
+        }));
+        let mut registered = self.buffers.lock().unwrap_or_else(PoisonError::into_inner);
+        registered.retain(|buffer| buffer.strong_count() > 0);
+        registered.push(Arc::downgrade(&buffer));
+        buffers.insert(
+            self.id,
+            ThreadBuffer {
+                shared: Arc::downgrade(self),
+                buffer: buffer.clone(),
+            },
+        );
+        buffer
+    }
+
+    /// Merge the buffered values of every thread into the target bucket.
+    fn merge_all(&self) {
+        let buffers: Vec<Arc<Mutex<LocalBuffer>>> = self
+            .buffers
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+        for buffer in buffers {
+            let mut buffer = buffer.lock().unwrap_or_else(PoisonError::into_inner);
+            if buffer.writes > 0 {
+                buffer.merge()
+            }
+        }
+    }
+
+    fn index(&self, name: &MetricName, kind: InputKind) -> usize {
+        let key = (name.clone(), kind);
+        if let Some(index) = read_lock!(self.indexes).get(&key) {
+            return *index;
+        }
+        let mut indexes = write_lock!(self.indexes);
+        let next = indexes.len();
+        *indexes.entry(key).or_insert(next)
+    }
+}
+
+/// Scope buffering writes in thread-local buckets, merged into a shared bucket
+/// every `max_writes` writes or `max_delay` elapsed, whichever comes first.
+/// Values of threads that stop writing are merged at most `max_delay` after their last write.
+/// Flushing the scope merges the values of every thread before flushing the target.
+/// Buffered values are aggregated by name only. If the target aggregates or ranks labeled variants
+/// (see `AtomicBucket::labeled_variants` and `AtomicBucket::top_k`), labeled values are written to it directly.
+#[derive(Clone)]
+pub struct ThreadBufferScope {
+    attributes: Attributes,
+    shared: Arc<Shared>,
+}
+
+impl ThreadBufferScope {
+    /// Buffer writes to a bucket in thread-local buckets.
+    pub fn wrap(target: AtomicBucket, max_writes: usize, max_delay: Duration) -> Self {
+        let shared = Arc::new(Shared {
+            id: BUFFER_ID.fetch_add(1, Ordering::Relaxed),
+            target,
+            max_writes,
+            max_delay,
+            indexes: RwLock::new(HashMap::new()),
+            buffers: Mutex::new(Vec::new()),
+            collector: Mutex::new(None),
+        });
+        let weak = Arc::downgrade(&shared);
+        let collector = SCHEDULER.schedule(max_delay, move |_| {
+            if let Some(shared) = weak.upgrade() {
+                shared.merge_all()
+            }
+        });
+        *shared
+            .collector
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(collector);
+        ThreadBufferScope {
+            attributes: Attributes::default(),
+            shared,
+        }
+    }
+
+    /// Merge the buffered values of every thread into the target bucket.
+    pub fn merge(&self) {
+        self.shared.merge_all()
+    }
+}
+
+impl WithAttributes for ThreadBufferScope {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+impl InputScope for ThreadBufferScope {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        // defined in the target too, keeping the metric from being purged while in use
+        let direct = self.shared.target.new_metric(name.clone(), kind);
+        if kind == InputKind::Text {
+            return direct;
+        }
+        // buffered values are absorbed as is, the target's prefix is applied here
+        let full_name = self.shared.target.prefix_append(name.clone());
+        let index = self.shared.index(&full_name, kind);
+        let keeps_labels = self.shared.target.keeps_labels();
+        let shared = self.shared.clone();
+        InputMetric::new(
+            MetricId::forge("thread_buffer", name),
+            move |value, labels| {
+                if keeps_labels && labels.digest().1 > 0 {
+                    // buffered values would lose their labels
+                    return direct.write(value, labels);
+                }
+                let buffered = BUFFERS.try_with(|buffers| {
+                    let buffer = shared.thread_buffer(&mut buffers.borrow_mut());
+                    let mut buffer = buffer.lock().unwrap_or_else(PoisonError::into_inner);
+                    buffer.write(&shared, index, &full_name, kind, value)
+                });
+                if buffered.is_err() {
+                    // the thread is exiting, its buffers are gone
+                    direct.write(value, labels)
+                }
+            },
+        )
+    }
+}
+
+impl Flush for ThreadBufferScope {
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        self.merge();
+        self.shared.target.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::clock::{mock_clock_advance, mock_clock_reset};
+    use crate::label::test::TEST_SEQUENCE;
+    use crate::stats::stats_all;
+
+    use std::sync::mpsc;
+    use std::thread;
+
+    #[test]
+    fn merge_every_n_writes_or_delay() {
+        mock_clock_reset();
+        let bucket = AtomicBucket::new();
+        bucket.stats(stats_all);
+        let buffered = ThreadBufferScope::wrap(bucket.clone(), 3, Duration::from_secs(1));
+        let counter = buffered.counter("hits");
+
+        counter.count(1);
+        counter.count(2);
+        assert_eq!(bucket.peek().get("hits.count"), None);
+        counter.count(3);
+        assert_eq!(bucket.peek()["hits.sum"], 6);
+
+        counter.count(4);
+        mock_clock_advance(Duration::from_secs(1));
+        counter.count(5);
+        assert_eq!(bucket.peek()["hits.sum"], 15);
+
+        counter.count(6);
+        buffered.merge();
+        assert_eq!(bucket.peek()["hits.count"], 6);
+    }
+    #[test]
+    fn flush_merges_idle_threads() {
+        let bucket = AtomicBucket::new();
+        bucket.stats(stats_all);
+        let buffered = ThreadBufferScope::wrap(bucket.clone(), 100, Duration::from_secs(60));
+        let counter = buffered.counter("hits");
+        let (written, wait_written) = mpsc::channel();
+        let (done, wait_done) = mpsc::channel::<()>();
+        let writer = thread::spawn(move || {
+            counter.count(1);
+            counter.count(2);
+            written.send(()).unwrap();
+            // stay alive, holding the buffer
+            let _ = wait_done.recv();
+        });
+
+        wait_written.recv().unwrap();
+        buffered.merge();
+        assert_eq!(bucket.peek()["hits.sum"], 3);
+        done.send(()).unwrap();
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn labeled_values_kept() {
+        let _lock = TEST_SEQUENCE.lock().expect("Test Sequence");
+        let bucket = AtomicBucket::new();
+        bucket.stats(stats_all);
+        bucket.labeled_variants(10);
+        let buffered = ThreadBufferScope::wrap(bucket.clone(), 100, Duration::from_secs(60));
+        let counter = buffered.new_metric("hits".into(), InputKind::Counter);
+        counter.write(1, labels![]);
+        assert_eq!(bucket.peek().get("hits.count"), None);
+        // not buffered, keeping its labels
+        counter.write(2, labels!("status" => "500"));
+        assert_eq!(bucket.peek()["hits.sum"], 2);
+        buffered.merge();
+        assert_eq!(bucket.peek()["hits.count"], 1);
+    }
+}
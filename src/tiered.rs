@@ -0,0 +1,111 @@
+//! Aggregate the same metrics at multiple resolutions.
+
+use crate::atomic::AtomicBucket;
+use crate::attributes::{Attributes, MetricId, OnFlush, Prefixed, WithAttributes};
+use crate::input::{InputKind, InputMetric, InputScope};
+use crate::name::MetricName;
+use crate::scheduler::{CancelHandle, ScheduleFlush};
+use crate::Flush;
+
+use std::io;
+use std::time::Duration;
+
+/// A bucket of buckets, each aggregating every metric over its own period.
+/// Each tier is flushed on its own schedule to its own drain,
+/// e.g. ten seconds resolution to short-retention storage and hourly resolution to long-term storage.
+#[derive(Clone, Default)]
+pub struct TieredBucket {
+    attributes: Attributes,
+    tiers: Vec<(Duration, AtomicBucket)>,
+}
+
+impl TieredBucket {
+    /// Create a new tiered bucket with no tiers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a clone of the tiered bucket with the new tier added.
+    /// The tier bucket's drain and statistics should be set beforehand.
+    pub fn add_tier(&self, period: Duration, bucket: AtomicBucket) -> Self {
+        let mut cloned = self.clone();
+        cloned.tiers.push((period, bucket));
+        cloned
+    }
+
+    /// Start flushing each tier at regular intervals of its own period.
+    /// Returns a handle for each tier, in the order the tiers were added.
+    pub fn flush_tiers(&self) -> Vec<CancelHandle> {
+        self.tiers
+            .iter()
+            .map(|(period, bucket)| bucket.flush_every(*period))
+            .collect()
+    }
+}
+
+impl InputScope for TieredBucket {
+    fn new_metric(&self, name: MetricName, kind: InputKind) -> InputMetric {
+        let name = self.prefix_append(name);
+        let metrics: Vec<InputMetric> = self
+            .tiers
+            .iter()
+            .map(|(_period, bucket)| bucket.new_metric(name.clone(), kind))
+            .collect();
+        InputMetric::new(MetricId::forge("tiered", name), move |value, labels| {
+            for metric in &metrics {
+                metric.write(value, labels.clone())
+            }
+        })
+    }
+}
+
+impl Flush for TieredBucket {
+    /// Flush all tiers at once, regardless of their periods.
+    fn flush(&self) -> io::Result<()> {
+        self.notify_flush_listeners();
+        for (_period, bucket) in &self.tiers {
+            bucket.flush()?;
+        }
+        Ok(())
+    }
+}
+
+impl WithAttributes for TieredBucket {
+    fn get_attributes(&self) -> &Attributes {
+        &self.attributes
+    }
+    fn mut_attributes(&mut self) -> &mut Attributes {
+        &mut self.attributes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::map::StatsMapScope;
+
+    #[test]
+    fn tiers_aggregate_separately() {
+        let fine = AtomicBucket::new();
+        let coarse = AtomicBucket::new();
+        let tiered = TieredBucket::new()
+            .add_tier(Duration::from_secs(10), fine.clone())
+            .add_tier(Duration::from_secs(60), coarse.clone())
+            .named("test");
+        let counter = tiered.counter("counter_a");
+
+        counter.count(3);
+        let map = StatsMapScope::default();
+        fine.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.counter_a"], 3);
+
+        counter.count(4);
+        let map = StatsMapScope::default();
+        fine.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.counter_a"], 4);
+
+        let map = StatsMapScope::default();
+        coarse.flush_to(&map).unwrap();
+        assert_eq!(map.into_map()["test.counter_a"], 7);
+    }
+}
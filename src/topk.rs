@@ -0,0 +1,123 @@
+//! A space-saving sketch for finding the heaviest keys of a stream (heavy hitters) in bounded memory.
+//! Kept here for low dependency count.
+//! See Metwally et al., "Efficient Computation of Frequent and Top-k Elements in Data Streams".
+
+use crate::MetricValue;
+
+use std::collections::HashMap;
+
+/// Tracks the keys with the highest total weight using a fixed number of counters.
+/// Keys with a total weight above the lightest counter are always tracked,
+/// but their weight can be overestimated by at most the error reported with them.
+#[derive(Debug, Clone)]
+pub struct SpaceSaving {
+    capacity: usize,
+    /// Total weight and maximum overestimation of every tracked key
+    counters: HashMap<String, (MetricValue, MetricValue)>,
+}
+
+impl SpaceSaving {
+    /// Create an empty sketch with the specified number of counters.
+    /// Using a few times more counters than the number of top keys required improves accuracy.
+    /// A sketch without counters tracks no key.
+    pub fn new(capacity: usize) -> Self {
+        SpaceSaving {
+            capacity,
+            counters: HashMap::with_capacity(capacity),
+        }
+    }
+
+    /// Add the weight to the key's total. Weights below one are ignored.
+    /// If all counters are taken, the lightest key is evicted and the new key inherits its weight as error.
+    pub fn insert(&mut self, key: &str, weight: MetricValue) {
+        if weight < 1 {
+            return;
+        }
+        if let Some(counter) = self.counters.get_mut(key) {
+            counter.0 = counter.0.saturating_add(weight);
+            return;
+        }
+        if self.counters.len() < self.capacity {
+            self.counters.insert(key.to_string(), (weight, 0));
+            return;
+        }
+        // linear scan, capacity is expected to stay small
+        let lightest = self
+            .counters
+            .iter()
+            .min_by_key(|(_, counter)| counter.0)
+            .map(|(key, counter)| (key.clone(), counter.0));
+        if let Some((evicted, min)) = lightest {
+            self.counters.remove(&evicted);
+            self.counters
+                .insert(key.to_string(), (min.saturating_add(weight), min));
+        }
+    }
+
+    /// Returns true if nothing was added to the sketch.
+    pub fn is_empty(&self) -> bool {
+        self.counters.is_empty()
+    }
+
+    /// Returns up to `k` of the heaviest keys with their estimated total weight and error, heaviest first.
+    pub fn top(&self, k: usize) -> Vec<(String, MetricValue, MetricValue)> {
+        let mut top: Vec<(String, MetricValue, MetricValue)> = self
+            .counters
+            .iter()
+            .map(|(key, counter)| (key.clone(), counter.0, counter.1))
+            .collect();
+        top.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top.truncate(k);
+        top
+    }
+
+    /// Forget all keys.
+    pub fn clear(&mut self) {
+        self.counters.clear()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn heavy_hitters() {
+        let mut sketch = SpaceSaving::new(8);
+        assert!(sketch.is_empty());
+        for i in 0..1_000 {
+            // a few heavy keys drowned in many light ones
+            sketch.insert(&format!("light{}", i), 1);
+            sketch.insert("heavy_a", 3);
+            if i % 2 == 0 {
+                sketch.insert("heavy_b", 3);
+            }
+        }
+        let top = sketch.top(2);
+        assert_eq!("heavy_a", top[0].0);
+        assert_eq!("heavy_b", top[1].0);
+        // heavy keys got in early and were never evicted
+        assert_eq!((3_000, 0), (top[0].1, top[0].2));
+        assert_eq!((1_500, 0), (top[1].1, top[1].2));
+    }
+
+    #[test]
+    fn no_counters() {
+        let mut sketch = SpaceSaving::new(0);
+        sketch.insert("a", 1);
+        assert!(sketch.is_empty());
+        assert!(sketch.top(1).is_empty());
+    }
+
+    #[test]
+    fn saturated_weight() {
+        let mut sketch = SpaceSaving::new(1);
+        sketch.insert("a", MetricValue::MAX);
+        sketch.insert("a", 1);
+        sketch.insert("b", 1);
+        assert_eq!(
+            vec![("b".to_string(), MetricValue::MAX, MetricValue::MAX)],
+            sketch.top(1)
+        );
+    }
+}
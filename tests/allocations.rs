@@ -0,0 +1,108 @@
+//! Count the allocations of the write path, which should not allocate for unlabeled writes.
+//! Network outputs format values in reusable buffers and should not allocate either.
+
+use dipstick::*;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+/// Counts allocations made by the current thread, leaving other tests' threads out.
+struct CountingAlloc;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+/// Returns the number of allocations made by the closure.
+fn allocations<F: FnOnce()>(f: F) -> usize {
+    let before = ALLOCATIONS.with(Cell::get);
+    f();
+    ALLOCATIONS.with(Cell::get) - before
+}
+
+#[test]
+fn unlabeled_bucket_writes_do_not_allocate() {
+    let bucket = AtomicBucket::new();
+    let counter = bucket.counter("counter");
+    let marker = bucket.marker("marker");
+    let gauge = bucket.gauge("gauge");
+    let level = bucket.level("level");
+    let timer = bucket.timer("timer");
+
+    let count = allocations(|| {
+        for i in 0..100 {
+            counter.count(i);
+            marker.mark();
+            gauge.value(i);
+            level.adjust(i);
+            timer.interval_us(i as u64);
+        }
+    });
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn unlabeled_statsd_writes_do_not_allocate() {
+    let server = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    let metrics = Statsd::send_to(server.local_addr().unwrap())
+        .unwrap()
+        .metrics();
+    let counter = metrics.counter("counter");
+    let level = metrics.level("level");
+    counter.count(1);
+    level.adjust(1);
+
+    let count = allocations(|| {
+        for i in 0..100 {
+            counter.count(i);
+            level.adjust(i);
+        }
+    });
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn unlabeled_graphite_writes_do_not_allocate() {
+    let server = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let metrics = Graphite::send_to(server.local_addr().unwrap())
+        .unwrap()
+        .metrics();
+    let counter = metrics.counter("counter");
+    let timer = metrics.timer("timer");
+    counter.count(1);
+
+    let count = allocations(|| {
+        for i in 0..100 {
+            counter.count(i);
+            timer.interval_us(i as u64);
+        }
+    });
+    assert_eq!(count, 0);
+}
+
+#[test]
+fn few_labels_allocate_less_than_many() {
+    let few = allocations(|| drop(labels!["a" => "1"]));
+    let many = allocations(|| {
+        drop(Labels::from(
+            vec![("a".to_string(), std::sync::Arc::new("1".to_string()))]
+                .into_iter()
+                .collect::<std::collections::HashMap<_, _>>(),
+        ))
+    });
+    assert!(few < many, "{} < {}", few, many);
+}